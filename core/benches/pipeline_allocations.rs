@@ -0,0 +1,124 @@
+// Heap allocations per chunk on the real push path: `AppState::push_samples` through the
+// `AudioProcessor` and the processing thread to a listener, in the default Streaming/F32
+// configuration, counted by a wrapping global allocator.
+//
+//     cargo bench -p suprasonic_core --bench pipeline_allocations
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use suprasonic_core::audio::StopReason;
+use suprasonic_core::diarization::Segment;
+use suprasonic_core::pool;
+use suprasonic_core::state::{AppState, SupraSonicError, TranscriptionListener};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// 30 ms at 16 kHz, the default processing chunk
+const CHUNK: usize = 480;
+const CHUNKS: usize = 10_000;
+// Chunks a push may run ahead of the listener
+const LAG: usize = 4;
+
+/// Counts the samples delivered; the audio itself is dropped, like a host that copies it out.
+#[derive(Clone, Default)]
+struct CountingListener {
+    samples: Arc<AtomicUsize>,
+}
+
+impl TranscriptionListener for CountingListener {
+    fn on_audio_data(&self, audio_data: Vec<f32>, _utterance_id: u64) {
+        self.samples.fetch_add(audio_data.len(), Ordering::Relaxed);
+    }
+    fn on_audio_data_i16(&self, _audio_data: Vec<i16>, _utterance_id: u64) {}
+    fn on_level_changed(&self, _level: f32) {}
+    fn on_waveform(&self, _envelope: Vec<f32>) {}
+    fn on_device_audio_data(&self, _device: String, _audio_data: Vec<f32>) {}
+    fn on_audio_skipped(&self, _reason: String) {}
+    fn on_segment(&self, _segment: Segment) {}
+    fn on_utterance_audio(&self, _audio_data: Vec<f32>, _utterance_id: u64, _is_final: bool) {}
+    fn on_features(&self, _features: Vec<f32>, _n_mels: u32, _utterance_id: u64) {}
+    fn on_error(&self, _error: SupraSonicError) {}
+    fn on_recording_stopped(&self, _reason: StopReason) {}
+    fn on_speaker_assigned(&self, _utterance_id: u64, _speaker_id: String) {}
+}
+
+/// Allocations made pushing `CHUNKS` chunks at `rate` until the listener has them all.
+fn run(state: &AppState, listener: &CountingListener, rate: u32) -> (u64, pool::PoolStats) {
+    let input_chunk = CHUNK * rate as usize / 16_000;
+    // Built up front: the push itself takes ownership, which allocates nothing
+    let pushes: Vec<Vec<f32>> = (0..CHUNKS)
+        .map(|i| (0..input_chunk).map(|n| ((n + i) % 64) as f32 / 256.0).collect())
+        .collect();
+    let delivered = listener.samples.load(Ordering::Relaxed);
+
+    let pool_before = pool::stats();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for (i, samples) in pushes.into_iter().enumerate() {
+        state.push_samples(samples, rate).expect("push");
+        // Paced like a live source: the next chunk once this one is through, give or take
+        // the resampler's latency
+        let expected = delivered + i.saturating_sub(LAG) * CHUNK;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.samples.load(Ordering::Relaxed) < expected {
+            assert!(Instant::now() < deadline, "the listener never received the pushed audio");
+            std::thread::yield_now();
+        }
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    let pool_after = pool::stats();
+    state.flush().expect("flush");
+
+    let pool = pool::PoolStats {
+        allocated: pool_after.allocated - pool_before.allocated,
+        reused: pool_after.reused - pool_before.reused,
+        pooled: pool_after.pooled,
+    };
+    (allocations, pool)
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("suprasonic-bench-{}.json", std::process::id()));
+    let state = AppState::new(path.to_string_lossy().into_owned());
+    let listener = CountingListener::default();
+    state.set_listener(Box::new(listener.clone()));
+
+    println!("{CHUNKS} chunks of {CHUNK} samples, push_samples -> listener");
+    for rate in [16_000, 48_000] {
+        // Warm up the pool and the resampler before counting
+        run(&state, &listener, rate);
+        let (allocations, pool) = run(&state, &listener, rate);
+        println!(
+            "  from {:>2} kHz  {:>6} allocations  ({:.2} per chunk; pool allocated {}, reused {})",
+            rate / 1000,
+            allocations,
+            allocations as f64 / CHUNKS as f64,
+            pool.allocated,
+            pool.reused
+        );
+    }
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,76 @@
+// Heap allocations per streamed chunk: a fresh Vec for every chunk against buffers
+// recycled through `BufferPool`, counted by a wrapping global allocator.
+//
+//     cargo bench -p suprasonic_core --bench pool_allocations
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use suprasonic_core::pool::BufferPool;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// 30 ms at 16 kHz, the default processing chunk
+const CHUNK: usize = 480;
+const CHUNKS: u64 = 10_000;
+
+/// Allocations made by `f`.
+fn allocations(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+// Stands in for a pipeline stage: fill a chunk, hand it on, let it go
+fn fill(buffer: &mut Vec<f32>, i: u64) {
+    buffer.extend((0..CHUNK).map(|n| ((n as u64 + i) % 64) as f32 / 64.0));
+    black_box(buffer.iter().sum::<f32>());
+}
+
+fn main() {
+    let fresh = allocations(|| {
+        for i in 0..CHUNKS {
+            let mut chunk = Vec::with_capacity(CHUNK);
+            fill(&mut chunk, i);
+            drop(black_box(chunk));
+        }
+    });
+
+    let pool = BufferPool::new();
+    let pooled = allocations(|| {
+        for i in 0..CHUNKS {
+            let mut chunk = pool.take(CHUNK);
+            fill(&mut chunk, i);
+            pool.recycle(black_box(chunk));
+        }
+    });
+
+    let stats = pool.stats();
+    assert_eq!(stats.allocated + stats.reused, CHUNKS, "every take is counted once");
+    assert!(pooled < fresh, "the pool should allocate less than a Vec per chunk");
+    println!("{CHUNKS} chunks of {CHUNK} samples");
+    println!("  fresh Vec per chunk  {fresh:>6} allocations");
+    println!("  BufferPool           {pooled:>6} allocations  ({} reused)", stats.reused);
+}
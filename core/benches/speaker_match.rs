@@ -0,0 +1,75 @@
+// Per-utterance speaker matching: `EmbeddingIndex` against a linear scan that scores
+// every enrolled embedding from scratch, for a few hundred speakers.
+//
+//     cargo bench -p suprasonic_core --bench speaker_match
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use suprasonic_core::diarization::{DistanceMetric, EmbeddingIndex, Speaker};
+
+const DIM: usize = 192;
+const QUERIES: usize = 64;
+
+// Deterministic pseudo-random embeddings (xorshift), so runs are comparable
+fn embeddings(count: usize, seed: u64) -> Vec<Vec<f32>> {
+    let mut state = seed | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+    };
+    (0..count).map(|_| (0..DIM).map(|_| next()).collect()).collect()
+}
+
+fn naive_best_match(speakers: &HashMap<String, Speaker>, metric: DistanceMetric, query: &[f32]) -> Option<(String, f32)> {
+    speakers
+        .values()
+        .filter_map(|s| s.embedding.as_ref().map(|e| (s.id.clone(), metric.score(e, query))))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// Mean time per query of `f` over all `queries`, repeated for about half a second.
+fn time_per_query(queries: &[Vec<f32>], mut f: impl FnMut(&[f32])) -> Duration {
+    let (start, mut rounds) = (Instant::now(), 0u32);
+    while start.elapsed() < Duration::from_millis(500) {
+        queries.iter().for_each(|q| f(q));
+        rounds += 1;
+    }
+    start.elapsed() / (rounds * queries.len() as u32)
+}
+
+fn main() {
+    let queries = embeddings(QUERIES, 7);
+    for count in [50, 200, 500] {
+        let speakers: HashMap<String, Speaker> = embeddings(count, 42)
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let id = format!("spk_{i}");
+                (id.clone(), Speaker { id, name: format!("Speaker {i}"), embedding: Some(e) })
+            })
+            .collect();
+
+        for metric in [DistanceMetric::Cosine, DistanceMetric::Euclidean] {
+            let index = EmbeddingIndex::build(&speakers, metric);
+            for q in &queries {
+                let (expected, got) = (naive_best_match(&speakers, metric, q), index.best_match(q));
+                assert_eq!(expected.map(|m| m.0), got.map(|m| m.0), "index disagrees with the scan");
+            }
+            let scan = time_per_query(&queries, |q| {
+                black_box(naive_best_match(&speakers, metric, black_box(q)));
+            });
+            let indexed = time_per_query(&queries, |q| {
+                black_box(index.best_match(black_box(q)));
+            });
+            println!(
+                "{count:>4} speakers {:<9}  scan {scan:>10.2?}  index {indexed:>10.2?}  ({:.1}x)",
+                format!("{metric:?}"),
+                scan.as_secs_f64() / indexed.as_secs_f64()
+            );
+        }
+    }
+}
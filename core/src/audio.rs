@@ -1,201 +1,2162 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use crossbeam_channel::{unbounded, Sender, Receiver};
-use ringbuf::{HeapRb, traits::*};
-use rubato::{Resampler, FastFixedIn, PolynomialDegree};
-use std::sync::{Arc, Mutex};
-use tracing;
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rubato::{
+    FastFixedIn, FftFixedIn, PolynomialDegree, Resampler, SincFixedIn, SincInterpolationParameters,
+    SincInterpolationType, WindowFunction,
+};
+
+use crate::echo::{self, EchoCanceller, EchoReference};
+use crate::features;
+use crate::filter::BandPass;
+use crate::metrics::PipelineMetrics;
+use crate::noise::NoiseProfile;
+use crate::pool;
+use crate::state::{KeywordSpotter, SupraSonicError};
 
 pub enum AudioPacket {
+    /// Rate of the `Samples` that follow. Required after `RecordingStarted`: samples
+    /// arriving before it wait for it.
     Format(u32),
     Samples(Vec<f32>),
+    /// Samples from one of several simultaneously captured devices, tagged by device name.
+    DeviceSamples(String, Vec<f32>),
     Level(f32),
+    /// With `waveform_resolution_ms`: (min, max) sample pairs covering one chunk, interleaved.
+    Waveform(Vec<f32>),
     Flush,
+    /// Ends the utterance like `Flush`, but drops what it holds instead of dispatching it.
+    Discard,
+    /// Stop calling the listener until `Resume`; hold packets for replay if `buffer`, else
+    /// drop the audio (errors and recording stops are still delivered).
+    Suspend { buffer: bool },
+    Resume,
+    /// Ends the processing thread once everything queued before it has been handled.
+    Shutdown,
+    /// Installs (or with None removes) the keyword spotter gating dispatch.
+    SetSpotter(Option<Arc<dyn KeywordSpotter>>),
+    /// Profiles the next `samples` dispatched samples and sends the result on `reply`.
+    ProfileNoise { samples: usize, reply: Sender<NoiseProfile> },
+    /// With `measure_latency`: follows a chunk, stamped with when its first sample was captured.
+    LatencyProbe(Instant),
+    /// Reopen the utterance the last flush closed, if nothing was recorded since.
+    ContinueUtterance,
+    /// A capture stream failed or changed underneath us, or a file feed failed; forwarded to
+    /// the listener's `on_error`.
+    StreamError(SupraSonicError),
+    /// Live capture is starting; the previous stream's format no longer applies.
+    RecordingStarted,
+    /// Capture ended; forwarded to the listener's `on_recording_stopped`.
+    RecordingStopped(StopReason),
 }
 
-pub struct AudioEngine {
-    command_tx: Sender<AudioCommand>,
+// Internal config constants
+// Default output rate; accepted output rates below (see `AudioConfig::output_sample_rate`)
+pub(crate) const TARGET_SAMPLE_RATE: usize = 16000;
+pub(crate) const MIN_OUTPUT_SAMPLE_RATE: u32 = 8_000;
+pub(crate) const MAX_OUTPUT_SAMPLE_RATE: u32 = 48_000;
+const ASR_CHUNK_MS: usize = 30; // ~30ms chunks, 480 samples at 16kHz
+// Accepted range for externally declared source rates
+pub(crate) const MIN_SOURCE_SAMPLE_RATE: u32 = 1_000;
+pub(crate) const MAX_SOURCE_SAMPLE_RATE: u32 = 768_000;
+// Coarsest level rate: one level per 100 chunks (~3s)
+const MAX_LEVEL_DECIMATION: u32 = 100;
+// Slowest accepted meter attack/release
+pub(crate) const MAX_METER_TIME_MS: u32 = 10_000;
+// Levels at or above this peak count as clipped (within ~0.01 dB of full scale)
+pub(crate) const CLIP_LEVEL: f32 = 0.999;
+// Largest fixed dispatch frame (10s at 16kHz)
+const MAX_DISPATCH_FRAME_SIZE: usize = TARGET_SAMPLE_RATE * 10;
+// Accepted loudness normalization targets
+const MIN_LOUDNESS_TARGET_LUFS: f32 = -60.0;
+const MAX_LOUDNESS_TARGET_LUFS: f32 = -5.0;
+// Accepted capture ring buffer lengths
+const MIN_RING_BUFFER_MS: u32 = 100;
+const MAX_RING_BUFFER_MS: u32 = 60_000;
+
+/// Sample format delivered to the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum SampleFormat {
+    /// 32-bit float in [-1.0, 1.0] via `on_audio_data`.
+    F32,
+    /// 16-bit signed PCM via `on_audio_data_i16`.
+    I16,
 }
 
-enum AudioCommand {
-    Start,
-    Stop,
+/// How processed audio is handed to the listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum DispatchMode {
+    /// Every ~30ms chunk is forwarded as soon as it is ready.
+    Streaming,
+    /// Chunks are buffered and the whole utterance is forwarded on flush.
+    Utterance,
 }
 
-// Internal config constants
-const TARGET_SAMPLE_RATE: usize = 16000;
-const ASR_CHUNK_MS: usize = 30; // ~30ms chunks
-const ASR_CHUNK_SIZE: usize = (TARGET_SAMPLE_RATE * ASR_CHUNK_MS) / 1000; // 480 samples
-const RING_BUFFER_SIZE: usize = 16000 * 5; // 5 seconds buffer
-
-impl AudioEngine {
-    pub fn new(data_tx: Sender<AudioPacket>) -> Self {
-        let (cmd_tx, cmd_rx) = unbounded();
-        
-        std::thread::spawn(move || {
-            let mut stream: Option<cpal::Stream> = None;
-            let mut _stream_guard: Option<Arc<Mutex<bool>>> = None; // Keep alive check if needed
-
-            while let Ok(cmd) = cmd_rx.recv() {
-                match cmd {
-                    AudioCommand::Start => {
-                        if stream.is_some() { continue; }
-                        
-                        tracing::info!("Starting audio capture...");
-                        match Self::build_stream(data_tx.clone()) {
-                            Ok(s) => {
-                                if let Err(e) = s.play() {
-                                    tracing::error!("Failed to play stream: {}", e);
-                                } else {
-                                    stream = Some(s);
-                                    tracing::info!("Audio stream started successfully");
-                                }
-                            },
-                            Err(e) => tracing::error!("Failed to build stream: {}", e),
-                        }
-                    }
-                    AudioCommand::Stop => {
-                        if stream.is_some() {
-                             tracing::info!("Stopping audio capture...");
-                        }
-                        stream = None; 
-                        let _ = data_tx.send(AudioPacket::Flush);
-                    }
-                }
-            }
-        });
+/// What `transcribe_file` does when the listener can't keep up with the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum FileBackpressure {
+    /// Pause decoding until the listener catches up; nothing is lost.
+    #[default]
+    Block,
+    /// Keep decoding and discard the oldest audio not yet queued for the listener,
+    /// counted in `MetricsSnapshot::file_chunks_dropped`.
+    DropOldest,
+    /// Abort the transcription.
+    Error,
+}
+
+/// What `stop_recording` does with audio captured before a `pause_recording` that is
+/// still in effect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum StopWhilePaused {
+    /// Dispatch it as any stop would: the partial last chunk, the utterance so far.
+    #[default]
+    Flush,
+    /// Drop whatever hasn't been dispatched yet, e.g. when pause means "cancel".
+    Discard,
+}
+
+/// Why recording stopped, as reported by `on_recording_stopped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum StopReason {
+    /// `stop_recording` was called.
+    Requested,
+    /// Nothing above the silence threshold for `auto_stop_silence_ms`.
+    SilenceTimeout,
+}
+
+/// How multi-channel device input is folded to the mono pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum DownmixMode {
+    /// Mean of all channels.
+    Average,
+    /// Only the first channel (e.g. the voice input of an interface).
+    FirstChannel,
+    /// Centre of a stereo pair, (L + R) / 2: isolates a dead-centre speaker.
+    Mid,
+    /// Difference of a stereo pair, (L - R) / 2: the de-centred ambience.
+    Side,
+}
 
+/// Folds interleaved frames of `channels` samples into mono, from the channels set in
+/// `channel_mask` (bit 0 = first channel; 0 = all of them).
+///
+/// `Average` averages the selected channels and `FirstChannel` takes the lowest one.
+/// `Mid`/`Side` use the lowest two and fall back to `FirstChannel` with only one. They are
+/// scaled by 1/2 so full-scale input stays within [-1.0, 1.0].
+pub fn downmix(interleaved: &[f32], channels: usize, mode: DownmixMode, channel_mask: u64) -> Vec<f32> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+    let mut selected: Vec<usize> = (0..channels.min(64)).filter(|c| channel_mask & (1 << c) != 0).collect();
+    if selected.is_empty() {
+        selected = (0..channels).collect();
+    }
+    let (first, second) = (selected[0], selected.get(1).copied());
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| match (mode, second) {
+            (DownmixMode::Average, _) => selected.iter().map(|&c| frame[c]).sum::<f32>() / selected.len() as f32,
+            (DownmixMode::FirstChannel, _) | (DownmixMode::Mid | DownmixMode::Side, None) => frame[first],
+            (DownmixMode::Mid, Some(second)) => (frame[first] + frame[second]) * 0.5,
+            (DownmixMode::Side, Some(second)) => (frame[first] - frame[second]) * 0.5,
+        })
+        .collect()
+}
+
+/// Splits interleaved frames of `channels` samples into one buffer per channel, in
+/// channel order. A trailing partial frame (`data.len() % channels` samples) is dropped,
+/// as the capture path does; no channels gives no buffers.
+pub fn deinterleave(data: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    if channels == 0 {
+        return Vec::new();
+    }
+    let frames = data.len() / channels;
+    let mut planar: Vec<Vec<f32>> = (0..channels).map(|_| Vec::with_capacity(frames)).collect();
+    for frame in data.chunks_exact(channels) {
+        for (channel, sample) in planar.iter_mut().zip(frame) {
+            channel.push(*sample);
+        }
+    }
+    planar
+}
+
+/// Inverse of `deinterleave`: one frame per sample index, channels in order. Stops at the
+/// shortest channel, so every frame is complete.
+pub fn interleave(planar: &[Vec<f32>]) -> Vec<f32> {
+    let frames = planar.iter().map(Vec::len).min().unwrap_or(0);
+    (0..frames).flat_map(|i| planar.iter().map(move |channel| channel[i])).collect()
+}
+
+/// Whether the OS lets the app use the microphone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum PermissionStatus {
+    Granted,
+    Denied,
+    /// The user has not been asked yet.
+    Undetermined,
+    /// Neither cpal nor the host has told us; recording may still work.
+    Unsupported,
+}
+
+impl PermissionStatus {
+    /// Maps a raw `AVAuthorizationStatus` (macOS/iOS) as reported by the host app.
+    pub fn from_av_authorization(raw: i64) -> Self {
+        match raw {
+            0 => PermissionStatus::Undetermined,
+            1 | 2 => PermissionStatus::Denied, // restricted, denied
+            3 => PermissionStatus::Granted,
+            _ => PermissionStatus::Unsupported,
+        }
+    }
+}
+
+/// Serializable as a whole (`to_json`/`from_json`) so a tuned setup can be saved and shared;
+/// fields missing from the JSON take their defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, uniffi::Record)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub sample_format: SampleFormat,
+    /// Apply TPDF dither when producing i16 output. Off keeps the conversion deterministic.
+    pub dither_i16: bool,
+    pub dispatch_mode: DispatchMode,
+    /// Channel folding for multi-channel capture devices.
+    pub downmix_mode: DownmixMode,
+    /// Bitmask of the channels the downmix draws from, bit 0 being the first (0 = all).
+    /// A device without one of the selected channels fails to start.
+    pub downmix_channels: u64,
+    /// Drop utterances that look like silence, tones or hum (Utterance mode only).
+    pub skip_non_speech: bool,
+    /// Finalize utterances automatically when speech is followed by silence (Utterance mode only).
+    pub vad_enabled: bool,
+    /// Utterance mode: also dispatch the utterance so far every this many ms of audio via
+    /// `on_utterance_audio` (0 = off). Always f32, whatever `sample_format` says.
+    pub interim_interval_ms: u32,
+    /// Utterance mode: embed every dispatched utterance with the installed `SpeakerEmbedder`
+    /// and assign it a speaker, on a worker thread of its own so a slow model never holds
+    /// up the audio. Results arrive via `on_speaker_assigned`.
+    pub assign_speakers: bool,
+    /// Measure capture-to-dispatch latency per chunk into the metrics (small per-chunk cost).
+    pub measure_latency: bool,
+    /// Chunk RMS at or above which the VAD declares speech onset.
+    pub vad_onset_threshold: f32,
+    /// Chunk RMS below which ongoing speech counts as silence (<= onset, for hysteresis).
+    pub vad_offset_threshold: f32,
+    /// Silence that must elapse after speech before the VAD finalizes the utterance.
+    pub vad_min_silence_ms: u32,
+    /// Stop recording altogether once live input has stayed below `vad_offset_threshold`
+    /// this long (0 = never). Independent of the VAD; time spent paused doesn't count.
+    pub auto_stop_silence_ms: u32,
+    /// Report `InputSilent` via `on_error` once live input has been exactly zero this long
+    /// (0 = never): a muted or failed input, as opposed to a quiet room's noise floor.
+    pub dead_input_ms: u32,
+    /// Trailing silence left on a VAD-finalized utterance; the rest of the silence waited
+    /// out is cut. Capped at what was actually waited (about `vad_min_silence_ms`).
+    pub vad_trailing_silence_ms: u32,
+    /// Utterance mode with the VAD: audio kept from just before speech onset; the rest of
+    /// the silence waited through before anyone speaks is dropped. Tail-trimmed like
+    /// `vad_trailing_silence_ms`, at chunk granularity.
+    pub vad_leading_silence_ms: u32,
+    /// Utterance mode: finalize an utterance once it is this long, even mid-speech, if
+    /// silence hasn't ended it first (0 = no cap). With the VAD on, counted from speech
+    /// onset. At least one 30ms chunk; cuts fall on the first chunk boundary past it, or
+    /// with the VAD on and speech pausing, when it resumes (if silence doesn't end it).
+    pub max_utterance_ms: u32,
+    /// Compute and send `AudioPacket::Level` for each chunk. Turn off for headless use.
+    pub emit_levels: bool,
+    /// How far each level window reaches back into the previous chunk (0 = disjoint chunks).
+    pub level_overlap_ms: u32,
+    /// Send one level per this many chunks, carrying the max over them (1 = every chunk).
+    pub level_decimation: u32,
+    /// Meter ballistics: time constants for the level to rise towards a louder chunk and
+    /// fall back after it (0 = follow the peak at once). Short attacks suit percussive
+    /// sources; the defaults are for voice.
+    pub meter_attack_ms: u32,
+    pub meter_release_ms: u32,
+    /// Send a (min, max) sample pair per this many ms of audio via `on_waveform`, for
+    /// drawing a waveform finer than the per-chunk level (0 = off, at most the 30ms chunk).
+    pub waveform_resolution_ms: u32,
+    /// Dispatch zeroed chunks in place of audio that would otherwise not reach the listener
+    /// (silence between VAD utterances, a closed keyword gate) to keep streaming models warm.
+    pub keep_warm_silence: bool,
+    /// Band-limit the output to `band_low_hz..band_high_hz` (e.g. 300..3400 for
+    /// narrowband models). 0 leaves that edge open; both 0 disables the filter.
+    pub band_low_hz: f32,
+    pub band_high_hz: f32,
+    /// Live capture: cancel the echo of the audio passed to `push_echo_reference`, over an
+    /// echo path up to this long (0 = off, at most `echo::MAX_ECHO_TAIL_MS`). Cost grows
+    /// with the length; 64 ms covers a laptop's speakers.
+    pub echo_tail_ms: u32,
+    /// How long `is_clip_latched` stays true after a clipped level (0 = only while clipping).
+    pub clip_hold_ms: u32,
+    /// Headroom for adjusting the live resampler's ratio at runtime (>= 1.0, 1.0 = fixed).
+    pub max_resample_ratio_relative: f64,
+    /// Utterance mode: scale each final utterance to this integrated loudness (LUFS,
+    /// e.g. -23) at flush, capped so it never clips (None = off). Interim audio stays raw.
+    pub loudness_target_lufs: Option<f32>,
+    /// Replace NaN/Inf input samples with silence before they reach the resampler.
+    pub sanitize_non_finite: bool,
+    /// Keep each recording's pipeline warnings and errors (and this crate's info events)
+    /// and add them to `export_session` as `diagnostics.log`.
+    pub diagnostic_log: bool,
+    /// Add `audio.sha256`, the SHA-256 of the WAV's PCM data, to `export_session`, so an
+    /// archived recording can later be checked for alteration.
+    pub export_audio_checksum: bool,
+    /// Caps `transcribe_file` at this multiple of real time (0 = only bounded by the listener).
+    pub file_realtime_factor: f64,
+    /// What `transcribe_file` does once the listener lags a second behind the file.
+    pub file_backpressure: FileBackpressure,
+    /// Rate to resample live capture from instead of what the device reports (None = trust the device).
+    pub source_rate_override: Option<u32>,
+    /// Captured audio dropped right after a stream starts, to skip device start-up pops.
+    pub warmup_discard_ms: u32,
+    /// Captured audio the ring buffer between the device callback and the resampler can
+    /// hold. Only filled while processing stalls, so it adds no latency by itself; when
+    /// it runs full, audio is dropped.
+    pub ring_buffer_ms: u32,
+    /// Whether `stop_recording` during a pause dispatches or drops the audio from before it.
+    pub stop_while_paused: StopWhilePaused,
+    /// Crossfade applied where audio from before and after `pause_recording` meet (0 = butt splice).
+    /// Capture latency grows by the same amount, since that much audio is held back.
+    pub splice_crossfade_ms: u32,
+    /// Streaming mode: deliver exactly this many samples per `on_audio_data` call (0 = the
+    /// native 480-sample chunks). The remainder at a flush carries into the next utterance
+    /// unless `pad_final_frame` is set.
+    pub dispatch_frame_size: u32,
+    /// Zero-pad and deliver the last partial frame at a flush instead of carrying it over.
+    pub pad_final_frame: bool,
+    /// Deliver log-mel frames with this many bands via `on_features` instead of raw audio
+    /// (0 = raw audio). Interim `on_utterance_audio` calls stay raw.
+    pub mel_bands: u32,
+    /// Analysis window and hop of the mel frames.
+    pub mel_window_ms: u32,
+    pub mel_hop_ms: u32,
+    /// Channel count the host's model requires (0 = don't check). Configs whose output
+    /// can't match it are rejected instead of delivering wrong-shaped buffers.
+    pub expected_output_channels: u32,
+    /// Rate all audio is resampled to and delivered at, normally the model's input rate
+    /// (see `AppState::set_model_sample_rate`). Within 8..=48 kHz.
+    pub output_sample_rate: u32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
         Self {
-            command_tx: cmd_tx,
+            sample_format: SampleFormat::F32,
+            dither_i16: false,
+            dispatch_mode: DispatchMode::Streaming,
+            downmix_mode: DownmixMode::Average,
+            downmix_channels: 0,
+            skip_non_speech: false,
+            vad_enabled: false,
+            vad_onset_threshold: 0.02,
+            vad_offset_threshold: 0.01,
+            vad_min_silence_ms: 600,
+            auto_stop_silence_ms: 0,
+            dead_input_ms: 3000,
+            vad_trailing_silence_ms: 600,
+            vad_leading_silence_ms: 300,
+            max_utterance_ms: 0,
+            emit_levels: true,
+            level_overlap_ms: 0,
+            level_decimation: 1,
+            meter_attack_ms: 10,
+            meter_release_ms: 300,
+            waveform_resolution_ms: 0,
+            keep_warm_silence: false,
+            interim_interval_ms: 0,
+            assign_speakers: false,
+            measure_latency: false,
+            band_low_hz: 0.0,
+            band_high_hz: 0.0,
+            clip_hold_ms: 1500,
+            echo_tail_ms: 0,
+            max_resample_ratio_relative: 1.0,
+            loudness_target_lufs: None,
+            sanitize_non_finite: true,
+            diagnostic_log: false,
+            export_audio_checksum: false,
+            file_realtime_factor: 0.0,
+            file_backpressure: FileBackpressure::Block,
+            source_rate_override: None,
+            warmup_discard_ms: 50,
+            ring_buffer_ms: 5000,
+            stop_while_paused: StopWhilePaused::Flush,
+            splice_crossfade_ms: 5,
+            dispatch_frame_size: 0,
+            pad_final_frame: false,
+            mel_bands: 0,
+            mel_window_ms: 25,
+            mel_hop_ms: 10,
+            expected_output_channels: 0,
+            output_sample_rate: TARGET_SAMPLE_RATE as u32,
         }
     }
+}
 
-    fn build_stream(data_tx: Sender<AudioPacket>) -> anyhow::Result<cpal::Stream> {
-        let host = cpal::default_host();
-        let device = host.default_input_device()
-            .ok_or_else(|| anyhow::anyhow!("No input device found"))?;
-        
-        let config = device.default_input_config()?;
-        let source_sample_rate = config.sample_rate().0 as usize;
-        
-        tracing::info!("Input device: {:?}, Source Rate: {}, Target Rate: {}", 
-            device.name().unwrap_or_default(), source_sample_rate, TARGET_SAMPLE_RATE);
+impl AudioConfig {
+    /// This config, with the buffering set up for about `target_ms` of capture-to-dispatch
+    /// latency (Streaming mode, not counting the device's own buffer) at `source_rate`.
+    ///
+    /// The floor is one 30ms chunk plus the resampler's delay at that rate. The splice
+    /// crossfade is shortened if it doesn't fit. Spare budget goes into larger dispatch
+    /// frames, in whole chunks, trading latency for fewer callbacks. The ring buffer is
+    /// sized to ride out stalls of ten times the target, and at least a second.
+    pub fn for_target_latency(&self, target_ms: u32, source_rate: u32) -> anyhow::Result<AudioConfig> {
+        if !(MIN_SOURCE_SAMPLE_RATE..=MAX_SOURCE_SAMPLE_RATE).contains(&source_rate) {
+            anyhow::bail!(
+                "Unsupported sample rate {} Hz (expected {}..={} Hz)",
+                source_rate, MIN_SOURCE_SAMPLE_RATE, MAX_SOURCE_SAMPLE_RATE
+            );
+        }
+        let resampler_ms = if source_rate == self.output_sample_rate {
+            0.0
+        } else {
+            StreamResampler::new(source_rate as usize, self.output_sample_rate as usize, self)?.latency().ms
+        };
+        let floor_ms = ASR_CHUNK_MS as f64 + resampler_ms;
+        if (target_ms as f64) < floor_ms {
+            anyhow::bail!(
+                "{}ms latency is not achievable at {} Hz: the minimum is {:.1}ms ({}ms chunk + {:.1}ms resampler)",
+                target_ms, source_rate, floor_ms, ASR_CHUNK_MS, resampler_ms
+            );
+        }
 
-        // Notify of format (always 16kHz now)
-        let _ = data_tx.send(AudioPacket::Format(TARGET_SAMPLE_RATE as u32));
+        let mut config = self.clone();
+        let mut spare_ms = target_ms as f64 - floor_ms;
+        config.splice_crossfade_ms = config.splice_crossfade_ms.min(spare_ms as u32);
+        spare_ms -= config.splice_crossfade_ms as f64;
+        let extra_chunks = (spare_ms / ASR_CHUNK_MS as f64) as usize;
+        config.dispatch_frame_size = match extra_chunks {
+            0 => 0,
+            n => (self.chunk_size() * (n + 1)).min(MAX_DISPATCH_FRAME_SIZE) as u32,
+        };
+        config.ring_buffer_ms = target_ms.saturating_mul(10).clamp(1000, MAX_RING_BUFFER_MS);
+        config.validate()?;
+        Ok(config)
+    }
 
-        // Create Ring Buffer
-        let rb = HeapRb::<f32>::new(RING_BUFFER_SIZE);
-        let (mut producer, consumer) = rb.split();
+    /// This config as it plays out on a stream of `channels` channels the pipeline
+    /// resamples from `source_rate`: `source_rate_override` holds that rate,
+    /// `dispatch_frame_size` the samples actually delivered per Streaming-mode call, and
+    /// `downmix_mode` is `FirstChannel` where the requested mode falls back to it (mono
+    /// input). Everything else is applied as requested.
+    pub fn effective(&self, source_rate: u32, channels: u16) -> AudioConfig {
+        let mut config = self.clone();
+        config.source_rate_override = Some(source_rate);
+        if config.dispatch_mode == DispatchMode::Streaming && config.dispatch_frame_size == 0 {
+            config.dispatch_frame_size = config.chunk_size() as u32;
+        }
+        if channels <= 1 {
+            config.downmix_mode = DownmixMode::FirstChannel;
+        }
+        config
+    }
 
-        // Spawn separate processing thread to handle resampling/chunking
-        std::thread::spawn(move || {
-            Self::process_audio(consumer, source_sample_rate, data_tx);
-        });
+    /// Checks the settings that depend on the input: every channel in `downmix_channels`
+    /// must exist on a `channels`-channel stream.
+    pub fn validate_for_input(&self, channels: usize) -> anyhow::Result<()> {
+        let available = if channels >= 64 { u64::MAX } else { (1u64 << channels) - 1 };
+        if self.downmix_channels & !available != 0 {
+            anyhow::bail!(
+                "downmix_channels {:#b} selects channels the {}-channel input doesn't have",
+                self.downmix_channels, channels
+            );
+        }
+        Ok(())
+    }
 
-        // The audio callback only pushes to ring buffer (Real-time safe)
-        let stream = device.build_input_stream(
-            &config.into(),
-            move |data: &[f32], _: &_| {
-                let _ = producer.push_slice(data); 
-            },
-            move |err| {
-                tracing::error!("Audio stream error: {}", err);
-            },
-            None
-        )?;
-        
-        Ok(stream)
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
     }
 
-    fn process_audio(
-        mut consumer: impl Consumer<Item = f32>, 
-        source_rate: usize, 
-        data_tx: Sender<AudioPacket>
-    ) {
-        // ... (resampler setup same)
-        // Setup Resampler if needed
-        let mut resampler: Option<FastFixedIn<f32>> = if source_rate != TARGET_SAMPLE_RATE {
-             let resample_ratio = TARGET_SAMPLE_RATE as f64 / source_rate as f64;
-             let chunk_size = 1024; 
-             match FastFixedIn::<f32>::new(
+    /// Parses and validates a config saved with `to_json`. Unknown fields, out-of-range
+    /// values and invalid combinations are all rejected.
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_str(json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Channels per dispatched frame. Everything is downmixed, so this is always mono.
+    pub fn output_channels(&self) -> u32 {
+        1
+    }
+
+    /// Samples per processed chunk (30ms at the output rate).
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.output_sample_rate as usize * ASR_CHUNK_MS / 1000
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.expected_output_channels != 0 && self.expected_output_channels != self.output_channels() {
+            anyhow::bail!(
+                "expected_output_channels is {} but this config delivers {}-channel audio",
+                self.expected_output_channels,
+                self.output_channels()
+            );
+        }
+        if self.max_resample_ratio_relative.is_nan() || self.max_resample_ratio_relative < 1.0 {
+            anyhow::bail!(
+                "max_resample_ratio_relative must be >= 1.0, got {}",
+                self.max_resample_ratio_relative
+            );
+        }
+        for (name, value) in [
+            ("vad_onset_threshold", self.vad_onset_threshold),
+            ("vad_offset_threshold", self.vad_offset_threshold),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                anyhow::bail!("{} must be a non-negative number, got {}", name, value);
+            }
+        }
+        if !self.file_realtime_factor.is_finite() || self.file_realtime_factor < 0.0 {
+            anyhow::bail!("file_realtime_factor must be a non-negative number, got {}", self.file_realtime_factor);
+        }
+        if !(MIN_OUTPUT_SAMPLE_RATE..=MAX_OUTPUT_SAMPLE_RATE).contains(&self.output_sample_rate) {
+            anyhow::bail!(
+                "output_sample_rate must be within {}..={} Hz, got {}",
+                MIN_OUTPUT_SAMPLE_RATE, MAX_OUTPUT_SAMPLE_RATE, self.output_sample_rate
+            );
+        }
+        let nyquist = self.output_sample_rate as f32 / 2.0;
+        for (name, value) in [("band_low_hz", self.band_low_hz), ("band_high_hz", self.band_high_hz)] {
+            if !value.is_finite() || value < 0.0 || value >= nyquist {
+                anyhow::bail!("{} must be within 0..{} Hz, got {}", name, nyquist, value);
+            }
+        }
+        if self.band_low_hz > 0.0 && self.band_high_hz > 0.0 && self.band_low_hz >= self.band_high_hz {
+            anyhow::bail!(
+                "band_low_hz ({}) must be below band_high_hz ({})",
+                self.band_low_hz, self.band_high_hz
+            );
+        }
+        if !(1..=MAX_LEVEL_DECIMATION).contains(&self.level_decimation) {
+            anyhow::bail!(
+                "level_decimation must be within 1..={}, got {}",
+                MAX_LEVEL_DECIMATION, self.level_decimation
+            );
+        }
+        if self.echo_tail_ms > echo::MAX_ECHO_TAIL_MS {
+            anyhow::bail!("echo_tail_ms must be at most {}, got {}", echo::MAX_ECHO_TAIL_MS, self.echo_tail_ms);
+        }
+        for (name, value) in [("meter_attack_ms", self.meter_attack_ms), ("meter_release_ms", self.meter_release_ms)] {
+            if value > MAX_METER_TIME_MS {
+                anyhow::bail!("{} must be at most {}, got {}", name, MAX_METER_TIME_MS, value);
+            }
+        }
+        if let Some(target) = self.loudness_target_lufs.filter(|t| !(MIN_LOUDNESS_TARGET_LUFS..=MAX_LOUDNESS_TARGET_LUFS).contains(t)) {
+            anyhow::bail!(
+                "loudness_target_lufs must be within {}..={}, got {}",
+                MIN_LOUDNESS_TARGET_LUFS, MAX_LOUDNESS_TARGET_LUFS, target
+            );
+        }
+        if !(MIN_RING_BUFFER_MS..=MAX_RING_BUFFER_MS).contains(&self.ring_buffer_ms) {
+            anyhow::bail!(
+                "ring_buffer_ms must be within {}..={}, got {}",
+                MIN_RING_BUFFER_MS, MAX_RING_BUFFER_MS, self.ring_buffer_ms
+            );
+        }
+        if self.max_utterance_ms != 0 && (self.max_utterance_ms as usize) < ASR_CHUNK_MS {
+            anyhow::bail!("max_utterance_ms must be 0 or at least the {}ms chunk, got {}", ASR_CHUNK_MS, self.max_utterance_ms);
+        }
+        if self.waveform_resolution_ms as usize > ASR_CHUNK_MS {
+            anyhow::bail!(
+                "waveform_resolution_ms must be at most the {}ms chunk, got {}",
+                ASR_CHUNK_MS, self.waveform_resolution_ms
+            );
+        }
+        if self.dispatch_frame_size as usize > MAX_DISPATCH_FRAME_SIZE {
+            anyhow::bail!(
+                "dispatch_frame_size must be at most {} samples, got {}",
+                MAX_DISPATCH_FRAME_SIZE, self.dispatch_frame_size
+            );
+        }
+        if self.mel_bands > 0 {
+            if self.mel_bands > features::MAX_MEL_BANDS {
+                anyhow::bail!("mel_bands must be at most {}, got {}", features::MAX_MEL_BANDS, self.mel_bands);
+            }
+            if !(1..=features::MAX_MEL_WINDOW_MS).contains(&self.mel_window_ms) {
+                anyhow::bail!("mel_window_ms must be within 1..={}, got {}", features::MAX_MEL_WINDOW_MS, self.mel_window_ms);
+            }
+            if !(1..=self.mel_window_ms).contains(&self.mel_hop_ms) {
+                anyhow::bail!("mel_hop_ms must be within 1..=mel_window_ms ({}), got {}", self.mel_window_ms, self.mel_hop_ms);
+            }
+        }
+        if let Some(rate) = self.source_rate_override {
+            if !(MIN_SOURCE_SAMPLE_RATE..=MAX_SOURCE_SAMPLE_RATE).contains(&rate) {
+                anyhow::bail!(
+                    "source_rate_override must be within {}..={} Hz, got {}",
+                    MIN_SOURCE_SAMPLE_RATE, MAX_SOURCE_SAMPLE_RATE, rate
+                );
+            }
+        }
+        if self.vad_offset_threshold > self.vad_onset_threshold {
+            anyhow::bail!(
+                "vad_offset_threshold ({}) must not exceed vad_onset_threshold ({})",
+                self.vad_offset_threshold,
+                self.vad_onset_threshold
+            );
+        }
+        Ok(())
+    }
+}
+
+// Above this source rate the cubic resampler's lack of an anti-aliasing filter shows
+const SINC_MIN_SOURCE_RATE: usize = 96_000;
+// Longest resampler output block accepted, in ms at the output rate. FFT blocks grow as the
+// rates' GCD shrinks, up to a whole second (and as long a wait for each chunk) for coprime ones
+const MAX_RESAMPLER_BLOCK_MS: usize = 500;
+// Resampler input block at 48kHz; scaled up for higher rates to keep ~21ms blocks
+const RESAMPLER_BASE_CHUNK: usize = 1024;
+
+/// Builds the live-path resampler from `source_rate` to `target_rate`.
+///
+/// Up to 96 kHz a cubic `FastFixedIn` is used. Beyond that (192 kHz interfaces, down-ratios
+/// of 1/12 and more) a windowed-sinc resampler low-passes before decimating, since cubic
+/// interpolation alone folds everything above 8 kHz back into the speech band.
+///
+/// Only an invalid `config` is an error: should rubato refuse the rates, a
+/// `LinearResampler` stands in (counted in `MetricsSnapshot::resampler_fallbacks`).
+pub fn build_resampler(
+    source_rate: usize,
+    target_rate: usize,
+    config: &AudioConfig,
+) -> anyhow::Result<AudioResampler> {
+    config.validate()?;
+    let kind = if source_rate > SINC_MIN_SOURCE_RATE { ResamplerKind::Sinc } else { ResamplerKind::Cubic };
+    let built = build_resampler_kind(kind, source_rate, target_rate, config.max_resample_ratio_relative);
+    Ok(or_linear_fallback(built, source_rate, target_rate))
+}
+
+// Degraded audio beats a dead capture thread
+fn or_linear_fallback(built: anyhow::Result<AudioResampler>, source_rate: usize, target_rate: usize) -> AudioResampler {
+    built.unwrap_or_else(|e| {
+        tracing::warn!("Resampler for {} Hz -> {} Hz failed ({}); falling back to linear interpolation", source_rate, target_rate, e);
+        AudioResampler::Linear(LinearResampler::new(source_rate, target_rate, resampler_chunk_size(source_rate)))
+    })
+}
+
+/// The resampler implementations available, fastest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum ResamplerKind {
+    /// `LinearResampler`, the fallback.
+    Linear,
+    /// Cubic polynomial (`FastFixedIn`), the live path up to 96 kHz.
+    Cubic,
+    /// Windowed sinc (`SincFixedIn`), the live path above 96 kHz.
+    Sinc,
+    /// FFT (`FftFixedIn`), the file path.
+    Fft,
+}
+
+fn resampler_chunk_size(source_rate: usize) -> usize {
+    RESAMPLER_BASE_CHUNK * (source_rate / 48_000).max(1)
+}
+
+/// Builds one specific resampler; `max_resample_ratio_relative` is ignored by the fixed-ratio kinds.
+pub fn build_resampler_kind(
+    kind: ResamplerKind,
+    source_rate: usize,
+    target_rate: usize,
+    max_resample_ratio_relative: f64,
+) -> anyhow::Result<AudioResampler> {
+    let resample_ratio = target_rate as f64 / source_rate as f64;
+    let chunk_size = resampler_chunk_size(source_rate);
+    let resampler = match kind {
+        ResamplerKind::Linear => AudioResampler::Linear(LinearResampler::new(source_rate, target_rate, chunk_size)),
+        ResamplerKind::Cubic => AudioResampler::Fast(FastFixedIn::<f32>::new(
+            resample_ratio,
+            max_resample_ratio_relative,
+            PolynomialDegree::Cubic,
+            chunk_size,
+            1,
+        )?),
+        ResamplerKind::Sinc => {
+            let params = SincInterpolationParameters {
+                sinc_len: 128,
+                f_cutoff: 0.925,
+                interpolation: SincInterpolationType::Linear,
+                oversampling_factor: 128,
+                window: WindowFunction::BlackmanHarris2,
+            };
+            AudioResampler::Sinc(SincFixedIn::<f32>::new(
                 resample_ratio,
-                1.0,
-                PolynomialDegree::Cubic,
-                chunk_size, 
-                1
-            ) {
-                Ok(r) => Some(r),
-                Err(e) => {
-                    tracing::error!("Failed to create resampler: {}", e);
-                    return;
-                }
+                max_resample_ratio_relative,
+                params,
+                chunk_size,
+                1,
+            )?)
+        }
+        ResamplerKind::Fft => AudioResampler::Fft(FftFixedIn::<f32>::new(source_rate, target_rate, chunk_size, 2, 1)?),
+    };
+    Ok(resampler)
+}
+
+/// Builds the file-path resampler: FFT-based, fixed ratio, better quality than the live
+/// one at the cost of a block of latency (irrelevant offline).
+pub fn build_offline_resampler(source_rate: usize, target_rate: usize) -> anyhow::Result<AudioResampler> {
+    build_resampler_kind(ResamplerKind::Fft, source_rate, target_rate, 1.0)
+}
+
+/// The resampler picked by `build_resampler`/`build_offline_resampler`
+/// (rubato's trait isn't object safe).
+pub enum AudioResampler {
+    Fast(FastFixedIn<f32>),
+    Sinc(SincFixedIn<f32>),
+    Fft(FftFixedIn<f32>),
+    /// Fallback when rubato refuses to build; no anti-aliasing, so expect some fold-over.
+    Linear(LinearResampler),
+}
+
+/// Plain linear interpolation over fixed input blocks, carrying the fractional read
+/// position and the previous block's last sample across calls. Adds no delay.
+pub struct LinearResampler {
+    // Input samples per output sample
+    step: f64,
+    chunk_size: usize,
+    // Read position relative to the start of the next block; -1.0 is the previous last sample
+    position: f64,
+    previous: f32,
+}
+
+impl LinearResampler {
+    pub fn new(source_rate: usize, target_rate: usize, chunk_size: usize) -> Self {
+        Self { step: source_rate as f64 / target_rate as f64, chunk_size, position: 0.0, previous: 0.0 }
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(self.output_frames_max());
+        self.process_into(input, &mut out);
+        out
+    }
+
+    fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let at = |i: isize| if i < 0 { self.previous } else { input[i as usize] };
+        let last = input.len() as f64 - 1.0;
+        while self.position <= last {
+            let base = self.position.floor();
+            let frac = (self.position - base) as f32;
+            let a = at(base as isize);
+            out.push(if frac == 0.0 { a } else { a + frac * (at(base as isize + 1) - a) });
+            self.position += self.step;
+        }
+        self.position -= input.len() as f64;
+        self.previous = input.last().copied().unwrap_or(self.previous);
+    }
+
+    fn output_frames_max(&self) -> usize {
+        (self.chunk_size as f64 / self.step).ceil() as usize + 1
+    }
+}
+
+/// Delay the resampler adds between input and output.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct ResamplerLatency {
+    /// In output samples.
+    pub samples: u32,
+    pub ms: f64,
+}
+
+impl AudioResampler {
+    /// Lead-in before the first real output sample, in output samples.
+    pub fn output_delay(&self) -> usize {
+        match self {
+            AudioResampler::Fast(r) => r.output_delay(),
+            AudioResampler::Sinc(r) => r.output_delay(),
+            AudioResampler::Fft(r) => r.output_delay(),
+            AudioResampler::Linear(_) => 0,
+        }
+    }
+
+    pub fn input_frames_next(&self) -> usize {
+        match self {
+            AudioResampler::Fast(r) => r.input_frames_next(),
+            AudioResampler::Sinc(r) => r.input_frames_next(),
+            AudioResampler::Fft(r) => r.input_frames_next(),
+            AudioResampler::Linear(r) => r.chunk_size,
+        }
+    }
+
+    pub fn output_frames_max(&self) -> usize {
+        match self {
+            AudioResampler::Fast(r) => r.output_frames_max(),
+            AudioResampler::Sinc(r) => r.output_frames_max(),
+            AudioResampler::Fft(r) => r.output_frames_max(),
+            AudioResampler::Linear(r) => r.output_frames_max(),
+        }
+    }
+
+    /// Adjusts the ratio relative to the one built with, within `max_resample_ratio_relative`
+    /// (drift compensation). The fixed-ratio kinds only accept 1.0.
+    pub fn set_ratio_relative(&mut self, relative: f64) -> anyhow::Result<()> {
+        match self {
+            AudioResampler::Fast(r) => r.set_resample_ratio_relative(relative, true)?,
+            AudioResampler::Sinc(r) => r.set_resample_ratio_relative(relative, true)?,
+            AudioResampler::Fft(_) | AudioResampler::Linear(_) if relative != 1.0 => {
+                anyhow::bail!("this resampler has a fixed ratio, can't adjust it by {}", relative)
+            }
+            AudioResampler::Fft(_) | AudioResampler::Linear(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Resamples exactly `input_frames_next()` mono samples.
+    pub fn process(&mut self, input: &[f32]) -> Option<Vec<f32>> {
+        let waves_out = match self {
+            AudioResampler::Fast(r) => r.process(&[input], None),
+            AudioResampler::Sinc(r) => r.process(&[input], None),
+            AudioResampler::Fft(r) => r.process(&[input], None),
+            AudioResampler::Linear(r) => return Some(r.process(input)),
+        };
+        waves_out.ok().and_then(|w| w.into_iter().next())
+    }
+
+    /// `process` into `out`, returning how many samples it wrote; allocates nothing once
+    /// `out` has grown to `output_frames_max`.
+    pub fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) -> Option<usize> {
+        out.resize(self.output_frames_max(), 0.0);
+        let written = match self {
+            AudioResampler::Fast(r) => r.process_into_buffer(&[input], std::slice::from_mut(out), None),
+            AudioResampler::Sinc(r) => r.process_into_buffer(&[input], std::slice::from_mut(out), None),
+            AudioResampler::Fft(r) => r.process_into_buffer(&[input], std::slice::from_mut(out), None),
+            AudioResampler::Linear(r) => {
+                out.clear();
+                r.process_into(input, out);
+                return Some(out.len());
+            }
+        };
+        written.ok().map(|(_, out_frames)| out_frames)
+    }
+
+    /// Resamples a final short block (zero-padded internally to a full block).
+    pub fn process_partial(&mut self, input: &[f32]) -> Option<Vec<f32>> {
+        // rubato wants None rather than an empty slice for an all-zero block
+        let wave_in = [input];
+        let wave_in = (!input.is_empty()).then_some(&wave_in[..]);
+        let waves_out = match self {
+            AudioResampler::Fast(r) => r.process_partial(wave_in, None),
+            AudioResampler::Sinc(r) => r.process_partial(wave_in, None),
+            AudioResampler::Fft(r) => r.process_partial(wave_in, None),
+            AudioResampler::Linear(r) => {
+                let mut block = input.to_vec();
+                block.resize(r.chunk_size, 0.0);
+                return Some(r.process(&block));
+            }
+        };
+        waves_out.ok().and_then(|w| w.into_iter().next())
+    }
+}
+
+/// Incremental resampling in bounded memory: feed chunks of any size to `process`, then
+/// call `finish` once. Only one resampler block is ever held, and the output is the same
+/// however the input was split.
+///
+/// Output is delay-compensated: the resampler's lead-in is dropped and `finish` flushes
+/// its tail, so N input samples always give round(N * ratio) output samples.
+pub struct StreamResampler {
+    resampler: AudioResampler,
+    target_rate: usize,
+    ratio: f64,
+    // Input waiting for a full resampler block
+    pending: Vec<f32>,
+    // Output of the latest block, reused from block to block
+    block: Vec<f32>,
+    // Lead-in output samples still to drop
+    delay_remaining: usize,
+    input_total: u64,
+    output_total: u64,
+}
+
+impl StreamResampler {
+    /// Live-path quality (see `build_resampler`).
+    pub fn new(source_rate: usize, target_rate: usize, config: &AudioConfig) -> anyhow::Result<Self> {
+        let resampler = build_resampler(source_rate, target_rate, config)?;
+        Self::from_resampler(resampler, source_rate, target_rate)
+    }
+
+    /// File-path quality (see `build_offline_resampler`).
+    pub fn new_offline(source_rate: usize, target_rate: usize) -> anyhow::Result<Self> {
+        let resampler = build_offline_resampler(source_rate, target_rate)?;
+        Self::from_resampler(resampler, source_rate, target_rate)
+    }
+
+    /// Fails fast on block sizes the chunker can't work with, rather than letting a
+    /// misconfigured resampler deliver nothing or stall for seconds between chunks.
+    pub fn from_resampler(resampler: AudioResampler, source_rate: usize, target_rate: usize) -> anyhow::Result<Self> {
+        let input_block = resampler.input_frames_next();
+        let output_block = resampler.output_frames_max();
+        if input_block == 0 || output_block == 0 {
+            anyhow::bail!(
+                "Resampler for {} Hz -> {} Hz has an empty block ({} in, {} out)",
+                source_rate, target_rate, input_block, output_block
+            );
+        }
+        let max_output_block = target_rate * MAX_RESAMPLER_BLOCK_MS / 1000;
+        if output_block > max_output_block {
+            anyhow::bail!(
+                "Resampling {} Hz -> {} Hz needs {}-sample output blocks (limit {}); the rates share too small a common factor",
+                source_rate, target_rate, output_block, max_output_block
+            );
+        }
+        Ok(Self {
+            delay_remaining: resampler.output_delay(),
+            resampler,
+            target_rate,
+            ratio: target_rate as f64 / source_rate as f64,
+            pending: Vec::with_capacity(2048),
+            block: Vec::new(),
+            input_total: 0,
+            output_total: 0,
+        })
+    }
+
+    /// Whether this is the linear fallback rather than the resampler that was asked for.
+    pub fn is_degraded(&self) -> bool {
+        matches!(self.resampler, AudioResampler::Linear(_))
+    }
+
+    pub fn latency(&self) -> ResamplerLatency {
+        let samples = self.resampler.output_delay();
+        ResamplerLatency {
+            samples: samples as u32,
+            ms: samples as f64 * 1000.0 / self.target_rate as f64,
+        }
+    }
+
+    /// Appends the output of every block `input` completes to `out`.
+    pub fn process_into(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.input_total += input.len() as u64;
+        self.pending.extend_from_slice(input);
+        let mut consumed = 0;
+        loop {
+            let required_input = self.resampler.input_frames_next();
+            if self.pending.len() - consumed < required_input { break; }
+
+            let mut block = std::mem::take(&mut self.block);
+            if let Some(written) = self.resampler.process_into(&self.pending[consumed..consumed + required_input], &mut block) {
+                self.emit(&block[..written], usize::MAX, out);
+            }
+            self.block = block;
+            consumed += required_input;
+        }
+        self.pending.drain(..consumed);
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut out = Vec::new();
+        self.process_into(input, &mut out);
+        out
+    }
+
+    /// Appends the rest of the output to `out`: the partial block plus whatever the
+    /// resampler was still holding back.
+    pub fn finish_into(&mut self, out: &mut Vec<f32>) {
+        let expected = (self.input_total as f64 * self.ratio).round() as u64;
+        let pending = std::mem::take(&mut self.pending);
+        let mut input: &[f32] = &pending;
+        // Zero blocks yield output at the resampling ratio on average (FFT ones in bursts)
+        let per_block = ((self.resampler.input_frames_next() as f64 * self.ratio) as usize).max(1);
+        let missing = expected.saturating_sub(self.output_total) as usize;
+        let max_blocks = 4 + 2 * (self.delay_remaining + missing) / per_block;
+        for _ in 0..max_blocks {
+            let missing = expected.saturating_sub(self.output_total) as usize;
+            if missing == 0 {
+                break;
+            }
+            let Some(out_channel) = self.resampler.process_partial(input) else { break };
+            self.emit(&out_channel, missing, out);
+            input = &[];
+        }
+    }
+
+    pub fn finish(&mut self) -> Vec<f32> {
+        let mut out = Vec::new();
+        self.finish_into(&mut out);
+        out
+    }
+
+    /// Drops the lead-in, then appends up to `limit` samples.
+    fn emit(&mut self, block: &[f32], limit: usize, out: &mut Vec<f32>) {
+        let skip = self.delay_remaining.min(block.len());
+        self.delay_remaining -= skip;
+        let block = &block[skip..];
+        let block = &block[..limit.min(block.len())];
+        self.output_total += block.len() as u64;
+        out.extend_from_slice(block);
+    }
+}
+
+/// Converts f32 samples to i16 PCM, clamping anything beyond full scale: -1.0 and below
+/// give `i16::MIN`, 1.0 and above `i16::MAX`.
+pub fn f32_to_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter().map(|&s| to_i16_scale(s).round() as i16).collect()
+}
+
+// Full scale is one step larger below zero than above it
+fn to_i16_scale(sample: f32) -> f32 {
+    let sample = sample.clamp(-1.0, 1.0);
+    if sample < 0.0 {
+        sample * -(i16::MIN as f32)
+    } else {
+        sample * i16::MAX as f32
+    }
+}
+
+/// Converts to i16 with triangular-PDF dither of +/-1 LSB, masking quantization distortion
+/// on quiet signals. `state` is the xorshift RNG state; any non-zero seed works.
+pub fn f32_to_i16_dithered(samples: &[f32], state: &mut u64) -> Vec<i16> {
+    let mut uniform = || {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    };
+    samples
+        .iter()
+        .map(|&s| {
+            let dither = uniform() + uniform();
+            (to_i16_scale(s) + dither)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
+/// Joins audio across a capture pause without a click.
+///
+/// Holds back the last `crossfade` samples while running; after a pause they are
+/// crossfaded into the first samples captured on resume. With `crossfade == 0` the
+/// two sides are butted together, which is still continuous for the resampler.
+pub struct Splicer {
+    crossfade: usize,
+    tail: Vec<f32>,
+    // Set by `pause`: the next push starts with a fade from `tail`
+    fade_in: bool,
+}
+
+impl Splicer {
+    pub fn new(crossfade: usize) -> Self {
+        Self { crossfade, tail: Vec::with_capacity(crossfade), fade_in: false }
+    }
+
+    /// Returns the samples ready to be processed.
+    pub fn push(&mut self, samples: &[f32]) -> Vec<f32> {
+        let mut out = std::mem::take(&mut self.tail);
+        if self.fade_in && !out.is_empty() {
+            self.fade_in = false;
+            let n = out.len().min(samples.len());
+            let start = out.len() - n;
+            for i in 0..n {
+                let t = (i + 1) as f32 / (n + 1) as f32;
+                out[start + i] = out[start + i] * (1.0 - t) + samples[i] * t;
             }
+            out.extend_from_slice(&samples[n..]);
+        } else {
+            self.fade_in = false;
+            out.extend_from_slice(samples);
+        }
+
+        let keep = self.crossfade.min(out.len());
+        self.tail = out.split_off(out.len() - keep);
+        out
+    }
+
+    pub fn pause(&mut self) {
+        self.fade_in = self.crossfade > 0;
+    }
+
+    /// The held-back tail, at end of capture.
+    pub fn finish(&mut self) -> Vec<f32> {
+        self.fade_in = false;
+        std::mem::take(&mut self.tail)
+    }
+}
+
+/// One point of a `GainEnvelope`: linear `gain` at `at_seconds` into the recording.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct GainPoint {
+    pub at_seconds: f64,
+    pub gain: f32,
+}
+
+/// Time-varying gain for live capture (scripted mixing, ducking on a schedule): linear
+/// interpolation between points, holding the first value before them and the last after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GainEnvelope {
+    points: Vec<GainPoint>,
+}
+
+impl GainEnvelope {
+    /// Needs at least one point, times finite, non-negative and strictly increasing, gains
+    /// finite and non-negative.
+    pub fn new(points: Vec<GainPoint>) -> anyhow::Result<Self> {
+        if points.is_empty() {
+            anyhow::bail!("A gain envelope needs at least one point");
+        }
+        if let Some(p) = points.iter().find(|p| !p.at_seconds.is_finite() || p.at_seconds < 0.0 || !p.gain.is_finite() || p.gain < 0.0) {
+            anyhow::bail!("Gain point {:?} must have a finite time >= 0 and a finite gain >= 0", p);
+        }
+        if points.windows(2).any(|w| w[1].at_seconds <= w[0].at_seconds) {
+            anyhow::bail!("Gain point times must be strictly increasing");
+        }
+        Ok(Self { points })
+    }
+
+    pub fn gain_at(&self, seconds: f64) -> f32 {
+        let next = self.points.partition_point(|p| p.at_seconds <= seconds);
+        match (next.checked_sub(1).map(|i| self.points[i]), self.points.get(next)) {
+            (Some(a), Some(b)) => {
+                let t = ((seconds - a.at_seconds) / (b.at_seconds - a.at_seconds)) as f32;
+                a.gain + (b.gain - a.gain) * t
+            }
+            (Some(last), None) => last.gain,
+            (None, Some(first)) => first.gain,
+            (None, None) => 1.0,
+        }
+    }
+
+    /// Scales `samples` at `sample_rate`, the first of which is `start_seconds` into the
+    /// recording.
+    pub fn apply(&self, samples: &mut [f32], start_seconds: f64, sample_rate: usize) {
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample *= self.gain_at(start_seconds + i as f64 / sample_rate as f64);
+        }
+    }
+}
+
+/// Peak level over a window that overlaps the tail of the previous chunk.
+struct LevelWindow {
+    overlap: usize,
+    history: Vec<f32>,
+}
+
+impl LevelWindow {
+    fn new(overlap: usize) -> Self {
+        Self { overlap, history: Vec::with_capacity(overlap) }
+    }
+
+    fn level(&mut self, chunk: &[f32]) -> f32 {
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        let level = peak(&self.history).max(peak(chunk));
+
+        if self.overlap > 0 {
+            self.history.extend_from_slice(chunk);
+            let excess = self.history.len().saturating_sub(self.overlap);
+            self.history.drain(0..excess);
+        }
+        level
+    }
+}
+
+/// Attack/release smoothing of the per-chunk peak, one-pole in each direction. A clipped
+/// chunk shows at once whatever the attack, so clip indication is never delayed.
+struct MeterBallistics {
+    attack_secs: f32,
+    release_secs: f32,
+    value: f32,
+}
+
+impl MeterBallistics {
+    fn new(attack_ms: u32, release_ms: u32) -> Self {
+        Self { attack_secs: attack_ms as f32 / 1000.0, release_secs: release_ms as f32 / 1000.0, value: 0.0 }
+    }
+
+    /// The meter reading after `peak` was measured over `secs` of audio.
+    fn step(&mut self, peak: f32, secs: f32) -> f32 {
+        let tau = if peak > self.value { self.attack_secs } else { self.release_secs };
+        self.value = if tau <= 0.0 || peak >= CLIP_LEVEL {
+            peak
+        } else {
+            peak + (self.value - peak) * (-secs / tau).exp()
+        };
+        self.value
+    }
+
+    fn reset(&mut self) {
+        self.value = 0.0;
+    }
+}
+
+/// (min, max) of every `resolution_ms` slice of `chunk`, interleaved; a short last slice
+/// gets a pair too.
+pub fn waveform_envelope(chunk: &[f32], resolution_ms: u32, sample_rate: u32) -> Vec<f32> {
+    let slice = (sample_rate as usize * resolution_ms as usize / 1000).max(1);
+    chunk
+        .chunks(slice)
+        .flat_map(|s| {
+            let (min, max) = s.iter().fold((f32::MAX, f32::MIN), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+            [min, max]
+        })
+        .collect()
+}
+
+/// Turns mono audio at any source rate into ASR chunks at the output rate (plus levels) on `data_tx`.
+///
+/// Shared by live capture and `push_samples`; input may arrive in slices of any length.
+pub struct AudioProcessor {
+    resampler: Option<StreamResampler>,
+    band_pass: Option<BandPass>,
+    // Output waiting for a full ASR chunk
+    accumulated: Vec<f32>,
+    level_window: LevelWindow,
+    meter: MeterBallistics,
+    // Max level over the chunks since the last `Level` packet, and how many there were
+    held_level: f32,
+    held_level_chunks: u32,
+    // Runtime switch for level reporting (see `with_level_reporting`); None = always on
+    level_reporting: Option<Arc<AtomicBool>>,
+    // Echo canceller and the playback it cancels (see `with_echo_reference`)
+    echo: Option<(EchoCanceller, Arc<EchoReference>)>,
+    // Capture time of the newest sample pushed so far (for `measure_latency`)
+    newest_capture: Instant,
+    // Non-finite input is logged once per stream, not per slice
+    warned_non_finite: bool,
+    config: AudioConfig,
+    data_tx: Sender<AudioPacket>,
+    source: Option<String>,
+    metrics: Arc<PipelineMetrics>,
+}
+
+impl AudioProcessor {
+    pub fn new(
+        source_rate: usize,
+        config: AudioConfig,
+        data_tx: Sender<AudioPacket>,
+        source: Option<String>,
+        metrics: Arc<PipelineMetrics>,
+    ) -> anyhow::Result<Self> {
+        // Setup Resampler if needed
+        let output_rate = config.output_sample_rate as usize;
+        let resampler = if source_rate != output_rate {
+            Some(StreamResampler::new(source_rate, output_rate, &config)?)
         } else {
             None
         };
+        Ok(Self::with_resampler(resampler, config, data_tx, source, metrics))
+    }
 
-        // Buffers
-        let mut input_buffer = Vec::with_capacity(2048);
-        let mut accumulated_samples = Vec::with_capacity(ASR_CHUNK_SIZE * 2);
+    /// Same as `new`, with the FFT resampler used for files.
+    pub fn new_offline(
+        source_rate: usize,
+        config: AudioConfig,
+        data_tx: Sender<AudioPacket>,
+        metrics: Arc<PipelineMetrics>,
+    ) -> anyhow::Result<Self> {
+        config.validate()?;
+        let output_rate = config.output_sample_rate as usize;
+        let resampler = if source_rate != output_rate {
+            Some(StreamResampler::new_offline(source_rate, output_rate)?)
+        } else {
+            None
+        };
+        Ok(Self::with_resampler(resampler, config, data_tx, None, metrics))
+    }
 
-        loop {
-            // 1. Read from RingBuffer
-            let available = consumer.occupied_len();
-            if available == 0 {
-                std::thread::sleep(std::time::Duration::from_millis(5));
-                continue;
-            }
-
-            // If we have a resampler, we need specific chunk sizes
-            if let Some(ref mut r) = resampler {
-                let required_input = r.input_frames_next(); 
-                
-                if available >= required_input {
-                    input_buffer.resize(required_input, 0.0);
-                    let read_count = consumer.pop_slice(&mut input_buffer);
-                    if read_count < required_input { continue; }
-
-                    let waves_in = vec![input_buffer.clone()]; 
-                    // 0.14.0 process returns Result<Vec<Vec<f32>>>
-                    if let Ok(waves_out) = r.process(&waves_in, None) {
-                         if let Some(out_channel) = waves_out.get(0) {
-                             accumulated_samples.extend_from_slice(out_channel);
-                         }
-                    }
-                }
-            } else {
-                // No resampling, just passthrough
-                let chunk_to_read = available.min(1024);
-                input_buffer.resize(chunk_to_read, 0.0);
-                let _ = consumer.pop_slice(&mut input_buffer);
-                accumulated_samples.extend_from_slice(&input_buffer);
-            }
-
-            // 2. Chunk for ASR (20-30ms)
-            while accumulated_samples.len() >= ASR_CHUNK_SIZE {
-                let chunk: Vec<f32> = accumulated_samples.drain(0..ASR_CHUNK_SIZE).collect();
-                
-                // Calculate level for UI
-                let mut max = 0.0f32;
-                for &s in &chunk {
-                    let abs = s.abs();
-                    if abs > max { max = abs };
-                }
-                
-                // Send Level
-                let _ = data_tx.send(AudioPacket::Level(max));
-                // Send Samples
-                let _ = data_tx.send(AudioPacket::Samples(chunk));
-            }
-        }
-    }
-
-    pub fn start_capture(&self) -> anyhow::Result<()> {
-        self.command_tx.send(AudioCommand::Start).map_err(|e| anyhow::anyhow!("Failed to send start command: {}", e))?;
+    fn with_resampler(
+        resampler: Option<StreamResampler>,
+        config: AudioConfig,
+        data_tx: Sender<AudioPacket>,
+        source: Option<String>,
+        metrics: Arc<PipelineMetrics>,
+    ) -> Self {
+        if resampler.as_ref().is_some_and(|r| r.is_degraded()) {
+            metrics.record_resampler_fallback();
+        }
+        pool::SAMPLES.size_for_rate(config.output_sample_rate);
+        Self {
+            resampler,
+            band_pass: BandPass::new(config.band_low_hz, config.band_high_hz, config.output_sample_rate),
+            accumulated: Vec::with_capacity(config.chunk_size() * 2),
+            level_window: LevelWindow::new(config.output_sample_rate as usize * config.level_overlap_ms as usize / 1000),
+            meter: MeterBallistics::new(config.meter_attack_ms, config.meter_release_ms),
+            held_level: 0.0,
+            held_level_chunks: 0,
+            level_reporting: None,
+            echo: None,
+            newest_capture: Instant::now(),
+            warned_non_finite: false,
+            config,
+            data_tx,
+            source,
+            metrics,
+        }
+    }
+
+    /// Levels are only computed and sent while `enabled` is set, on top of `emit_levels`.
+    pub fn with_level_reporting(mut self, enabled: Arc<AtomicBool>) -> Self {
+        self.level_reporting = Some(enabled);
+        self
+    }
+
+    /// Cancels the echo of `reference` from the input when `echo_tail_ms` is set. Each
+    /// resampled input sample takes one reference sample, so one processor per reference.
+    pub fn with_echo_reference(mut self, reference: Arc<EchoReference>) -> Self {
+        if self.config.echo_tail_ms > 0 {
+            self.echo = Some((EchoCanceller::new(self.config.echo_tail_ms, self.config.output_sample_rate), reference));
+        }
+        self
+    }
+
+    /// Rate of the chunks this processor sends.
+    pub fn output_rate(&self) -> u32 {
+        self.config.output_sample_rate
+    }
+
+    /// Latency of this processor's resampler (zero when no resampling is needed).
+    pub fn resampler_latency(&self) -> ResamplerLatency {
+        self.resampler
+            .as_ref()
+            .map(|r| r.latency())
+            .unwrap_or(ResamplerLatency { samples: 0, ms: 0.0 })
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.push_captured(samples, Instant::now());
+    }
+
+    /// `push` for input whose last sample was captured at `captured_at` rather than now.
+    pub fn push_captured(&mut self, samples: &[f32], captured_at: Instant) {
+        self.newest_capture = captured_at;
+        let samples = self.sanitize(samples);
+        let samples = samples.as_ref();
+
+        // 1. Resample (the resampler needs specific block sizes)
+        let start = self.accumulated.len();
+        if let Some(r) = self.resampler.as_mut() {
+            r.process_into(samples, &mut self.accumulated);
+        } else {
+            // No resampling, just passthrough
+            self.accumulated.extend_from_slice(samples);
+        }
+        self.filter_from(start);
+
+        // 2. Chunk for ASR (20-30ms)
+        let chunk_size = self.config.chunk_size();
+        while self.accumulated.len() >= chunk_size {
+            let mut chunk = pool::SAMPLES.take(chunk_size);
+            chunk.extend(self.accumulated.drain(0..chunk_size));
+            self.emit(chunk);
+        }
+    }
+
+    /// Switches to input at `source_rate` mid-stream. What the old resampler still holds
+    /// is pushed through it first, so audio from before the switch keeps its pitch.
+    pub fn set_source_rate(&mut self, source_rate: usize) -> anyhow::Result<()> {
+        let output_rate = self.output_rate() as usize;
+        let resampler = if source_rate != output_rate {
+            Some(StreamResampler::new(source_rate, output_rate, &self.config)?)
+        } else {
+            None
+        };
+        self.drain_resampler();
+        if resampler.as_ref().is_some_and(|r| r.is_degraded()) {
+            self.metrics.record_resampler_fallback();
+        }
+        self.resampler = resampler;
         Ok(())
     }
 
-    pub fn stop_capture(&self) {
-        let _ = self.command_tx.send(AudioCommand::Stop);
+    /// Sends `error` to the listener's `on_error`, in order with the audio.
+    pub fn report_error(&self, error: SupraSonicError) {
+        let _ = self.data_tx.send(AudioPacket::StreamError(error));
+    }
+
+    /// Cancels the echo in, then band-passes, what was resampled into `accumulated` from
+    /// `start` on.
+    fn filter_from(&mut self, start: usize) {
+        if let Some((canceller, reference)) = self.echo.as_mut() {
+            let reference = reference.take(self.accumulated.len() - start);
+            canceller.process(&mut self.accumulated[start..], &reference);
+        }
+        if let Some(band_pass) = self.band_pass.as_mut() {
+            band_pass.process(&mut self.accumulated[start..]);
+        }
+    }
+
+    /// Moves the resampler's backlog and delay line into `accumulated`.
+    fn drain_resampler(&mut self) {
+        if let Some(r) = self.resampler.as_mut() {
+            let start = self.accumulated.len();
+            r.finish_into(&mut self.accumulated);
+            self.filter_from(start);
+        }
+    }
+
+    /// Pushes out the tail that never filled a resampler block or an ASR chunk.
+    pub fn finish(&mut self) {
+        self.drain_resampler();
+
+        let tail = std::mem::take(&mut self.accumulated);
+        for chunk in tail.chunks(self.config.chunk_size()) {
+            let mut buffer = pool::SAMPLES.take(chunk.len());
+            buffer.extend_from_slice(chunk);
+            self.emit(buffer);
+        }
+        // Don't lose the peak of a partial decimation group
+        if self.held_level_chunks > 0 {
+            self.send_held_level();
+        }
+    }
+
+    fn send_held_level(&mut self) {
+        let _ = self.data_tx.send(AudioPacket::Level(self.held_level));
+        self.held_level = 0.0;
+        self.held_level_chunks = 0;
+    }
+
+    /// Counts non-finite samples (flaky drivers emit them) and zeroes them if configured.
+    fn sanitize<'a>(&mut self, samples: &'a [f32]) -> Cow<'a, [f32]> {
+        let bad = samples.iter().filter(|s| !s.is_finite()).count();
+        if bad == 0 {
+            return Cow::Borrowed(samples);
+        }
+        self.metrics.record_non_finite(bad as u64);
+        if !self.warned_non_finite {
+            self.warned_non_finite = true;
+            tracing::warn!("Non-finite input samples ({} in one block, sanitizing: {})", bad, self.config.sanitize_non_finite);
+        }
+        if !self.config.sanitize_non_finite {
+            return Cow::Borrowed(samples);
+        }
+        Cow::Owned(samples.iter().map(|&s| if s.is_finite() { s } else { 0.0 }).collect())
+    }
+
+    fn emit(&mut self, chunk: Vec<f32>) {
+        let reporting = self.level_reporting.as_ref().is_none_or(|f| f.load(Ordering::Relaxed));
+        if !reporting {
+            // Don't fold a peak from before the meter was switched off into the next level
+            self.held_level = 0.0;
+            self.held_level_chunks = 0;
+            self.meter.reset();
+        } else if self.config.emit_levels {
+            // Calculate level for UI
+            let peak = self.level_window.level(&chunk);
+            let level = self.meter.step(peak, chunk.len() as f32 / self.config.output_sample_rate as f32);
+            self.held_level = self.held_level.max(level);
+            self.held_level_chunks += 1;
+
+            // Send Level
+            if self.held_level_chunks >= self.config.level_decimation.max(1) {
+                self.send_held_level();
+            }
+        }
+        if reporting && self.config.waveform_resolution_ms > 0 {
+            let _ = self.data_tx.send(AudioPacket::Waveform(waveform_envelope(&chunk, self.config.waveform_resolution_ms, self.config.output_sample_rate)));
+        }
+        // The chunk's first sample is behind the newest input by the resampler delay,
+        // whatever is still accumulated, and the chunk itself
+        let probe = self.config.measure_latency.then(|| {
+            let behind = self.accumulated.len() + chunk.len() + self.resampler_latency().samples as usize;
+            let behind = Duration::from_secs_f64(behind as f64 / self.config.output_sample_rate as f64);
+            self.newest_capture.checked_sub(behind).unwrap_or(self.newest_capture)
+        });
+        // Send Samples
+        let packet = match &self.source {
+            Some(device) => AudioPacket::DeviceSamples(device.clone(), chunk),
+            None => AudioPacket::Samples(chunk),
+        };
+        let _ = self.data_tx.send(packet);
+        if let Some(captured_at) = probe {
+            let _ = self.data_tx.send(AudioPacket::LatencyProbe(captured_at));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Everything a processor at `source_rate` sends for `samples`, finished.
+    fn process(config: AudioConfig, source_rate: usize, samples: &[f32]) -> Vec<AudioPacket> {
+        process_with_metrics(config, source_rate, samples, Arc::new(PipelineMetrics::new()))
+    }
+
+    fn process_with_metrics(config: AudioConfig, source_rate: usize, samples: &[f32], metrics: Arc<PipelineMetrics>) -> Vec<AudioPacket> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut processor = AudioProcessor::new(source_rate, config, tx, None, metrics).unwrap();
+        processor.push(samples);
+        processor.finish();
+        drop(processor);
+        rx.into_iter().collect()
+    }
+
+    fn sample_count(packets: &[AudioPacket]) -> usize {
+        packets.iter().map(|p| if let AudioPacket::Samples(s) = p { s.len() } else { 0 }).sum()
+    }
+
+    fn levels(packets: &[AudioPacket]) -> Vec<f32> {
+        packets.iter().filter_map(|p| if let AudioPacket::Level(l) = p { Some(*l) } else { None }).collect()
+    }
+
+    #[test]
+    fn f32_to_i16_maps_known_amplitudes() {
+        assert_eq!(f32_to_i16(&[0.0, 0.5, -0.5, 1.0, -1.0]), vec![0, 16384, -16384, i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn f32_to_i16_clamps_out_of_range_samples() {
+        assert_eq!(f32_to_i16(&[1.5, -1.5, 100.0, -100.0]), vec![i16::MAX, i16::MIN, i16::MAX, i16::MIN]);
+        assert_eq!(f32_to_i16(&[f32::INFINITY, f32::NEG_INFINITY]), vec![i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn overlapping_level_window_keeps_a_peak_at_the_chunk_boundary() {
+        let mut first = vec![0.0; 480];
+        first[479] = 0.8;
+        let second = vec![0.1; 480];
+
+        let mut disjoint = LevelWindow::new(0);
+        disjoint.level(&first);
+        assert_eq!(disjoint.level(&second), 0.1);
+
+        let mut overlapping = LevelWindow::new(160);
+        assert_eq!(overlapping.level(&first), 0.8);
+        assert_eq!(overlapping.level(&second), 0.8);
+        // Once the peak is further back than the overlap, it no longer counts
+        assert_eq!(overlapping.level(&second), 0.1);
+    }
+
+    #[test]
+    fn disabled_levels_send_no_level_packets_but_keep_the_samples() {
+        let input = vec![0.25; 4800];
+        let enabled = process(AudioConfig::default(), 16000, &input);
+        assert_eq!(levels(&enabled).len(), 10);
+
+        let disabled = process(AudioConfig { emit_levels: false, ..Default::default() }, 16000, &input);
+        assert!(levels(&disabled).is_empty());
+        assert_eq!(sample_count(&disabled), 4800);
+    }
+
+    #[test]
+    fn resampler_ratio_adjusts_within_the_configured_bound_only() {
+        let config = AudioConfig { max_resample_ratio_relative: 1.1, ..Default::default() };
+        let mut resampler = build_resampler(48000, 16000, &config).unwrap();
+        resampler.set_ratio_relative(1.05).unwrap();
+        resampler.set_ratio_relative(1.0 / 1.05).unwrap();
+        assert!(resampler.set_ratio_relative(1.2).is_err());
+        assert!(resampler.set_ratio_relative(0.8).is_err());
+        // Still usable after a rejected adjustment
+        assert!(resampler.process(&vec![0.0; resampler.input_frames_next()]).is_some());
+
+        // The default keeps the ratio fixed
+        let mut fixed = build_resampler(48000, 16000, &AudioConfig::default()).unwrap();
+        assert!(fixed.set_ratio_relative(1.01).is_err());
+    }
+
+    #[test]
+    fn max_resample_ratio_relative_below_one_is_rejected() {
+        for bad in [0.99, 0.0, f64::NAN] {
+            let config = AudioConfig { max_resample_ratio_relative: bad, ..Default::default() };
+            assert!(config.validate().is_err());
+            assert!(build_resampler(48000, 16000, &config).is_err());
+        }
+    }
+
+    fn output_samples(packets: &[AudioPacket]) -> Vec<f32> {
+        packets.iter().filter_map(|p| if let AudioPacket::Samples(s) = p { Some(s.as_slice()) } else { None }).flatten().copied().collect()
+    }
+
+    #[test]
+    fn non_finite_samples_are_zeroed_and_counted() {
+        let mut input = vec![0.1; 4800];
+        input[10] = f32::NAN;
+        input[2000] = f32::INFINITY;
+        input[3000] = f32::NEG_INFINITY;
+        let metrics = Arc::new(PipelineMetrics::new());
+        let packets = process_with_metrics(AudioConfig::default(), 16000, &input, metrics.clone());
+
+        let output = output_samples(&packets);
+        assert!(output.len() >= 4800);
+        assert!(output.iter().all(|s| s.is_finite()));
+        assert!(levels(&packets).iter().all(|l| l.is_finite()));
+        assert_eq!(metrics.snapshot().non_finite_samples, 3);
+        // The good samples are untouched and the bad ones are zero
+        assert_eq!(output[10], 0.0);
+        assert_eq!(output[11], 0.1);
+    }
+
+    #[test]
+    fn av_authorization_status_maps_to_permission_status() {
+        assert_eq!(PermissionStatus::from_av_authorization(0), PermissionStatus::Undetermined);
+        assert_eq!(PermissionStatus::from_av_authorization(1), PermissionStatus::Denied);
+        assert_eq!(PermissionStatus::from_av_authorization(2), PermissionStatus::Denied);
+        assert_eq!(PermissionStatus::from_av_authorization(3), PermissionStatus::Granted);
+        assert_eq!(PermissionStatus::from_av_authorization(-1), PermissionStatus::Unsupported);
+        assert_eq!(PermissionStatus::from_av_authorization(4), PermissionStatus::Unsupported);
+    }
+
+    #[test]
+    fn mid_and_side_split_a_stereo_signal() {
+        // A centred voice (equal in both channels) over ambience that is out of phase
+        let (voice, ambience) = ([0.4, -0.2, 0.1], [0.1, 0.05, -0.3]);
+        let stereo: Vec<f32> = voice.iter().zip(&ambience).flat_map(|(v, a)| [v + a, v - a]).collect();
+
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6);
+        assert!(close(&downmix(&stereo, 2, DownmixMode::Mid, 0), &voice));
+        assert!(close(&downmix(&stereo, 2, DownmixMode::Side, 0), &ambience));
+        // Full-scale opposite channels stay in range
+        assert_eq!(downmix(&[1.0, -1.0], 2, DownmixMode::Side, 0), [1.0]);
+        assert_eq!(downmix(&[1.0, -1.0], 2, DownmixMode::Mid, 0), [0.0]);
+    }
+
+    #[test]
+    fn mid_and_side_use_a_single_selected_channel_as_is() {
+        assert_eq!(downmix(&[0.3, 0.9, -0.3, 0.1], 2, DownmixMode::Side, 0b10), [0.9, 0.1]);
+    }
+
+    #[test]
+    fn dither_perturbs_quiet_audio_by_at_most_one_step() {
+        // A quiet ramp spanning a few LSBs
+        let quiet: Vec<f32> = (0..4096).map(|i| (i % 64) as f32 / 64.0 * 4.0 / 32768.0).collect();
+        let plain = f32_to_i16(&quiet);
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        let dithered = f32_to_i16_dithered(&quiet, &mut state);
+
+        assert_ne!(dithered, plain);
+        assert!(dithered.iter().zip(&plain).all(|(d, p)| (*d as i32 - *p as i32).abs() <= 1));
+        // Unbiased: the mean level is kept
+        let mean = |v: &[i16]| v.iter().map(|&s| s as f64).sum::<f64>() / v.len() as f64;
+        assert!((mean(&dithered) - mean(&plain)).abs() < 0.1);
+    }
+
+    #[test]
+    fn dither_stays_in_range_at_full_scale() {
+        let mut state = 1;
+        let dithered = f32_to_i16_dithered(&[1.0, -1.0, 1.5, -1.5].repeat(256), &mut state);
+        assert!(dithered.chunks(4).all(|c| c[0] >= i16::MAX - 1 && c[1] <= i16::MIN + 1));
+    }
+
+    fn max_step(samples: &[f32]) -> f32 {
+        samples.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn resumed_sine_has_no_jump_at_the_splice() {
+        let sine = |from: usize, len: usize| -> Vec<f32> {
+            (from..from + len).map(|i| 0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 16000.0).sin()).collect()
+        };
+        // Paused at a zero crossing for a stretch that resumes at the peak
+        let (before, after) = (sine(0, 1600), sine(1600 + 9, 1600));
+        let butted: Vec<f32> = [before.clone(), after.clone()].concat();
+        assert!(max_step(&butted) > 0.4, "the test splice should click without a crossfade");
+
+        let mut splicer = Splicer::new(80);
+        let mut out = splicer.push(&before);
+        splicer.pause();
+        out.extend(splicer.push(&after));
+        out.extend(splicer.finish());
+
+        assert_eq!(out.len(), before.len() + after.len() - 80);
+        // No step larger than the sine's own (2*pi*440/16000 * 0.5 ~ 0.09) plus the fade
+        assert!(max_step(&out) < 0.15, "step {}", max_step(&out));
+    }
+
+    fn tone(freq: f32, rate: usize, secs: usize) -> Vec<f32> {
+        (0..rate * secs).map(|i| 0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin()).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn resampling_192k_to_16k_keeps_the_length_and_filters_out_ultrasonics() {
+        // Blocks stay ~21ms long at the source rate
+        assert_eq!(build_resampler(192_000, 16000, &AudioConfig::default()).unwrap().input_frames_next(), 4096);
+
+        let speech_band = output_samples(&process(AudioConfig::default(), 192_000, &tone(1000.0, 192_000, 2)));
+        assert!(speech_band.len().abs_diff(32000) < 600, "got {} samples", speech_band.len());
+        // Skip the filter's settling time at either end
+        let steady = |s: &[f32]| rms(&s[4000..s.len() - 4000]);
+        assert!((steady(&speech_band) - 0.5 / 2f32.sqrt()).abs() < 0.05);
+
+        // 20 kHz is far above the 8 kHz output Nyquist; without a low-pass it folds down to 4 kHz
+        let ultrasonic = output_samples(&process(AudioConfig::default(), 192_000, &tone(20_000.0, 192_000, 2)));
+        assert!(ultrasonic.len().abs_diff(32000) < 600);
+        assert!(steady(&ultrasonic) < 0.01, "aliased energy {}", steady(&ultrasonic));
+    }
+
+    #[test]
+    fn reported_latency_follows_the_resampler_config() {
+        let latency = |source_rate, offline| {
+            let (tx, _rx) = crossbeam_channel::unbounded();
+            let metrics = Arc::new(PipelineMetrics::new());
+            let processor = if offline {
+                AudioProcessor::new_offline(source_rate, AudioConfig::default(), tx, metrics)
+            } else {
+                AudioProcessor::new(source_rate, AudioConfig::default(), tx, None, metrics)
+            };
+            processor.unwrap().resampler_latency()
+        };
+        // No resampler, no delay
+        assert_eq!(latency(16000, false), ResamplerLatency { samples: 0, ms: 0.0 });
+
+        let live = latency(48000, false);
+        let offline = latency(48000, true);
+        assert!(offline.samples > 0);
+        // The FFT resampler buys quality with a longer delay than the live one
+        assert!(offline.samples > live.samples);
+        for l in [live, offline] {
+            assert_eq!(l.ms, l.samples as f64 / 16.0);
+        }
+        assert_eq!(offline, latency(48000, true));
+    }
+
+    #[test]
+    fn decimated_levels_keep_the_peak_of_every_group() {
+        let mut input = vec![0.05; 4800];
+        // A spike in the second chunk of a pair, and one in the trailing odd chunk
+        input[480 * 3 + 100] = 0.9;
+        input.extend_from_slice(&[0.05; 480]);
+        input[480 * 10 + 7] = 0.7;
+
+        let every = levels(&process(AudioConfig::default(), 16000, &input));
+        let halved = levels(&process(AudioConfig { level_decimation: 2, ..Default::default() }, 16000, &input));
+        assert_eq!(every.len(), 11);
+        // The unpaired last chunk is still reported
+        assert_eq!(halved.len(), 6);
+        let maxima: Vec<f32> = every.chunks(2).map(|g| g.iter().copied().fold(0.0, f32::max)).collect();
+        assert_eq!(halved, maxima);
+        assert_eq!(halved.iter().copied().fold(0.0, f32::max), every.iter().copied().fold(0.0, f32::max));
+    }
+
+    #[test]
+    fn switching_level_reporting_off_stops_levels_but_not_samples() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let reporting = Arc::new(AtomicBool::new(true));
+        let mut processor = AudioProcessor::new(16000, AudioConfig::default(), tx, None, Arc::new(PipelineMetrics::new()))
+            .unwrap()
+            .with_level_reporting(reporting.clone());
+        let step = |processor: &mut AudioProcessor| {
+            processor.push(&[0.3; 4800]);
+            let packets: Vec<AudioPacket> = rx.try_iter().collect();
+            (levels(&packets).len(), sample_count(&packets))
+        };
+
+        assert_eq!(step(&mut processor), (10, 4800));
+        reporting.store(false, Ordering::Relaxed);
+        assert_eq!(step(&mut processor), (0, 4800));
+        reporting.store(true, Ordering::Relaxed);
+        assert_eq!(step(&mut processor), (10, 4800));
+    }
+
+    #[test]
+    fn a_config_that_cannot_deliver_the_expected_channels_is_rejected_at_build_time() {
+        for ok in [0, 1] {
+            assert!(AudioConfig { expected_output_channels: ok, ..Default::default() }.validate().is_ok());
+        }
+        let stereo_model = AudioConfig { expected_output_channels: 2, ..Default::default() };
+        let error = stereo_model.validate().unwrap_err().to_string();
+        assert!(error.contains("expected_output_channels is 2"), "{error}");
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        assert!(AudioProcessor::new(48000, stereo_model.clone(), tx, None, Arc::new(PipelineMetrics::new())).is_err());
+        assert!(AudioConfig::from_json(&serde_json::to_string(&stereo_model).unwrap()).is_err());
+    }
+
+    #[test]
+    fn chunked_streaming_resampling_matches_one_shot() {
+        let input = tone(440.0, 44100, 3);
+        let expected_len = (input.len() as f64 * 16000.0 / 44100.0).round() as usize;
+        type Build = fn() -> StreamResampler;
+        let builds: [Build; 2] = [
+            || StreamResampler::new(44100, 16000, &AudioConfig::default()).unwrap(),
+            || StreamResampler::new_offline(44100, 16000).unwrap(),
+        ];
+        for build in builds {
+            let mut one_shot = build();
+            let mut whole = one_shot.process(&input);
+            whole.extend(one_shot.finish());
+            assert_eq!(whole.len(), expected_len);
+
+            // Ragged chunks, from single samples to several blocks at once
+            let mut streaming = build();
+            let (mut streamed, mut rest, mut size) = (Vec::new(), input.as_slice(), 1);
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(size.min(rest.len()));
+                streaming.process_into(chunk, &mut streamed);
+                // Never more than a block of input held back
+                assert!(streaming.pending.len() < streaming.resampler.input_frames_next());
+                rest = tail;
+                size = size * 7 % 5000 + 1;
+            }
+            streaming.finish_into(&mut streamed);
+            assert_eq!(streamed, whole);
+        }
+    }
+
+    #[test]
+    fn configured_band_limits_apply_after_resampling() {
+        let narrowband = AudioConfig { band_low_hz: 300.0, band_high_hz: 3400.0, ..Default::default() };
+        let hum = output_samples(&process(narrowband.clone(), 48000, &tone(75.0, 48000, 1)));
+        let voice = output_samples(&process(narrowband, 48000, &tone(1000.0, 48000, 1)));
+        assert!(rms(&hum[4000..12000]) < 0.01, "hum {}", rms(&hum[4000..12000]));
+        assert!(rms(&voice[4000..12000]) > 0.3);
+        // Off by default
+        let open = output_samples(&process(AudioConfig::default(), 48000, &tone(75.0, 48000, 1)));
+        assert!(rms(&open[4000..12000]) > 0.3);
+    }
+
+    #[test]
+    fn resampler_blocks_the_chunker_cannot_use_are_rejected() {
+        // Coprime with a 48 kHz model, an odd file rate needs whole-second FFT output blocks
+        let error = StreamResampler::new_offline(44101, 48000).err().expect("coprime rates accepted").to_string();
+        assert!(error.contains("common factor"), "{error}");
+        // The limit is in time, so the same pair is refused at any output rate
+        for target in [8000, 16000, 24000] {
+            assert!(StreamResampler::new_offline(44101, target).is_err(), "44101 -> {target}");
+        }
+        let model_48k = AudioConfig { output_sample_rate: 48000, ..Default::default() };
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        assert!(AudioProcessor::new_offline(44101, model_48k, tx, Arc::new(PipelineMetrics::new())).is_err());
+        // Usual pairs are fine on both paths
+        for rate in [8000, 22050, 44100, 48000, 96000] {
+            for target in [16000, 48000] {
+                assert!(StreamResampler::new_offline(rate, target).is_ok(), "{rate} -> {target}");
+                assert!(StreamResampler::new(rate, target, &AudioConfig::default()).is_ok(), "{rate} -> {target}");
+            }
+        }
+    }
+
+    #[test]
+    fn failed_resampler_construction_falls_back_to_degraded_but_present_audio() {
+        // rubato refuses a ratio range below 1, which `AudioConfig::validate` never lets through
+        let failed = build_resampler_kind(ResamplerKind::Cubic, 48000, 16000, 0.5);
+        assert!(failed.is_err());
+        let resampler = StreamResampler::from_resampler(or_linear_fallback(failed, 48000, 16000), 48000, 16000).unwrap();
+        assert!(resampler.is_degraded());
+
+        let metrics = Arc::new(PipelineMetrics::new());
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut processor = AudioProcessor::with_resampler(Some(resampler), AudioConfig::default(), tx, None, metrics.clone());
+        processor.push(&tone(440.0, 48000, 1));
+        processor.finish();
+        drop(processor);
+        let out = output_samples(&rx.into_iter().collect::<Vec<_>>());
+
+        assert!(out.len().abs_diff(16000) < 1100, "got {} samples", out.len());
+        assert!((rms(&out[1000..out.len() - 1000]) - 0.5 / 2f32.sqrt()).abs() < 0.05, "the tone comes through");
+        assert_eq!(metrics.snapshot().resampler_fallbacks, 1);
+    }
+
+    #[test]
+    fn fully_populated_config_round_trips_through_json() {
+        // No `..Default::default()`: a new field has to be added here, set off its default
+        let config = AudioConfig {
+            sample_format: SampleFormat::I16,
+            dither_i16: true,
+            dispatch_mode: DispatchMode::Utterance,
+            downmix_mode: DownmixMode::FirstChannel,
+            downmix_channels: 0b110,
+            skip_non_speech: true,
+            vad_enabled: true,
+            interim_interval_ms: 900,
+            assign_speakers: true,
+            measure_latency: true,
+            vad_onset_threshold: 0.05,
+            vad_offset_threshold: 0.03,
+            vad_min_silence_ms: 450,
+            auto_stop_silence_ms: 8000,
+            dead_input_ms: 4000,
+            vad_trailing_silence_ms: 300,
+            vad_leading_silence_ms: 150,
+            max_utterance_ms: 20_000,
+            emit_levels: false,
+            level_overlap_ms: 10,
+            level_decimation: 3,
+            meter_attack_ms: 5,
+            meter_release_ms: 500,
+            waveform_resolution_ms: 20,
+            keep_warm_silence: true,
+            band_low_hz: 100.0,
+            band_high_hz: 3800.0,
+            echo_tail_ms: 64,
+            clip_hold_ms: 1000,
+            max_resample_ratio_relative: 1.1,
+            loudness_target_lufs: Some(-23.0),
+            sanitize_non_finite: false,
+            diagnostic_log: true,
+            export_audio_checksum: true,
+            file_realtime_factor: 4.0,
+            file_backpressure: FileBackpressure::DropOldest,
+            source_rate_override: Some(44100),
+            warmup_discard_ms: 100,
+            ring_buffer_ms: 2000,
+            stop_while_paused: StopWhilePaused::Discard,
+            splice_crossfade_ms: 10,
+            dispatch_frame_size: 320,
+            pad_final_frame: true,
+            mel_bands: 40,
+            mel_window_ms: 20,
+            mel_hop_ms: 5,
+            expected_output_channels: 1,
+            output_sample_rate: 8000,
+        };
+        config.validate().unwrap();
+        let json = config.to_json();
+        let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let defaults: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&AudioConfig::default().to_json()).unwrap();
+        for (field, value) in &fields {
+            assert_ne!(Some(value), defaults.get(field), "{} is still at its default", field);
+        }
+
+        let loaded = AudioConfig::from_json(&json).unwrap();
+        assert_eq!(loaded.to_json(), json);
+        assert_eq!(loaded.loudness_target_lufs, Some(-23.0));
+        assert_eq!(loaded.file_backpressure, FileBackpressure::DropOldest);
+    }
+
+    #[test]
+    fn config_json_with_unknown_or_invalid_values_is_rejected() {
+        assert!(AudioConfig::from_json(r#"{"vad_enabled": true, "turbo": 1}"#).is_err(), "unknown field");
+        assert!(AudioConfig::from_json(r#"{"downmix_mode": "Surround"}"#).is_err(), "unknown variant");
+        assert!(AudioConfig::from_json(r#"{"max_resample_ratio_relative": 0.5}"#).is_err(), "fails validation");
+        // Missing fields take their defaults
+        assert_eq!(AudioConfig::from_json(r#"{"vad_enabled": true}"#).unwrap().ring_buffer_ms, 5000);
+    }
+
+    fn waveforms(packets: &[AudioPacket]) -> Vec<&[f32]> {
+        packets.iter().filter_map(|p| if let AudioPacket::Waveform(e) = p { Some(e.as_slice()) } else { None }).collect()
+    }
+
+    #[test]
+    fn waveform_envelope_has_a_min_max_pair_per_slice() {
+        // 5 ms at 16 kHz: 80-sample slices, so 6 pairs per 30 ms chunk
+        let packets = process(AudioConfig { waveform_resolution_ms: 5, ..Default::default() }, 16000, &tone(1000.0, 16000, 1)[..480 * 33]);
+        let envelopes = waveforms(&packets);
+        assert_eq!((envelopes.len(), sample_count(&packets)), (33, 480 * 33));
+        assert!(envelopes.iter().all(|e| e.len() == 12));
+        // Each slice holds a full 1 kHz cycle, so both peaks show
+        assert!(envelopes.iter().flat_map(|e| e.chunks(2)).all(|pair| pair[0] < -0.45 && pair[1] > 0.45));
+
+        let ramp: Vec<f32> = (0..480).map(|i| i as f32).collect();
+        let envelope = waveform_envelope(&ramp, 7, 16000);
+        // 112-sample slices; the short last one gets a pair too
+        assert_eq!(envelope, [0.0, 111.0, 112.0, 223.0, 224.0, 335.0, 336.0, 447.0, 448.0, 479.0]);
+    }
+
+    #[test]
+    fn waveform_is_opt_in_and_bounded_by_the_chunk() {
+        assert!(waveforms(&process(AudioConfig::default(), 16000, &tone(1000.0, 16000, 1))).is_empty());
+        assert!(AudioConfig { waveform_resolution_ms: 31, ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn target_latency_gives_a_consistent_config_within_budget() {
+        let base = AudioConfig::default();
+        let resampler_ms = StreamResampler::new(48000, 16000, &base).unwrap().latency().ms;
+        let mut last_frame = 0;
+        for target in [60, 100, 250, 1000] {
+            let config = base.for_target_latency(target, 48000).unwrap();
+            config.validate().unwrap();
+            let frame = match config.dispatch_frame_size { 0 => 480, n => n as usize };
+            assert_eq!(frame % 480, 0, "{target}ms: frames are whole chunks");
+            let total_ms = frame as f64 / 16.0 + resampler_ms + config.splice_crossfade_ms as f64;
+            assert!(total_ms <= target as f64, "{target}ms target, {total_ms:.1}ms configured");
+            // Within a chunk of the target: the spare went into the frame
+            assert!(target as f64 - total_ms < 30.0 + base.splice_crossfade_ms as f64, "{target}ms: {total_ms:.1}ms");
+            assert!(frame >= last_frame, "more latency never means smaller frames");
+            last_frame = frame;
+            assert_eq!(config.ring_buffer_ms, (target * 10).max(1000));
+        }
+
+        // Native 16 kHz needs no resampler: one chunk is enough
+        let native = base.for_target_latency(30, 16000).unwrap();
+        assert_eq!((native.dispatch_frame_size, native.splice_crossfade_ms), (0, 0));
+        assert!(base.for_target_latency(29, 16000).is_err());
+        assert!(base.for_target_latency(30, 48000).is_err(), "the resampler adds to the floor");
+        assert!(base.for_target_latency(100, 999).is_err(), "unsupported rate");
+    }
+
+    #[test]
+    fn interleave_and_deinterleave_round_trip() {
+        // Three channels, each sample tagged with its frame and channel
+        let interleaved: Vec<f32> = (0..5).flat_map(|frame| (0..3).map(move |ch| frame as f32 * 10.0 + ch as f32)).collect();
+        let planar = deinterleave(&interleaved, 3);
+        assert_eq!(planar, [vec![0.0, 10.0, 20.0, 30.0, 40.0], vec![1.0, 11.0, 21.0, 31.0, 41.0], vec![2.0, 12.0, 22.0, 32.0, 42.0]]);
+        assert_eq!(interleave(&planar), interleaved);
+
+        // A trailing partial frame is dropped, the whole frames before it kept
+        let ragged = [&interleaved[..], &[50.0, 51.0]].concat();
+        assert_eq!(deinterleave(&ragged, 3), planar);
+        assert_eq!(interleave(&deinterleave(&ragged, 3)), interleaved);
+        // Uneven channels interleave up to the shortest
+        let uneven = vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0]];
+        assert_eq!(interleave(&uneven), [1.0, 4.0, 2.0, 5.0]);
+
+        // Mono is the identity; no channels, or no audio, give nothing
+        assert_eq!(deinterleave(&[1.0, 2.0], 1), [vec![1.0, 2.0]]);
+        assert!(deinterleave(&interleaved, 0).is_empty());
+        assert_eq!(deinterleave(&[], 2), [Vec::<f32>::new(), Vec::new()]);
+        assert!(interleave(&[]).is_empty());
+    }
+
+    /// Frequency of a tone from its rising zero crossings.
+    fn frequency(samples: &[f32], rate: usize) -> f32 {
+        let rising = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        rising as f32 * rate as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn resampler_is_rebuilt_for_a_mid_stream_rate_change() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut processor = AudioProcessor::new(48000, AudioConfig::default(), tx, None, Arc::new(PipelineMetrics::new())).unwrap();
+        // A second of 1 kHz before the device switches to 44.1 kHz, a second after
+        processor.push(&tone(1000.0, 48000, 1));
+        processor.set_source_rate(44100).unwrap();
+        processor.push(&tone(1000.0, 44100, 1));
+        processor.finish();
+        drop(processor);
+        let out = output_samples(&rx.into_iter().collect::<Vec<_>>());
+
+        // Both halves come out as a second of the same 1 kHz: no pitch shift, nothing
+        // lost or doubled at the switch (read as 48 kHz, the second would be 919 ms of 1088 Hz)
+        assert!(out.len().abs_diff(32000) < 480, "{}", out.len());
+        let (before, after) = (&out[1000..15000], &out[17000..31000]);
+        assert!((frequency(before, 16000) - 1000.0).abs() < 10.0, "{}", frequency(before, 16000));
+        assert!((frequency(after, 16000) - 1000.0).abs() < 10.0, "{}", frequency(after, 16000));
+        assert!((rms(after) - rms(before)).abs() < 0.02);
+    }
+
+    #[test]
+    fn gain_envelope_ramp_is_interpolated_at_each_sample() {
+        // Unity until 1 s, down to a quarter by 3 s, held there
+        let points = vec![GainPoint { at_seconds: 1.0, gain: 1.0 }, GainPoint { at_seconds: 3.0, gain: 0.25 }];
+        let envelope = GainEnvelope::new(points).unwrap();
+        let rate = 1000;
+        let mut whole = vec![0.8; 4 * rate];
+        envelope.apply(&mut whole, 0.0, rate);
+        for (sample, expected_gain) in [(0, 1.0), (999, 1.0), (1000, 1.0), (1500, 0.8125), (2000, 0.625), (2750, 0.34375), (3000, 0.25), (3999, 0.25)] {
+            assert!((whole[sample] - 0.8 * expected_gain).abs() < 1e-6, "sample {sample}: {}", whole[sample]);
+        }
+
+        // Block by block, each starting where the last ended, gives the same
+        let mut blocks = vec![0.8; 4 * rate];
+        for (i, block) in blocks.chunks_mut(333).enumerate() {
+            envelope.apply(block, (i * 333) as f64 / rate as f64, rate);
+        }
+        assert!(whole.iter().zip(&blocks).all(|(a, b)| (a - b).abs() < 1e-6));
+
+        assert!(GainEnvelope::new(Vec::new()).is_err());
+        assert!(GainEnvelope::new(vec![GainPoint { at_seconds: 1.0, gain: 1.0 }, GainPoint { at_seconds: 1.0, gain: 0.5 }]).is_err());
+        assert!(GainEnvelope::new(vec![GainPoint { at_seconds: 0.0, gain: -1.0 }]).is_err());
+    }
+
+    #[test]
+    fn downmix_averages_only_the_selected_channels() {
+        // Two frames of a six-mic array: front pair 0/1, sides 2/3, rear 4/5
+        let frames = [0.2, 0.4, 0.9, 0.9, -0.8, -0.8, -0.2, 0.0, 0.5, 0.5, 0.7, 0.7];
+        let close = |a: &[f32], b: &[f32]| a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6);
+        assert!(close(&downmix(&frames, 6, DownmixMode::Average, 0b000011), &[0.3, -0.1]));
+        assert!(close(&downmix(&frames, 6, DownmixMode::Average, 0b110000), &[-0.8, 0.7]));
+        // Non-adjacent picks, and an empty mask meaning all of them
+        assert!(close(&downmix(&frames, 6, DownmixMode::Average, 0b010001), &[-0.3, 0.25]));
+        assert!(close(&downmix(&frames, 6, DownmixMode::Average, 0), &[0.8 / 6.0, 2.2 / 6.0]));
+        // The other modes take the lowest selected channels
+        assert!(close(&downmix(&frames, 6, DownmixMode::FirstChannel, 0b001100), &[0.9, 0.5]));
+        assert!(close(&downmix(&frames, 6, DownmixMode::Mid, 0b100010), &[-0.2, 0.35]));
+
+        // Channels the device doesn't have are rejected up front
+        let front = AudioConfig { downmix_channels: 0b000011, ..Default::default() };
+        assert!(front.validate_for_input(6).is_ok());
+        assert!(front.validate_for_input(2).is_ok());
+        assert!(front.validate_for_input(1).is_err());
+        let rear = AudioConfig { downmix_channels: 0b110000, ..Default::default() };
+        assert!(rear.validate_for_input(4).is_err());
+    }
+
+    #[test]
+    fn meter_ballistics_follow_the_configured_time_constants() {
+        // 30 ms chunks: a steady peak of 0.5, then silence
+        let mut voice = MeterBallistics::new(100, 300);
+        let rise = voice.step(0.5, 0.03);
+        assert!((rise - 0.5 * (1.0 - (-0.3f32).exp())).abs() < 1e-6, "{rise}");
+        let held = (0..30).map(|_| voice.step(0.5, 0.03)).last().unwrap();
+        assert!((held - 0.5).abs() < 1e-3, "{held}");
+        let fall = voice.step(0.0, 0.03);
+        assert!((fall - held * (-0.1f32).exp()).abs() < 1e-6, "{fall}");
+
+        let mut instant = MeterBallistics::new(0, 0);
+        assert_eq!(instant.step(0.5, 0.03), 0.5);
+        assert_eq!(instant.step(0.0, 0.03), 0.0);
+
+        // A clip shows at once however slow the attack
+        assert_eq!(MeterBallistics::new(5000, 300).step(1.0, 0.03), 1.0);
+    }
+
+    #[test]
+    fn configured_meter_constants_shape_the_emitted_levels() {
+        let mut burst = vec![0.5; TARGET_SAMPLE_RATE / 10];
+        burst.extend(vec![0.0; TARGET_SAMPLE_RATE / 10]);
+        let meter = |attack, release| {
+            levels(&process(AudioConfig { meter_attack_ms: attack, meter_release_ms: release, ..Default::default() }, TARGET_SAMPLE_RATE, &burst))
+        };
+        let (fast, slow) = (meter(0, 0), meter(200, 2000));
+        assert_eq!(fast.len(), slow.len());
+        assert!(slow[0] < fast[0] * 0.3, "slow attack rises gradually: {slow:?} vs {fast:?}");
+        let last = fast.len() - 1;
+        assert!(fast[last] < 0.01 && slow[last] > 0.1, "slow release still shows the burst: {slow:?} vs {fast:?}");
+    }
+
+    #[test]
+    fn processor_cancels_the_echo_of_the_pushed_reference() {
+        // Playback as a two-tone chord; the mic hears it 5 ms later at half volume
+        let playback: Vec<f32> = (0..3 * TARGET_SAMPLE_RATE).map(|i| 0.3 * (i as f32 * 0.11).sin() + 0.2 * (i as f32 * 0.37).sin()).collect();
+        let mic: Vec<f32> = (0..playback.len()).map(|i| i.checked_sub(80).map_or(0.0, |j| 0.5 * playback[j])).collect();
+        let tail_energy = |echo_tail_ms| {
+            let (tx, rx) = crossbeam_channel::unbounded();
+            let config = AudioConfig { echo_tail_ms, ..Default::default() };
+            let reference = Arc::new(EchoReference::default());
+            let mut processor = AudioProcessor::new(TARGET_SAMPLE_RATE, config, tx, None, Arc::new(PipelineMetrics::new()))
+                .unwrap()
+                .with_echo_reference(reference.clone());
+            // Playback is pushed as it is played, just ahead of the mic picking it up
+            for (played, heard) in playback.chunks(480).zip(mic.chunks(480)) {
+                reference.push(played, TARGET_SAMPLE_RATE as u32).unwrap();
+                processor.push(heard);
+            }
+            processor.finish();
+            drop(processor);
+            let output = output_samples(&rx.into_iter().collect::<Vec<_>>());
+            output[2 * TARGET_SAMPLE_RATE..].iter().map(|s| s * s).sum::<f32>()
+        };
+        let (open, cancelled) = (tail_energy(0), tail_energy(16));
+        assert!(cancelled < open * 0.01, "{cancelled} vs {open}");
+    }
+
+    #[test]
+    fn the_drained_resampler_tail_takes_its_share_of_the_reference() {
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let config = AudioConfig { echo_tail_ms: 16, ..Default::default() };
+        let reference = Arc::new(EchoReference::default());
+        let mut processor = AudioProcessor::new(48000, config, tx, None, Arc::new(PipelineMetrics::new()))
+            .unwrap()
+            .with_echo_reference(reference.clone());
+        // One second each way: every output sample, tail included, is matched to one reference sample
+        reference.push(&[0.25; TARGET_SAMPLE_RATE], TARGET_SAMPLE_RATE as u32).unwrap();
+        processor.push(&tone(440.0, 48000, 1));
+        processor.finish();
+        assert!(reference.take(TARGET_SAMPLE_RATE).iter().all(|&s| s == 0.0), "reference left over after the tail");
+    }
+
+    #[test]
+    fn output_rate_sets_the_resampling_target_and_chunk_size() {
+        let at_8k = AudioConfig { output_sample_rate: 8000, ..Default::default() };
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let processor = AudioProcessor::new(48000, at_8k.clone(), tx, None, Arc::new(PipelineMetrics::new())).unwrap();
+        assert_eq!(processor.output_rate(), 8000);
+        assert!(processor.resampler.is_some());
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        assert!(AudioProcessor::new(8000, at_8k.clone(), tx, None, Arc::new(PipelineMetrics::new())).unwrap().resampler.is_none());
+
+        let packets = process(at_8k, 48000, &tone(440.0, 48000, 1));
+        let chunks: Vec<usize> = packets.iter().filter_map(|p| if let AudioPacket::Samples(s) = p { Some(s.len()) } else { None }).collect();
+        assert!(chunks[..chunks.len() - 1].iter().all(|&n| n == 240), "30 ms at 8 kHz: {chunks:?}");
+        let output = output_samples(&packets);
+        assert!(output.len().abs_diff(8000) <= 240);
+        assert!((frequency(&output[2000..6000], 8000) - 440.0).abs() < 5.0);
     }
 }
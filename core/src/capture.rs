@@ -0,0 +1,1487 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam_channel::{unbounded, Sender};
+use ringbuf::{HeapProd, HeapRb, traits::*};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing;
+
+use crate::audio::{
+    self, AudioConfig, AudioPacket, AudioProcessor, DownmixMode, GainEnvelope, Splicer, StopReason, StopWhilePaused,
+};
+use crate::diagnostics;
+use crate::echo::EchoReference;
+use crate::metrics::{self, Heartbeat, PipelineMetrics};
+use crate::state::SupraSonicError;
+use crate::vad;
+
+pub struct AudioEngine {
+    command_tx: Sender<AudioCommand>,
+    heartbeat: Arc<Heartbeat>,
+    thread: Option<JoinHandle<()>>,
+    // While set, the workers drop captured audio but keep their resampler state
+    paused: Arc<AtomicBool>,
+    effective_config: Arc<Mutex<Option<AudioConfig>>>,
+    gain_envelope: Arc<Mutex<Option<Arc<GainEnvelope>>>>,
+    echo_reference: Arc<EchoReference>,
+    // RecordingStarted packets sent so far
+    recording_starts: Arc<AtomicU64>,
+}
+
+enum AudioCommand {
+    /// Capture from the default device; `continue_utterance` reopens the last flushed one.
+    Start { continue_utterance: bool },
+    /// Capture from each named input device at once.
+    StartDevices(Vec<String>),
+    Stop,
+    /// Stop, then exit the engine thread.
+    Shutdown,
+}
+
+/// Routes cpal error callbacks back to the engine thread, tagged with the stream generation.
+#[derive(Clone)]
+struct StreamErrorSink {
+    tx: Sender<(u64, SupraSonicError)>,
+    generation: u64,
+}
+
+/// A live capture: the cpal stream plus the processing thread draining its ring buffer.
+struct CaptureSession {
+    // The cpal stream, or a test's stand-in; dropping it stops the callbacks
+    stream: Box<dyn std::any::Any>,
+    running: Arc<AtomicBool>,
+    worker: JoinHandle<()>,
+}
+
+impl CaptureSession {
+    /// Stops the stream and waits until everything it captured has been dispatched.
+    fn finish(self) {
+        let CaptureSession { stream, running, worker } = self;
+        drop(stream);
+        running.store(false, Ordering::SeqCst);
+        let _ = worker.join();
+    }
+}
+
+/// Flags the host flips at runtime and what the streams report back, shared by every
+/// stream of the engine.
+#[derive(Clone)]
+struct StreamSwitches {
+    paused: Arc<AtomicBool>,
+    level_reporting: Arc<AtomicBool>,
+    // Beaten by the workers whenever input is above the silence threshold (or paused)
+    sound: Arc<Heartbeat>,
+    // Config as applied to the first stream built since the last (re)start
+    effective_config: Arc<Mutex<Option<AudioConfig>>>,
+    gain_envelope: Arc<Mutex<Option<Arc<GainEnvelope>>>>,
+    // Seconds each stream (by device tag, None = default device) has read this recording:
+    // the gain envelope's clock, kept across stream-error recovery
+    envelope_clock: Arc<Mutex<HashMap<Option<String>, f64>>>,
+    // Playback to cancel from the default-device stream (see `AudioConfig::echo_tail_ms`)
+    echo_reference: Arc<EchoReference>,
+}
+
+impl StreamSwitches {
+    fn forget_effective_config(&self) {
+        if let Ok(mut effective) = self.effective_config.lock() {
+            *effective = None;
+        }
+    }
+
+    fn restart_envelope_clock(&self) {
+        if let Ok(mut clock) = self.envelope_clock.lock() {
+            clock.clear();
+        }
+    }
+}
+
+/// What every stream of a recording is built with.
+struct SessionContext<'a> {
+    data_tx: &'a Sender<AudioPacket>,
+    config: AudioConfig,
+    errors: StreamErrorSink,
+    metrics: &'a Arc<PipelineMetrics>,
+    switches: &'a StreamSwitches,
+}
+
+/// Opens the input streams of a recording: cpal's devices, or a test's scripted input.
+trait InputBackend: Send + 'static {
+    /// The streams that started, for the default input (`devices` None) or each named one.
+    fn start(&self, ctx: &SessionContext, devices: Option<&[String]>) -> Vec<CaptureSession>;
+}
+
+struct CpalInput;
+
+/// A stream as negotiated with its device.
+struct StreamFormat {
+    device: String,
+    source: Option<String>,
+    sample_rate: usize,
+    channels: usize,
+}
+
+/// How the worker interprets the raw interleaved samples of one stream.
+struct InputLayout {
+    device: String,
+    // The device tag (None = default device)
+    source: Option<String>,
+    channels: usize,
+    sample_rate: usize,
+    downmix_mode: DownmixMode,
+    downmix_channels: u64,
+    // Interleaved samples to drop at stream start (device warmup clicks)
+    warmup_samples: usize,
+    // Mono source-rate samples crossfaded across a pause
+    crossfade_samples: usize,
+    // RMS below which input counts as silence for auto-stop
+    silence_threshold: f32,
+    dead_input_ms: u32,
+    // Drop the held-back tail instead of processing it when the stream ends paused
+    discard_when_paused: bool,
+}
+
+// Frames popped from the ring buffer per read
+const READ_FRAMES: usize = 2048;
+// Reads shorter than this wait for more audio, up to the timeout (a third of a 30ms chunk,
+// so the resampler still gets fed several times per chunk)
+const MIN_READ_MS: usize = 10;
+const READ_COALESCE_TIMEOUT: Duration = Duration::from_millis(20);
+
+// Window over which the worker measures the rate audio really arrives at, and how far
+// off (relative) two windows in a row must be to count as a rate change
+const RATE_CHECK_WINDOW: Duration = Duration::from_secs(2);
+const RATE_CHANGE_TOLERANCE: f64 = 0.05;
+// Rates a device may switch to, and how close a measurement must be to snap to one
+const STANDARD_RATES: [u32; 12] = [8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400, 192000];
+const RATE_SNAP_TOLERANCE: f64 = 0.02;
+
+/// Measures the rate frames really arrive at, to catch devices (aggregate ones in
+/// particular) that change rate mid-stream without cpal telling anyone. The first window
+/// after a (re)start is skipped: stream start-up delivers in bursts.
+struct RateMonitor {
+    expected: usize,
+    window_start: Option<Instant>,
+    skip_window: bool,
+    frames: usize,
+    off_windows: u32,
+    silenced: bool,
+}
+
+impl RateMonitor {
+    fn new(expected: usize) -> Self {
+        Self { expected, window_start: None, skip_window: true, frames: 0, off_windows: 0, silenced: false }
+    }
+
+    /// Forgets the current measurement, e.g. after audio was dropped.
+    fn restart(&mut self) {
+        *self = Self { silenced: self.silenced, ..Self::new(self.expected) };
+    }
+
+    /// Stops reporting until rebuilt for a new rate.
+    fn silence(&mut self) {
+        self.silenced = true;
+    }
+
+    /// Counts `frames` read at `now`; returns the measured rate (Hz) once two full
+    /// windows in a row were off from the expected one.
+    fn observe(&mut self, frames: usize, now: Instant) -> Option<f64> {
+        let start = *self.window_start.get_or_insert(now);
+        self.frames += frames;
+        let elapsed = now.duration_since(start);
+        if elapsed < RATE_CHECK_WINDOW {
+            return None;
+        }
+        let measured = self.frames as f64 / elapsed.as_secs_f64();
+        self.window_start = Some(now);
+        self.frames = 0;
+        if std::mem::take(&mut self.skip_window) || self.silenced {
+            return None;
+        }
+        if (measured - self.expected as f64).abs() <= self.expected as f64 * RATE_CHANGE_TOLERANCE {
+            self.off_windows = 0;
+            return None;
+        }
+        self.off_windows += 1;
+        (self.off_windows >= 2).then_some(measured)
+    }
+}
+
+/// The standard rate within `RATE_SNAP_TOLERANCE` of a measured one, if any.
+fn nearest_standard_rate(measured: f64) -> Option<u32> {
+    STANDARD_RATES
+        .into_iter()
+        .min_by(|a, b| (*a as f64 - measured).abs().total_cmp(&(*b as f64 - measured).abs()))
+        .filter(|rate| (*rate as f64 - measured).abs() <= *rate as f64 * RATE_SNAP_TOLERANCE)
+}
+
+/// Spots a run of exactly-zero input, which a live microphone never produces (even a
+/// quiet room has a noise floor) but a muted or failed input does.
+struct DeadInputDetector {
+    // Zero frames that make a run worth reporting (0 = off)
+    limit_frames: usize,
+    zero_frames: usize,
+    reported: bool,
+}
+
+impl DeadInputDetector {
+    fn new(dead_input_ms: u32, sample_rate: usize) -> Self {
+        Self { limit_frames: sample_rate * dead_input_ms as usize / 1000, zero_frames: 0, reported: false }
+    }
+
+    /// Counts the interleaved `samples`; true once per run, when it reaches the limit.
+    fn observe(&mut self, samples: &[f32], channels: usize) -> bool {
+        if self.limit_frames == 0 {
+            return false;
+        }
+        if samples.iter().any(|&s| s != 0.0) {
+            // Only the frames after the last non-zero one start the next run
+            let last = samples.iter().rposition(|&s| s != 0.0).unwrap_or(0);
+            self.zero_frames = (samples.len() - last - 1) / channels;
+            self.reported = false;
+            return false;
+        }
+        self.zero_frames += samples.len() / channels;
+        if self.reported || self.zero_frames < self.limit_frames {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+
+    /// Forgets the current run, e.g. across a pause.
+    fn reset(&mut self) {
+        self.zero_frames = 0;
+    }
+}
+
+/// Holds back reads of fewer than `min_frames` until `timeout` has passed since audio first
+/// showed up, so a device delivering in tiny bursts is read in batches rather than a few
+/// samples at a time.
+struct ReadCoalescer {
+    min_frames: usize,
+    timeout: Duration,
+    waiting_since: Option<Instant>,
+}
+
+impl ReadCoalescer {
+    fn new(sample_rate: usize) -> Self {
+        Self { min_frames: (sample_rate * MIN_READ_MS / 1000).max(1), timeout: READ_COALESCE_TIMEOUT, waiting_since: None }
+    }
+
+    /// Whether `frames` available at `now` should be read now.
+    fn ready(&mut self, frames: usize, now: Instant) -> bool {
+        if frames >= self.min_frames {
+            self.waiting_since = None;
+            return true;
+        }
+        let since = *self.waiting_since.get_or_insert(now);
+        let ready = now.duration_since(since) >= self.timeout;
+        if ready {
+            self.waiting_since = None;
+        }
+        ready
+    }
+}
+
+impl AudioEngine {
+    pub fn new(
+        data_tx: Sender<AudioPacket>,
+        config: Arc<Mutex<AudioConfig>>,
+        metrics: Arc<PipelineMetrics>,
+        level_reporting: Arc<AtomicBool>,
+        recording: Arc<AtomicBool>,
+    ) -> Self {
+        Self::with_backend(data_tx, config, metrics, level_reporting, recording, CpalInput)
+    }
+
+    fn with_backend(
+        data_tx: Sender<AudioPacket>,
+        config: Arc<Mutex<AudioConfig>>,
+        metrics: Arc<PipelineMetrics>,
+        level_reporting: Arc<AtomicBool>,
+        // The host's "is recording" flag; set on every start, cleared when silence stops it
+        recording: Arc<AtomicBool>,
+        backend: impl InputBackend,
+    ) -> Self {
+        let (cmd_tx, cmd_rx) = unbounded();
+        let heartbeat = Arc::new(Heartbeat::new());
+        let thread_heartbeat = heartbeat.clone();
+        let paused = Arc::new(AtomicBool::new(false));
+        let effective_config = Arc::new(Mutex::new(None));
+        let gain_envelope = Arc::new(Mutex::new(None));
+        let echo_reference = Arc::new(EchoReference::default());
+        let recording_starts = Arc::new(AtomicU64::new(0));
+        let thread_recording_starts = recording_starts.clone();
+        let switches = StreamSwitches {
+            paused: paused.clone(),
+            level_reporting,
+            sound: Arc::new(Heartbeat::new()),
+            effective_config: effective_config.clone(),
+            gain_envelope: gain_envelope.clone(),
+            envelope_clock: Arc::new(Mutex::new(HashMap::new())),
+            echo_reference: echo_reference.clone(),
+        };
+        
+        let thread = std::thread::spawn(move || {
+            let mut sessions: Vec<CaptureSession> = Vec::new();
+            // None captures the default device untagged; Some lists the devices to tag
+            let mut devices: Option<Vec<String>> = None;
+            // Bumped per stream so errors from an already-replaced stream are ignored
+            let mut generation: u64 = 0;
+            let (error_tx, error_rx) = unbounded::<(u64, SupraSonicError)>();
+            // `auto_stop_silence_ms` and `stop_while_paused` of the running recording
+            let mut auto_stop: Option<Duration> = None;
+            let mut stop_while_paused = StopWhilePaused::Flush;
+
+            loop {
+                thread_heartbeat.beat();
+                if let Some(timeout) = auto_stop.filter(|t| !sessions.is_empty() && !switches.sound.is_alive(*t)) {
+                    tracing::info!("No input above the silence threshold for {:?}, stopping capture", timeout);
+                    // Up front: draining the streams can take a while
+                    recording.store(false, Ordering::SeqCst);
+                    for s in sessions.drain(..) {
+                        s.finish();
+                    }
+                    let _ = data_tx.send(AudioPacket::Flush);
+                    let _ = data_tx.send(AudioPacket::RecordingStopped(StopReason::SilenceTimeout));
+                    diagnostics::end();
+                }
+                crossbeam_channel::select! {
+                    recv(cmd_rx) -> cmd => match cmd {
+                        Ok(cmd @ (AudioCommand::Start { .. } | AudioCommand::StartDevices(_))) => {
+                            if !sessions.is_empty() { continue; }
+                            
+                            tracing::info!("Starting audio capture...");
+                            // Again here: an auto-stop handled after the host's start cleared it
+                            recording.store(true, Ordering::SeqCst);
+                            let continue_utterance = matches!(cmd, AudioCommand::Start { continue_utterance: true });
+                            devices = match cmd {
+                                AudioCommand::StartDevices(names) => Some(names),
+                                _ => None,
+                            };
+                            generation += 1;
+                            switches.paused.store(false, Ordering::SeqCst);
+                            metrics.reset_capture();
+                            let errors = StreamErrorSink { tx: error_tx.clone(), generation };
+                            let config = config.lock().map(|c| c.clone()).unwrap_or_default();
+                            auto_stop = (config.auto_stop_silence_ms > 0)
+                                .then(|| Duration::from_millis(config.auto_stop_silence_ms as u64));
+                            stop_while_paused = config.stop_while_paused;
+                            switches.sound.beat();
+                            switches.forget_effective_config();
+                            switches.restart_envelope_clock();
+                            // Ahead of the new streams' Format packets
+                            let _ = data_tx.send(AudioPacket::RecordingStarted);
+                            // Counted once sent, so a Format sent on seeing the new count follows it
+                            thread_recording_starts.fetch_add(1, Ordering::SeqCst);
+                            // Behind the last Stop's Flush, which was sent from this thread too
+                            if continue_utterance {
+                                let _ = data_tx.send(AudioPacket::ContinueUtterance);
+                            }
+                            // Playback from before the recording would misalign the canceller
+                            switches.echo_reference.restart(config.output_sample_rate);
+                            let ctx = SessionContext { data_tx: &data_tx, config, errors, metrics: &metrics, switches: &switches };
+                            sessions = backend.start(&ctx, devices.as_deref());
+                        }
+                        Ok(AudioCommand::Stop) => {
+                            let was_running = !sessions.is_empty();
+                            if was_running {
+                                tracing::info!("Stopping audio capture...");
+                            }
+                            // The workers see the same flag and drop their tails too
+                            let discard = switches.paused.load(Ordering::SeqCst)
+                                && stop_while_paused == StopWhilePaused::Discard;
+                            for s in sessions.drain(..) {
+                                s.finish();
+                            }
+                            let _ = data_tx.send(if discard { AudioPacket::Discard } else { AudioPacket::Flush });
+                            // Not again if silence already stopped it
+                            if was_running {
+                                let _ = data_tx.send(AudioPacket::RecordingStopped(StopReason::Requested));
+                            }
+                        }
+                        Ok(AudioCommand::Shutdown) | Err(_) => {
+                            for s in sessions.drain(..) {
+                                s.finish();
+                            }
+                            break;
+                        }
+                    },
+                    recv(error_rx) -> err => {
+                        let Ok((err_generation, error)) = err else { break };
+                        if err_generation != generation || sessions.is_empty() { continue; }
+
+                        // Dispatch what was captured right before the glitch, then reopen the devices
+                        tracing::warn!("Audio stream error, recovering: {}", error);
+                        for s in sessions.drain(..) {
+                            s.finish();
+                        }
+                        let _ = data_tx.send(AudioPacket::Flush);
+                        let _ = data_tx.send(AudioPacket::StreamError(error));
+
+                        generation += 1;
+                        let errors = StreamErrorSink { tx: error_tx.clone(), generation };
+                        let config = config.lock().map(|c| c.clone()).unwrap_or_default();
+                        // The devices may come back with another format
+                        switches.forget_effective_config();
+                        let ctx = SessionContext { data_tx: &data_tx, config, errors, metrics: &metrics, switches: &switches };
+                        sessions = backend.start(&ctx, devices.as_deref());
+                    },
+                    default(metrics::HEARTBEAT_INTERVAL) => {}
+                }
+            }
+        });
+
+        Self {
+            command_tx: cmd_tx,
+            heartbeat,
+            thread: Some(thread),
+            paused,
+            effective_config,
+            gain_envelope,
+            echo_reference,
+            recording_starts,
+        }
+    }
+
+    /// How many recordings were started. A start is only counted once its `RecordingStarted`
+    /// is in the packet channel; samples sent after it from anywhere else need a new `Format`.
+    pub fn recording_starts(&self) -> u64 {
+        self.recording_starts.load(Ordering::SeqCst)
+    }
+
+    /// Queues playback for the echo canceller (see `EchoReference::push`).
+    pub fn push_echo_reference(&self, samples: &[f32], sample_rate: u32) -> anyhow::Result<()> {
+        self.echo_reference.push(samples, sample_rate)
+    }
+
+    /// Applies `envelope` to every stream from now on (None = unity gain). Its time is
+    /// counted from the start of each recording, pauses included.
+    pub fn set_gain_envelope(&self, envelope: Option<GainEnvelope>) {
+        if let Ok(mut gain) = self.gain_envelope.lock() {
+            *gain = envelope.map(Arc::new);
+        }
+    }
+
+    /// The config as applied to the running (or last) recording's stream, see
+    /// `AudioConfig::effective`; None before any stream was built.
+    pub fn effective_config(&self) -> Option<AudioConfig> {
+        self.effective_config.lock().ok().and_then(|c| c.clone())
+    }
+
+    /// Keeps the streams open but stops feeding the pipeline; the utterance stays open.
+    pub fn pause_capture(&self) -> anyhow::Result<()> {
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn resume_capture(&self) -> anyhow::Result<()> {
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Stops capture and waits for the engine thread to exit. Safe to call more than once.
+    pub fn shutdown(&mut self) {
+        let _ = self.command_tx.send(AudioCommand::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// False once the engine thread has stopped responding (e.g. it panicked).
+    pub fn is_alive(&self) -> bool {
+        self.heartbeat.is_alive(metrics::HEARTBEAT_TIMEOUT)
+    }
+
+    /// Names of the input devices the default host can see.
+    pub fn list_input_devices() -> Vec<String> {
+        cpal::default_host()
+            .input_devices()
+            .map(|devices| devices.filter_map(|d| d.name().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    fn build_stream(ctx: &SessionContext, device: cpal::Device, source: Option<String>) -> anyhow::Result<CaptureSession> {
+        let audio_config = &ctx.config;
+        let device_name = device.name().unwrap_or_default();
+        let default_config = device.default_input_config()?;
+        let config = match (audio_config.source_rate_override, device.supported_input_configs()) {
+            (None, Ok(supported)) => select_input_config(supported, default_config, audio_config.output_sample_rate),
+            _ => default_config,
+        };
+        let reported_rate = config.sample_rate().0;
+        let source_sample_rate = resampling_rate(reported_rate, audio_config);
+        let channels = config.channels().max(1) as usize;
+        let format = StreamFormat { device: device_name.clone(), source, sample_rate: source_sample_rate, channels };
+        let (mut producer, running, worker) = Self::spawn_worker(ctx, format)?;
+        let errors = ctx.errors.clone();
+        let callback_metrics = ctx.metrics.clone();
+
+        // The audio callback only pushes to ring buffer (Real-time safe)
+        let stream = device.build_input_stream(
+            &config.into(),
+            move |data: &[f32], _: &_| {
+                callback_metrics.record_capture_buffer(data.len() / channels, reported_rate);
+                let pushed = producer.push_slice(data);
+                if pushed < data.len() {
+                    callback_metrics.record_ring_overflow(data.len() - pushed);
+                }
+            },
+            move |err| {
+                tracing::error!("Audio stream error: {}", err);
+                let _ = errors.tx.send((errors.generation, stream_error(&err, &device_name)));
+            },
+            None
+        );
+
+        let played = stream.map_err(anyhow::Error::from).and_then(|stream| match stream.play() {
+            Ok(()) => Ok(stream),
+            Err(e) => Err(anyhow::anyhow!("Failed to play stream: {}", e)),
+        });
+        match played {
+            Ok(stream) => Ok(CaptureSession { stream: Box::new(stream), running, worker }),
+            Err(e) => {
+                running.store(false, Ordering::SeqCst);
+                let _ = worker.join();
+                Err(e)
+            }
+        }
+    }
+
+    /// Announces `format` and starts the thread that processes what gets pushed into the
+    /// returned ring buffer, until the flag is cleared and the buffer is drained.
+    fn spawn_worker(
+        ctx: &SessionContext,
+        format: StreamFormat,
+    ) -> anyhow::Result<(HeapProd<f32>, Arc<AtomicBool>, JoinHandle<()>)> {
+        let audio_config = ctx.config.clone();
+        let StreamFormat { device: device_name, source, sample_rate: source_sample_rate, channels } = format;
+        audio_config.validate_for_input(channels)?;
+        if let Ok(mut effective) = ctx.switches.effective_config.lock() {
+            // With several devices, the first one stands for the recording
+            effective.get_or_insert_with(|| audio_config.effective(source_sample_rate as u32, channels as u16));
+        }
+        
+        tracing::info!("Input device: {:?}, Source Rate: {}, Channels: {}, Target Rate: {}", 
+            device_name, source_sample_rate, channels, audio_config.output_sample_rate);
+
+        // Notify of format (the output rate, whatever the device runs at)
+        let _ = ctx.data_tx.send(AudioPacket::Format(audio_config.output_sample_rate));
+
+        // Create Ring Buffer
+        let rb = HeapRb::<f32>::new((source_sample_rate * audio_config.ring_buffer_ms as usize / 1000).max(1) * channels);
+        let (producer, consumer) = rb.split();
+
+        // Spawn separate processing thread to handle resampling/chunking
+        let layout = InputLayout {
+            device: device_name,
+            source: source.clone(),
+            channels,
+            sample_rate: source_sample_rate,
+            downmix_mode: audio_config.downmix_mode,
+            downmix_channels: audio_config.downmix_channels,
+            warmup_samples: source_sample_rate * audio_config.warmup_discard_ms as usize / 1000 * channels,
+            crossfade_samples: source_sample_rate * audio_config.splice_crossfade_ms as usize / 1000,
+            silence_threshold: audio_config.vad_offset_threshold,
+            dead_input_ms: audio_config.dead_input_ms,
+            discard_when_paused: audio_config.stop_while_paused == StopWhilePaused::Discard,
+        };
+        // Tagged streams are extra devices; the reference can only be matched against one
+        let echo_reference = source.is_none().then(|| ctx.switches.echo_reference.clone());
+        let metrics = ctx.metrics.clone();
+        let mut processor = AudioProcessor::new(source_sample_rate, audio_config, ctx.data_tx.clone(), source, metrics.clone())?
+            .with_level_reporting(ctx.switches.level_reporting.clone());
+        if let Some(reference) = echo_reference {
+            processor = processor.with_echo_reference(reference);
+        }
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let switches = ctx.switches.clone();
+        let worker = std::thread::spawn(move || {
+            Self::process_audio(consumer, processor, running_clone, switches, layout, metrics);
+        });
+        Ok((producer, running, worker))
+    }
+
+    fn process_audio(
+        mut consumer: impl Consumer<Item = f32>, 
+        mut processor: AudioProcessor,
+        running: Arc<AtomicBool>,
+        switches: StreamSwitches,
+        layout: InputLayout,
+        metrics: Arc<PipelineMetrics>,
+    ) {
+        let capacity = consumer.capacity().get();
+        let InputLayout {
+            device,
+            source,
+            channels,
+            mut sample_rate,
+            downmix_mode,
+            downmix_channels,
+            warmup_samples,
+            crossfade_samples,
+            silence_threshold,
+            dead_input_ms,
+            discard_when_paused,
+        } = layout;
+        let StreamSwitches { paused, sound, gain_envelope, envelope_clock, .. } = switches;
+        let mut input_buffer = vec![0.0f32; READ_FRAMES * channels];
+        let mut warmup_remaining = warmup_samples;
+        let mut splicer = Splicer::new(crossfade_samples);
+        let mut was_paused = false;
+        let mut overflowed = metrics.ring_overflow_samples();
+        let mut coalescer = ReadCoalescer::new(sample_rate);
+        let mut rate_monitor = RateMonitor::new(sample_rate);
+        let mut dead_input = DeadInputDetector::new(dead_input_ms, sample_rate);
+        // Seconds read since the recording started, the gain envelope's clock: a stream
+        // replacing a failed one carries on from where that one stopped
+        let mut clock = envelope_clock.lock().ok().and_then(|c| c.get(&source).copied()).unwrap_or(0.0);
+        let mut push = |processor: &mut AudioProcessor, samples: &[f32], captured_at: Instant, sample_rate: usize| {
+            let block_start = clock;
+            clock += (samples.len() / channels) as f64 / sample_rate as f64;
+            // The first callbacks after start may hold a pop or stale device buffer
+            let skip = warmup_remaining.min(samples.len());
+            warmup_remaining -= skip;
+            let samples = &samples[skip..];
+            if samples.is_empty() {
+                return;
+            }
+            // Paused audio is dropped before the resampler, so its state spans the gap.
+            // A pause isn't silence: auto-stop only counts live input
+            if paused.load(Ordering::SeqCst) {
+                sound.beat();
+                // Muting during a pause is deliberate
+                dead_input.reset();
+                if !was_paused {
+                    splicer.pause();
+                    was_paused = true;
+                }
+                return;
+            }
+            was_paused = false;
+            if dead_input.observe(samples, channels) {
+                tracing::warn!("{:?} has delivered only digital silence for {} ms; is it muted?", device, dead_input_ms);
+                processor.report_error(SupraSonicError::InputSilent(format!(
+                    "{}: no signal for {} ms",
+                    device, dead_input_ms
+                )));
+            }
+            let mut mono = if channels == 1 {
+                Cow::Borrowed(samples)
+            } else {
+                Cow::Owned(audio::downmix(samples, channels, downmix_mode, downmix_channels))
+            };
+            // Before the splicer, which delays what it returns by the crossfade
+            if let Some(envelope) = gain_envelope.lock().ok().and_then(|g| g.clone()) {
+                envelope.apply(mono.to_mut(), block_start + (skip / channels) as f64 / sample_rate as f64, sample_rate);
+            }
+            let mono = splicer.push(&mono);
+            if vad::rms(&mono) >= silence_threshold {
+                sound.beat();
+            }
+            processor.push_captured(&mono, captured_at);
+        };
+        // The newest sample read was captured before everything still in the ring, and
+        // the splicer holds back another crossfade's worth
+        let captured_at = |remaining: usize, sample_rate: usize| {
+            let behind = (remaining / channels + crossfade_samples) as f64 / sample_rate as f64;
+            let now = Instant::now();
+            now.checked_sub(Duration::from_secs_f64(behind)).unwrap_or(now)
+        };
+
+        loop {
+            // 1. Read from RingBuffer
+            let available = consumer.occupied_len();
+            metrics.record_ring_fill(available, capacity);
+            // The callback can't log, so overflows are reported from here
+            let overflow = metrics.ring_overflow_samples();
+            if overflow > overflowed {
+                tracing::warn!("Capture ring buffer overflowed: {} samples dropped", overflow - overflowed);
+                // Dropped audio would read as a slower device
+                rate_monitor.restart();
+            }
+            overflowed = overflow;
+            if available == 0 {
+                // Stream is gone: whatever is left gets drained below
+                if !running.load(Ordering::SeqCst) || !consumer.write_is_held() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(5));
+                continue;
+            }
+
+            // Whole frames only, so channels stay aligned
+            let frames = (available / channels).min(READ_FRAMES);
+            if frames == 0 || !coalescer.ready(frames, Instant::now()) {
+                if !running.load(Ordering::SeqCst) || !consumer.write_is_held() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                continue;
+            }
+            let read_count = consumer.pop_slice(&mut input_buffer[..frames * channels]);
+            let captured_at = captured_at(consumer.occupied_len(), sample_rate);
+            push(&mut processor, &input_buffer[..read_count], captured_at, sample_rate);
+
+            if let Some(measured) = rate_monitor.observe(read_count / channels, Instant::now()) {
+                match nearest_standard_rate(measured) {
+                    Some(rate) if rate as usize != sample_rate => {
+                        // The output rate stays the same, so there's no new Format; only the resampler changes
+                        tracing::warn!("{:?} now delivers about {:.0} Hz; resampling from {} Hz instead of {} Hz", device, measured, rate, sample_rate);
+                        if let Err(e) = processor.set_source_rate(rate as usize) {
+                            tracing::error!("Could not rebuild the resampler for {} Hz: {}", rate, e);
+                        }
+                        processor.report_error(SupraSonicError::SampleRateChanged(format!(
+                            "{}: {} Hz -> {} Hz",
+                            device, sample_rate, rate
+                        )));
+                        sample_rate = rate as usize;
+                        coalescer = ReadCoalescer::new(sample_rate);
+                        rate_monitor = RateMonitor::new(sample_rate);
+                    }
+                    _ => {
+                        tracing::warn!("{:?} delivers about {:.0} Hz, expected {} Hz; audio may be pitch-shifted", device, measured, sample_rate);
+                        processor.report_error(SupraSonicError::SampleRateChanged(format!(
+                            "{}: delivers about {:.0} Hz, expected {} Hz",
+                            device, measured, sample_rate
+                        )));
+                        // Once is enough when there's nothing to switch to
+                        rate_monitor.silence();
+                    }
+                }
+            }
+        }
+
+        // 2. Drain what the callback wrote before the stream stopped
+        loop {
+            let read_count = consumer.pop_slice(&mut input_buffer);
+            if read_count == 0 { break; }
+            let captured_at = captured_at(consumer.occupied_len(), sample_rate);
+            push(&mut processor, &input_buffer[..read_count], captured_at, sample_rate);
+        }
+        if let Ok(mut clocks) = envelope_clock.lock() {
+            clocks.insert(source, clock);
+        }
+        // Stopped mid-pause: the crossfade tail, resampler backlog and partial chunk all
+        // predate the pause, and go out with it unless the config says to drop them
+        if discard_when_paused && paused.load(Ordering::SeqCst) {
+            return;
+        }
+        processor.push(&splicer.finish());
+        processor.finish();
+    }
+
+    pub fn start_capture(&self) -> anyhow::Result<()> {
+        self.command_tx
+            .send(AudioCommand::Start { continue_utterance: false })
+            .map_err(|e| anyhow::anyhow!("Failed to send start command: {}", e))?;
+        Ok(())
+    }
+
+    /// `start_capture`, reopening the utterance the last stop flushed (see `AudioPacket::ContinueUtterance`).
+    pub fn continue_capture(&self) -> anyhow::Result<()> {
+        self.command_tx
+            .send(AudioCommand::Start { continue_utterance: true })
+            .map_err(|e| anyhow::anyhow!("Failed to send start command: {}", e))?;
+        Ok(())
+    }
+
+    pub fn start_capture_devices(&self, device_names: Vec<String>) -> anyhow::Result<()> {
+        if device_names.is_empty() {
+            anyhow::bail!("No input devices given");
+        }
+        self.command_tx.send(AudioCommand::StartDevices(device_names)).map_err(|e| anyhow::anyhow!("Failed to send start command: {}", e))?;
+        Ok(())
+    }
+
+    pub fn stop_capture(&self) {
+        let _ = self.command_tx.send(AudioCommand::Stop);
+    }
+}
+
+impl InputBackend for CpalInput {
+    fn start(&self, ctx: &SessionContext, devices: Option<&[String]>) -> Vec<CaptureSession> {
+        let host = cpal::default_host();
+        let targets: Vec<(cpal::Device, Option<String>)> = match devices {
+            None => match host.default_input_device() {
+                Some(d) => vec![(d, None)],
+                None => {
+                    tracing::error!("Failed to build stream: No input device found");
+                    return Vec::new();
+                }
+            },
+            Some(names) => {
+                let available: Vec<cpal::Device> = host.input_devices().map(|d| d.collect()).unwrap_or_default();
+                names.iter().filter_map(|name| {
+                    let device = available.iter().find(|d| d.name().ok().as_deref() == Some(name.as_str()));
+                    if device.is_none() {
+                        tracing::error!("Input device not found: {}", name);
+                    }
+                    device.map(|d| (d.clone(), Some(name.clone())))
+                }).collect()
+            }
+        };
+
+        let mut sessions = Vec::new();
+        for (device, source) in targets {
+            match AudioEngine::build_stream(ctx, device, source) {
+                Ok(s) => {
+                    tracing::info!("Audio stream started successfully");
+                    sessions.push(s);
+                },
+                Err(e) => tracing::error!("Failed to build stream: {}", e),
+            }
+        }
+        sessions
+    }
+}
+
+/// What the host sees of a cpal stream failure on `device`.
+pub fn stream_error(err: &cpal::StreamError, device: &str) -> SupraSonicError {
+    match err {
+        cpal::StreamError::DeviceNotAvailable => SupraSonicError::DeviceDisconnected(device.to_string()),
+        cpal::StreamError::BackendSpecific { err } => SupraSonicError::AudioBackend(err.description.clone()),
+    }
+}
+
+/// The rate to resample from for a device reporting `reported_rate`. Some virtual
+/// devices/VMs misreport their rate; `source_rate_override` wins.
+fn resampling_rate(reported_rate: u32, config: &AudioConfig) -> usize {
+    let rate = config.source_rate_override.unwrap_or(reported_rate);
+    if rate != reported_rate {
+        tracing::warn!("Device reports {} Hz, resampling from overridden {} Hz", reported_rate, rate);
+    }
+    rate as usize
+}
+
+/// The config to open an input at: one running natively at `output_rate`, so the audio
+/// needs no resampling, when the device offers it as f32; otherwise `default`.
+/// Among native configs, the default's channel count wins, then the fewest channels.
+pub fn select_input_config(
+    supported: impl IntoIterator<Item = cpal::SupportedStreamConfigRange>,
+    default: cpal::SupportedStreamConfig,
+    output_rate: u32,
+) -> cpal::SupportedStreamConfig {
+    let target = cpal::SampleRate(output_rate);
+    if default.sample_rate() == target {
+        return default;
+    }
+    supported
+        .into_iter()
+        .filter(|range| range.sample_format() == cpal::SampleFormat::F32)
+        .filter(|range| range.min_sample_rate() <= target && target <= range.max_sample_rate())
+        .min_by_key(|range| (range.channels() != default.channels(), range.channels()))
+        .map(|range| range.with_sample_rate(target))
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::GainPoint;
+
+    /// Stands in for cpal: every started stream delivers `signal` in real time through the
+    /// real worker, and the first one can be made to fail.
+    #[derive(Clone)]
+    struct ScriptedInput {
+        sample_rate: usize,
+        // Per-device rates, for the named devices that don't run at `sample_rate`
+        device_rates: Vec<(&'static str, usize)>,
+        // Sample `frame` of the named device
+        signal: fn(&str, u64) -> f32,
+        // The first stream reports an error once it has delivered this many frames
+        fail_after_frames: Option<u64>,
+        failed: Arc<AtomicBool>,
+        // Frames each finished stream delivered, in start order
+        delivered: Arc<Mutex<Vec<u64>>>,
+        // Instead of real time, the nth stream pushes bursts[n] frames at once (the last
+        // entry for later streams) and then goes quiet
+        bursts: Vec<u64>,
+    }
+
+    impl ScriptedInput {
+        fn new(signal: fn(&str, u64) -> f32) -> Self {
+            Self {
+                sample_rate: 16000,
+                device_rates: Vec::new(),
+                signal,
+                fail_after_frames: None,
+                failed: Arc::new(AtomicBool::new(false)),
+                delivered: Arc::new(Mutex::new(Vec::new())),
+                bursts: Vec::new(),
+            }
+        }
+    }
+
+    /// The scripted "stream": dropping it stops the feeder, like dropping a cpal stream.
+    struct ScriptedStream {
+        stop: Arc<AtomicBool>,
+        feeder: Option<JoinHandle<()>>,
+    }
+
+    impl Drop for ScriptedStream {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(feeder) = self.feeder.take() {
+                let _ = feeder.join();
+            }
+        }
+    }
+
+    impl InputBackend for ScriptedInput {
+        fn start(&self, ctx: &SessionContext, devices: Option<&[String]>) -> Vec<CaptureSession> {
+            let sources: Vec<Option<String>> = match devices {
+                None => vec![None],
+                Some(names) => names.iter().cloned().map(Some).collect(),
+            };
+            sources
+                .into_iter()
+                .map(|source| {
+                    let device = source.clone().unwrap_or_else(|| "scripted".to_string());
+                    let sample_rate = self.device_rates.iter().find(|(name, _)| *name == device).map_or(self.sample_rate, |(_, rate)| *rate);
+                    let format = StreamFormat { device: device.clone(), source, sample_rate, channels: 1 };
+                    let (mut producer, running, worker) = AudioEngine::spawn_worker(ctx, format).unwrap();
+                    let stop = Arc::new(AtomicBool::new(false));
+                    let (script, errors, stop_feeder, metrics) = (self.clone(), ctx.errors.clone(), stop.clone(), ctx.metrics.clone());
+                    let feeder = std::thread::spawn(move || {
+                        let mut frame = 0u64;
+                        let mut fails = script.fail_after_frames.filter(|_| !script.failed.swap(true, Ordering::SeqCst));
+                        let started = script.delivered.lock().unwrap().len();
+                        let burst = script.bursts.get(started).or(script.bursts.last()).copied();
+                        // Real-time blocks of 10ms
+                        let block_frames = burst.unwrap_or(sample_rate as u64 / 100);
+                        while !stop_feeder.load(Ordering::SeqCst) {
+                            if burst.is_some() && frame > 0 {
+                                std::thread::sleep(Duration::from_millis(10));
+                                continue;
+                            }
+                            let block: Vec<f32> = (frame..frame + block_frames).map(|f| (script.signal)(&device, f)).collect();
+                            let pushed = producer.push_slice(&block);
+                            // A burst is one device callback: what doesn't fit is lost, as in `build_stream`
+                            if burst.is_some() && pushed < block.len() {
+                                metrics.record_ring_overflow(block.len() - pushed);
+                            }
+                            frame += pushed as u64;
+                            if fails.is_some_and(|limit| frame >= limit) {
+                                fails = None;
+                                let _ = errors.tx.send((errors.generation, SupraSonicError::AudioBackend("scripted".to_string())));
+                            }
+                            std::thread::sleep(Duration::from_millis(10));
+                        }
+                        script.delivered.lock().unwrap().push(frame);
+                    });
+                    CaptureSession { stream: Box::new(ScriptedStream { stop, feeder: Some(feeder) }), running, worker }
+                })
+                .collect()
+        }
+    }
+
+    struct Harness {
+        engine: AudioEngine,
+        packets: crossbeam_channel::Receiver<AudioPacket>,
+        metrics: Arc<PipelineMetrics>,
+        recording: Arc<AtomicBool>,
+    }
+
+    fn engine(config: AudioConfig, input: &ScriptedInput) -> Harness {
+        let (tx, packets) = unbounded();
+        let config = Arc::new(Mutex::new(config));
+        let metrics = Arc::new(PipelineMetrics::new());
+        let recording = Arc::new(AtomicBool::new(false));
+        let engine = AudioEngine::with_backend(tx, config, metrics.clone(), Arc::new(AtomicBool::new(true)), recording.clone(), input.clone());
+        Harness { engine, packets, metrics, recording }
+    }
+
+    /// Packets until one matches `until` (included), or panics after `timeout`.
+    fn collect_until(packets: &crossbeam_channel::Receiver<AudioPacket>, timeout: Duration, mut until: impl FnMut(&AudioPacket) -> bool) -> Vec<AudioPacket> {
+        let deadline = Instant::now() + timeout;
+        let mut out = Vec::new();
+        loop {
+            let packet = packets.recv_deadline(deadline).expect("timed out waiting for the engine");
+            let done = until(&packet);
+            out.push(packet);
+            if done {
+                return out;
+            }
+        }
+    }
+
+    fn sample_count(packets: &[AudioPacket]) -> u64 {
+        packets.iter().map(|p| if let AudioPacket::Samples(s) = p { s.len() as u64 } else { 0 }).sum()
+    }
+
+    fn quiet_tone(_device: &str, frame: u64) -> f32 {
+        0.1 * (frame as f32 * 0.3).sin()
+    }
+
+    #[test]
+    fn stream_error_dispatches_the_audio_captured_before_it() {
+        let mut input = ScriptedInput::new(quiet_tone);
+        input.fail_after_frames = Some(8000);
+        let config = AudioConfig { warmup_discard_ms: 0, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture().unwrap();
+
+        let before = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Flush));
+        let next = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Format(_)));
+        assert!(matches!(next.first(), Some(AudioPacket::StreamError(SupraSonicError::AudioBackend(_)))));
+        // Everything the failed stream delivered went out ahead of the flush
+        let delivered = input.delivered.lock().unwrap()[0];
+        assert!(delivered >= 8000);
+        assert_eq!(sample_count(&before), delivered);
+
+        // And capture carries on with a fresh stream
+        collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Samples(_)));
+        harness.engine.shutdown();
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt()
+    }
+
+    // mic_a is loud, mic_b quiet, so a mixed-up tag shows in the level
+    fn panel_tone(device: &str, frame: u64) -> f32 {
+        let amplitude = if device == "mic_a" { 0.5 } else { 0.05 };
+        amplitude * (frame as f32 * 0.05).sin()
+    }
+
+    #[test]
+    fn each_device_stream_is_tagged_with_its_device() {
+        let mut input = ScriptedInput::new(panel_tone);
+        input.device_rates = vec![("mic_a", 48000), ("mic_b", 22050)];
+        let config = AudioConfig { warmup_discard_ms: 0, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture_devices(vec!["mic_a".to_string(), "mic_b".to_string()]).unwrap();
+
+        let mut per_device: HashMap<String, Vec<f32>> = HashMap::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while per_device.values().filter(|s| s.len() >= 16000).count() < 2 {
+            match harness.packets.recv_deadline(deadline).expect("timed out waiting for both devices") {
+                AudioPacket::DeviceSamples(device, samples) => per_device.entry(device).or_default().extend(samples),
+                AudioPacket::Samples(_) => panic!("untagged samples from a device capture"),
+                _ => {}
+            }
+        }
+        harness.engine.shutdown();
+
+        let mut devices: Vec<_> = per_device.keys().cloned().collect();
+        devices.sort();
+        assert_eq!(devices, ["mic_a", "mic_b"]);
+        // Both were resampled to the output rate from their own rates: equal durations
+        // of a sine keep their level
+        let (a, b) = (rms(&per_device["mic_a"][1600..16000]), rms(&per_device["mic_b"][1600..16000]));
+        assert!((a - 0.5 / 2f32.sqrt()).abs() < 0.05, "mic_a rms {a}");
+        assert!((b - 0.05 / 2f32.sqrt()).abs() < 0.01, "mic_b rms {b}");
+    }
+
+    /// Output samples for one second of audio from a device reporting `reported_rate`
+    /// that actually delivers 48 kHz.
+    fn output_for_a_second(reported_rate: u32, config: &AudioConfig) -> usize {
+        let (tx, rx) = unbounded();
+        let rate = resampling_rate(reported_rate, config);
+        let mut processor = AudioProcessor::new(rate, config.clone(), tx, None, Arc::new(PipelineMetrics::new())).unwrap();
+        processor.push(&vec![0.1; 48000]);
+        processor.finish();
+        drop(processor);
+        rx.try_iter().map(|p| if let AudioPacket::Samples(s) = p { s.len() } else { 0 }).sum()
+    }
+
+    #[test]
+    fn source_rate_override_sets_the_resampling_ratio() {
+        let reported = 44100;
+        let auto = AudioConfig { warmup_discard_ms: 0, ..Default::default() };
+        let overridden = AudioConfig { source_rate_override: Some(48000), ..auto.clone() };
+        assert_eq!(resampling_rate(reported, &auto), 44100);
+        assert_eq!(resampling_rate(reported, &overridden), 48000);
+
+        // Trusting the device stretches a second of 48 kHz audio by 48000/44100 (give or
+        // take the padded final chunk)
+        let (stretched, corrected) = (output_for_a_second(reported, &auto), output_for_a_second(reported, &overridden));
+        let expected_stretch = 16000 * 48000 / 44100;
+        assert!(stretched.abs_diff(expected_stretch) < 600, "{stretched}");
+        assert!(corrected.abs_diff(16000) < 600, "{corrected}");
+        assert!(stretched > corrected);
+    }
+
+    // Each sample carries its frame number, so the output shows which ones were dropped
+    fn frame_ramp(_device: &str, frame: u64) -> f32 {
+        frame as f32 / 1_000_000.0
+    }
+
+    #[test]
+    fn warmup_window_is_discarded_and_the_rest_passes() {
+        let input = ScriptedInput::new(frame_ramp);
+        let config = AudioConfig { warmup_discard_ms: 100, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture().unwrap();
+        let packets = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Samples(_)));
+        harness.engine.shutdown();
+
+        let Some(AudioPacket::Samples(first)) = packets.last() else { unreachable!() };
+        // 100ms at 16 kHz: frames 0..1600 are gone, 1600 onwards arrive in order
+        assert!((first[0] - 0.0016).abs() < 1e-6, "first sample {}", first[0]);
+        assert!(first.windows(2).all(|w| (w[1] - w[0] - 1e-6).abs() < 1e-7));
+    }
+
+    /// Samples until `count` have arrived.
+    fn wait_for_samples(packets: &crossbeam_channel::Receiver<AudioPacket>, count: u64) {
+        let mut seen = 0;
+        collect_until(packets, Duration::from_secs(5), |p| {
+            seen += sample_count(std::slice::from_ref(p));
+            seen >= count
+        });
+    }
+
+    #[test]
+    fn ring_high_water_mark_reports_the_fullest_the_ring_got_per_recording() {
+        let mut input = ScriptedInput::new(quiet_tone);
+        // 1s of ring at 16 kHz, overfilled by the first recording
+        input.bursts = vec![40000, 6000];
+        let config = AudioConfig { warmup_discard_ms: 0, ring_buffer_ms: 1000, ..Default::default() };
+        let mut harness = engine(config, &input);
+
+        harness.engine.start_capture().unwrap();
+        wait_for_samples(&harness.packets, 15840);
+        let snapshot = harness.metrics.snapshot();
+        assert_eq!((snapshot.ring_high_water, snapshot.ring_capacity), (16000, 16000));
+        harness.engine.stop_capture();
+
+        // A new recording starts from zero
+        harness.engine.start_capture().unwrap();
+        wait_for_samples(&harness.packets, 5760);
+        assert_eq!(harness.metrics.snapshot().ring_high_water, 6000);
+        harness.engine.shutdown();
+    }
+
+    #[test]
+    fn measured_latency_covers_chunking_and_stays_within_bounds() {
+        use crate::processing::{Dispatcher, ProcessingLoop};
+
+        let input = ScriptedInput::new(quiet_tone);
+        let config = AudioConfig { warmup_discard_ms: 0, measure_latency: true, ..Default::default() };
+        let mut harness = engine(config.clone(), &input);
+        let mut processing = ProcessingLoop::new(Arc::new(Dispatcher::new()), Arc::new(Mutex::new(config)), harness.metrics.clone());
+        harness.engine.start_capture().unwrap();
+        let (deadline, mut probes) = (Instant::now() + Duration::from_secs(5), 0);
+        while probes < 20 {
+            let packet = harness.packets.recv_deadline(deadline).expect("timed out waiting for latency probes");
+            probes += matches!(packet, AudioPacket::LatencyProbe(_)) as u32;
+            processing.handle(packet);
+        }
+        harness.engine.shutdown();
+
+        let snapshot = harness.metrics.snapshot();
+        // A 30ms chunk's first sample is a chunk old when it goes out, plus up to a 10ms
+        // device block waiting in the ring: about 40ms, nothing near 100ms
+        assert!((30.0..60.0).contains(&snapshot.latency_avg_ms), "{snapshot:?}");
+        assert!(snapshot.latency_max_ms < 100.0, "{snapshot:?}");
+        assert!(snapshot.latency_last_ms > 0.0);
+    }
+
+    #[test]
+    fn continuing_right_after_a_stop_reopens_behind_its_flush() {
+        let input = ScriptedInput::new(quiet_tone);
+        let mut harness = engine(AudioConfig { warmup_discard_ms: 0, ..Default::default() }, &input);
+        harness.engine.start_capture().unwrap();
+        wait_for_samples(&harness.packets, 480);
+        // No wait in between: the stop's flush is still to come when the continue is sent
+        harness.engine.stop_capture();
+        harness.engine.continue_capture().unwrap();
+
+        let packets = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::ContinueUtterance));
+        let position = |matches: fn(&AudioPacket) -> bool| packets.iter().position(matches).unwrap();
+        let flush = position(|p| matches!(p, AudioPacket::Flush));
+        let started = position(|p| matches!(p, AudioPacket::RecordingStarted));
+        assert!(flush < started && started == packets.len() - 2, "continue follows the new start, behind the flush");
+        harness.engine.shutdown();
+    }
+
+    fn range(channels: u16, min: u32, max: u32, format: cpal::SampleFormat) -> cpal::SupportedStreamConfigRange {
+        cpal::SupportedStreamConfigRange::new(channels, cpal::SampleRate(min), cpal::SampleRate(max), cpal::SupportedBufferSize::Unknown, format)
+    }
+
+    fn stream_config(channels: u16, rate: u32) -> cpal::SupportedStreamConfig {
+        cpal::SupportedStreamConfig::new(channels, cpal::SampleRate(rate), cpal::SupportedBufferSize::Unknown, cpal::SampleFormat::F32)
+    }
+
+    #[test]
+    fn input_config_selection_prefers_a_native_target_rate() {
+        use cpal::SampleFormat::{F32, I16};
+        let pick = |supported: Vec<cpal::SupportedStreamConfigRange>, default: cpal::SupportedStreamConfig| {
+            let chosen = select_input_config(supported, default, 16000);
+            (chosen.channels(), chosen.sample_rate().0)
+        };
+
+        // A range covering 16 kHz is opened at it, keeping the default's channel count
+        let usb_mic = vec![range(1, 8000, 48000, F32), range(2, 8000, 48000, F32), range(2, 44100, 96000, F32)];
+        assert_eq!(pick(usb_mic.clone(), stream_config(2, 48000)), (2, 16000));
+        // Otherwise the fewest channels
+        assert_eq!(pick(vec![range(4, 16000, 16000, F32), range(2, 16000, 16000, F32)], stream_config(1, 48000)), (2, 16000));
+        // Only f32 streams qualify, and no native rate means the default as is
+        assert_eq!(pick(vec![range(1, 16000, 16000, I16)], stream_config(1, 48000)), (1, 48000));
+        assert_eq!(pick(vec![range(2, 44100, 96000, F32)], stream_config(2, 48000)), (2, 48000));
+        // Already at the target: nothing to change
+        assert_eq!(pick(usb_mic, stream_config(1, 16000)), (1, 16000));
+        // The target follows the configured output rate
+        let chosen = select_input_config(vec![range(1, 8000, 48000, F32)], stream_config(1, 48000), 8000);
+        assert_eq!(chosen.sample_rate().0, 8000);
+    }
+
+    #[test]
+    fn cpal_stream_errors_map_to_distinct_variants() {
+        let unplugged = stream_error(&cpal::StreamError::DeviceNotAvailable, "USB Mic");
+        assert!(matches!(&unplugged, SupraSonicError::DeviceDisconnected(device) if device == "USB Mic"), "{unplugged:?}");
+
+        let fault = cpal::StreamError::BackendSpecific { err: cpal::BackendSpecificError { description: "xrun".to_string() } };
+        assert!(matches!(stream_error(&fault, "USB Mic"), SupraSonicError::AudioBackend(description) if description == "xrun"));
+    }
+
+    #[test]
+    fn forced_ring_overflow_is_written_to_the_diagnostic_log() {
+        use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+        // The first `AppState` installs the layer too; either way it sees the worker's events
+        let _ = tracing_subscriber::registry().with(diagnostics::layer()).try_init();
+        let mut input = ScriptedInput::new(quiet_tone);
+        // 40000 samples at once into a 16000-sample ring
+        input.bursts = vec![40000];
+        let config = AudioConfig { warmup_discard_ms: 0, ring_buffer_ms: 1000, diagnostic_log: true, ..Default::default() };
+        let mut harness = engine(config, &input);
+        diagnostics::begin();
+        harness.engine.start_capture().unwrap();
+        wait_for_samples(&harness.packets, 15840);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !diagnostics::text().contains("Capture ring buffer overflowed: 24000 samples dropped") {
+            assert!(Instant::now() < deadline, "no overflow in the log:\n{}", diagnostics::text());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(harness.metrics.ring_overflow_samples(), 24000);
+        diagnostics::end();
+        harness.engine.shutdown();
+    }
+
+    // Half a second of tone, then nothing
+    fn tone_then_silence(_device: &str, frame: u64) -> f32 {
+        if frame < 8000 { 0.5 * (frame as f32 * 0.05).sin() } else { 0.0 }
+    }
+
+    #[test]
+    fn sustained_silence_stops_the_recording() {
+        let input = ScriptedInput::new(tone_then_silence);
+        let config = AudioConfig { warmup_discard_ms: 0, auto_stop_silence_ms: 300, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture().unwrap();
+        assert!(harness.packets.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(harness.recording.load(Ordering::SeqCst));
+
+        let packets = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::RecordingStopped(_)));
+        assert!(!harness.recording.load(Ordering::SeqCst), "the host's flag is cleared with the stop");
+        assert!(matches!(packets[packets.len() - 2..], [AudioPacket::Flush, AudioPacket::RecordingStopped(StopReason::SilenceTimeout)]));
+        // The tone plus at least the timeout's worth of silence went out first
+        assert!(sample_count(&packets) >= 8000 + 4800, "{}", sample_count(&packets));
+
+        // Already stopped: an explicit stop doesn't report it again
+        harness.engine.stop_capture();
+        let after = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Flush));
+        assert!(!after.iter().any(|p| matches!(p, AudioPacket::RecordingStopped(_))));
+        harness.engine.shutdown();
+    }
+
+    #[test]
+    fn effective_config_reports_the_fallback_rate_a_device_runs_at() {
+        // 16 kHz was asked for, but the device only does 44.1 kHz and up
+        let negotiated = select_input_config(vec![range(1, 44100, 96000, cpal::SampleFormat::F32)], stream_config(1, 44100), 16000);
+        let mut input = ScriptedInput::new(quiet_tone);
+        input.sample_rate = negotiated.sample_rate().0 as usize;
+        let requested = AudioConfig { warmup_discard_ms: 0, ..Default::default() };
+        let mut harness = engine(requested.clone(), &input);
+        assert!(harness.engine.effective_config().is_none());
+
+        harness.engine.start_capture().unwrap();
+        collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Samples(_)));
+        let effective = harness.engine.effective_config().unwrap();
+        harness.engine.shutdown();
+
+        assert_eq!(requested.source_rate_override, None);
+        assert_eq!(effective.source_rate_override, Some(44100));
+        assert_eq!(effective.output_sample_rate, 16000);
+        // Mono input can't be averaged, and Streaming delivers whole 30 ms chunks
+        assert_eq!((requested.downmix_mode, effective.downmix_mode), (DownmixMode::Average, DownmixMode::FirstChannel));
+        assert_eq!((requested.dispatch_frame_size, effective.dispatch_frame_size), (0, 480));
+    }
+
+    #[test]
+    fn tiny_reads_are_coalesced_until_enough_audio_or_the_timeout() {
+        // 10 ms at 16 kHz
+        let mut coalescer = ReadCoalescer::new(16000);
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        // A sample or two trickling in is held back...
+        assert!(!coalescer.ready(1, at(0)));
+        assert!(!coalescer.ready(2, at(1)));
+        assert!(!coalescer.ready(90, at(5)));
+        // ...until a read's worth is there
+        assert!(coalescer.ready(160, at(6)));
+        assert!(coalescer.ready(2048, at(7)));
+
+        // A device that stays this slow is still read, once the timeout has passed
+        assert!(!coalescer.ready(3, at(10)));
+        assert!(!coalescer.ready(3, at(29)));
+        assert!(coalescer.ready(3, at(30)));
+        // And the wait starts over after each read
+        assert!(!coalescer.ready(1, at(31)));
+        assert!(coalescer.ready(1, at(51)));
+    }
+
+    /// Samples dispatched and the packet ending a recording that is stopped while paused
+    /// two chunks and a bit into it.
+    fn stopped_while_paused(stop_while_paused: StopWhilePaused) -> (Vec<f32>, AudioPacket) {
+        let mut input = ScriptedInput::new(frame_ramp);
+        input.bursts = vec![1000];
+        let config = AudioConfig { warmup_discard_ms: 0, splice_crossfade_ms: 0, stop_while_paused, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture().unwrap();
+        let mut chunks = 0;
+        let mut packets = collect_until(&harness.packets, Duration::from_secs(5), |p| {
+            chunks += matches!(p, AudioPacket::Samples(_)) as usize;
+            chunks == 2
+        });
+        // Paused mid-chunk: 40 samples are still waiting for the third
+        harness.engine.pause_capture().unwrap();
+        harness.engine.stop_capture();
+        packets.extend(collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::RecordingStopped(_))));
+        harness.engine.shutdown();
+
+        let samples = packets.iter().filter_map(|p| if let AudioPacket::Samples(s) = p { Some(s.clone()) } else { None }).flatten().collect();
+        let end = packets.into_iter().rev().nth(1).unwrap();
+        (samples, end)
+    }
+
+    #[test]
+    fn stop_while_paused_flushes_or_discards_the_pre_pause_audio() {
+        let (flushed, end) = stopped_while_paused(StopWhilePaused::Flush);
+        assert!(matches!(end, AudioPacket::Flush));
+        // The partial chunk from before the pause went out too, nothing lost or repeated
+        assert_eq!(flushed.len(), 1000);
+        assert!(flushed.iter().enumerate().all(|(i, s)| *s == frame_ramp("", i as u64)));
+
+        let (discarded, end) = stopped_while_paused(StopWhilePaused::Discard);
+        assert!(matches!(end, AudioPacket::Discard));
+        assert_eq!(discarded, flushed[..960]);
+    }
+
+    #[test]
+    fn rate_monitor_reports_a_device_that_switched_rate() {
+        let start = Instant::now();
+        // Reads n = from..to, at n * 100 ms, of a device expected at 48 kHz that runs at `rate`
+        let feed = |monitor: &mut RateMonitor, rate: usize, reads: std::ops::Range<u64>| {
+            reads.fold(None, |reported, n| reported.or(monitor.observe(rate / 10, start + Duration::from_millis(n * 100))))
+        };
+        let mut monitor = RateMonitor::new(48000);
+        // The first window is start-up; then one window off isn't enough, two in a row are
+        assert_eq!(feed(&mut monitor, 48000, 1..22), None);
+        assert_eq!(feed(&mut monitor, 44100, 22..42), None);
+        let measured = feed(&mut monitor, 44100, 42..62).expect("two windows at 44.1 kHz");
+        assert_eq!(measured, 44100.0);
+        assert_eq!(nearest_standard_rate(measured), Some(44100));
+        assert_eq!(nearest_standard_rate(44500.0), Some(44100));
+        // Nothing standard nearby: detected, but nothing to switch to
+        assert_eq!(nearest_standard_rate(40000.0), None);
+
+        // An overflow restarts the measurement, start-up window included
+        let mut monitor = RateMonitor::new(48000);
+        feed(&mut monitor, 48000, 1..22);
+        feed(&mut monitor, 44100, 22..42);
+        monitor.restart();
+        assert_eq!(feed(&mut monitor, 44100, 42..83), None);
+        assert!(feed(&mut monitor, 44100, 83..103).is_some());
+    }
+
+    fn level(_device: &str, _frame: u64) -> f32 {
+        0.5
+    }
+
+    #[test]
+    fn gain_envelope_carries_on_across_stream_error_recovery() {
+        let mut input = ScriptedInput::new(level);
+        input.fail_after_frames = Some(8000);
+        let config = AudioConfig { warmup_discard_ms: 0, splice_crossfade_ms: 0, ..Default::default() };
+        let mut harness = engine(config, &input);
+        // A 0 -> 1 ramp over the first two seconds
+        let ramp = vec![GainPoint { at_seconds: 0.0, gain: 0.0 }, GainPoint { at_seconds: 2.0, gain: 1.0 }];
+        harness.engine.set_gain_envelope(Some(GainEnvelope::new(ramp).unwrap()));
+        harness.engine.start_capture().unwrap();
+
+        let before = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::StreamError(_)));
+        collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Format(_)));
+        let after = collect_until(&harness.packets, Duration::from_secs(5), |p| matches!(p, AudioPacket::Samples(_)));
+        harness.engine.shutdown();
+
+        let first: Vec<f32> = before.iter().filter_map(|p| if let AudioPacket::Samples(s) = p { Some(s.clone()) } else { None }).flatten().collect();
+        let delivered = input.delivered.lock().unwrap()[0];
+        assert_eq!(first.len() as u64, delivered);
+        let gain_at = |frame: u64| frame as f32 / 32000.0;
+        assert!((first[4000] - 0.5 * gain_at(4000)).abs() < 1e-6);
+        // The reopened stream picks the ramp up where the failed one left off
+        let AudioPacket::Samples(resumed) = after.last().unwrap() else { unreachable!() };
+        assert!((resumed[0] - 0.5 * gain_at(delivered)).abs() < 1e-6, "{} at frame {delivered}", resumed[0]);
+        assert!((resumed[100] - 0.5 * gain_at(delivered + 100)).abs() < 1e-6);
+    }
+
+    fn digital_silence(_device: &str, _frame: u64) -> f32 {
+        0.0
+    }
+
+    // A noise floor far below any speech, but never exactly zero for long
+    fn room_tone(_device: &str, frame: u64) -> f32 {
+        ((frame * 7919 % 13) as f32 - 6.0) * 1e-5
+    }
+
+    /// The InputSilent errors a second of `signal` raises with `dead_input_ms` at 300.
+    fn silent_input_errors(signal: fn(&str, u64) -> f32) -> Vec<String> {
+        let input = ScriptedInput::new(signal);
+        let config = AudioConfig { warmup_discard_ms: 0, dead_input_ms: 300, ..Default::default() };
+        let mut harness = engine(config, &input);
+        harness.engine.start_capture().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut errors = Vec::new();
+        while let Ok(packet) = harness.packets.recv_deadline(deadline) {
+            if let AudioPacket::StreamError(SupraSonicError::InputSilent(message)) = packet {
+                errors.push(message);
+            }
+        }
+        harness.engine.shutdown();
+        errors
+    }
+
+    #[test]
+    fn sustained_digital_silence_is_reported_but_a_quiet_room_is_not() {
+        // Once per run, however long it lasts
+        assert_eq!(silent_input_errors(digital_silence), ["scripted: no signal for 300 ms"]);
+        assert!(silent_input_errors(room_tone).is_empty());
+        assert!(vad::rms(&(0..16000).map(|f| room_tone("", f)).collect::<Vec<_>>()) < 1e-4);
+    }
+
+    #[test]
+    fn dead_input_runs_restart_at_any_non_zero_sample() {
+        // 100 frames of stereo at 1 kHz
+        let mut detector = DeadInputDetector::new(100, 1000);
+        assert!(!detector.observe(&[0.0; 120], 2));
+        // One tiny sample near the end of a block: only the frames after it count
+        let mut block = vec![0.0; 120];
+        block[99] = 1e-7;
+        assert!(!detector.observe(&block, 2));
+        // 10 trailing frames + 85 + 4 = 99, one short of the limit
+        assert!(!detector.observe(&[0.0; 170], 2));
+        assert!(!detector.observe(&[0.0; 8], 2));
+        assert!(detector.observe(&[0.0; 2], 2));
+        assert!(!detector.observe(&[0.0; 400], 2), "reported once per run");
+
+        assert!(!DeadInputDetector::new(0, 1000).observe(&[0.0; 100_000], 1), "0 turns it off");
+    }
+}
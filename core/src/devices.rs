@@ -0,0 +1,150 @@
+// Remembered capture settings per input device, persisted next to the speaker registry.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::audio::{AudioConfig, DownmixMode};
+
+/// Written beside the registry file, in the same directory.
+pub const DEVICE_PREFS_FILE: &str = "device_prefs.json";
+
+/// The parts of `AudioConfig` that depend on which device is capturing. Fields missing
+/// from the file (saved by an older version) take the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, uniffi::Record)]
+#[serde(default)]
+pub struct DevicePreference {
+    pub source_rate_override: Option<u32>,
+    pub downmix_mode: DownmixMode,
+    pub downmix_channels: u64,
+    pub meter_attack_ms: u32,
+    pub meter_release_ms: u32,
+}
+
+impl Default for DevicePreference {
+    /// What a device nobody configured gets: the `AudioConfig` defaults.
+    fn default() -> Self {
+        Self::from_config(&AudioConfig::default())
+    }
+}
+
+impl DevicePreference {
+    pub fn from_config(config: &AudioConfig) -> Self {
+        Self {
+            source_rate_override: config.source_rate_override,
+            downmix_mode: config.downmix_mode,
+            downmix_channels: config.downmix_channels,
+            meter_attack_ms: config.meter_attack_ms,
+            meter_release_ms: config.meter_release_ms,
+        }
+    }
+
+    pub fn apply(&self, config: &mut AudioConfig) {
+        config.source_rate_override = self.source_rate_override;
+        config.downmix_mode = self.downmix_mode;
+        config.downmix_channels = self.downmix_channels;
+        config.meter_attack_ms = self.meter_attack_ms;
+        config.meter_release_ms = self.meter_release_ms;
+    }
+}
+
+/// Device name -> last remembered preference, saved on every change.
+#[derive(Debug)]
+pub struct DevicePreferences {
+    path: PathBuf,
+    devices: Mutex<HashMap<String, DevicePreference>>,
+}
+
+impl DevicePreferences {
+    /// Loads the preferences stored beside `registry_path`; a missing or unreadable file
+    /// just means nothing is remembered yet.
+    pub fn load(registry_path: &Path) -> Self {
+        let path = registry_path.with_file_name(DEVICE_PREFS_FILE);
+        let devices = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                tracing::error!("Ignoring unreadable device preferences {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, devices: Mutex::new(devices) }
+    }
+
+    pub fn get(&self, device: &str) -> Option<DevicePreference> {
+        self.devices.lock().ok().and_then(|d| d.get(device).copied())
+    }
+
+    pub fn remember(&self, device: String, preference: DevicePreference) {
+        let Ok(mut devices) = self.devices.lock() else { return };
+        devices.insert(device, preference);
+        let json = serde_json::to_string_pretty(&*devices).unwrap_or_default();
+        if let Err(e) = fs::write(&self.path, json) {
+            tracing::error!("Failed to save device preferences: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_registry_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("suprasonic-{}-{}", name, std::process::id()));
+        let _ = fs::create_dir_all(&path);
+        let _ = fs::remove_file(path.join(DEVICE_PREFS_FILE));
+        path.join("speakers.json")
+    }
+
+    #[test]
+    fn remembered_preferences_survive_a_reload_and_unseen_devices_get_defaults() {
+        let registry = temp_registry_path("device-prefs");
+        let interface = DevicePreference {
+            source_rate_override: Some(44100),
+            downmix_mode: DownmixMode::FirstChannel,
+            downmix_channels: 0b10,
+            ..DevicePreference::default()
+        };
+        DevicePreferences::load(&registry).remember("Interface".to_string(), interface);
+
+        let reloaded = DevicePreferences::load(&registry);
+        assert_eq!(reloaded.get("Interface"), Some(interface));
+        assert_eq!(reloaded.get("Webcam"), None);
+
+        let mut config = AudioConfig::default();
+        interface.apply(&mut config);
+        assert_eq!(DevicePreference::from_config(&config), interface);
+        let _ = fs::remove_dir_all(registry.parent().unwrap());
+    }
+
+    #[test]
+    fn unreadable_file_means_nothing_is_remembered() {
+        let registry = temp_registry_path("device-prefs-corrupt");
+        fs::write(registry.with_file_name(DEVICE_PREFS_FILE), "{ not json").unwrap();
+        assert_eq!(DevicePreferences::load(&registry).get("Interface"), None);
+        let _ = fs::remove_dir_all(registry.parent().unwrap());
+    }
+
+    #[test]
+    fn meter_constants_are_remembered_per_device() {
+        let registry = temp_registry_path("device-prefs-meter");
+        let prefs = DevicePreferences::load(&registry);
+        let drums = DevicePreference { meter_attack_ms: 1, meter_release_ms: 80, ..DevicePreference::default() };
+        prefs.remember("Drum mic".to_string(), drums);
+        prefs.remember("Headset".to_string(), DevicePreference::default());
+
+        let reloaded = DevicePreferences::load(&registry);
+        assert_eq!(reloaded.get("Drum mic").map(|p| (p.meter_attack_ms, p.meter_release_ms)), Some((1, 80)));
+        let headset = reloaded.get("Headset").unwrap();
+        assert_eq!((headset.meter_attack_ms, headset.meter_release_ms), (10, 300), "voice-oriented defaults");
+
+        // Saved before the meter fields existed
+        let old = r#"{"Interface": {"source_rate_override": 48000, "downmix_mode": "Average"}}"#;
+        fs::write(registry.with_file_name(DEVICE_PREFS_FILE), old).unwrap();
+        let interface = DevicePreferences::load(&registry).get("Interface").unwrap();
+        assert_eq!(interface.source_rate_override, Some(48000));
+        assert_eq!((interface.meter_attack_ms, interface.meter_release_ms), (10, 300));
+        let _ = fs::remove_dir_all(registry.parent().unwrap());
+    }
+}
@@ -0,0 +1,91 @@
+// Per-recording diagnostic log: the pipeline's `tracing` events, kept for the session export.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Zip entry `export_session` writes the log to, next to the WAV.
+pub const ARCHIVE_DIAGNOSTICS_ENTRY: &str = "diagnostics.log";
+// Lines kept per recording; later ones are only counted
+const MAX_DIAGNOSTIC_LINES: usize = 2000;
+
+// Cheap check for the layer, so nothing is formatted while no recording is logged
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<Option<RecordingLog>> = Mutex::new(None);
+
+struct RecordingLog {
+    started: Instant,
+    lines: Vec<String>,
+    dropped: usize,
+}
+
+/// Starts a fresh log for the recording that is starting, discarding the previous one.
+pub fn begin() {
+    if let Ok(mut log) = LOG.lock() {
+        *log = Some(RecordingLog { started: Instant::now(), lines: Vec::new(), dropped: 0 });
+        CAPTURING.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Stops logging; the log stays readable with `text` until the next `begin`.
+pub fn end() {
+    CAPTURING.store(false, Ordering::SeqCst);
+}
+
+/// The current (or last) recording's log, one event per line; empty if none was kept.
+pub fn text() -> String {
+    let Ok(log) = LOG.lock() else { return String::new() };
+    let Some(log) = log.as_ref() else { return String::new() };
+    let mut text = log.lines.join("\n");
+    if log.dropped > 0 {
+        let _ = write!(text, "\n... {} more events not kept", log.dropped);
+    }
+    text
+}
+
+/// Installed beside the console output by `AppState::new`. Keeps this crate's events from
+/// INFO up and everyone else's warnings and errors.
+pub fn layer() -> DiagnosticsLayer {
+    DiagnosticsLayer
+}
+
+pub struct DiagnosticsLayer;
+
+impl<S: Subscriber> Layer<S> for DiagnosticsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !CAPTURING.load(Ordering::Relaxed) {
+            return;
+        }
+        let meta = event.metadata();
+        let ours = meta.target().starts_with(env!("CARGO_CRATE_NAME"));
+        if *meta.level() > if ours { Level::INFO } else { Level::WARN } {
+            return;
+        }
+        let Ok(mut log) = LOG.lock() else { return };
+        let Some(log) = log.as_mut() else { return };
+        if log.lines.len() >= MAX_DIAGNOSTIC_LINES {
+            log.dropped += 1;
+            return;
+        }
+        let mut line = format!("{:>10.3}s {:>5} {}: ", log.started.elapsed().as_secs_f64(), meta.level(), meta.target());
+        event.record(&mut LineVisitor(&mut line));
+        log.lines.push(line);
+    }
+}
+
+/// Appends the event's message, then any other fields as `name=value`.
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, "{:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
@@ -1,74 +1,654 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::state::{RegistryListener, SpeakerEmbedder, SupraSonicError};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Speaker {
     pub id: String,
     pub name: String,
     pub embedding: Option<Vec<f32>>, // 192 (ECAPA) or 512 (x-vector)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
 pub struct Segment {
     pub start: f64,
     pub end: f64,
     pub text: String,
     pub speaker_id: String,
     pub is_final: bool,
+    /// The `utterance_id` of the audio this segment was transcribed from, if known.
+    #[serde(default)]
+    pub utterance_id: Option<u64>,
+    /// BCP-47 language tag (e.g. "en", "es-MX") as provided by the host; not detected here.
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// Recognizer confidence in 0..=1, as provided by the host.
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// Set by the transcript when `confidence` is below the configured minimum and the
+    /// policy is `LowConfidencePolicy::Mark`.
+    #[serde(default)]
+    pub low_confidence: bool,
+}
+
+/// Result of registering a speaker.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct SpeakerRegistration {
+    /// The name as stored, after trimming and whitespace collapsing.
+    pub name: String,
+    /// Other speaker ids already using the same display name (case-insensitive).
+    pub duplicate_ids: Vec<String>,
+}
+
+/// What a registry file holds, as reported by `SpeakerRegistry::validate_json`.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct RegistrySummary {
+    /// Schema version the file was written with (0 = from before versioning).
+    pub version: u32,
+    pub speaker_count: u32,
+    /// Speakers that carry an embedding.
+    pub enrolled_count: u32,
+    /// Distinct embedding dimensions found, ascending; more than one means some
+    /// speakers won't be matched until re-enrolled.
+    pub embedding_dims: Vec<u32>,
+    pub metric: DistanceMetric,
+}
+
+/// How the registry changed over a session, as reported by `finalize_session`. Ids are
+/// sorted; a speaker added and enrolled in the same session is listed under both.
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct RegistryChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub renamed: Vec<String>,
+    /// Speakers whose embedding was enrolled, replaced or imported.
+    pub enrolled: Vec<String>,
+    /// Auto speakers folded into another by `finalize_session`, in the order merged. A
+    /// merged-away speaker created this session is in none of the other lists.
+    pub merged: Vec<SpeakerMerge>,
+}
+
+/// One auto speaker merged into another (see `DiarizationService::set_merge_threshold`).
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct SpeakerMerge {
+    /// The speaker that no longer exists.
+    pub from: String,
+    /// The one it became, whose embedding now averages both.
+    pub into: String,
+}
+
+impl RegistryChanges {
+    /// What changed from `before` to `after`.
+    fn between(before: &HashMap<String, Speaker>, after: &HashMap<String, Speaker>) -> Self {
+        let mut changes = RegistryChanges::default();
+        for (id, speaker) in after {
+            match before.get(id) {
+                None => changes.added.push(id.clone()),
+                Some(earlier) if earlier.name != speaker.name => changes.renamed.push(id.clone()),
+                _ => {}
+            }
+            let enrolled_before = before.get(id).and_then(|s| s.embedding.as_ref());
+            if speaker.embedding.is_some() && speaker.embedding.as_ref() != enrolled_before {
+                changes.enrolled.push(id.clone());
+            }
+        }
+        changes.removed = before.keys().filter(|id| !after.contains_key(*id)).cloned().collect();
+        for ids in [&mut changes.added, &mut changes.removed, &mut changes.renamed, &mut changes.enrolled] {
+            ids.sort();
+        }
+        changes
+    }
+
+    /// One (speaker, kind) per change, in the order `RegistryChangeKind` lists the kinds.
+    fn events(self) -> impl Iterator<Item = (String, RegistryChangeKind)> {
+        let tag = |ids: Vec<String>, kind| ids.into_iter().map(move |id| (id, kind));
+        tag(self.added, RegistryChangeKind::Added)
+            .chain(tag(self.removed, RegistryChangeKind::Removed))
+            .chain(tag(self.renamed, RegistryChangeKind::Renamed))
+            .chain(tag(self.enrolled, RegistryChangeKind::Enrolled))
+    }
+}
+
+/// What happened to a speaker, as reported to a `RegistryListener`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum RegistryChangeKind {
+    Added,
+    Removed,
+    Renamed,
+    /// Its embedding was enrolled, replaced or imported.
+    Enrolled,
+}
+
+/// Trims and collapses runs of whitespace so "  Ada   Lovelace " becomes "Ada Lovelace".
+pub fn normalize_speaker_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Minimum cosine similarity for an embedding to be attributed to a known speaker
+const MATCH_THRESHOLD: f32 = 0.7;
+// Largest Euclidean distance still attributed to a known speaker (about the cosine
+// threshold for unit-length embeddings)
+const MAX_EUCLIDEAN_DISTANCE: f32 = 0.8;
+
+/// How a registry compares voice embeddings. Stored with the registry, since enrolled
+/// embeddings only make sense under the metric their model was trained for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, uniffi::Enum)]
+pub enum DistanceMetric {
+    /// Cosine similarity, matched from 0.7 up. Embeddings are unit-normalized first, so
+    /// their scale doesn't matter, only their direction.
+    #[default]
+    Cosine,
+    /// Euclidean distance, matched up to 0.8. Embeddings are compared as given, so the
+    /// model must emit them at a consistent scale (the threshold suits unit-length ones).
+    Euclidean,
+}
+
+impl DistanceMetric {
+    /// How close `a` is to `b`, higher meaning closer: the cosine similarity, or the
+    /// negated Euclidean distance. Mismatched dimensions score as far apart as possible.
+    pub fn score(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::Cosine => cosine_similarity(a, b),
+            DistanceMetric::Euclidean if a.len() != b.len() => f32::NEG_INFINITY,
+            DistanceMetric::Euclidean => -euclidean_distance(a, b),
+        }
+    }
+
+    /// Lowest `score` still attributed to a known speaker.
+    fn match_threshold(self) -> f32 {
+        match self {
+            DistanceMetric::Cosine => MATCH_THRESHOLD,
+            DistanceMetric::Euclidean => -MAX_EUCLIDEAN_DISTANCE,
+        }
+    }
+
+    /// The form `EmbeddingIndex` keeps an embedding in; None if it can't be scored.
+    fn prepare(self, embedding: &[f32]) -> Option<Vec<f32>> {
+        match self {
+            DistanceMetric::Cosine => normalized(embedding),
+            DistanceMetric::Euclidean => embedding.iter().all(|x| x.is_finite()).then(|| embedding.to_vec()),
+        }
+    }
+
+    /// `score` of two embeddings already `prepare`d.
+    fn score_prepared(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::Cosine => a.iter().zip(b).map(|(x, y)| x * y).sum(),
+            DistanceMetric::Euclidean => -euclidean_distance(a, b),
+        }
+    }
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a <= f32::EPSILON || norm_b <= f32::EPSILON {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+pub fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt()
+}
+
+fn normalized(v: &[f32]) -> Option<Vec<f32>> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm <= f32::EPSILON || !norm.is_finite() {
+        return None;
+    }
+    Some(v.iter().map(|x| x / norm).collect())
+}
+
+/// Embeddings stored contiguously in the form their metric scores fastest (unit-normalized
+/// for cosine, so matching is one dot product per speaker instead of recomputing norms on
+/// every utterance). Each embedding dimension gets its own block, so speakers enrolled by
+/// different models stay matchable by queries from the model that produced them.
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingIndex {
+    metric: DistanceMetric,
+    by_dim: HashMap<usize, DimIndex>,
 }
 
+#[derive(Debug, Clone, Default)]
+struct DimIndex {
+    ids: Vec<String>,
+    vectors: Vec<f32>,
+}
+
+impl EmbeddingIndex {
+    /// Indexes every speaker with an embedding, grouped by the embedding's dimension.
+    pub fn build(speakers: &HashMap<String, Speaker>, metric: DistanceMetric) -> Self {
+        let mut index = Self { metric, ..Self::default() };
+        let mut ids: Vec<&String> = speakers.keys().collect();
+        ids.sort();
+
+        for id in ids {
+            let Some(embedding) = speakers[id].embedding.as_deref() else { continue };
+            if let Some(prepared) = metric.prepare(embedding) {
+                let block = index.by_dim.entry(embedding.len()).or_default();
+                block.ids.push(id.clone());
+                block.vectors.extend_from_slice(&prepared);
+            }
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_dim.values().map(|block| block.ids.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_dim.is_empty()
+    }
+
+    /// Best-matching speaker id and its `DistanceMetric::score`, among the speakers whose
+    /// embedding has the query's dimension.
+    pub fn best_match(&self, embedding: &[f32]) -> Option<(String, f32)> {
+        let dim = embedding.len();
+        let block = self.by_dim.get(&dim)?;
+        let query = self.metric.prepare(embedding)?;
+
+        block
+            .vectors
+            .chunks_exact(dim)
+            .zip(&block.ids)
+            .map(|(v, id)| (id, self.metric.score_prepared(v, &query)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, score)| (id.clone(), score))
+    }
+}
+
+/// Which halves of the registry an export carries, or an import applies. Names are
+/// PII; embeddings are voiceprints; some deployments must store them separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum RegistryParts {
+    All,
+    /// Ids and names; every embedding is left out.
+    NamesOnly,
+    /// Ids and embeddings of enrolled speakers; names are replaced by the id.
+    EmbeddingsOnly,
+}
+
+// Bump together with a new step in `migrate_registry` whenever the stored shape changes
+pub const REGISTRY_SCHEMA_VERSION: u32 = 2;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpeakerRegistry {
+    /// Schema version the JSON was written with; files from before versioning are 0.
+    #[serde(default)]
+    pub version: u32,
     pub speakers: HashMap<String, Speaker>,
+    metric: DistanceMetric,
+    #[serde(skip)]
+    index: EmbeddingIndex,
+}
+
+impl Default for SpeakerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SpeakerRegistry {
     pub fn new() -> Self {
+        Self::with_metric(DistanceMetric::default())
+    }
+
+    /// An empty registry matching voices under `metric`.
+    pub fn with_metric(metric: DistanceMetric) -> Self {
         Self {
+            version: REGISTRY_SCHEMA_VERSION,
             speakers: HashMap::new(),
+            metric,
+            index: EmbeddingIndex::default(),
         }
     }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// Switches the metric for every later match; enrolled embeddings are kept as they are.
+    pub fn set_metric(&mut self, metric: DistanceMetric) {
+        self.metric = metric;
+        self.rebuild_index();
+    }
+
+    /// Must run after anything that changes embeddings, removes speakers or switches the metric.
+    fn rebuild_index(&mut self) {
+        self.index = EmbeddingIndex::build(&self.speakers, self.metric);
+    }
     
-    pub fn add_speaker(&mut self, id: String, name: String) {
+    /// Adds or renames a speaker. Blank names are rejected; duplicate display names are
+    /// allowed but reported so the UI can flag them.
+    pub fn add_speaker(&mut self, id: String, name: String) -> Result<SpeakerRegistration, SupraSonicError> {
+        let id = id.trim().to_string();
+        if id.is_empty() {
+            return Err(SupraSonicError::InvalidArgument("Speaker id must not be empty".to_string()));
+        }
+        let name = normalize_speaker_name(&name);
+        if name.is_empty() {
+            return Err(SupraSonicError::InvalidArgument("Speaker name must not be empty".to_string()));
+        }
+
+        let mut duplicate_ids: Vec<String> = self.speakers.values()
+            .filter(|s| s.id != id && s.name.to_lowercase() == name.to_lowercase())
+            .map(|s| s.id.clone())
+            .collect();
+        duplicate_ids.sort();
+        if !duplicate_ids.is_empty() {
+            tracing::warn!("Speaker name {:?} is also used by {:?}", name, duplicate_ids);
+        }
+
         // If ID exists, update name. If not, create new.
         if let Some(speaker) = self.speakers.get_mut(&id) {
-            speaker.name = name;
+            speaker.name = name.clone();
         } else {
             self.speakers.insert(id.clone(), Speaker {
                 id: id.clone(),
-                name,
+                name: name.clone(),
                 embedding: None,
             });
         }
+        Ok(SpeakerRegistration { name, duplicate_ids })
+    }
+
+    /// Stores (or replaces) a speaker's voice embedding.
+    pub fn enroll_embedding(&mut self, id: &str, embedding: Vec<f32>) -> Result<(), SupraSonicError> {
+        check_embedding(&embedding)?;
+        let speaker = self.speakers.get_mut(id)
+            .ok_or_else(|| SupraSonicError::InvalidArgument(format!("Unknown speaker: {}", id)))?;
+        speaker.embedding = Some(embedding);
+        self.rebuild_index();
+        Ok(())
+    }
+
+    pub fn remove_speaker(&mut self, id: &str) -> Option<Speaker> {
+        let removed = self.speakers.remove(id);
+        if removed.is_some() {
+            self.rebuild_index();
+        }
+        removed
     }
     
     pub fn get_speaker_name(&self, id: &str) -> Option<String> {
         self.speakers.get(id).map(|s| s.name.clone())
     }
 
-    // Placeholder for Embedding Matching
-    pub fn assign_speaker(&self, _embedding: &[f32]) -> String {
-        // TODO: Cosine Similarity
-        // For now, return a generic ID that the UI can then "renaming"
-        "Guest".to_string() 
+    /// Closest enrolled speaker within the metric's match threshold, with its score.
+    pub fn best_match(&self, embedding: &[f32]) -> Option<(String, f32)> {
+        self.index.best_match(embedding).filter(|(_, score)| *score >= self.metric.match_threshold())
+    }
+
+    pub fn assign_speaker(&self, embedding: &[f32]) -> String {
+        // Unknown voices get a generic ID that the UI can then rename
+        self.best_match(embedding)
+            .map(|(id, _)| id)
+            .unwrap_or_else(|| "Guest".to_string())
+    }
+
+    /// Adds a speaker enrolled with `embedding` for a voice nobody matched, named after
+    /// the first free `auto_N` id ("Speaker N") so the UI can rename it later.
+    pub fn add_auto_speaker(&mut self, embedding: Vec<f32>) -> Result<String, SupraSonicError> {
+        check_embedding(&embedding)?;
+        let n = (1..).find(|n| !self.speakers.contains_key(&format!("auto_{}", n))).unwrap_or_default();
+        let id = format!("auto_{}", n);
+        self.speakers.insert(id.clone(), Speaker { id: id.clone(), name: format!("Speaker {}", n), embedding: Some(embedding) });
+        self.rebuild_index();
+        Ok(id)
+    }
+
+    /// Folds each of `ids` whose embedding scores at least `threshold` against an earlier one
+    /// into the closest such, averaging their embeddings. `ids` are taken oldest first, so
+    /// the earliest of a group of near-duplicates survives.
+    pub fn merge_near_duplicates(&mut self, ids: &[String], threshold: f32) -> Vec<SpeakerMerge> {
+        let mut kept: Vec<String> = Vec::new();
+        let mut merges = Vec::new();
+        for id in ids {
+            let Some(embedding) = self.speakers.get(id).and_then(|s| s.embedding.clone()) else { continue };
+            let nearest = kept
+                .iter()
+                .filter_map(|k| self.speakers[k].embedding.as_ref().map(|e| (k, self.metric.score(e, &embedding))))
+                .filter(|(_, score)| *score >= threshold)
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(k, _)| k.clone());
+            match nearest {
+                Some(into) => {
+                    if let Some(target) = self.speakers.get_mut(&into).and_then(|s| s.embedding.as_mut()) {
+                        target.iter_mut().zip(&embedding).for_each(|(t, e)| *t = (*t + e) / 2.0);
+                    }
+                    self.speakers.remove(id);
+                    merges.push(SpeakerMerge { from: id.clone(), into });
+                }
+                None => kept.push(id.clone()),
+            }
+        }
+        if !merges.is_empty() {
+            self.rebuild_index();
+        }
+        merges
+    }
+
+    /// Whichever of `ids` is closest to `embedding`, however far; None if none is enrolled.
+    pub fn nearest_of(&self, ids: &[String], embedding: &[f32]) -> Option<String> {
+        ids.iter()
+            .filter_map(|id| self.speakers.get(id)?.embedding.as_ref().map(|e| (id, self.metric.score(e, embedding))))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id.clone())
     }
     
     pub fn to_json(&self) -> String {
         serde_json::to_string_pretty(&self).unwrap_or_default()
     }
+
+    /// A copy holding only `parts`. Still a regular registry, so it loads anywhere a
+    /// full one does; the two partial exports rejoin on speaker id via `merge`.
+    pub fn with_parts(&self, parts: RegistryParts) -> Self {
+        let mut copy = Self::with_metric(self.metric);
+        copy.speakers = self.speakers.iter()
+            .filter_map(|(id, s)| {
+                let speaker = match parts {
+                    RegistryParts::All => s.clone(),
+                    RegistryParts::NamesOnly => Speaker { embedding: None, ..s.clone() },
+                    RegistryParts::EmbeddingsOnly => Speaker { name: s.id.clone(), ..s.clone() },
+                };
+                (parts != RegistryParts::EmbeddingsOnly || s.embedding.is_some()).then(|| (id.clone(), speaker))
+            })
+            .collect();
+        copy.rebuild_index();
+        copy
+    }
+
+    /// Applies `parts` of `other` on top of this registry, returning how many speakers it
+    /// touched. Names-only keeps local embeddings and vice versa; speakers new to this
+    /// registry are added, named after their id when the import carries no names. The
+    /// metric stays this registry's.
+    pub fn merge(&mut self, other: SpeakerRegistry, parts: RegistryParts) -> u32 {
+        let mut merged = 0;
+        for (id, incoming) in other.speakers {
+            // Partial exports without names carry the id in their place
+            let name = Some(normalize_speaker_name(&incoming.name))
+                .filter(|name| !name.is_empty() && *name != id && parts != RegistryParts::EmbeddingsOnly);
+            let speaker = self.speakers.entry(id.clone())
+                .or_insert_with(|| Speaker { id: id.clone(), name: id, embedding: None });
+            if let Some(name) = name {
+                speaker.name = name;
+            }
+            if parts != RegistryParts::NamesOnly && incoming.embedding.is_some() {
+                speaker.embedding = incoming.embedding;
+            }
+            merged += 1;
+        }
+        self.rebuild_index();
+        merged
+    }
     
+    /// Lenient load: anything unreadable yields an empty registry. Prefer `try_from_json`
+    /// when the source must not be silently replaced.
     pub fn from_json(json: &str) -> Self {
-        serde_json::from_str(json).unwrap_or_else(|_| Self::new())
+        Self::try_from_json(json).unwrap_or_else(|e| {
+            tracing::error!("Discarding unreadable speaker registry: {}", e);
+            Self::new()
+        })
+    }
+
+    /// Parses a registry of any known schema version, upgrading it to the current one.
+    pub fn try_from_json(json: &str) -> Result<Self, SupraSonicError> {
+        let invalid = |e: serde_json::Error| SupraSonicError::InvalidArgument(format!("Invalid speaker registry: {}", e));
+        let value: serde_json::Value = serde_json::from_str(json).map_err(invalid)?;
+        let value = migrate_registry(value)?;
+        let mut registry: Self = serde_json::from_value(value).map_err(invalid)?;
+        registry.rebuild_index();
+        Ok(registry)
+    }
+
+    /// Checks registry JSON the way `try_from_json` would load it and summarises its
+    /// contents, e.g. to preview an import. Touches no service state.
+    pub fn validate_json(json: &str) -> Result<RegistrySummary, SupraSonicError> {
+        if json.trim().is_empty() {
+            return Err(SupraSonicError::InvalidArgument("Speaker registry file is empty".to_string()));
+        }
+        let registry = Self::try_from_json(json)?;
+        // Parsed and checked by `try_from_json` already
+        let version = serde_json::from_str::<serde_json::Value>(json)
+            .ok()
+            .and_then(|v| v.as_object().and_then(|root| registry_version(root).ok()))
+            .unwrap_or(0);
+        let mut embedding_dims: Vec<u32> =
+            registry.speakers.values().filter_map(|s| s.embedding.as_ref()).map(|e| e.len() as u32).collect();
+        embedding_dims.sort_unstable();
+        embedding_dims.dedup();
+        Ok(RegistrySummary {
+            version,
+            speaker_count: registry.speakers.len() as u32,
+            enrolled_count: registry.speakers.values().filter(|s| s.embedding.is_some()).count() as u32,
+            embedding_dims,
+            metric: registry.metric,
+        })
+    }
+}
+
+/// Upgrades stored registry JSON one version at a time to `REGISTRY_SCHEMA_VERSION`,
+/// filling defaults for anything older files lack.
+fn migrate_registry(mut value: serde_json::Value) -> Result<serde_json::Value, SupraSonicError> {
+    let Some(root) = value.as_object_mut() else {
+        return Err(SupraSonicError::InvalidArgument("Speaker registry must be a JSON object".to_string()));
+    };
+    let mut version = registry_version(root)?;
+    if version > REGISTRY_SCHEMA_VERSION {
+        return Err(SupraSonicError::InvalidArgument(format!(
+            "Speaker registry version {} is newer than supported ({})",
+            version, REGISTRY_SCHEMA_VERSION
+        )));
+    }
+
+    if version == 0 {
+        // Unversioned files: speakers may lack `id`/`name`/`embedding`; derive them from the map key
+        let speakers = root.entry("speakers").or_insert_with(|| serde_json::json!({}));
+        if let Some(speakers) = speakers.as_object_mut() {
+            for (key, speaker) in speakers.iter_mut() {
+                let Some(speaker) = speaker.as_object_mut() else { continue };
+                speaker.entry("id").or_insert_with(|| key.clone().into());
+                let name = speaker.get("id").cloned().unwrap_or_else(|| key.clone().into());
+                speaker.entry("name").or_insert(name);
+                speaker.entry("embedding").or_insert(serde_json::Value::Null);
+            }
+        }
+        version = 1;
+    }
+
+    if version == 1 {
+        // Matching was always cosine before the metric became selectable
+        root.entry("metric").or_insert_with(|| serde_json::json!(DistanceMetric::Cosine));
+        version = 2;
+    }
+
+    root.insert("version".to_string(), version.into());
+    Ok(value)
+}
+
+fn check_embedding(embedding: &[f32]) -> Result<(), SupraSonicError> {
+    if embedding.is_empty() || embedding.iter().any(|v| !v.is_finite()) {
+        return Err(SupraSonicError::InvalidArgument("Embedding must be non-empty and finite".to_string()));
+    }
+    Ok(())
+}
+
+/// The `version` of stored registry JSON; 0 when absent (files from before versioning).
+fn registry_version(root: &serde_json::Map<String, serde_json::Value>) -> Result<u32, SupraSonicError> {
+    let Some(value) = root.get("version") else { return Ok(0) };
+    value.as_u64().and_then(|v| u32::try_from(v).ok()).ok_or_else(|| {
+        SupraSonicError::InvalidArgument(format!("Speaker registry version {} is not a schema version", value))
+    })
+}
+
+/// Deterministic display color for a speaker id, identical on every platform and run.
+///
+/// Hashes the id with FNV-1a (std's hasher is not stable across Rust versions) and maps
+/// it onto a hue wheel with fixed, readable saturation/value.
+pub fn speaker_color(id: &str) -> (u8, u8, u8) {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in id.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
+
+    // Golden-ratio stepping spreads consecutive hashes around the wheel; only 24 bits go in
+    // so the product keeps a fractional part in f64
+    let hue = ((hash >> 40) as f64 * 0.618_033_988_749_895).fract() * 360.0;
+    let saturation = 0.55 + (hash & 0x3) as f64 * 0.05;
+    let value = 0.80 + ((hash >> 2) & 0x3) as f64 * 0.05;
+    hsv_to_rgb(hue, saturation, value)
+}
+
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> (u8, u8, u8) {
+    let c = value * saturation;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = value - c;
+    let (r, g, b) = match hue as u32 {
+        0..=59 => (c, x, 0.0),
+        60..=119 => (x, c, 0.0),
+        120..=179 => (0.0, c, x),
+        180..=239 => (0.0, x, c),
+        240..=299 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let to_u8 = |v: f64| ((v + m) * 255.0).round() as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
 }
 
+// Default debounce for embedding updates; speaker adds/removes are always saved at once
+const AUTOSAVE_INTERVAL_MS: u64 = 2000;
+// How often the autosave thread re-checks when the interval is 0 (immediate saves)
+const AUTOSAVE_IDLE_POLL_MS: u64 = 500;
+
 // Helper to manage storage path
 pub struct DiarizationService {
     registry: Arc<Mutex<SpeakerRegistry>>,
     storage_path: PathBuf,
+    // Set when the registry changed but has not been written yet
+    dirty: Arc<AtomicBool>,
+    autosave_interval_ms: Arc<AtomicU64>,
+    embedder: Mutex<Option<Arc<dyn SpeakerEmbedder>>>,
+    // The speakers as of the start of the session, for `finalize_session`
+    session_baseline: Mutex<HashMap<String, Speaker>>,
+    listener: Mutex<Option<Arc<dyn RegistryListener>>>,
+    // Speakers `assign_speaker` may create per session (0 = unmatched voices are "Guest")
+    max_auto_speakers: AtomicU32,
+    // The ones it created this session, oldest first
+    session_auto_speakers: Mutex<Vec<String>>,
+    // Score at which `finalize_session` merges two of those; None leaves them apart
+    merge_threshold: Mutex<Option<f32>>,
 }
 
 impl DiarizationService {
@@ -76,29 +656,268 @@ impl DiarizationService {
          let path = PathBuf::from(storage_path);
          let registry = if path.exists() {
              let content = fs::read_to_string(&path).unwrap_or_default();
-             SpeakerRegistry::from_json(&content)
+             SpeakerRegistry::try_from_json(&content).unwrap_or_else(|e| {
+                 // Keep the unreadable file around instead of overwriting it on the next save
+                 let backup = path.with_extension("json.bak");
+                 tracing::error!("{}; moving it to {:?} and starting empty", e, backup);
+                 let _ = fs::rename(&path, &backup);
+                 SpeakerRegistry::new()
+             })
          } else {
              SpeakerRegistry::new()
          };
          
-         Self {
+         let service = Self {
+             session_baseline: Mutex::new(registry.speakers.clone()),
              registry: Arc::new(Mutex::new(registry)),
              storage_path: path,
-         }
+             dirty: Arc::new(AtomicBool::new(false)),
+             autosave_interval_ms: Arc::new(AtomicU64::new(AUTOSAVE_INTERVAL_MS)),
+             embedder: Mutex::new(None),
+             listener: Mutex::new(None),
+             max_auto_speakers: AtomicU32::new(0),
+             session_auto_speakers: Mutex::new(Vec::new()),
+             merge_threshold: Mutex::new(None),
+         };
+         service.spawn_autosave();
+         service
     }
-    
+
+    /// Writes pending changes every autosave interval until the service is dropped.
+    fn spawn_autosave(&self) {
+        let registry: Weak<Mutex<SpeakerRegistry>> = Arc::downgrade(&self.registry);
+        let dirty = self.dirty.clone();
+        let interval_ms = self.autosave_interval_ms.clone();
+        let path = self.storage_path.clone();
+
+        std::thread::spawn(move || loop {
+            let ms = match interval_ms.load(Ordering::Relaxed) {
+                0 => AUTOSAVE_IDLE_POLL_MS,
+                ms => ms,
+            };
+            std::thread::sleep(Duration::from_millis(ms));
+            let Some(registry) = registry.upgrade() else { break };
+            if dirty.load(Ordering::SeqCst) {
+                write_registry(&registry, &path, &dirty);
+            }
+        });
+    }
+
+    /// Debounce window for embedding updates; 0 writes every change immediately.
+    pub fn set_autosave_interval(&self, interval: Duration) {
+        self.autosave_interval_ms.store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
     pub fn save(&self) {
-        if let Ok(reg) = self.registry.lock() {
-            let json = reg.to_json();
-            let _ = fs::write(&self.storage_path, json);
+        write_registry(&self.registry, &self.storage_path, &self.dirty);
+    }
+
+    /// Writes any changes still waiting for the autosave, e.g. on shutdown.
+    pub fn flush_save(&self) {
+        if self.dirty.load(Ordering::SeqCst) {
+            self.save();
+        }
+    }
+
+    /// Ends a session: writes the registry now (pending autosave or not) and reports what
+    /// changed since the service started or the last call, which also starts the next
+    /// session, with a fresh `set_max_auto_speakers` allowance. Auto-created speakers are
+    /// reported as added; with `set_merge_threshold`, near-duplicates among them are
+    /// merged first.
+    pub fn finalize_session(&self) -> Result<RegistryChanges, SupraSonicError> {
+        let merged = self.merge_session_auto_speakers()?;
+        let reg = self.registry.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+        let mut baseline = self.session_baseline.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+        // Cleared under the registry lock, as the autosave does
+        self.dirty.store(false, Ordering::SeqCst);
+        if let Err(e) = fs::write(&self.storage_path, reg.to_json()) {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(SupraSonicError::General(format!("Failed to save speaker registry: {}", e)));
+        }
+
+        let changes = RegistryChanges { merged, ..RegistryChanges::between(&baseline, &reg.speakers) };
+        *baseline = reg.speakers.clone();
+        if let Ok(mut created) = self.session_auto_speakers.lock() {
+            created.clear();
+        }
+        Ok(changes)
+    }
+
+    /// Lets `finalize_session` merge auto speakers of the session that score at least
+    /// `threshold` against each other (`DistanceMetric::score` units, so a cosine
+    /// similarity or a negated distance). Only a threshold below the metric's match
+    /// threshold can merge anything: auto speakers exist because they scored under it.
+    /// None (the default) keeps every auto speaker.
+    pub fn set_merge_threshold(&self, threshold: Option<f32>) {
+        if let Ok(mut t) = self.merge_threshold.lock() {
+            *t = threshold;
+        }
+    }
+
+    fn merge_session_auto_speakers(&self) -> Result<Vec<SpeakerMerge>, SupraSonicError> {
+        let Some(threshold) = self.merge_threshold.lock().ok().and_then(|t| *t) else { return Ok(Vec::new()) };
+        // A copy, so the listener `mutate` calls can assign speakers itself
+        let created = self.session_auto_speakers.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.clone();
+        let merged = self.mutate(|reg| Ok(reg.merge_near_duplicates(&created, threshold)))?;
+        if let Ok(mut created) = self.session_auto_speakers.lock() {
+            created.retain(|id| !merged.iter().any(|m| &m.from == id));
+        }
+        Ok(merged)
+    }
+
+    /// Reports every change to the speakers from now on (None stops it).
+    pub fn set_listener(&self, listener: Option<Arc<dyn RegistryListener>>) {
+        if let Ok(mut l) = self.listener.lock() {
+            *l = listener;
+        }
+    }
+
+    /// Runs `f` on the registry, then tells the listener, if any, what it changed. The
+    /// listener is called after the lock is released, so it may read the registry.
+    fn mutate<T>(&self, f: impl FnOnce(&mut SpeakerRegistry) -> Result<T, SupraSonicError>) -> Result<T, SupraSonicError> {
+        let listening = self.listener.lock().is_ok_and(|l| l.is_some());
+        let (result, changes) = {
+            let mut reg = self.registry.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+            // Only worth a copy of the speakers when someone is listening
+            let before = listening.then(|| reg.speakers.clone());
+            let result = f(&mut reg)?;
+            (result, before.map(|before| RegistryChanges::between(&before, &reg.speakers)))
+        };
+        if let Some(changes) = changes {
+            self.announce(changes);
+        }
+        Ok(result)
+    }
+
+    /// Tells the listener, if any, about `changes` already made. Call with no lock held.
+    fn announce(&self, changes: RegistryChanges) {
+        let Some(listener) = self.listener.lock().ok().and_then(|l| l.clone()) else { return };
+        for (id, kind) in changes.events() {
+            listener.on_registry_changed(id, kind);
+        }
+    }
+
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+        if self.autosave_interval_ms.load(Ordering::Relaxed) == 0 {
+            self.save();
         }
     }
     
-    pub fn register_speaker(&self, id: String, name: String) {
-        if let Ok(mut reg) = self.registry.lock() {
-            reg.add_speaker(id, name);
+    pub fn register_speaker(&self, id: String, name: String) -> Result<SpeakerRegistration, SupraSonicError> {
+        let registration = self.mutate(|reg| reg.add_speaker(id, name))?;
+        self.save();
+        Ok(registration)
+    }
+    
+    pub fn enroll_embedding(&self, id: String, embedding: Vec<f32>) -> Result<(), SupraSonicError> {
+        self.mutate(|reg| reg.enroll_embedding(&id, embedding))?;
+        // Embeddings can be refined many times per session; let the autosave coalesce them
+        self.mark_dirty();
+        Ok(())
+    }
+
+    pub fn remove_speaker(&self, id: String) -> Result<bool, SupraSonicError> {
+        let removed = self.mutate(|reg| Ok(reg.remove_speaker(&id).is_some()))?;
+        if removed {
+            self.save();
+        }
+        Ok(removed)
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.registry.lock().map(|reg| reg.metric()).unwrap_or_default()
+    }
+
+    /// Switches how voices are matched and saves the registry with the new metric.
+    pub fn set_metric(&self, metric: DistanceMetric) -> Result<(), SupraSonicError> {
+        self.registry.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.set_metric(metric);
+        self.save();
+        Ok(())
+    }
+
+    /// The matching enrolled speaker. Unmatched voices are "Guest", or with
+    /// `set_max_auto_speakers`, a new speaker each until the session has created that
+    /// many; after that the nearest of those, so noisy audio can't spawn dozens.
+    pub fn assign_speaker(&self, embedding: &[f32]) -> String {
+        let max = self.max_auto_speakers.load(Ordering::Relaxed) as usize;
+        if max == 0 {
+            return self.registry.lock()
+                .map(|reg| reg.assign_speaker(embedding))
+                .unwrap_or_else(|_| "Guest".to_string());
         }
+        let added = {
+            let Ok(mut created) = self.session_auto_speakers.lock() else { return "Guest".to_string() };
+            let Ok(mut reg) = self.registry.lock() else { return "Guest".to_string() };
+            if let Some((id, _)) = reg.best_match(embedding) {
+                return id;
+            }
+            // Deleted by the host meanwhile: no longer counts against the cap
+            created.retain(|id| reg.speakers.contains_key(id));
+            if created.len() >= max {
+                return reg.nearest_of(&created, embedding).unwrap_or_else(|| "Guest".to_string());
+            }
+            match reg.add_auto_speaker(embedding.to_vec()) {
+                Ok(id) => {
+                    created.push(id.clone());
+                    id
+                }
+                Err(e) => {
+                    tracing::warn!("Speaker assignment failed: {}", e);
+                    return "Guest".to_string();
+                }
+            }
+        };
+        // Both locks are released, so the listener may call back into the service
         self.save();
+        self.announce(RegistryChanges { added: vec![added.clone()], enrolled: vec![added.clone()], ..Default::default() });
+        added
+    }
+
+    /// Caps the speakers `assign_speaker` creates for unmatched voices per session
+    /// (until `finalize_session`); 0 turns creation off.
+    pub fn set_max_auto_speakers(&self, max: u32) {
+        self.max_auto_speakers.store(max, Ordering::Relaxed);
+    }
+
+    pub fn set_embedder(&self, embedder: Option<Arc<dyn SpeakerEmbedder>>) {
+        if let Ok(mut e) = self.embedder.lock() {
+            *e = embedder;
+        }
+    }
+
+    /// Runs the installed embedder; the lock is not held while the model works.
+    fn embed(&self, samples: Vec<f32>, sample_rate: u32) -> Result<Vec<f32>, SupraSonicError> {
+        if samples.is_empty() {
+            return Err(SupraSonicError::InvalidArgument("Cannot embed an empty sample".to_string()));
+        }
+        let embedder = self.embedder.lock()
+            .map_err(|e| SupraSonicError::Lock(e.to_string()))?
+            .clone()
+            .ok_or_else(|| SupraSonicError::General("No speaker embedder set".to_string()))?;
+        embedder.embed(samples, sample_rate)
+    }
+
+    pub fn enroll_audio(&self, id: String, samples: Vec<f32>, sample_rate: u32) -> Result<(), SupraSonicError> {
+        let embedding = self.embed(samples, sample_rate)?;
+        self.enroll_embedding(id, embedding)
+    }
+
+    pub fn assign_audio(&self, samples: Vec<f32>, sample_rate: u32) -> Result<String, SupraSonicError> {
+        let embedding = self.embed(samples, sample_rate)?;
+        Ok(self.assign_speaker(&embedding))
+    }
+
+    /// Merges a registry exported with `SpeakerRegistry::with_parts` into the stored one.
+    pub fn import_registry(&self, json: &str, parts: RegistryParts) -> Result<u32, SupraSonicError> {
+        let imported = SpeakerRegistry::try_from_json(json)?;
+        let merged = self.mutate(|reg| Ok(reg.merge(imported, parts)))?;
+        self.mark_dirty();
+        Ok(merged)
+    }
+
+    pub fn registry_snapshot(&self) -> SpeakerRegistry {
+        self.registry.lock().map(|r| r.clone()).unwrap_or_default()
     }
     
     pub fn get_speaker_name(&self, id: String) -> String {
@@ -108,3 +927,597 @@ impl DiarizationService {
         id
     }
 }
+
+impl Drop for DiarizationService {
+    fn drop(&mut self) {
+        self.flush_save();
+    }
+}
+
+fn write_registry(registry: &Mutex<SpeakerRegistry>, path: &Path, dirty: &AtomicBool) {
+    if let Ok(reg) = registry.lock() {
+        // Cleared under the lock so a change made while writing marks it dirty again
+        dirty.store(false, Ordering::SeqCst);
+        let json = reg.to_json();
+        if let Err(e) = fs::write(path, json) {
+            tracing::error!("Failed to save speaker registry: {}", e);
+            dirty.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> f64 {
+        let d = |x: u8, y: u8| (x as f64 - y as f64).powi(2);
+        (d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)).sqrt()
+    }
+
+    #[test]
+    fn speaker_color_is_stable_for_an_id() {
+        for id in ["spk_0", "spk_1", "Alice", ""] {
+            assert_eq!(speaker_color(id), speaker_color(id));
+        }
+        assert_eq!(speaker_color("spk_0"), speaker_color(&String::from("spk_0")));
+    }
+
+    #[test]
+    fn speaker_colors_of_different_ids_are_distinct() {
+        let colors: Vec<_> = (0..8).map(|i| speaker_color(&format!("spk_{i}"))).collect();
+        let mut close = 0;
+        for (i, a) in colors.iter().enumerate() {
+            for b in &colors[i + 1..] {
+                if distance(*a, *b) < 30.0 {
+                    close += 1;
+                }
+            }
+        }
+        // A hue wheel can't keep every pair apart, but near-duplicates should be rare
+        assert!(close <= 2, "{close} near-identical pairs in {colors:?}");
+    }
+
+    #[test]
+    fn speaker_names_are_trimmed_and_collapsed() {
+        let mut registry = SpeakerRegistry::new();
+        let registration = registry.add_speaker(" spk_0 ".to_string(), "  Ada   \t Lovelace \n".to_string()).unwrap();
+        assert_eq!(registration.name, "Ada Lovelace");
+        assert_eq!(registry.speakers["spk_0"].name, "Ada Lovelace");
+    }
+
+    #[test]
+    fn blank_speaker_names_and_ids_are_rejected() {
+        let mut registry = SpeakerRegistry::new();
+        for name in ["", "   ", "\t\n"] {
+            assert!(matches!(registry.add_speaker("spk_0".to_string(), name.to_string()), Err(SupraSonicError::InvalidArgument(_))));
+        }
+        assert!(matches!(registry.add_speaker(" ".to_string(), "Alice".to_string()), Err(SupraSonicError::InvalidArgument(_))));
+        assert!(registry.speakers.is_empty());
+    }
+
+    #[test]
+    fn duplicate_display_names_are_allowed_but_reported() {
+        let mut registry = SpeakerRegistry::new();
+        assert!(registry.add_speaker("spk_0".to_string(), "Alice".to_string()).unwrap().duplicate_ids.is_empty());
+        let second = registry.add_speaker("spk_1".to_string(), "alice ".to_string()).unwrap();
+        assert_eq!(second.duplicate_ids, ["spk_0"]);
+        assert_eq!(registry.speakers.len(), 2);
+        // A speaker isn't reported as its own duplicate
+        assert_eq!(registry.add_speaker("spk_0".to_string(), "Alice".to_string()).unwrap().duplicate_ids, ["spk_1"]);
+    }
+
+    // Deterministic pseudo-random vectors (xorshift) in -1..1
+    fn random_vectors(count: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed | 1;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+        };
+        (0..count).map(|_| (0..dim).map(|_| next()).collect()).collect()
+    }
+
+    #[test]
+    fn embedding_index_matches_a_linear_scan() {
+        let speakers: HashMap<String, Speaker> = random_vectors(300, 64, 42)
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                // Unnormalized, so cosine and Euclidean rank differently
+                let e: Vec<f32> = e.iter().map(|x| x * (1.0 + i as f32 % 5.0)).collect();
+                let id = format!("spk_{i}");
+                (id.clone(), Speaker { id, name: format!("Speaker {i}"), embedding: Some(e) })
+            })
+            .collect();
+
+        for metric in [DistanceMetric::Cosine, DistanceMetric::Euclidean] {
+            let index = EmbeddingIndex::build(&speakers, metric);
+            assert_eq!(index.len(), 300);
+            for query in random_vectors(50, 64, 7) {
+                let (expected_id, expected_score) = speakers
+                    .values()
+                    .map(|s| (s.id.clone(), metric.score(s.embedding.as_ref().unwrap(), &query)))
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap();
+                let (id, score) = index.best_match(&query).unwrap();
+                assert_eq!(id, expected_id, "{metric:?}");
+                assert!((score - expected_score).abs() < 1e-4, "{metric:?}: {score} vs {expected_score}");
+            }
+        }
+    }
+
+    #[test]
+    fn embedding_index_matches_a_linear_scan_across_dimensions() {
+        let dims = [64, 32, 192];
+        let speakers: HashMap<String, Speaker> = dims
+            .iter()
+            .enumerate()
+            .flat_map(|(d, &dim)| random_vectors(100, dim, 42 + d as u64).into_iter().map(move |e| (d, e)))
+            .enumerate()
+            .map(|(i, (d, e))| {
+                let e: Vec<f32> = e.iter().map(|x| x * (1.0 + i as f32 % 5.0)).collect();
+                let id = format!("spk_{d}_{i}");
+                (id.clone(), Speaker { id, name: format!("Speaker {i}"), embedding: Some(e) })
+            })
+            .collect();
+
+        for metric in [DistanceMetric::Cosine, DistanceMetric::Euclidean] {
+            let index = EmbeddingIndex::build(&speakers, metric);
+            assert_eq!(index.len(), 300);
+            for &dim in &dims {
+                for query in random_vectors(20, dim, 7) {
+                    let (expected_id, expected_score) = speakers
+                        .values()
+                        .filter(|s| s.embedding.as_ref().unwrap().len() == dim)
+                        .map(|s| (s.id.clone(), metric.score(s.embedding.as_ref().unwrap(), &query)))
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                        .unwrap();
+                    let (id, score) = index.best_match(&query).unwrap();
+                    assert_eq!(id, expected_id, "{metric:?} {dim}");
+                    assert!((score - expected_score).abs() < 1e-4, "{metric:?} {dim}: {score} vs {expected_score}");
+                }
+            }
+            assert_eq!(index.best_match(&[1.0; 16]), None);
+        }
+    }
+
+    #[test]
+    fn embedding_index_follows_enroll_and_remove() {
+        let mut registry = SpeakerRegistry::new();
+        registry.add_speaker("spk_0".to_string(), "Alice".to_string()).unwrap();
+        registry.add_speaker("spk_1".to_string(), "Bob".to_string()).unwrap();
+        registry.enroll_embedding("spk_0", vec![1.0, 0.0]).unwrap();
+        assert_eq!(registry.best_match(&[0.0, 1.0]), None);
+        registry.enroll_embedding("spk_1", vec![0.0, 1.0]).unwrap();
+        assert_eq!(registry.best_match(&[0.1, 1.0]).map(|m| m.0).as_deref(), Some("spk_1"));
+        registry.remove_speaker("spk_1");
+        assert_eq!(registry.best_match(&[0.1, 1.0]), None);
+    }
+
+    fn temp_registry_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("suprasonic-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn stored(path: &Path) -> SpeakerRegistry {
+        SpeakerRegistry::from_json(&fs::read_to_string(path).unwrap())
+    }
+
+    #[test]
+    fn rapid_embedding_updates_coalesce_until_the_autosave_or_a_flush() {
+        let path = temp_registry_path("autosave");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        service.set_autosave_interval(Duration::from_secs(60));
+        service.register_speaker("spk_0".to_string(), "Alice".to_string()).unwrap();
+
+        for i in 0..100 {
+            service.enroll_embedding("spk_0".to_string(), vec![1.0, i as f32]).unwrap();
+        }
+        // None of the updates has been written yet
+        assert_eq!(stored(&path).speakers["spk_0"].embedding, None);
+
+        service.flush_save();
+        assert_eq!(stored(&path).speakers["spk_0"].embedding, Some(vec![1.0, 99.0]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn autosave_writes_pending_updates_on_its_own() {
+        let path = temp_registry_path("autosave-thread");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        service.set_autosave_interval(Duration::from_millis(50));
+        service.register_speaker("spk_0".to_string(), "Alice".to_string()).unwrap();
+        service.enroll_embedding("spk_0".to_string(), vec![1.0, 2.0]).unwrap();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while stored(&path).speakers["spk_0"].embedding.is_none() {
+            assert!(std::time::Instant::now() < deadline, "autosave never wrote the update");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unversioned_registry_migrates_to_the_current_schema_with_speakers_intact() {
+        // v0: no version, no metric, speakers keyed by id and missing fields
+        let v0 = r#"{"speakers": {
+            "spk_0": {"name": "Ada", "embedding": [0.6, 0.8]},
+            "spk_1": {"id": "spk_1"}
+        }}"#;
+        let registry = SpeakerRegistry::try_from_json(v0).unwrap();
+        assert_eq!(registry.version, REGISTRY_SCHEMA_VERSION);
+        assert_eq!(registry.metric(), DistanceMetric::Cosine);
+        assert_eq!(registry.speakers.len(), 2);
+        let ada = &registry.speakers["spk_0"];
+        assert_eq!((ada.id.as_str(), ada.name.as_str()), ("spk_0", "Ada"));
+        assert_eq!(ada.embedding.as_deref(), Some(&[0.6, 0.8][..]));
+        let unnamed = &registry.speakers["spk_1"];
+        assert_eq!(unnamed.name, "spk_1");
+        assert!(unnamed.embedding.is_none());
+        // Enrolled speakers are matchable right after the upgrade
+        assert_eq!(registry.best_match(&[0.6, 0.8]).map(|(id, _)| id), Some("spk_0".to_string()));
+
+        let summary = SpeakerRegistry::validate_json(v0).unwrap();
+        assert_eq!((summary.version, summary.speaker_count, summary.enrolled_count), (0, 2, 1));
+    }
+
+    #[test]
+    fn v1_registry_gains_the_cosine_metric() {
+        let v1 = r#"{"version": 1, "speakers": {"spk_0": {"id": "spk_0", "name": "Ada", "embedding": null}}}"#;
+        let registry = SpeakerRegistry::try_from_json(v1).unwrap();
+        assert_eq!(registry.version, 2);
+        assert_eq!(registry.metric(), DistanceMetric::Cosine);
+        assert_eq!(registry.speakers["spk_0"].name, "Ada");
+
+        // Round trips as the current version
+        let reloaded = SpeakerRegistry::try_from_json(&serde_json::to_string(&registry).unwrap()).unwrap();
+        assert_eq!(reloaded.version, REGISTRY_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn malformed_or_newer_registry_versions_are_rejected() {
+        for version in ["-1", "1.5", "\"2\"", "4294967298", "3"] {
+            let json = format!(r#"{{"version": {}, "speakers": {{}}}}"#, version);
+            assert!(
+                matches!(SpeakerRegistry::try_from_json(&json), Err(SupraSonicError::InvalidArgument(_))),
+                "version {} accepted",
+                version
+            );
+        }
+    }
+
+    /// Embeds a sample as the direction its first value points at, logging each call.
+    struct StubEmbedder {
+        calls: Mutex<Vec<(usize, u32)>>,
+    }
+
+    impl SpeakerEmbedder for StubEmbedder {
+        fn embed(&self, samples: Vec<f32>, sample_rate: u32) -> Result<Vec<f32>, SupraSonicError> {
+            self.calls.lock().unwrap().push((samples.len(), sample_rate));
+            let angle = samples[0] * std::f32::consts::FRAC_PI_2;
+            Ok(vec![angle.cos(), angle.sin()])
+        }
+    }
+
+    #[test]
+    fn audio_enroll_and_assign_run_the_installed_embedder() {
+        let path = temp_registry_path("embedder");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        service.register_speaker("spk_0".to_string(), "Ada".to_string()).unwrap();
+        // Nothing to embed with yet
+        assert!(service.enroll_audio("spk_0".to_string(), vec![0.0; 160], 16000).is_err());
+
+        let embedder = Arc::new(StubEmbedder { calls: Mutex::new(Vec::new()) });
+        service.set_embedder(Some(embedder.clone()));
+        service.enroll_audio("spk_0".to_string(), vec![0.0; 1600], 16000).unwrap();
+        assert_eq!(service.registry_snapshot().speakers["spk_0"].embedding.as_deref(), Some(&[1.0, 0.0][..]));
+
+        assert_eq!(service.assign_audio(vec![0.05; 800], 16000).unwrap(), "spk_0");
+        // Orthogonal to everyone enrolled
+        assert_eq!(service.assign_audio(vec![1.0; 800], 16000).unwrap(), "Guest");
+        assert_eq!(*embedder.calls.lock().unwrap(), [(1600, 16000), (800, 16000), (800, 16000)]);
+
+        // Empty samples never reach the model
+        assert!(service.assign_audio(Vec::new(), 16000).is_err());
+        assert_eq!(embedder.calls.lock().unwrap().len(), 3);
+        drop(service);
+        let _ = fs::remove_file(&path);
+    }
+
+    fn enrolled_pair() -> SpeakerRegistry {
+        let mut registry = SpeakerRegistry::new();
+        registry.add_speaker("spk_0".to_string(), "Ada".to_string()).unwrap();
+        registry.add_speaker("spk_1".to_string(), "Grace".to_string()).unwrap();
+        registry.enroll_embedding("spk_0", vec![1.0, 0.0]).unwrap();
+        registry
+    }
+
+    #[test]
+    fn names_only_export_omits_embeddings_and_round_trips_names() {
+        let json = enrolled_pair().with_parts(RegistryParts::NamesOnly).to_json();
+        assert!(!json.contains("1.0"), "embedding leaked into {json}");
+        let names = SpeakerRegistry::try_from_json(&json).unwrap();
+        assert_eq!(names.speakers.len(), 2);
+        assert_eq!(names.speakers["spk_0"].name, "Ada");
+        assert!(names.speakers.values().all(|s| s.embedding.is_none()));
+    }
+
+    #[test]
+    fn split_exports_rejoin_on_speaker_id() {
+        let original = enrolled_pair();
+        let voiceprints = SpeakerRegistry::try_from_json(&original.with_parts(RegistryParts::EmbeddingsOnly).to_json()).unwrap();
+        // Only enrolled speakers, and no names
+        assert_eq!(voiceprints.speakers.len(), 1);
+        assert_eq!(voiceprints.speakers["spk_0"].name, "spk_0");
+        let names = SpeakerRegistry::try_from_json(&original.with_parts(RegistryParts::NamesOnly).to_json()).unwrap();
+
+        let mut restored = SpeakerRegistry::new();
+        assert_eq!(restored.merge(voiceprints, RegistryParts::EmbeddingsOnly), 1);
+        assert_eq!(restored.merge(names, RegistryParts::NamesOnly), 2);
+        for id in ["spk_0", "spk_1"] {
+            assert_eq!(restored.speakers[id], original.speakers[id]);
+        }
+        assert_eq!(restored.best_match(&[1.0, 0.0]).map(|m| m.0).as_deref(), Some("spk_0"));
+    }
+
+    #[test]
+    fn assignment_creates_speakers_up_to_the_cap_then_reuses_the_nearest() {
+        let path = temp_registry_path("auto_speakers");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        assert_eq!(service.assign_speaker(&[1.0, 0.0, 0.0]), "Guest", "off by default");
+
+        service.set_max_auto_speakers(2);
+        let first = service.assign_speaker(&[1.0, 0.0, 0.0]);
+        let second = service.assign_speaker(&[0.0, 1.0, 0.0]);
+        assert_ne!(first, second);
+        assert_eq!(service.assign_speaker(&[0.98, 0.05, 0.0]), first, "a returning voice matches");
+        // A third voice matches neither, but the cap is reached: it goes to the nearer one
+        assert_eq!(service.assign_speaker(&[0.3, 0.6, 0.74]), second);
+        assert_eq!(service.registry_snapshot().speakers.len(), 2);
+
+        service.finalize_session().unwrap();
+        let third = service.assign_speaker(&[0.0, 0.0, 1.0]);
+        assert!(third != first && third != second, "the next session gets a new allowance");
+        service.flush_save();
+        assert_eq!(stored(&path).speakers.len(), 3, "new speakers are saved");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn validate_json_summarises_valid_files_and_rejects_bad_ones() {
+        let mut registry = SpeakerRegistry::new();
+        registry.add_speaker("a".to_string(), "Ada".to_string()).unwrap();
+        registry.add_speaker("b".to_string(), "Bob".to_string()).unwrap();
+        registry.add_speaker("c".to_string(), "Cy".to_string()).unwrap();
+        registry.enroll_embedding("a", vec![1.0, 0.0]).unwrap();
+        registry.enroll_embedding("b", vec![0.0, 1.0, 0.0]).unwrap();
+        let summary = SpeakerRegistry::validate_json(&registry.to_json()).unwrap();
+        assert_eq!(
+            summary,
+            RegistrySummary {
+                version: REGISTRY_SCHEMA_VERSION,
+                speaker_count: 3,
+                enrolled_count: 2,
+                embedding_dims: vec![2, 3],
+                metric: DistanceMetric::Cosine,
+            }
+        );
+
+        let empty = SpeakerRegistry::new().to_json();
+        assert_eq!(SpeakerRegistry::validate_json(&empty).unwrap().speaker_count, 0);
+
+        let rejected = |json: &str| matches!(SpeakerRegistry::validate_json(json), Err(SupraSonicError::InvalidArgument(_)));
+        assert!(rejected(""), "empty file");
+        assert!(rejected("  \n"), "blank file");
+        assert!(rejected(r#"{"version": 2, "speakers": {"#), "truncated");
+        assert!(rejected("[1, 2]"), "not an object");
+        assert!(rejected(r#"{"version": 99, "speakers": {}}"#), "newer version");
+    }
+
+    #[test]
+    fn each_metric_ranks_synthetic_vectors_its_own_way() {
+        // Unnormalized: `far` points the query's way but is ten times as long
+        let vectors = [("far", vec![10.0, 0.5]), ("near", vec![1.1, 0.6]), ("orthogonal", vec![0.0, 1.0]), ("opposite", vec![-1.0, 0.0])];
+        let ranking = |metric: DistanceMetric, query: &[f32]| {
+            let mut ranked: Vec<(&str, f32)> = vectors.iter().map(|(id, v)| (*id, metric.score(v, query))).collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            ranked.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+        let query = [1.0, 0.0];
+        // Cosine only sees direction; Euclidean sees how far off the length is too
+        assert_eq!(ranking(DistanceMetric::Cosine, &query), ["far", "near", "orthogonal", "opposite"]);
+        assert_eq!(ranking(DistanceMetric::Euclidean, &query), ["near", "orthogonal", "opposite", "far"]);
+        // Scaling the query changes nothing for cosine, everything for Euclidean
+        let scaled = [10.0, 0.0];
+        assert_eq!(ranking(DistanceMetric::Cosine, &scaled), ranking(DistanceMetric::Cosine, &query));
+        assert_eq!(ranking(DistanceMetric::Euclidean, &scaled)[0], "far");
+
+        // The registry matches and picks nearest by the same order, within each threshold
+        for (metric, expected) in [(DistanceMetric::Cosine, "far"), (DistanceMetric::Euclidean, "near")] {
+            let mut registry = SpeakerRegistry::with_metric(metric);
+            for (id, v) in &vectors {
+                registry.add_speaker(id.to_string(), id.to_string()).unwrap();
+                registry.enroll_embedding(id, v.clone()).unwrap();
+            }
+            assert_eq!(registry.best_match(&query).map(|(id, _)| id).as_deref(), Some(expected), "{metric:?}");
+            let ids: Vec<String> = vectors.iter().map(|(id, _)| id.to_string()).collect();
+            assert_eq!(registry.nearest_of(&ids, &query).as_deref(), Some(expected), "{metric:?}");
+            // Nothing within the threshold: cosine 0, distance 1.41
+            assert_eq!(registry.best_match(&[0.0, -1.0]), None, "{metric:?}");
+        }
+    }
+
+    #[test]
+    fn finalize_session_persists_pending_updates_and_reports_them() {
+        let path = temp_registry_path("finalize");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        service.set_autosave_interval(Duration::from_secs(3600));
+        for (id, name) in [("alice", "Alice"), ("bob", "Bob"), ("carol", "Carol")] {
+            service.register_speaker(id.to_string(), name.to_string()).unwrap();
+        }
+        assert_eq!(service.finalize_session().unwrap().added, ["alice", "bob", "carol"]);
+
+        service.register_speaker("bob".to_string(), "Robert".to_string()).unwrap();
+        service.remove_speaker("carol".to_string()).unwrap();
+        service.enroll_embedding("alice".to_string(), vec![1.0, 0.0]).unwrap();
+        service.set_max_auto_speakers(1);
+        let newcomer = service.assign_speaker(&[0.0, 1.0]);
+        // Waits for the autosave
+        service.enroll_embedding("bob".to_string(), vec![0.6, 0.8]).unwrap();
+        assert!(stored(&path).speakers["bob"].embedding.is_none());
+
+        let changes = service.finalize_session().unwrap();
+        assert_eq!(
+            changes,
+            RegistryChanges {
+                added: vec![newcomer.clone()],
+                removed: vec!["carol".to_string()],
+                renamed: vec!["bob".to_string()],
+                enrolled: vec!["alice".to_string(), newcomer, "bob".to_string()],
+                merged: vec![],
+            }
+        );
+        let on_disk = stored(&path);
+        assert_eq!(on_disk.speakers["bob"].embedding.as_deref(), Some(&[0.6, 0.8][..]));
+        assert_eq!(on_disk.speakers["bob"].name, "Robert");
+        assert!(!on_disk.speakers.contains_key("carol"));
+
+        // The next session starts from here
+        assert_eq!(service.finalize_session().unwrap(), RegistryChanges::default());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn finalize_session_merges_near_duplicate_auto_speakers_when_asked() {
+        use RegistryChangeKind::*;
+        let path = temp_registry_path("finalize_merge");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        service.set_max_auto_speakers(4);
+        // Cosine 0.6 from the first: too far to match it online, close enough to merge
+        let first = service.assign_speaker(&[1.0, 0.0, 0.0]);
+        let other = service.assign_speaker(&[0.0, 0.0, 1.0]);
+        let duplicate = service.assign_speaker(&[0.6, 0.8, 0.0]);
+        assert_eq!([&first, &other, &duplicate], ["auto_1", "auto_2", "auto_3"]);
+
+        let log = Arc::new(ChangeLog::default());
+        service.set_listener(Some(log.clone()));
+        service.set_merge_threshold(Some(0.5));
+        let changes = service.finalize_session().unwrap();
+        assert_eq!(changes.merged, [SpeakerMerge { from: duplicate.clone(), into: first.clone() }]);
+        assert_eq!(changes.added, [first.clone(), other.clone()]);
+        assert!(changes.removed.is_empty());
+        assert_eq!(log.take(), [(duplicate.clone(), Removed), (first.clone(), Enrolled)]);
+
+        let on_disk = stored(&path);
+        assert!(!on_disk.speakers.contains_key(&duplicate));
+        assert_eq!(on_disk.speakers[&first].embedding.as_deref(), Some(&[0.8, 0.4, 0.0][..]));
+        assert_eq!(on_disk.speakers[&other].embedding.as_deref(), Some(&[0.0, 0.0, 1.0][..]));
+
+        // Without a threshold, the next session's lookalikes stay apart
+        service.set_merge_threshold(None);
+        let again = service.assign_speaker(&[0.0, -1.0, 0.0]);
+        let lookalike = service.assign_speaker(&[-0.8, -0.6, 0.0]);
+        let changes = service.finalize_session().unwrap();
+        assert!(changes.merged.is_empty());
+        assert_eq!(changes.added, [again, lookalike]);
+        let _ = fs::remove_file(path);
+    }
+
+    /// Every (speaker, change) it was told about, in order.
+    #[derive(Default)]
+    struct ChangeLog(Mutex<Vec<(String, RegistryChangeKind)>>);
+
+    impl RegistryListener for ChangeLog {
+        fn on_registry_changed(&self, speaker_id: String, change: RegistryChangeKind) {
+            self.0.lock().unwrap().push((speaker_id, change));
+        }
+    }
+
+    impl ChangeLog {
+        fn take(&self) -> Vec<(String, RegistryChangeKind)> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    /// Assigns the same voice again from inside the callback for every speaker added.
+    #[derive(Default)]
+    struct Reassigner {
+        service: std::sync::OnceLock<Weak<DiarizationService>>,
+        assigned: Mutex<Vec<String>>,
+    }
+
+    impl RegistryListener for Reassigner {
+        fn on_registry_changed(&self, _speaker_id: String, change: RegistryChangeKind) {
+            let Some(service) = self.service.get().and_then(Weak::upgrade) else { return };
+            if change == RegistryChangeKind::Added {
+                self.assigned.lock().unwrap().push(service.assign_speaker(&[0.0, 1.0]));
+            }
+        }
+    }
+
+    #[test]
+    fn a_registry_listener_may_call_back_into_the_service() {
+        let path = temp_registry_path("reentrant_listener");
+        let service = Arc::new(DiarizationService::new(path.to_string_lossy().into_owned()));
+        let listener = Arc::new(Reassigner::default());
+        let _ = listener.service.set(Arc::downgrade(&service));
+        service.set_listener(Some(listener.clone()));
+        service.set_max_auto_speakers(2);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = service.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(worker.assign_speaker(&[0.0, 1.0]));
+        });
+        let assigned = rx.recv_timeout(Duration::from_secs(5)).expect("assign_speaker deadlocked in the listener");
+        assert_eq!(*listener.assigned.lock().unwrap(), [assigned]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn every_kind_of_registry_change_reaches_the_listener() {
+        use RegistryChangeKind::*;
+        let path = temp_registry_path("registry_listener");
+        let service = DiarizationService::new(path.to_string_lossy().into_owned());
+        let log = Arc::new(ChangeLog::default());
+        service.set_listener(Some(log.clone()));
+        let change = |id: &str, kind| (id.to_string(), kind);
+
+        service.register_speaker("alice".to_string(), "Alice".to_string()).unwrap();
+        assert_eq!(log.take(), [change("alice", Added)]);
+        service.register_speaker("alice".to_string(), "Alice".to_string()).unwrap();
+        assert_eq!(log.take(), [], "nothing changed");
+        service.register_speaker("alice".to_string(), "Alice B.".to_string()).unwrap();
+        assert_eq!(log.take(), [change("alice", Renamed)]);
+        service.enroll_embedding("alice".to_string(), vec![1.0, 0.0, 0.0]).unwrap();
+        assert_eq!(log.take(), [change("alice", Enrolled)]);
+
+        // Online assignment creating a speaker, and then matching it
+        service.set_max_auto_speakers(1);
+        let auto = service.assign_speaker(&[0.0, 1.0, 0.0]);
+        assert_eq!(log.take(), [change(&auto, Added), change(&auto, Enrolled)]);
+        assert_eq!(service.assign_speaker(&[0.0, 0.99, 0.1]), auto);
+        assert_eq!(log.take(), []);
+
+        // A merged import: one new speaker, one renamed and re-enrolled
+        let mut imported = SpeakerRegistry::new();
+        imported.add_speaker("bob".to_string(), "Bob".to_string()).unwrap();
+        imported.add_speaker("alice".to_string(), "Alice Cooper".to_string()).unwrap();
+        imported.enroll_embedding("alice", vec![0.0, 0.0, 1.0]).unwrap();
+        service.import_registry(&imported.to_json(), RegistryParts::All).unwrap();
+        assert_eq!(log.take(), [change("bob", Added), change("alice", Renamed), change("alice", Enrolled)]);
+
+        assert!(service.remove_speaker(auto.clone()).unwrap());
+        assert_eq!(log.take(), [change(&auto, Removed)]);
+        assert!(!service.remove_speaker(auto).unwrap());
+        assert_eq!(log.take(), []);
+
+        service.set_listener(None);
+        service.register_speaker("carol".to_string(), "Carol".to_string()).unwrap();
+        assert_eq!(log.take(), []);
+        let _ = std::fs::remove_file(path);
+    }
+}
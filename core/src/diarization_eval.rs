@@ -0,0 +1,152 @@
+// Diarization scoring against hand-labelled reference segments.
+//
+// Time-based diarization error rate: the timeline is cut at every segment boundary of
+// either side, and each piece is scored by how many speakers the reference and the
+// hypothesis have talking in it. Hypothesis speakers are mapped one-to-one onto reference
+// speakers first, greedily by overlap (exact for the usual case of a clear best match).
+
+use std::collections::{HashMap, HashSet};
+
+use crate::diarization::Segment;
+
+/// How a hypothesis (diarizer) segmentation compares to the reference, in seconds of speech.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct DiarizationReport {
+    /// Total reference speech; overlapped speech counts once per speaker.
+    pub reference_seconds: f64,
+    /// Reference speech the hypothesis has nobody (or too few speakers) for.
+    pub missed_seconds: f64,
+    /// Hypothesis speech where the reference has nobody (or fewer speakers).
+    pub false_alarm_seconds: f64,
+    /// Speech attributed to the wrong speaker after mapping.
+    pub confusion_seconds: f64,
+    /// (missed + false alarm + confusion) / reference speech; can exceed 1. 0 without
+    /// reference speech.
+    pub error_rate: f64,
+    /// One entry per reference speaker, sorted by id.
+    pub mapping: Vec<SpeakerMapping>,
+}
+
+/// The hypothesis speaker a reference speaker was matched with.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct SpeakerMapping {
+    pub reference_id: String,
+    /// None if every hypothesis speaker overlapping it was taken by another.
+    pub hypothesis_id: Option<String>,
+    pub overlap_seconds: f64,
+}
+
+/// Scores `hypothesis` against `reference` by their start/end and speaker ids; segment
+/// text and finality are ignored, as are segments that don't have a positive duration.
+pub fn evaluate(reference: &[Segment], hypothesis: &[Segment]) -> DiarizationReport {
+    let reference: Vec<&Segment> = reference.iter().filter(|s| s.end > s.start).collect();
+    let hypothesis: Vec<&Segment> = hypothesis.iter().filter(|s| s.end > s.start).collect();
+
+    let mut bounds: Vec<f64> = reference.iter().chain(&hypothesis).flat_map(|s| [s.start, s.end]).collect();
+    bounds.sort_by(f64::total_cmp);
+    bounds.dedup();
+    let speaking = |segments: &[&Segment], from: f64, to: f64| -> HashSet<String> {
+        segments.iter().filter(|s| s.start < to && s.end > from).map(|s| s.speaker_id.clone()).collect()
+    };
+    // (duration, reference speakers, hypothesis speakers) per elementary piece
+    let pieces: Vec<(f64, HashSet<String>, HashSet<String>)> = bounds
+        .windows(2)
+        .map(|w| (w[1] - w[0], speaking(&reference, w[0], w[1]), speaking(&hypothesis, w[0], w[1])))
+        .collect();
+
+    let mut overlaps: HashMap<(&str, &str), f64> = HashMap::new();
+    for (duration, refs, hyps) in &pieces {
+        for r in refs {
+            for h in hyps {
+                *overlaps.entry((r.as_str(), h.as_str())).or_default() += duration;
+            }
+        }
+    }
+    let mut pairs: Vec<((&str, &str), f64)> = overlaps.into_iter().collect();
+    // Ties broken by id so the report is deterministic
+    pairs.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut mapped: HashMap<&str, (&str, f64)> = HashMap::new();
+    let mut taken: HashSet<&str> = HashSet::new();
+    for ((r, h), overlap) in pairs {
+        if !mapped.contains_key(r) && !taken.contains(h) {
+            mapped.insert(r, (h, overlap));
+            taken.insert(h);
+        }
+    }
+
+    let (mut reference_seconds, mut missed, mut false_alarm, mut confusion) = (0.0, 0.0, 0.0, 0.0);
+    for (duration, refs, hyps) in &pieces {
+        let correct = refs
+            .iter()
+            .filter(|r| mapped.get(r.as_str()).is_some_and(|(h, _)| hyps.contains(*h)))
+            .count();
+        reference_seconds += duration * refs.len() as f64;
+        missed += duration * refs.len().saturating_sub(hyps.len()) as f64;
+        false_alarm += duration * hyps.len().saturating_sub(refs.len()) as f64;
+        confusion += duration * (refs.len().min(hyps.len()) - correct) as f64;
+    }
+
+    let mut reference_ids: Vec<&str> = reference.iter().map(|s| s.speaker_id.as_str()).collect();
+    reference_ids.sort_unstable();
+    reference_ids.dedup();
+    let mapping = reference_ids
+        .into_iter()
+        .map(|r| SpeakerMapping {
+            reference_id: r.to_string(),
+            hypothesis_id: mapped.get(r).map(|(h, _)| h.to_string()),
+            overlap_seconds: mapped.get(r).map_or(0.0, |(_, overlap)| *overlap),
+        })
+        .collect();
+
+    DiarizationReport {
+        reference_seconds,
+        missed_seconds: missed,
+        false_alarm_seconds: false_alarm,
+        confusion_seconds: confusion,
+        error_rate: if reference_seconds > 0.0 { (missed + false_alarm + confusion) / reference_seconds } else { 0.0 },
+        mapping,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::segment;
+
+    fn turns(turns: &[(&str, f64, f64)]) -> Vec<Segment> {
+        turns.iter().map(|(speaker, start, end)| Segment { start: *start, end: *end, ..segment(speaker, "") }).collect()
+    }
+
+    #[test]
+    fn error_rate_of_a_hand_labelled_example() {
+        // B talks over A for 2 s; nobody talks from 25 to 26 s
+        let reference = turns(&[("alice", 0.0, 10.0), ("bob", 10.0, 20.0), ("alice", 20.0, 25.0), ("bob", 22.0, 24.0)]);
+        // Turn changes a second off, the overlap missed, a cough taken for a third speaker
+        let hypothesis = turns(&[("spk_1", 0.0, 9.0), ("spk_2", 9.0, 21.0), ("spk_1", 21.0, 25.0), ("spk_3", 26.0, 27.0)]);
+        let report = evaluate(&reference, &hypothesis);
+
+        // 25 s of speech, 2 more of overlap: 2 s missed (bob in the overlap), 1 s false
+        // alarm (spk_3), 2 s confused (9-10 and 20-21, where spk_2 stands in for alice)
+        assert_eq!(report.reference_seconds, 27.0);
+        assert_eq!(report.missed_seconds, 2.0);
+        assert_eq!(report.false_alarm_seconds, 1.0);
+        assert_eq!(report.confusion_seconds, 2.0);
+        assert_eq!(report.error_rate, 5.0 / 27.0);
+        let mapping: Vec<(&str, Option<&str>, f64)> =
+            report.mapping.iter().map(|m| (m.reference_id.as_str(), m.hypothesis_id.as_deref(), m.overlap_seconds)).collect();
+        assert_eq!(mapping, [("alice", Some("spk_1"), 13.0), ("bob", Some("spk_2"), 10.0)]);
+    }
+
+    #[test]
+    fn relabelled_speakers_score_perfectly() {
+        let reference = turns(&[("alice", 0.0, 4.0), ("bob", 4.0, 6.0), ("alice", 6.0, 7.5)]);
+        let hypothesis = turns(&[("auto_2", 0.0, 4.0), ("auto_1", 4.0, 6.0), ("auto_2", 6.0, 7.5), ("auto_1", 3.0, 3.0)]);
+        let report = evaluate(&reference, &hypothesis);
+        assert_eq!((report.error_rate, report.reference_seconds), (0.0, 7.5));
+        assert_eq!(report.mapping[1].hypothesis_id.as_deref(), Some("auto_1"));
+
+        // Nothing to compare against counts as no errors, not NaN
+        assert_eq!(evaluate(&[], &hypothesis).error_rate, 0.0);
+        assert_eq!(evaluate(&reference, &[]).missed_seconds, 7.5);
+    }
+}
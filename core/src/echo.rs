@@ -0,0 +1,202 @@
+// Acoustic echo cancellation against a host-supplied playback reference.
+//
+// The host pushes what it plays (TTS, call audio) as it plays it. Every mic sample, at the
+// output rate, takes the next reference sample from a FIFO, and an NLMS filter learns the path from
+// speaker to mic (delay, room, volume) to subtract its estimate of the echo. The filter
+// only reaches `echo_tail_ms` back, which also bounds the cost: two multiply-adds per tap
+// per sample.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::audio::{AudioConfig, StreamResampler, TARGET_SAMPLE_RATE};
+
+// Longest echo path the filter may cover; 2048 taps at 16 kHz, about 65M multiply-adds per second
+pub const MAX_ECHO_TAIL_MS: u32 = 128;
+// Reference held ahead of the mic at most; older samples are dropped
+const MAX_REFERENCE_MS: usize = 2000;
+// NLMS step size: fast convergence while staying stable
+const STEP_SIZE: f32 = 0.5;
+// Keeps the normalization finite while the reference is silent
+const REGULARIZATION: f32 = 1e-3;
+
+/// Playback audio waiting to be matched against the mic, at the output rate.
+#[derive(Default)]
+pub struct EchoReference {
+    queue: Mutex<ReferenceQueue>,
+}
+
+#[derive(Default)]
+struct ReferenceQueue {
+    samples: VecDeque<f32>,
+    // Rate the mic is processed at (None = the default until a recording starts)
+    output_rate: Option<u32>,
+    // (pushed rate, resampler), rebuilt when the pushed rate changes
+    resampler: Option<(u32, StreamResampler)>,
+}
+
+impl EchoReference {
+    /// Queues mono playback at `sample_rate`; it should arrive no later than it is heard,
+    /// since the filter can only cancel echo of reference it already has.
+    pub fn push(&self, samples: &[f32], sample_rate: u32) -> anyhow::Result<()> {
+        let Ok(mut queue) = self.queue.lock() else { return Ok(()) };
+        let queue = &mut *queue;
+        let output_rate = queue.output_rate.unwrap_or(TARGET_SAMPLE_RATE as u32) as usize;
+        let mut resampled = Vec::new();
+        let samples = if sample_rate as usize == output_rate {
+            samples
+        } else {
+            if queue.resampler.as_ref().is_none_or(|(rate, _)| *rate != sample_rate) {
+                let resampler = StreamResampler::new(sample_rate as usize, output_rate, &AudioConfig::default())?;
+                queue.resampler = Some((sample_rate, resampler));
+            }
+            if let Some((_, resampler)) = queue.resampler.as_mut() {
+                resampler.process_into(samples, &mut resampled);
+            }
+            &resampled
+        };
+        queue.samples.extend(samples);
+        let excess = queue.samples.len().saturating_sub(output_rate * MAX_REFERENCE_MS / 1000);
+        queue.samples.drain(..excess);
+        Ok(())
+    }
+
+    /// The next `n` reference samples, zero where none was pushed.
+    pub fn take(&self, n: usize) -> Vec<f32> {
+        let mut out = vec![0.0; n];
+        if let Ok(mut queue) = self.queue.lock() {
+            let available = queue.samples.len().min(n);
+            for (o, s) in out.iter_mut().zip(queue.samples.drain(..available)) {
+                *o = s;
+            }
+        }
+        out
+    }
+
+    /// Drops whatever is queued, e.g. playback from before the recording started, and
+    /// keeps what is pushed from now on at `output_rate`.
+    pub fn restart(&self, output_rate: u32) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.samples.clear();
+            if queue.output_rate != Some(output_rate) {
+                queue.output_rate = Some(output_rate);
+                queue.resampler = None;
+            }
+        }
+    }
+}
+
+/// Normalized LMS echo canceller over the last `taps` reference samples.
+pub struct EchoCanceller {
+    weights: Vec<f32>,
+    // Reference history, newest first, stored twice so any window is one slice
+    history: Vec<f32>,
+    position: usize,
+    // Sum of squares over the current window
+    energy: f32,
+}
+
+impl EchoCanceller {
+    pub fn new(tail_ms: u32, sample_rate: u32) -> Self {
+        let taps = (sample_rate as usize * tail_ms.min(MAX_ECHO_TAIL_MS) as usize / 1000).max(1);
+        Self { weights: vec![0.0; taps], history: vec![0.0; taps * 2], position: 0, energy: 0.0 }
+    }
+
+    /// Removes the echo of `reference` (sample for sample alongside it) from `mic`.
+    pub fn process(&mut self, mic: &mut [f32], reference: &[f32]) {
+        let taps = self.weights.len();
+        for (sample, &x) in mic.iter_mut().zip(reference) {
+            let oldest = self.history[self.position + taps - 1];
+            self.position = self.position.checked_sub(1).unwrap_or(taps - 1);
+            self.history[self.position] = x;
+            self.history[self.position + taps] = x;
+            self.energy = (self.energy + x * x - oldest * oldest).max(0.0);
+
+            let window = &self.history[self.position..self.position + taps];
+            let estimate: f32 = self.weights.iter().zip(window).map(|(w, x)| w * x).sum();
+            let error = *sample - estimate;
+            let step = STEP_SIZE * error / (self.energy + REGULARIZATION);
+            for (w, x) in self.weights.iter_mut().zip(window) {
+                *w += step * x;
+            }
+            *sample = error;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic white-ish noise in -0.5..0.5, standing in for playback.
+    fn noise(len: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    /// `reference` as heard by the mic: 10 ms late, quieter, with a weaker reflection.
+    fn echo_of(reference: &[f32]) -> Vec<f32> {
+        (0..reference.len())
+            .map(|i| 0.6 * i.checked_sub(160).map_or(0.0, |j| reference[j]) + 0.2 * i.checked_sub(400).map_or(0.0, |j| reference[j]))
+            .collect()
+    }
+
+    #[test]
+    fn delayed_copy_of_the_reference_is_attenuated() {
+        let reference = noise(3 * 16000, 1);
+        let mut mic = echo_of(&reference);
+        let before = energy(&mic[32000..]);
+        let mut canceller = EchoCanceller::new(32, 16000);
+        for (mic, reference) in mic.chunks_mut(480).zip(reference.chunks(480)) {
+            canceller.process(mic, reference);
+        }
+        // The last second, once the filter has converged: at least 30 dB down
+        let after = energy(&mic[32000..]);
+        assert!(after < before * 1e-3, "{} dB", 10.0 * (after / before).log10());
+    }
+
+    #[test]
+    fn near_end_audio_passes_while_the_reference_is_silent() {
+        let reference = noise(16000, 2);
+        let mut canceller = EchoCanceller::new(32, 16000);
+        canceller.process(&mut echo_of(&reference), &reference);
+
+        // Once the echo has died out, the mic comes through untouched
+        let mut mic = vec![0.0; 16000];
+        canceller.process(&mut mic[..1000], &[0.0; 1000]);
+        let speech: Vec<f32> = (0..4000).map(|i| 0.3 * (i as f32 * 0.05).sin()).collect();
+        let mut processed = speech.clone();
+        canceller.process(&mut processed, &[0.0; 4000]);
+        assert_eq!(processed, speech);
+    }
+
+    #[test]
+    fn reference_is_bounded_and_padded_with_silence() {
+        let reference = EchoReference::default();
+        reference.restart(16000);
+        reference.push(&noise(3 * 16000, 3), 16000).unwrap();
+        // Only the newest 2 s are kept
+        assert_eq!(reference.take(40000).iter().filter(|&&s| s != 0.0).count(), 32000);
+
+        reference.push(&[0.25; 100], 16000).unwrap();
+        let taken = reference.take(160);
+        assert_eq!(taken[..100], [0.25; 100]);
+        assert_eq!(taken[100..], [0.0; 60]);
+
+        // Another rate is resampled to the output rate; a restart forgets what was queued
+        reference.push(&vec![0.1; 48000], 48000).unwrap();
+        assert!(reference.take(17000).iter().filter(|&&s| s != 0.0).count().abs_diff(16000) <= 480);
+        reference.push(&[0.25; 100], 16000).unwrap();
+        reference.restart(16000);
+        assert_eq!(reference.take(10), [0.0; 10]);
+    }
+}
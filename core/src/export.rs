@@ -0,0 +1,372 @@
+// Session export: WAV encoding, transcript JSON and a minimal zip container.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::audio;
+use crate::diagnostics;
+use crate::diarization::{RegistryParts, Segment, SpeakerRegistry};
+
+pub const ARCHIVE_AUDIO_ENTRY: &str = "audio.wav";
+pub const ARCHIVE_TRANSCRIPT_ENTRY: &str = "transcript.json";
+pub const ARCHIVE_SPEAKERS_ENTRY: &str = "speakers.json";
+/// Sidecar holding the lowercase hex SHA-256 of `audio.wav`'s PCM data (the `data` chunk
+/// payload, headers excluded), followed by a newline.
+pub const ARCHIVE_CHECKSUM_ENTRY: &str = "audio.sha256";
+/// The `SessionMetadata`, as JSON; only written when some was set.
+pub const ARCHIVE_METADATA_ENTRY: &str = "session.json";
+
+// Bytes of the header `encode_wav` writes before the PCM data
+const WAV_HEADER_LEN: usize = 44;
+// Samples encoded between checksum updates
+const CHECKSUM_BLOCK: usize = 4096;
+
+/// Encodes mono f32 samples as a 16-bit PCM WAV file.
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    encode_wav_with_checksum(samples, sample_rate).0
+}
+
+/// `encode_wav`, also returning the SHA-256 of the PCM data, hashed as it is written.
+pub fn encode_wav_with_checksum(samples: &[f32], sample_rate: u32) -> (Vec<u8>, [u8; 32]) {
+    let pcm = audio::f32_to_i16(samples);
+    let data_len = (pcm.len() * 2) as u32;
+
+    let mut out = Vec::with_capacity(WAV_HEADER_LEN + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    out.extend_from_slice(&2u16.to_le_bytes()); // block align
+    out.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    let mut checksum = Sha256::new();
+    for block in pcm.chunks(CHECKSUM_BLOCK) {
+        let start = out.len();
+        for s in block {
+            out.extend_from_slice(&s.to_le_bytes());
+        }
+        checksum.update(&out[start..]);
+    }
+    (out, checksum.finalize().into())
+}
+
+/// Recomputes the checksum `audio.sha256` holds from a WAV written by `encode_wav`, for
+/// verifying an archived recording. None if `wav` isn't laid out that way.
+pub fn wav_pcm_checksum(wav: &[u8]) -> Option<String> {
+    let header = wav.get(..WAV_HEADER_LEN)?;
+    if &header[..4] != b"RIFF" || &header[36..40] != b"data" {
+        return None;
+    }
+    let data_len = u32::from_le_bytes(header[40..44].try_into().ok()?) as usize;
+    let data = wav.get(WAV_HEADER_LEN..WAV_HEADER_LEN + data_len)?;
+    Some(hex(&Sha256::digest(data)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn segments_to_json(segments: &[Segment]) -> String {
+    serde_json::to_string_pretty(segments).unwrap_or_default()
+}
+
+/// What the host knows about a recording, carried along in its exports. Every field is
+/// optional; the crate attaches no meaning to any of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, uniffi::Record)]
+#[serde(default)]
+pub struct SessionMetadata {
+    pub title: Option<String>,
+    pub participants: Vec<String>,
+    /// When the session took place, in milliseconds since the Unix epoch.
+    pub timestamp_ms: Option<i64>,
+    pub notes: Option<String>,
+}
+
+/// The transcript export: `{"metadata": ..., "segments": [...]}`, `metadata` being left
+/// out when none was set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TranscriptExport {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<SessionMetadata>,
+    pub segments: Vec<Segment>,
+}
+
+impl TranscriptExport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Optional entries `export_session` adds next to the standard ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportExtras<'a> {
+    /// Written as `diagnostics.log`.
+    pub diagnostics: Option<&'a str>,
+    /// Write `audio.sha256` (see `ARCHIVE_CHECKSUM_ENTRY`).
+    pub audio_checksum: bool,
+    /// Written as `session.json`.
+    pub metadata: Option<&'a SessionMetadata>,
+}
+
+/// Writes `audio.wav`, `transcript.json` and `speakers.json` into a single zip at `path`,
+/// plus the `extras` asked for.
+///
+/// Only speakers referenced by at least one segment are included, with `speaker_parts`
+/// of each.
+pub fn export_session(
+    path: &Path,
+    samples: &[f32],
+    sample_rate: u32,
+    segments: &[Segment],
+    registry: &SpeakerRegistry,
+    speaker_parts: RegistryParts,
+    extras: ExportExtras<'_>,
+) -> anyhow::Result<()> {
+    let referenced: HashSet<&str> = segments.iter().map(|s| s.speaker_id.as_str()).collect();
+    let mut speakers = SpeakerRegistry::with_metric(registry.metric());
+    speakers.speakers = registry
+        .speakers
+        .iter()
+        .filter(|(id, _)| referenced.contains(id.as_str()))
+        .map(|(id, s)| (id.clone(), s.clone()))
+        .collect();
+    let speakers = speakers.with_parts(speaker_parts);
+
+    let mut zip = ZipWriter::default();
+    let (wav, checksum) = encode_wav_with_checksum(samples, sample_rate);
+    zip.add(ARCHIVE_AUDIO_ENTRY, &wav);
+    zip.add(ARCHIVE_TRANSCRIPT_ENTRY, segments_to_json(segments).as_bytes());
+    zip.add(ARCHIVE_SPEAKERS_ENTRY, speakers.to_json().as_bytes());
+    if extras.audio_checksum {
+        zip.add(ARCHIVE_CHECKSUM_ENTRY, format!("{}\n", hex(&checksum)).as_bytes());
+    }
+    if let Some(metadata) = extras.metadata {
+        zip.add(ARCHIVE_METADATA_ENTRY, serde_json::to_string_pretty(metadata)?.as_bytes());
+    }
+    if let Some(log) = extras.diagnostics {
+        zip.add(diagnostics::ARCHIVE_DIAGNOSTICS_ENTRY, log.as_bytes());
+    }
+    fs::write(path, zip.finish())?;
+    Ok(())
+}
+
+/// Uncompressed ("stored") zip archive builder. Enough for handing off a few files.
+#[derive(Default)]
+struct ZipWriter {
+    body: Vec<u8>,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+// DOS date for 1980-01-01, the zip epoch
+const ZIP_DOS_DATE: u16 = 0x21;
+
+impl ZipWriter {
+    fn add(&mut self, name: &str, data: &[u8]) {
+        let offset = self.body.len() as u32;
+        let crc = crc32fast::hash(data);
+        let size = data.len() as u32;
+        let name = name.as_bytes();
+
+        // Local file header
+        self.body.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        self.body.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // flags
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // method: stored
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // mod time
+        self.body.extend_from_slice(&ZIP_DOS_DATE.to_le_bytes());
+        self.body.extend_from_slice(&crc.to_le_bytes());
+        self.body.extend_from_slice(&size.to_le_bytes()); // compressed
+        self.body.extend_from_slice(&size.to_le_bytes()); // uncompressed
+        self.body.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // extra len
+        self.body.extend_from_slice(name);
+        self.body.extend_from_slice(data);
+
+        // Central directory record
+        let cd = &mut self.central_directory;
+        cd.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        cd.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        cd.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        cd.extend_from_slice(&0u16.to_le_bytes()); // flags
+        cd.extend_from_slice(&0u16.to_le_bytes()); // method
+        cd.extend_from_slice(&0u16.to_le_bytes()); // mod time
+        cd.extend_from_slice(&ZIP_DOS_DATE.to_le_bytes());
+        cd.extend_from_slice(&crc.to_le_bytes());
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        cd.extend_from_slice(&0u16.to_le_bytes()); // extra len
+        cd.extend_from_slice(&0u16.to_le_bytes()); // comment len
+        cd.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        cd.extend_from_slice(&0u16.to_le_bytes()); // internal attrs
+        cd.extend_from_slice(&0u32.to_le_bytes()); // external attrs
+        cd.extend_from_slice(&offset.to_le_bytes());
+        cd.extend_from_slice(name);
+
+        self.entries += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let cd_offset = self.body.len() as u32;
+        let cd_size = self.central_directory.len() as u32;
+        self.body.append(&mut self.central_directory);
+
+        // End of central directory
+        self.body.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // disk with CD
+        self.body.extend_from_slice(&self.entries.to_le_bytes());
+        self.body.extend_from_slice(&self.entries.to_le_bytes());
+        self.body.extend_from_slice(&cd_size.to_le_bytes());
+        self.body.extend_from_slice(&cd_offset.to_le_bytes());
+        self.body.extend_from_slice(&0u16.to_le_bytes()); // comment len
+        self.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::segment;
+
+    /// (name, data) of each entry in a stored zip, read through the central directory.
+    fn zip_entries(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let u16_at = |at: usize| u16::from_le_bytes(zip[at..at + 2].try_into().unwrap()) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(zip[at..at + 4].try_into().unwrap()) as usize;
+        let eocd = zip.len() - 22;
+        assert_eq!(u32_at(eocd), 0x06054b50);
+        let mut at = u32_at(eocd + 16);
+        (0..u16_at(eocd + 10))
+            .map(|_| {
+                assert_eq!(u32_at(at), 0x02014b50);
+                let (crc, size, name_len, offset) = (u32_at(at + 16) as u32, u32_at(at + 24), u16_at(at + 28), u32_at(at + 42));
+                let name = String::from_utf8(zip[at + 46..at + 46 + name_len].to_vec()).unwrap();
+                at += 46 + name_len;
+                let data_start = offset + 30 + u16_at(offset + 26) + u16_at(offset + 28);
+                let data = zip[data_start..data_start + size].to_vec();
+                assert_eq!(crc32fast::hash(&data), crc, "{name}");
+                (name, data)
+            })
+            .collect()
+    }
+
+    fn export(segments: &[Segment], registry: &SpeakerRegistry, extras: ExportExtras) -> Vec<(String, Vec<u8>)> {
+        let path = std::env::temp_dir().join(format!("suprasonic-export-{}-{:?}.zip", std::process::id(), std::thread::current().id()));
+        export_session(&path, &[0.0, 0.25, -0.25], 16000, segments, registry, RegistryParts::All, extras).unwrap();
+        let zip = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        zip_entries(&zip)
+    }
+
+    #[test]
+    fn session_archive_holds_audio_transcript_and_referenced_speakers() {
+        let mut registry = SpeakerRegistry::new();
+        registry.add_speaker("spk_0".to_string(), "Alice".to_string()).unwrap();
+        registry.add_speaker("spk_1".to_string(), "Bob".to_string()).unwrap();
+        let segments = [segment("spk_0", "hello")];
+
+        let entries = export(&segments, &registry, ExportExtras::default());
+        let names: Vec<&str> = entries.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, [ARCHIVE_AUDIO_ENTRY, ARCHIVE_TRANSCRIPT_ENTRY, ARCHIVE_SPEAKERS_ENTRY]);
+
+        assert_eq!(entries[0].1, encode_wav(&[0.0, 0.25, -0.25], 16000));
+        assert!(String::from_utf8_lossy(&entries[1].1).contains("hello"));
+        let speakers = SpeakerRegistry::from_json(std::str::from_utf8(&entries[2].1).unwrap());
+        assert!(speakers.speakers.contains_key("spk_0"));
+        assert!(!speakers.speakers.contains_key("spk_1"));
+    }
+
+    #[test]
+    fn segment_language_round_trips_with_and_without_a_tag() {
+        let tagged = Segment { lang: Some("es-MX".to_string()), ..segment("spk_0", "hola") };
+        let untagged = segment("spk_1", "hello");
+        let export = TranscriptExport { metadata: None, segments: vec![tagged.clone(), untagged.clone()] };
+        let back = TranscriptExport::from_json(&export.to_json()).unwrap();
+        assert_eq!(back.segments, [tagged, untagged]);
+
+        // Transcripts written before the field existed still load
+        let old = r#"{"segments": [{"start": 0.0, "end": 1.0, "text": "hi", "speaker_id": "spk_0", "is_final": true}]}"#;
+        assert_eq!(TranscriptExport::from_json(old).unwrap().segments[0].lang, None);
+    }
+
+    #[test]
+    fn archived_checksum_matches_a_recomputation_over_the_written_samples() {
+        // Several checksum blocks and a ragged last one
+        let samples: Vec<f32> = (0..CHECKSUM_BLOCK * 3 + 123).map(|i| 0.5 * (i as f32 * 0.01).sin()).collect();
+        let path = std::env::temp_dir().join(format!("suprasonic-checksum-{}.zip", std::process::id()));
+        let extras = ExportExtras { audio_checksum: true, ..Default::default() };
+        export_session(&path, &samples, 16000, &[], &SpeakerRegistry::new(), RegistryParts::All, extras).unwrap();
+        let entries = zip_entries(&fs::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let entry = |name: &str| entries.iter().find(|(n, _)| n == name).map(|(_, data)| data.clone()).unwrap();
+        let (wav, sidecar) = (entry(ARCHIVE_AUDIO_ENTRY), String::from_utf8(entry(ARCHIVE_CHECKSUM_ENTRY)).unwrap());
+
+        // Hashing the samples in one go gives what was hashed while writing them
+        let written: Vec<u8> = audio::f32_to_i16(&samples).iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(&wav[WAV_HEADER_LEN..], written);
+        assert_eq!(sidecar, format!("{}\n", hex(&Sha256::digest(&written))));
+        assert_eq!(wav_pcm_checksum(&wav).unwrap(), sidecar.trim_end());
+
+        // A single flipped bit no longer verifies, and a header that isn't ours isn't read
+        let mut corrupted = wav.clone();
+        corrupted[WAV_HEADER_LEN + 1000] ^= 1;
+        assert_ne!(wav_pcm_checksum(&corrupted).unwrap(), sidecar.trim_end());
+        assert_eq!(wav_pcm_checksum(&wav[..WAV_HEADER_LEN - 1]), None);
+        assert_eq!(wav_pcm_checksum(b"OggS and then some more bytes than a header has, surely"), None);
+    }
+
+    #[test]
+    fn pcm_checksum_matches_the_nist_sha256_vectors() {
+        // The PCM data wrapped in the header `encode_wav` writes
+        let wav = |data: &[u8]| {
+            let mut wav = encode_wav(&[], 16000);
+            wav[40..44].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            wav.extend_from_slice(data);
+            wav
+        };
+        let vectors: [(&[u8], &str); 3] = [
+            (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (data, expected) in vectors {
+            assert_eq!(wav_pcm_checksum(&wav(data)).unwrap(), expected);
+        }
+        assert_eq!(
+            wav_pcm_checksum(&wav(&vec![b'a'; 1_000_000])).unwrap(),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+        // Streamed while writing: no samples hash like no data
+        assert_eq!(hex(&encode_wav_with_checksum(&[], 16000).1), vectors[0].1);
+    }
+
+    #[test]
+    fn session_metadata_is_archived_only_when_set() {
+        let names = |entries: &[(String, Vec<u8>)]| entries.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        let without = export(&[], &SpeakerRegistry::new(), ExportExtras::default());
+        assert!(!names(&without).contains(&ARCHIVE_METADATA_ENTRY.to_string()));
+
+        let metadata = SessionMetadata { title: Some("Standup".to_string()), notes: Some("remote".to_string()), ..Default::default() };
+        let with = export(&[], &SpeakerRegistry::new(), ExportExtras { metadata: Some(&metadata), ..Default::default() });
+        let (_, json) = with.iter().find(|(n, _)| n == ARCHIVE_METADATA_ENTRY).unwrap();
+        assert_eq!(serde_json::from_slice::<SessionMetadata>(json).unwrap(), metadata);
+    }
+}
@@ -0,0 +1,173 @@
+// Log-mel spectrogram front-end for hosts whose model takes features instead of audio.
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use realfft::{RealFftPlanner, RealToComplex};
+
+// Floor before the log, so digital silence gives a finite value (about -23)
+const LOG_FLOOR: f32 = 1e-10;
+// Bounds checked by `AudioConfig::validate`
+pub const MAX_MEL_BANDS: u32 = 256;
+pub const MAX_MEL_WINDOW_MS: u32 = 100;
+
+/// Turns a sample stream into log-mel frames: Hann window, power spectrum, triangular
+/// HTK-mel filters from 0 Hz to Nyquist, natural log. Input and window position carry
+/// over between `push` calls, so chunk boundaries don't matter.
+pub struct MelExtractor {
+    // (n_mels, window_ms, hop_ms, sample_rate) as passed to `new`
+    params: (u32, u32, u32, u32),
+    n_mels: usize,
+    window_len: usize,
+    hop_len: usize,
+    window: Vec<f32>,
+    // Per band: first FFT bin and the filter weights from there on
+    filters: Vec<(usize, Vec<f32>)>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    pending: Vec<f32>,
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+impl MelExtractor {
+    pub fn new(n_mels: u32, window_ms: u32, hop_ms: u32, sample_rate: u32) -> Self {
+        let params = (n_mels, window_ms, hop_ms, sample_rate);
+        let n_mels = n_mels.max(1) as usize;
+        let window_len = (sample_rate as usize * window_ms as usize / 1000).max(1);
+        let hop_len = (sample_rate as usize * hop_ms as usize / 1000).max(1);
+        let fft_len = window_len.next_power_of_two();
+        let bins = fft_len / 2 + 1;
+
+        // Periodic Hann, zero-padded up to the FFT length
+        let window = (0..window_len)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / window_len as f32).cos())
+            .collect();
+
+        let max_mel = hz_to_mel(sample_rate as f32 / 2.0);
+        let edges: Vec<f32> = (0..n_mels + 2)
+            .map(|i| mel_to_hz(max_mel * i as f32 / (n_mels + 1) as f32) * fft_len as f32 / sample_rate as f32)
+            .collect();
+        let filters = edges
+            .windows(3)
+            .map(|e| {
+                let (low, centre, high) = (e[0], e[1], e[2]);
+                let first = low.ceil() as usize;
+                let last = (high.floor() as usize).min(bins - 1);
+                let weights = (first..=last.max(first))
+                    .map(|bin| {
+                        let bin = bin as f32;
+                        if bin <= centre {
+                            (bin - low) / (centre - low).max(f32::EPSILON)
+                        } else {
+                            (high - bin) / (high - centre).max(f32::EPSILON)
+                        }
+                        .max(0.0)
+                    })
+                    .collect();
+                (first.min(bins - 1), weights)
+            })
+            .collect();
+
+        Self {
+            params,
+            n_mels,
+            window_len,
+            hop_len,
+            window,
+            filters,
+            fft: RealFftPlanner::<f32>::new().plan_fft_forward(fft_len),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn n_mels(&self) -> usize {
+        self.n_mels
+    }
+
+    /// Whether this extractor was built with these `new` parameters.
+    pub fn is_built_for(&self, n_mels: u32, window_ms: u32, hop_ms: u32, sample_rate: u32) -> bool {
+        self.params == (n_mels, window_ms, hop_ms, sample_rate)
+    }
+
+    /// Log-mel frames completed by `samples`, frame after frame, `n_mels` values each.
+    pub fn push(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.pending.extend_from_slice(samples);
+        let mut frames = Vec::new();
+        let mut input = self.fft.make_input_vec();
+        let mut spectrum = self.fft.make_output_vec();
+        let mut start = 0;
+        while self.pending.len() - start >= self.window_len {
+            input.fill(0.0);
+            for (x, (s, w)) in input.iter_mut().zip(self.pending[start..].iter().zip(&self.window)) {
+                *x = s * w;
+            }
+            if self.fft.process(&mut input, &mut spectrum).is_err() {
+                break;
+            }
+            frames.extend(self.filters.iter().map(|(first, weights)| {
+                let energy: f32 = spectrum[*first..]
+                    .iter()
+                    .zip(weights)
+                    .map(|(c, w)| c.norm_sqr() * w)
+                    .sum();
+                energy.max(LOG_FLOOR).ln()
+            }));
+            start += self.hop_len;
+        }
+        self.pending.drain(..start.min(self.pending.len()));
+        frames
+    }
+
+    /// Drops any partial window, e.g. between utterances.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|n| 0.5 * (2.0 * PI * freq * n as f32 / 16000.0).sin()).collect()
+    }
+
+    #[test]
+    fn one_second_gives_the_expected_frame_count_and_dimension() {
+        let mut extractor = MelExtractor::new(40, 25, 10, 16000);
+        let frames = extractor.push(&tone(1000.0, 16000));
+        assert_eq!(extractor.n_mels(), 40);
+        // 400-sample windows every 160 samples
+        assert_eq!(frames.len(), 98 * 40);
+        assert!(frames.iter().all(|v| v.is_finite()));
+
+        // The tone's band is the loudest in every frame
+        let peak = |frame: &[f32]| (0..frame.len()).max_by(|&a, &b| frame[a].total_cmp(&frame[b])).unwrap();
+        let first = peak(&frames[..40]);
+        assert!(frames.chunks(40).all(|f| peak(f) == first));
+        let centre = mel_to_hz(hz_to_mel(8000.0) * (first + 1) as f32 / 41.0);
+        assert!((centre - 1000.0).abs() < 150.0, "peak band centred on {} Hz", centre);
+    }
+
+    #[test]
+    fn chunked_input_matches_one_push() {
+        let signal = tone(440.0, 16000);
+        let whole = MelExtractor::new(40, 25, 10, 16000).push(&signal);
+        let mut extractor = MelExtractor::new(40, 25, 10, 16000);
+        let chunked: Vec<f32> = signal.chunks(480).flat_map(|c| extractor.push(c)).collect();
+        assert_eq!(chunked, whole);
+    }
+
+    #[test]
+    fn silence_stays_finite() {
+        let frames = MelExtractor::new(80, 25, 10, 16000).push(&[0.0; 1600]);
+        assert_eq!(frames.len(), 8 * 80);
+        assert!(frames.iter().all(|v| *v == LOG_FLOOR.ln()));
+    }
+}
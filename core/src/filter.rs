@@ -0,0 +1,240 @@
+// IIR filters applied to the output stream, with state carried across chunks.
+
+use std::f32::consts::PI;
+
+/// Second-order section (RBJ cookbook coefficients, direct form I).
+#[derive(Debug, Clone)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+// Butterworth Q for a single second-order section
+const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+impl Biquad {
+    fn from_coefficients(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn high_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * BUTTERWORTH_Q);
+        Self::from_coefficients(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn low_pass(cutoff_hz: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * BUTTERWORTH_Q);
+        Self::from_coefficients(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Band-limits audio to `low_hz..high_hz` (either edge may be 0 = open) with two
+/// cascaded Butterworth sections per edge (24 dB/octave).
+#[derive(Debug, Clone)]
+pub struct BandPass {
+    sections: Vec<Biquad>,
+}
+
+impl BandPass {
+    /// None when both edges are open, i.e. there is nothing to filter.
+    pub fn new(low_hz: f32, high_hz: f32, sample_rate: u32) -> Option<Self> {
+        let rate = sample_rate as f32;
+        let mut sections = Vec::new();
+        if low_hz > 0.0 {
+            sections.extend([Biquad::high_pass(low_hz, rate), Biquad::high_pass(low_hz, rate)]);
+        }
+        if high_hz > 0.0 {
+            sections.extend([Biquad::low_pass(high_hz, rate), Biquad::low_pass(high_hz, rate)]);
+        }
+        (!sections.is_empty()).then_some(Self { sections })
+    }
+
+    /// Filters `samples` in place, continuing from the previous call.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = self.sections.iter_mut().fold(*sample, |x, s| s.process(x));
+        }
+    }
+}
+
+// ITU-R BS.1770 K-weighting: a +4 dB shelf modelling the head, then a 38 Hz high-pass
+const SHELF_HZ: f64 = 1681.974450955533;
+const SHELF_GAIN_DB: f64 = 3.999843853973347;
+const SHELF_Q: f64 = 0.7071752369554196;
+const K_HIGH_PASS_HZ: f64 = 38.13547087602444;
+const K_HIGH_PASS_Q: f64 = 0.5003270373238773;
+// BS.1770 gating: 400ms blocks every 100ms, an absolute and a relative gate
+const LOUDNESS_BLOCK_MS: usize = 400;
+const LOUDNESS_STEP_MS: usize = 100;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// The two K-weighting sections, derived for `sample_rate` (the standard only tabulates 48 kHz).
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+    let k = (std::f64::consts::PI * SHELF_HZ / rate).tan();
+    let vh = 10f64.powf(SHELF_GAIN_DB / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Biquad::from_coefficients(
+        [(vh + vb * k / SHELF_Q + k * k) as f32, (2.0 * (k * k - vh)) as f32, (vh - vb * k / SHELF_Q + k * k) as f32],
+        [(1.0 + k / SHELF_Q + k * k) as f32, (2.0 * (k * k - 1.0)) as f32, (1.0 - k / SHELF_Q + k * k) as f32],
+    );
+    let k = (std::f64::consts::PI * K_HIGH_PASS_HZ / rate).tan();
+    let high_pass = Biquad::from_coefficients(
+        [1.0, -2.0, 1.0],
+        [(1.0 + k / K_HIGH_PASS_Q + k * k) as f32, (2.0 * (k * k - 1.0)) as f32, (1.0 - k / K_HIGH_PASS_Q + k * k) as f32],
+    );
+    [shelf, high_pass]
+}
+
+/// Gated integrated loudness of mono `samples`, in LUFS (BS.1770 / EBU R128). Audio
+/// shorter than one block is measured as a single block. None when everything is below
+/// the -70 LUFS gate, e.g. silence.
+pub fn integrated_loudness(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let mut stages = k_weighting(sample_rate);
+    let weighted: Vec<f64> = samples
+        .iter()
+        .map(|&x| stages.iter_mut().fold(x, |x, s| s.process(x)) as f64)
+        .collect();
+    let block = (sample_rate as usize * LOUDNESS_BLOCK_MS / 1000).min(weighted.len()).max(1);
+    let step = (sample_rate as usize * LOUDNESS_STEP_MS / 1000).max(1);
+    let powers: Vec<f64> = (0..=weighted.len().saturating_sub(block))
+        .step_by(step)
+        .map(|start| weighted[start..start + block].iter().map(|x| x * x).sum::<f64>() / block as f64)
+        .collect();
+
+    let lufs = |power: f64| -0.691 + 10.0 * power.max(f64::MIN_POSITIVE).log10();
+    let gated_mean = |gate: f64| {
+        let kept: Vec<f64> = powers.iter().copied().filter(|p| lufs(*p) > gate).collect();
+        (!kept.is_empty()).then(|| kept.iter().sum::<f64>() / kept.len() as f64)
+    };
+    let relative_gate = lufs(gated_mean(ABSOLUTE_GATE_LUFS)?) + RELATIVE_GATE_LU;
+    gated_mean(relative_gate.max(ABSOLUTE_GATE_LUFS)).map(|p| lufs(p) as f32)
+}
+
+/// Scales `samples` toward `target_lufs`, never past full scale: the gain is capped so
+/// the peak stays at or below 1.0. Returns the gain applied in dB, or None (and leaves
+/// the audio alone) when it has no measurable loudness.
+pub fn normalize_loudness(samples: &mut [f32], sample_rate: u32, target_lufs: f32) -> Option<f32> {
+    let loudness = integrated_loudness(samples, sample_rate)?;
+    let peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    let gain = 10f32.powf((target_lufs - loudness) / 20.0).min(1.0 / peak.max(f32::EPSILON));
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+    Some(20.0 * gain.log10())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f32, secs: f32) -> Vec<f32> {
+        (0..(16000.0 * secs) as usize).map(|i| 0.5 * (2.0 * std::f32::consts::PI * freq * i as f32 / 16000.0).sin()).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Output level relative to input for a tone, once the filter has settled.
+    fn gain(filter: &mut BandPass, freq: f32) -> f32 {
+        let input = tone(freq, 1.0);
+        let mut output = input.clone();
+        filter.process(&mut output);
+        rms(&output[4000..]) / rms(&input[4000..])
+    }
+
+    #[test]
+    fn narrowband_filter_keeps_speech_and_attenuates_outside_the_band() {
+        let narrowband = || BandPass::new(300.0, 3400.0, 16000).unwrap();
+        assert!(gain(&mut narrowband(), 1000.0) > 0.95);
+        // Two octaves out at 24 dB/octave
+        assert!(gain(&mut narrowband(), 75.0) < 0.01);
+        assert!(gain(&mut narrowband(), 7000.0) < 0.05);
+        assert!(BandPass::new(0.0, 0.0, 16000).is_none());
+    }
+
+    #[test]
+    fn band_pass_state_carries_across_chunks() {
+        let input: Vec<f32> = tone(150.0, 0.5).iter().zip(tone(2500.0, 0.5)).map(|(a, b)| a + b).collect();
+        let mut whole = input.clone();
+        BandPass::new(300.0, 3400.0, 16000).unwrap().process(&mut whole);
+
+        let mut chunked = input;
+        let mut filter = BandPass::new(300.0, 3400.0, 16000).unwrap();
+        chunked.chunks_mut(480).for_each(|c| filter.process(c));
+        assert_eq!(chunked, whole);
+    }
+
+    #[test]
+    fn known_loudness_tone_is_measured_and_scaled_to_the_target() {
+        // A full-scale 1 kHz sine is -3.01 LUFS; at half scale, 6.02 dB less
+        let mut audio = tone(1000.0, 3.0);
+        let measured = integrated_loudness(&audio, 16000).unwrap();
+        assert!((measured + 9.03).abs() < 0.2, "measured {measured} LUFS");
+
+        let gain_db = normalize_loudness(&mut audio, 16000, -23.0).unwrap();
+        assert!((gain_db - (-23.0 - measured)).abs() < 0.01);
+        assert!((integrated_loudness(&audio, 16000).unwrap() + 23.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn loudness_gain_stops_at_full_scale_and_skips_silence() {
+        let mut audio = tone(1000.0, 1.0);
+        // Reaching 0 LUFS would need +9 dB; the 0.5 peak allows only +6
+        let gain_db = normalize_loudness(&mut audio, 16000, 0.0).unwrap();
+        assert!((gain_db - 6.02).abs() < 0.01, "{gain_db} dB");
+        assert!(audio.iter().all(|s| s.abs() <= 1.0));
+
+        let mut silence = vec![0.0; 16000];
+        assert_eq!(normalize_loudness(&mut silence, 16000, -23.0), None);
+        assert!(silence.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn quiet_passages_are_gated_out_of_the_measurement() {
+        let loud = tone(1000.0, 2.0);
+        // Two seconds at -40 dB relative: below the relative gate
+        let mut with_pause: Vec<f32> = loud.iter().map(|s| s * 0.01).collect();
+        with_pause.extend_from_slice(&loud);
+        let difference = integrated_loudness(&with_pause, 16000).unwrap() - integrated_loudness(&loud, 16000).unwrap();
+        assert!(difference.abs() < 0.5, "the quiet half moved the loudness by {difference} LU");
+    }
+}
@@ -2,6 +2,28 @@ uniffi::setup_scaffolding!();
 
 pub mod state;
 pub mod audio;
+#[cfg(feature = "capture")]
+pub mod capture;
+#[cfg(not(feature = "capture"))]
+#[path = "capture_disabled.rs"]
+pub mod capture;
+pub mod devices;
+pub mod diagnostics;
 pub mod diarization;
+pub mod diarization_eval;
+pub mod echo;
+pub mod export;
+pub mod features;
+pub mod filter;
+pub mod metrics;
+pub mod noise;
+pub mod offline;
+pub mod pool;
+mod processing;
+pub mod resample_quality;
+#[cfg(test)]
+mod test_support;
+pub mod transcript;
+pub mod vad;
 
-pub use audio::AudioEngine;
+pub use capture::AudioEngine;
@@ -0,0 +1,229 @@
+// Pipeline counters and thread heartbeats, updated lock-free from the processing threads.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// How often idle pipeline threads wake up just to beat
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+// A thread that hasn't beaten for this long is considered dead
+pub(crate) const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness signal for a long-running thread; it calls `beat` every loop iteration.
+#[derive(Debug)]
+pub struct Heartbeat {
+    origin: Instant,
+    last_beat_ms: AtomicU64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self { origin: Instant::now(), last_beat_ms: AtomicU64::new(0) }
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn beat(&self) {
+        self.last_beat_ms.store(self.origin.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        let now = self.origin.elapsed().as_millis() as u64;
+        let since = now.saturating_sub(self.last_beat_ms.load(Ordering::Relaxed));
+        since <= timeout.as_millis() as u64
+    }
+}
+
+/// Shared by every stage that wants to report something; read with `snapshot`.
+#[derive(Debug, Default)]
+pub struct PipelineMetrics {
+    non_finite_samples: AtomicU64,
+    ring_high_water: AtomicU64,
+    ring_capacity: AtomicU64,
+    // Captured samples the ring buffer had no room for
+    ring_overflow_samples: AtomicU64,
+    // Frames per capture callback and the device rate, as negotiated by the backend
+    capture_buffer_frames: AtomicU64,
+    capture_sample_rate: AtomicU64,
+    // Capture-to-dispatch latency, in microseconds (see `AudioConfig::measure_latency`)
+    latency_last_us: AtomicU64,
+    latency_max_us: AtomicU64,
+    latency_sum_us: AtomicU64,
+    latency_count: AtomicU64,
+    resampler_fallbacks: AtomicU64,
+    file_chunks_dropped: AtomicU64,
+}
+
+/// Point-in-time copy of the pipeline counters.
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct MetricsSnapshot {
+    /// NaN/Inf input samples seen (and zeroed, when `sanitize_non_finite` is on).
+    pub non_finite_samples: u64,
+    /// Most samples seen waiting in a capture ring buffer since recording started.
+    pub ring_high_water: u64,
+    /// Size of that ring buffer, in samples (0 before the first recording).
+    pub ring_capacity: u64,
+    /// Captured samples dropped because that ring buffer was full.
+    pub ring_overflow_samples: u64,
+    /// Capture-to-dispatch latency of the latest chunk, with `measure_latency` on (0 otherwise).
+    pub latency_last_ms: f64,
+    /// Worst capture-to-dispatch latency seen.
+    pub latency_max_ms: f64,
+    /// Mean capture-to-dispatch latency over every measured chunk.
+    pub latency_avg_ms: f64,
+    /// Streams that ran on the linear fallback because the real resampler failed to build.
+    pub resampler_fallbacks: u64,
+    /// Chunks of file audio discarded under `FileBackpressure::DropOldest`.
+    pub file_chunks_dropped: u64,
+}
+
+/// Capture buffering negotiated with the audio backend.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct CaptureLatency {
+    /// Frames the device delivers per callback.
+    pub buffer_frames: u32,
+    pub sample_rate: u32,
+    /// Buffering delay those frames add, before any resampler latency.
+    pub latency_ms: f64,
+}
+
+impl PipelineMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_non_finite(&self, count: u64) {
+        self.non_finite_samples.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Called by the capture consumer with the ring buffer's current fill.
+    pub fn record_ring_fill(&self, occupied: usize, capacity: usize) {
+        self.ring_high_water.fetch_max(occupied as u64, Ordering::Relaxed);
+        self.ring_capacity.fetch_max(capacity as u64, Ordering::Relaxed);
+    }
+
+    /// Called from the capture callback with the samples it couldn't fit into the ring.
+    pub fn record_ring_overflow(&self, samples: usize) {
+        self.ring_overflow_samples.fetch_add(samples as u64, Ordering::Relaxed);
+    }
+
+    pub fn ring_overflow_samples(&self) -> u64 {
+        self.ring_overflow_samples.load(Ordering::Relaxed)
+    }
+
+    /// Called from the capture callback with the size of the buffer it was handed.
+    pub fn record_capture_buffer(&self, frames: usize, sample_rate: u32) {
+        self.capture_buffer_frames.store(frames as u64, Ordering::Relaxed);
+        self.capture_sample_rate.store(sample_rate as u64, Ordering::Relaxed);
+    }
+
+    /// The negotiated capture buffer, once a callback has run since recording started.
+    pub fn capture_latency(&self) -> Option<CaptureLatency> {
+        let buffer_frames = self.capture_buffer_frames.load(Ordering::Relaxed);
+        let sample_rate = self.capture_sample_rate.load(Ordering::Relaxed);
+        if buffer_frames == 0 || sample_rate == 0 {
+            return None;
+        }
+        Some(CaptureLatency {
+            buffer_frames: buffer_frames as u32,
+            sample_rate: sample_rate as u32,
+            latency_ms: buffer_frames as f64 * 1000.0 / sample_rate as f64,
+        })
+    }
+
+    pub fn record_resampler_fallback(&self) {
+        self.resampler_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_file_chunk_dropped(&self) {
+        self.file_chunks_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called once a chunk is through the pipeline, with the age of its first sample.
+    pub fn record_latency(&self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.latency_last_us.store(us, Ordering::Relaxed);
+        self.latency_max_us.fetch_max(us, Ordering::Relaxed);
+        self.latency_sum_us.fetch_add(us, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Forgets the per-recording capture stats, at the beginning of each recording.
+    pub fn reset_capture(&self) {
+        self.ring_high_water.store(0, Ordering::Relaxed);
+        self.ring_capacity.store(0, Ordering::Relaxed);
+        self.capture_buffer_frames.store(0, Ordering::Relaxed);
+        self.capture_sample_rate.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            non_finite_samples: self.non_finite_samples.load(Ordering::Relaxed),
+            ring_high_water: self.ring_high_water.load(Ordering::Relaxed),
+            ring_capacity: self.ring_capacity.load(Ordering::Relaxed),
+            ring_overflow_samples: self.ring_overflow_samples.load(Ordering::Relaxed),
+            latency_last_ms: self.latency_last_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            latency_max_ms: self.latency_max_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            latency_avg_ms: match self.latency_count.load(Ordering::Relaxed) {
+                0 => 0.0,
+                count => self.latency_sum_us.load(Ordering::Relaxed) as f64 / count as f64 / 1000.0,
+            },
+            resampler_fallbacks: self.resampler_fallbacks.load(Ordering::Relaxed),
+            file_chunks_dropped: self.file_chunks_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.non_finite_samples.store(0, Ordering::Relaxed);
+        self.ring_overflow_samples.store(0, Ordering::Relaxed);
+        self.resampler_fallbacks.store(0, Ordering::Relaxed);
+        self.file_chunks_dropped.store(0, Ordering::Relaxed);
+        for latency in [&self.latency_last_us, &self.latency_max_us, &self.latency_sum_us, &self.latency_count] {
+            latency.store(0, Ordering::Relaxed);
+        }
+        self.reset_capture();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_goes_stale_once_its_thread_is_gone() {
+        let heartbeat = std::sync::Arc::new(Heartbeat::new());
+        let beating = heartbeat.clone();
+        std::thread::spawn(move || {
+            for _ in 0..5 {
+                beating.beat();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert!(heartbeat.is_alive(Duration::from_millis(100)));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!heartbeat.is_alive(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn capture_latency_follows_the_negotiated_buffer_and_rate() {
+        let metrics = PipelineMetrics::new();
+        assert_eq!(metrics.capture_latency(), None);
+
+        metrics.record_capture_buffer(480, 48000);
+        assert_eq!(metrics.capture_latency(), Some(CaptureLatency { buffer_frames: 480, sample_rate: 48000, latency_ms: 10.0 }));
+        metrics.record_capture_buffer(1024, 44100);
+        let latency = metrics.capture_latency().unwrap();
+        assert_eq!((latency.buffer_frames, latency.sample_rate), (1024, 44100));
+        assert!((latency.latency_ms - 23.22).abs() < 0.01);
+
+        // Unknown again until the next recording's first callback
+        metrics.reset_capture();
+        assert_eq!(metrics.capture_latency(), None);
+    }
+}
@@ -0,0 +1,144 @@
+// Room noise profiling from a calibration clip.
+
+use realfft::RealFftPlanner;
+use std::f32::consts::PI;
+
+// Analysis frame: 32ms at 16kHz, 31.25 Hz per FFT bin
+const FRAME_LEN: usize = 512;
+// Equal-width bands the spectrum is summarized into (250 Hz each at 16kHz)
+pub const NOISE_BANDS: usize = 32;
+
+/// Noise floor of the room, measured from a stretch of (supposedly) non-speech audio.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct NoiseProfile {
+    /// Overall RMS of the clip.
+    pub floor_rms: f32,
+    /// Mean power per band, lowest band first; `NOISE_BANDS` equal-width bands up to Nyquist.
+    pub band_power: Vec<f32>,
+    /// Width of each band in Hz.
+    pub band_width_hz: f32,
+    /// Audio actually analyzed.
+    pub duration_ms: u32,
+}
+
+impl NoiseProfile {
+    /// Averages Hann-windowed FFT power over the clip's whole frames. Clips shorter than
+    /// one frame still get `floor_rms`, with an all-zero spectrum.
+    pub fn from_samples(samples: &[f32], sample_rate: u32) -> Self {
+        let bins = FRAME_LEN / 2;
+        let bins_per_band = bins / NOISE_BANDS;
+        let window: Vec<f32> = (0..FRAME_LEN)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / FRAME_LEN as f32).cos())
+            .collect();
+
+        let fft = RealFftPlanner::<f32>::new().plan_fft_forward(FRAME_LEN);
+        let mut input = fft.make_input_vec();
+        let mut spectrum = fft.make_output_vec();
+
+        let mut band_power = vec![0.0f32; NOISE_BANDS];
+        let mut frames = 0usize;
+        for frame in samples.chunks_exact(FRAME_LEN) {
+            for (x, (s, h)) in input.iter_mut().zip(frame.iter().zip(&window)) {
+                *x = s * h;
+            }
+            if fft.process(&mut input, &mut spectrum).is_err() {
+                break;
+            }
+            // The Nyquist bin falls outside the bands
+            for (bin, c) in spectrum[..bins].iter().enumerate() {
+                band_power[bin / bins_per_band] += c.norm_sqr() / FRAME_LEN as f32;
+            }
+            frames += 1;
+        }
+        if frames > 0 {
+            let scale = 1.0 / (frames * bins_per_band) as f32;
+            band_power.iter_mut().for_each(|p| *p *= scale);
+        }
+
+        Self {
+            floor_rms: crate::vad::rms(samples),
+            band_power,
+            band_width_hz: sample_rate as f32 / 2.0 / NOISE_BANDS as f32,
+            duration_ms: (samples.len() as u64 * 1000 / sample_rate.max(1) as u64) as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white_noise(len: usize, amplitude: f32) -> Vec<f32> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                ((state >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    #[test]
+    fn white_noise_profiles_as_a_flat_floor() {
+        let profile = NoiseProfile::from_samples(&white_noise(16000, 0.1), 16000);
+        assert_eq!((profile.duration_ms, profile.band_width_hz), (1000, 250.0));
+        // Uniform in +-0.1
+        assert!((profile.floor_rms - 0.1 / 3f32.sqrt()).abs() < 0.002);
+        assert_eq!(profile.band_power.len(), NOISE_BANDS);
+        // Band edges aside, every band holds about the same power (31 frames of averaging)
+        let inner = &profile.band_power[1..NOISE_BANDS - 1];
+        let (min, max) = inner.iter().fold((f32::MAX, 0.0f32), |(lo, hi), &p| (lo.min(p), hi.max(p)));
+        assert!(max / min < 2.0, "{min}..{max}");
+    }
+
+    #[test]
+    fn hum_shows_up_in_its_own_band() {
+        // Louder noise gives more power; a 1.1 kHz hum lands in the 1000..1250 Hz band
+        let quiet = NoiseProfile::from_samples(&white_noise(8000, 0.01), 16000);
+        let loud = NoiseProfile::from_samples(&white_noise(8000, 0.1), 16000);
+        assert!(loud.band_power[10] > 50.0 * quiet.band_power[10]);
+
+        let hum: Vec<f32> = (0..8000).map(|i| 0.2 * (2.0 * PI * 1100.0 * i as f32 / 16000.0).sin()).collect();
+        let profile = NoiseProfile::from_samples(&hum, 16000);
+        let loudest = (0..NOISE_BANDS).max_by(|&a, &b| profile.band_power[a].total_cmp(&profile.band_power[b])).unwrap();
+        assert_eq!(loudest, 4);
+    }
+
+    #[test]
+    fn band_power_matches_a_direct_dft() {
+        let samples: Vec<f32> = white_noise(4 * FRAME_LEN, 0.1)
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x + 0.3 * (2.0 * PI * 440.0 * i as f32 / 16000.0).sin())
+            .collect();
+        let frames = samples.len() / FRAME_LEN;
+        let bins_per_band = FRAME_LEN / 2 / NOISE_BANDS;
+        let mut expected = vec![0.0f32; NOISE_BANDS];
+        for frame in samples.chunks_exact(FRAME_LEN) {
+            for bin in 0..FRAME_LEN / 2 {
+                let w = 2.0 * PI * bin as f32 / FRAME_LEN as f32;
+                let (mut re, mut im) = (0.0f32, 0.0f32);
+                for (n, &x) in frame.iter().enumerate() {
+                    let h = 0.5 - 0.5 * (2.0 * PI * n as f32 / FRAME_LEN as f32).cos();
+                    re += x * h * (w * n as f32).cos();
+                    im -= x * h * (w * n as f32).sin();
+                }
+                expected[bin / bins_per_band] += (re * re + im * im) / FRAME_LEN as f32 / (frames * bins_per_band) as f32;
+            }
+        }
+
+        let profile = NoiseProfile::from_samples(&samples, 16000);
+        for (band, (&got, &want)) in profile.band_power.iter().zip(&expected).enumerate() {
+            assert!((got - want).abs() <= 1e-3 * want.max(1e-3), "band {band}: {got} vs {want}");
+        }
+    }
+
+    #[test]
+    fn clips_shorter_than_a_frame_only_get_a_floor() {
+        let profile = NoiseProfile::from_samples(&[0.5; 100], 16000);
+        assert_eq!(profile.floor_rms, 0.5);
+        assert!(profile.band_power.iter().all(|&p| p == 0.0));
+    }
+}
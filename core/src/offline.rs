@@ -0,0 +1,339 @@
+// Offline input: decoding audio files and feeding them through the live pipeline.
+
+use crossbeam_channel::Sender;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::audio::{self, AudioConfig, AudioPacket, AudioProcessor, FileBackpressure};
+use crate::metrics::PipelineMetrics;
+use crate::pool;
+
+// Source frames handed to the processor per step (~85ms at 48kHz)
+const FEED_BLOCK_FRAMES: usize = 4096;
+// Packets allowed to wait in the processing channel before the feeder pauses (~1s of chunks)
+pub(crate) const MAX_QUEUED_PACKETS: usize = 64;
+
+/// Decoded file audio, still at the file's own rate and channel layout.
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// Encodings `decode` understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum AudioFileFormat {
+    /// RIFF/WAVE, as read by `decode_wav`.
+    Wav,
+}
+
+/// Decodes an in-memory audio file of the given format.
+pub fn decode(bytes: &[u8], format: AudioFileFormat) -> anyhow::Result<DecodedAudio> {
+    match format {
+        AudioFileFormat::Wav => decode_wav(bytes),
+    }
+}
+
+/// Decodes a RIFF/WAVE file: 8/16/24/32-bit PCM or 32-bit float, any channel count.
+pub fn decode_wav(bytes: &[u8]) -> anyhow::Result<DecodedAudio> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        anyhow::bail!("Not a WAV file");
+    }
+
+    let mut format: Option<(u16, u16, u32, u16)> = None;
+    let mut data: Option<&[u8]> = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into()?) as usize;
+        let body = &bytes[pos + 8..(pos + 8 + len).min(bytes.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let mut tag = u16::from_le_bytes([body[0], body[1]]);
+                // WAVE_FORMAT_EXTENSIBLE keeps the real tag at the start of the sub-format GUID
+                if tag == 0xFFFE && body.len() >= 26 {
+                    tag = u16::from_le_bytes([body[24], body[25]]);
+                }
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let rate = u32::from_le_bytes(body[4..8].try_into()?);
+                let byte_rate = u32::from_le_bytes(body[8..12].try_into()?);
+                let block_align = u16::from_le_bytes([body[12], body[13]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                // Old recorders sometimes store a byte rate that implies a fractional sample
+                // rate; the integer sample rate field is what the samples were taken at
+                if block_align > 0 && byte_rate != rate.saturating_mul(block_align as u32) {
+                    tracing::warn!(
+                        "WAV byte rate {} implies {:.2} Hz; using the declared {} Hz",
+                        byte_rate,
+                        byte_rate as f64 / block_align as f64,
+                        rate
+                    );
+                }
+                format = Some((tag, channels, rate, bits));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are word-aligned
+        pos += 8 + len + (len & 1);
+    }
+
+    let (tag, channels, sample_rate, bits) = format.ok_or_else(|| anyhow::anyhow!("WAV file has no fmt chunk"))?;
+    let data = data.ok_or_else(|| anyhow::anyhow!("WAV file has no data chunk"))?;
+    if channels == 0 || sample_rate == 0 {
+        anyhow::bail!("WAV file declares {} channels at {} Hz", channels, sample_rate);
+    }
+
+    let samples: Vec<f32> = match (tag, bits) {
+        (1, 8) => data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect(),
+        (1, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect(),
+        (1, 24) => data
+            .chunks_exact(3)
+            .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0)
+            .collect(),
+        (1, 32) => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (3, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        _ => anyhow::bail!("Unsupported WAV encoding (format {}, {} bits)", tag, bits),
+    };
+
+    Ok(DecodedAudio { samples, sample_rate, channels })
+}
+
+/// Pushes decoded audio through resampling and chunking as if it came from a device.
+///
+/// Never lets more than `MAX_QUEUED_PACKETS` wait for the processing loop, so memory
+/// stays flat no matter how long the file is; `config.file_backpressure` decides what
+/// happens once that many are waiting. With `config.file_realtime_factor > 0` it also
+/// paces itself to at most that multiple of real time. Stops early once `cancelled`
+/// is set.
+pub(crate) fn feed(
+    decoded: DecodedAudio,
+    config: AudioConfig,
+    data_tx: Sender<AudioPacket>,
+    metrics: Arc<PipelineMetrics>,
+    level_reporting: Arc<AtomicBool>,
+    cancelled: &AtomicBool,
+) -> anyhow::Result<()> {
+    let channels = decoded.channels as usize;
+    config.validate_for_input(channels)?;
+    let mono = audio::downmix(&decoded.samples, channels, config.downmix_mode, config.downmix_channels);
+    let realtime_factor = config.file_realtime_factor;
+    let policy = config.file_backpressure;
+    // The processor's packets wait here until the processing channel has room
+    let (processed_tx, processed_rx) = crossbeam_channel::unbounded();
+    let mut processor = AudioProcessor::new_offline(decoded.sample_rate as usize, config, processed_tx, metrics.clone())?
+        .with_level_reporting(level_reporting);
+    let _ = data_tx.send(AudioPacket::Format(processor.output_rate()));
+
+    let started = Instant::now();
+    let mut fed_frames = 0usize;
+    let mut backlog = VecDeque::new();
+    let check_cancelled = || {
+        if cancelled.load(Ordering::SeqCst) {
+            anyhow::bail!("Cancelled: the pipeline was shut down");
+        }
+        Ok(())
+    };
+    for block in mono.chunks(FEED_BLOCK_FRAMES) {
+        check_cancelled()?;
+        if realtime_factor > 0.0 {
+            let audio_secs = fed_frames as f64 / decoded.sample_rate as f64;
+            let ahead = audio_secs / realtime_factor - started.elapsed().as_secs_f64();
+            if ahead > 0.0 {
+                std::thread::sleep(Duration::from_secs_f64(ahead));
+            }
+        }
+        processor.push(block);
+        fed_frames += block.len();
+        backlog.extend(processed_rx.try_iter());
+        forward(&mut backlog, &data_tx, policy, &metrics, &check_cancelled)?;
+    }
+    processor.finish();
+    backlog.extend(processed_rx.try_iter());
+    // The tail is at most one backlog's worth: wait for it rather than cut the file short
+    let policy = if policy == FileBackpressure::DropOldest { FileBackpressure::Block } else { policy };
+    forward(&mut backlog, &data_tx, policy, &metrics, &check_cancelled)?;
+    let _ = data_tx.send(AudioPacket::Flush);
+    Ok(())
+}
+
+/// Moves as much of `backlog` into the processing channel as fits under
+/// `MAX_QUEUED_PACKETS`, then applies `policy` to whatever is left.
+fn forward(
+    backlog: &mut VecDeque<AudioPacket>,
+    data_tx: &Sender<AudioPacket>,
+    policy: FileBackpressure,
+    metrics: &PipelineMetrics,
+    check_cancelled: &dyn Fn() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    loop {
+        while data_tx.len() < MAX_QUEUED_PACKETS {
+            let Some(packet) = backlog.pop_front() else { return Ok(()) };
+            let _ = data_tx.send(packet);
+        }
+        match policy {
+            FileBackpressure::Block => {
+                check_cancelled()?;
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            FileBackpressure::DropOldest => {
+                // Keep up to a channel's worth ready to go, so nothing gaps once it drains
+                while backlog.len() > MAX_QUEUED_PACKETS {
+                    if let Some(AudioPacket::Samples(samples)) = backlog.pop_front() {
+                        pool::SAMPLES.recycle(samples);
+                        metrics.record_file_chunk_dropped();
+                    }
+                }
+                return Ok(());
+            }
+            FileBackpressure::Error => {
+                anyhow::bail!("The listener fell behind the file by more than {} chunks", MAX_QUEUED_PACKETS);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(secs: usize) -> DecodedAudio {
+        let samples = (0..16000 * secs).map(|i| 0.2 * (i as f32 * 0.05).sin()).collect();
+        DecodedAudio { samples, sample_rate: 16000, channels: 1 }
+    }
+
+    fn feed_on_thread(decoded: DecodedAudio, config: AudioConfig, data_tx: Sender<AudioPacket>) -> std::thread::JoinHandle<anyhow::Result<()>> {
+        feed_with_metrics(decoded, config, data_tx, Arc::new(PipelineMetrics::new()))
+    }
+
+    fn feed_with_metrics(
+        decoded: DecodedAudio,
+        config: AudioConfig,
+        data_tx: Sender<AudioPacket>,
+        metrics: Arc<PipelineMetrics>,
+    ) -> std::thread::JoinHandle<anyhow::Result<()>> {
+        std::thread::spawn(move || {
+            let cancelled = AtomicBool::new(false);
+            feed(decoded, config, data_tx, metrics, Arc::new(AtomicBool::new(true)), &cancelled)
+        })
+    }
+
+    /// Samples received by a listener taking `per_packet` for each packet, until the feed ends.
+    fn slow_listener(rx: crossbeam_channel::Receiver<AudioPacket>, per_packet: Duration) -> usize {
+        rx.iter()
+            .map(|packet| {
+                std::thread::sleep(per_packet);
+                if let AudioPacket::Samples(data) = packet { data.len() } else { 0 }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn large_file_never_queues_more_than_the_bound() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let feeder = feed_on_thread(tone(60), AudioConfig::default(), tx);
+
+        // A consumer much slower than the feeder
+        let (mut max_queued, mut samples) = (0, 0);
+        for packet in rx.iter() {
+            max_queued = max_queued.max(rx.len() + 1);
+            if let AudioPacket::Samples(data) = packet {
+                samples += data.len();
+            }
+            std::thread::sleep(Duration::from_micros(200));
+        }
+        feeder.join().unwrap().unwrap();
+
+        // The Format ahead of the audio and the closing Flush are sent outside the bound
+        assert!(max_queued <= MAX_QUEUED_PACKETS + 1, "{max_queued} packets queued");
+        // Blocking loses nothing
+        assert!(samples >= 16000 * 60, "{samples} samples");
+    }
+
+    #[test]
+    fn error_policy_gives_up_when_nobody_consumes() {
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let config = AudioConfig { file_backpressure: FileBackpressure::Error, ..Default::default() };
+        assert!(feed_on_thread(tone(10), config, tx).join().unwrap().is_err());
+    }
+
+    #[test]
+    fn drop_oldest_policy_keeps_the_feeder_going_and_counts_what_a_slow_listener_missed() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let metrics = Arc::new(PipelineMetrics::new());
+        let config = AudioConfig { file_backpressure: FileBackpressure::DropOldest, ..Default::default() };
+        let feeder = feed_with_metrics(tone(30), config, tx, metrics.clone());
+        let received = slow_listener(rx, Duration::from_millis(1));
+        feeder.join().unwrap().unwrap();
+
+        let dropped = metrics.snapshot().file_chunks_dropped as usize;
+        assert!(dropped > 0, "a 1 ms-per-chunk listener can't keep up with the feeder");
+        // Every 30 ms chunk either arrived or was counted
+        assert!((received + dropped * 480).abs_diff(16000 * 30) < 480, "{received} received, {dropped} dropped");
+    }
+
+    #[test]
+    fn block_and_error_policies_with_a_slow_listener() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let metrics = Arc::new(PipelineMetrics::new());
+        let feeder = feed_with_metrics(tone(10), AudioConfig::default(), tx, metrics.clone());
+        assert!(slow_listener(rx, Duration::from_millis(1)) >= 16000 * 10, "blocking waits for the listener");
+        feeder.join().unwrap().unwrap();
+        assert_eq!(metrics.snapshot().file_chunks_dropped, 0);
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let config = AudioConfig { file_backpressure: FileBackpressure::Error, ..Default::default() };
+        let feeder = feed_on_thread(tone(10), config, tx);
+        let received = slow_listener(rx, Duration::from_millis(1));
+        let error = feeder.join().unwrap().unwrap_err();
+        assert!(error.to_string().contains("fell behind"), "{error}");
+        assert!(received < 16000 * 10, "the feed stopped early");
+    }
+
+    /// A mono 16-bit WAV whose header claims `byte_rate`, whatever the sample rate.
+    fn wav(samples: &[i16], sample_rate: u32, byte_rate: u32) -> Vec<u8> {
+        let data_len = samples.len() as u32 * 2;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        for field in [1u16, 1] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&byte_rate.to_le_bytes());
+        for field in [2u16, 16] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        samples.iter().for_each(|s| bytes.extend_from_slice(&s.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn odd_file_rates_resample_to_the_right_length() {
+        // Archival 44056 Hz, with a byte rate implying 44056.5 Hz
+        let samples: Vec<i16> = (0..44056 * 3).map(|i| ((i as f32 * 0.05).sin() * 8000.0) as i16).collect();
+        let decoded = decode_wav(&wav(&samples, 44056, 88113)).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.samples.len()), (44056, 44056 * 3));
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let feeder = feed_on_thread(decoded, AudioConfig::default(), tx);
+        let output: usize = rx.iter().map(|p| if let AudioPacket::Samples(s) = p { s.len() } else { 0 }).sum();
+        feeder.join().unwrap().unwrap();
+        assert!(output.abs_diff(48000) <= 1, "{output} samples for 3 s");
+    }
+}
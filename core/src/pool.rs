@@ -0,0 +1,138 @@
+// Recycled sample buffers, so steady-state streaming doesn't allocate a Vec per chunk.
+//
+// The FFI boundary gets an owned copy of each chunk (the listener keeps what it is given);
+// everywhere the pipeline is done with a chunk, it hands it back instead.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::audio::TARGET_SAMPLE_RATE;
+
+// Buffers kept for reuse; anything returned beyond this is freed
+const MAX_POOLED_BUFFERS: usize = 64;
+// Larger buffers (whole utterances) are freed rather than hoarded: ~1s of samples at the
+// output rate, the default one until a processor says otherwise
+const DEFAULT_MAX_CAPACITY: usize = TARGET_SAMPLE_RATE;
+
+/// The pool shared by every pipeline stage.
+pub(crate) static SAMPLES: BufferPool = BufferPool::new();
+
+/// Allocation counters; `allocated` only grows when the pool had nothing to hand out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub allocated: u64,
+    pub reused: u64,
+    pub pooled: usize,
+}
+
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<f32>>>,
+    max_capacity: AtomicUsize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferPool {
+    pub const fn new() -> Self {
+        Self { buffers: Mutex::new(Vec::new()), max_capacity: AtomicUsize::new(DEFAULT_MAX_CAPACITY), allocated: AtomicU64::new(0), reused: AtomicU64::new(0) }
+    }
+
+    /// An empty buffer with room for at least `capacity` samples.
+    pub fn take(&self, capacity: usize) -> Vec<f32> {
+        let recycled = self.buffers.lock().ok().and_then(|mut b| b.pop());
+        match recycled {
+            Some(mut buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Keeps buffers of up to a second at `sample_rate`, so chunks at higher output rates
+    /// still come back.
+    pub fn size_for_rate(&self, sample_rate: u32) {
+        self.max_capacity.store(sample_rate as usize, Ordering::Relaxed);
+    }
+
+    /// Returns a buffer the caller is done with.
+    pub fn recycle(&self, mut buffer: Vec<f32>) {
+        if buffer.capacity() == 0 || buffer.capacity() > self.max_capacity.load(Ordering::Relaxed) {
+            return;
+        }
+        buffer.clear();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(buffer);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            pooled: self.buffers.lock().map(|b| b.len()).unwrap_or(0),
+        }
+    }
+}
+
+/// Counters of the shared pipeline pool, for profiling allocation churn.
+pub fn stats() -> PoolStats {
+    SAMPLES.stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycled_buffers_come_back_empty_and_reused() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.take(480);
+        assert!(buffer.is_empty() && buffer.capacity() >= 480);
+        buffer.extend_from_slice(&[0.5; 480]);
+        let ptr = buffer.as_ptr();
+        pool.recycle(buffer);
+        assert_eq!(pool.stats(), PoolStats { allocated: 1, reused: 0, pooled: 1 });
+
+        let again = pool.take(480);
+        assert_eq!(again.as_ptr(), ptr);
+        assert!(again.is_empty());
+        assert_eq!(pool.stats(), PoolStats { allocated: 1, reused: 1, pooled: 0 });
+
+        // A reused buffer still grows to the capacity asked for
+        pool.recycle(again);
+        assert!(pool.take(4800).capacity() >= 4800);
+    }
+
+    #[test]
+    fn oversized_empty_and_surplus_buffers_are_freed() {
+        let pool = BufferPool::new();
+        pool.recycle(Vec::new());
+        pool.recycle(Vec::with_capacity(DEFAULT_MAX_CAPACITY + 1));
+        assert_eq!(pool.stats().pooled, 0);
+
+        // A second at 48 kHz is no longer oversized once the pool is sized for it
+        pool.size_for_rate(48_000);
+        pool.recycle(Vec::with_capacity(48_000));
+        assert_eq!(pool.stats().pooled, 1);
+        pool.take(0);
+
+        for _ in 0..MAX_POOLED_BUFFERS + 10 {
+            pool.recycle(Vec::with_capacity(480));
+        }
+        assert_eq!(pool.stats().pooled, MAX_POOLED_BUFFERS);
+    }
+}
@@ -0,0 +1,1190 @@
+// Background processing: turns `AudioPacket`s into listener callbacks.
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audio::{self, AudioConfig, AudioPacket, DispatchMode, SampleFormat, CLIP_LEVEL};
+use crate::features::MelExtractor;
+use crate::filter;
+use crate::metrics::PipelineMetrics;
+use crate::noise::NoiseProfile;
+use crate::pool;
+use crate::state::{KeywordSpotter, TranscriptionListener};
+use crate::vad::{self, Endpoint, Endpointer, SpeechClass};
+
+// Polling queue capacities used while no listener is set (~15s of 30ms chunks)
+const POLL_AUDIO_CAPACITY: usize = 512;
+const POLL_LEVEL_CAPACITY: usize = 64;
+// Utterances kept for `AppState::last_utterance_audio`, and their total length cap at the output rate
+const HISTORY_UTTERANCES: usize = 8;
+const HISTORY_MAX_SECS: usize = 120;
+// Utterances waiting for speaker assignment; the oldest is dropped past this
+const ASSIGNMENT_QUEUE_CAPACITY: usize = 32;
+/// An utterance waiting for speaker assignment: its id, audio and sample rate.
+pub(crate) type AssignmentJob = (u64, Vec<f32>, u32);
+
+/// Fans processed output out to the listener, or to the polling queues while none is set.
+pub(crate) struct Dispatcher {
+    pub(crate) listener: Mutex<Option<Arc<dyn TranscriptionListener>>>,
+    pub(crate) audio_queue: (Sender<Vec<f32>>, Receiver<Vec<f32>>),
+    pub(crate) level_queue: (Sender<f32>, Receiver<f32>),
+    pub(crate) assignment_queue: (Sender<AssignmentJob>, Receiver<AssignmentJob>),
+    // RNG state for i16 dithering
+    dither_state: Mutex<u64>,
+    // When the last clipped level was dispatched
+    last_clip: Mutex<Option<Instant>>,
+    // Recently dispatched utterances, oldest first
+    history: Mutex<VecDeque<Vec<f32>>>,
+    // With `mel_bands` set: the extractor, rebuilt whenever its parameters change
+    features: Mutex<Option<MelExtractor>>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new() -> Self {
+        Self {
+            listener: Mutex::new(None),
+            audio_queue: bounded(POLL_AUDIO_CAPACITY),
+            level_queue: bounded(POLL_LEVEL_CAPACITY),
+            assignment_queue: bounded(ASSIGNMENT_QUEUE_CAPACITY),
+            dither_state: Mutex::new(0x2545_F491_4F6C_DD1D),
+            last_clip: Mutex::new(None),
+            history: Mutex::new(VecDeque::new()),
+            features: Mutex::new(None),
+        }
+    }
+
+    // Looked up per dispatch (never cached), so a swapped listener takes over at the next callback
+    fn listener(&self) -> Option<Arc<dyn TranscriptionListener>> {
+        self.listener.lock().ok().and_then(|l| l.clone())
+    }
+
+    /// Runs `f` against the current listener, if any.
+    pub(crate) fn notify(&self, f: impl FnOnce(&dyn TranscriptionListener)) {
+        if let Some(listener) = self.listener() {
+            f(listener.as_ref());
+        }
+    }
+
+    fn audio(&self, config: &AudioConfig, data: Vec<f32>, utterance_id: u64) {
+        if config.mel_bands > 0 {
+            self.features(config, &data, utterance_id);
+            pool::SAMPLES.recycle(data);
+            return;
+        }
+        match self.listener() {
+            Some(listener) => match config.sample_format {
+                // The listener keeps what it is given, so it gets a copy and the chunk goes back
+                SampleFormat::F32 => {
+                    listener.on_audio_data(data.to_vec(), utterance_id);
+                    pool::SAMPLES.recycle(data);
+                }
+                SampleFormat::I16 => {
+                    listener.on_audio_data_i16(self.to_i16(config, &data), utterance_id);
+                    pool::SAMPLES.recycle(data);
+                }
+            },
+            None => push_bounded(&self.audio_queue, data),
+        }
+    }
+
+    /// `on_features` in place of audio; only for a listener, never queued for polling.
+    fn features(&self, config: &AudioConfig, data: &[f32], utterance_id: u64) {
+        let Some(listener) = self.listener() else { return };
+        let frames = {
+            let Ok(mut slot) = self.features.lock() else { return };
+            let (bands, window_ms, hop_ms, rate) = (config.mel_bands, config.mel_window_ms, config.mel_hop_ms, config.output_sample_rate);
+            if !slot.as_ref().is_some_and(|e| e.is_built_for(bands, window_ms, hop_ms, rate)) {
+                *slot = Some(MelExtractor::new(bands, window_ms, hop_ms, rate));
+            }
+            let Some(extractor) = slot.as_mut() else { return };
+            // Whole utterances stand alone; only a stream carries windows across calls
+            if config.dispatch_mode == DispatchMode::Utterance {
+                extractor.reset();
+            }
+            extractor.push(data)
+        };
+        if !frames.is_empty() {
+            listener.on_features(frames, config.mel_bands, utterance_id);
+        }
+    }
+
+    fn to_i16(&self, config: &AudioConfig, data: &[f32]) -> Vec<i16> {
+        match self.dither_state.lock() {
+            Ok(mut state) if config.dither_i16 => audio::f32_to_i16_dithered(data, &mut state),
+            _ => audio::f32_to_i16(data),
+        }
+    }
+
+    fn level(&self, level: f32) {
+        if level >= CLIP_LEVEL {
+            if let Ok(mut last_clip) = self.last_clip.lock() {
+                *last_clip = Some(Instant::now());
+            }
+        }
+        match self.listener() {
+            Some(listener) => listener.on_level_changed(level),
+            None => push_bounded(&self.level_queue, level),
+        }
+    }
+
+    /// `on_utterance_audio`; interims are only for a listener, never queued for polling.
+    fn utterance(&self, config: &AudioConfig, data: Vec<f32>, utterance_id: u64, is_final: bool) {
+        match self.listener() {
+            Some(listener) => listener.on_utterance_audio(data, utterance_id, is_final),
+            None if is_final => self.audio(config, data, utterance_id),
+            None => {}
+        }
+    }
+
+    /// Keeps a copy of a dispatched utterance, evicting the oldest past either bound.
+    fn remember_utterance(&self, config: &AudioConfig, utterance: &[f32]) {
+        let max_samples = config.output_sample_rate as usize * HISTORY_MAX_SECS;
+        let Ok(mut history) = self.history.lock() else { return };
+        history.push_back(utterance.to_vec());
+        let mut total: usize = history.iter().map(|u| u.len()).sum();
+        while history.len() > HISTORY_UTTERANCES || (total > max_samples && history.len() > 1) {
+            if let Some(evicted) = history.pop_front() {
+                total -= evicted.len();
+            }
+        }
+    }
+
+    /// The `n`th most recent dispatched utterance (0 = the last one).
+    pub(crate) fn recent_utterance(&self, n: usize) -> Option<Vec<f32>> {
+        let history = self.history.lock().ok()?;
+        history.iter().rev().nth(n).cloned()
+    }
+
+    /// Whether a clipped level was seen within the last `hold`; clears itself afterwards.
+    pub(crate) fn clip_latched(&self, hold: Duration) -> bool {
+        let Ok(mut last_clip) = self.last_clip.lock() else { return false };
+        match *last_clip {
+            Some(at) if at.elapsed() <= hold => true,
+            Some(_) => {
+                *last_clip = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Drops the oldest entry rather than blocking the pipeline when nobody polls.
+fn push_bounded<T>(queue: &(Sender<T>, Receiver<T>), item: T) {
+    if let Err(TrySendError::Full(item)) = queue.0.try_send(item) {
+        let _ = queue.1.try_recv();
+        let _ = queue.0.try_send(item);
+    }
+}
+
+/// Hands a complete utterance to the listener, unless it is empty or filtered as non-speech.
+pub(crate) fn finalize_utterance(
+    dispatcher: &Dispatcher,
+    config: &AudioConfig,
+    sample_rate: u32,
+    mut utterance: Vec<f32>,
+    utterance_id: u64,
+) {
+    if utterance.is_empty() {
+        return;
+    }
+
+    if config.skip_non_speech {
+        if let SpeechClass::NonSpeech(reason) = vad::classify_utterance(&utterance, sample_rate) {
+            tracing::info!("Background: Skipping non-speech utterance ({})", reason);
+            dispatcher.notify(|l| l.on_audio_skipped(reason));
+            return;
+        }
+    }
+
+    if let Some(target) = config.loudness_target_lufs {
+        filter::normalize_loudness(&mut utterance, sample_rate, target);
+    }
+    dispatcher.remember_utterance(config, &utterance);
+    if config.assign_speakers {
+        if dispatcher.assignment_queue.0.is_full() {
+            tracing::warn!("Background: Speaker assignment is falling behind; skipping the oldest waiting utterance");
+        }
+        push_bounded(&dispatcher.assignment_queue, (utterance_id, utterance.clone(), sample_rate));
+    }
+    if config.interim_interval_ms > 0 {
+        dispatcher.utterance(config, utterance, utterance_id, true);
+    } else {
+        dispatcher.audio(config, utterance, utterance_id);
+    }
+}
+
+// Packets held while suspended in buffering mode (~60s of chunks plus levels)
+const MAX_HELD_PACKETS: usize = 4000;
+// Chunks held while waiting for a stream's Format (~6s)
+const MAX_AWAITING_FORMAT: usize = 200;
+
+/// State of the background loop that consumes the packet channel.
+pub(crate) struct ProcessingLoop {
+    dispatcher: Arc<Dispatcher>,
+    config: Arc<Mutex<AudioConfig>>,
+    metrics: Arc<PipelineMetrics>,
+    // Rate of the current stream; None from a recording start (or startup) until its Format
+    sample_rate: Option<u32>,
+    // Samples that arrived while the rate was unknown, replayed once it is known
+    awaiting_format: VecDeque<Vec<f32>>,
+    audio_buffer: Vec<f32>,
+    endpointer: Endpointer,
+    frames: FrameAssembler,
+    // Id of the utterance currently being captured; bumped at every utterance boundary
+    utterance_id: u64,
+    spotter: Option<Arc<dyn KeywordSpotter>>,
+    // With a spotter: whether the keyword opened the gate for the current utterance
+    keyword_triggered: bool,
+    // Some while suspended; holds the packets to replay if buffering
+    suspended: Option<Suspension>,
+    noise_capture: Option<NoiseCapture>,
+    // Length of `audio_buffer` when the last interim was dispatched
+    interim_sent: usize,
+    // Id and audio of the utterance the last flush closed, until a new one starts
+    last_flushed: Option<(u64, Vec<f32>)>,
+}
+
+/// A calibration clip being recorded for `AppState::capture_noise_profile`.
+struct NoiseCapture {
+    needed: usize,
+    samples: Vec<f32>,
+    reply: Sender<NoiseProfile>,
+}
+
+/// Re-buffers the streamed chunks into frames of exactly `dispatch_frame_size` samples.
+#[derive(Default)]
+struct FrameAssembler {
+    pending: Vec<f32>,
+}
+
+impl FrameAssembler {
+    fn push(&mut self, data: &[f32], frame_size: usize) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / frame_size * frame_size;
+        let frames = self.pending[..whole]
+            .chunks_exact(frame_size)
+            .map(|f| {
+                let mut frame = pool::SAMPLES.take(frame_size);
+                frame.extend_from_slice(f);
+                frame
+            })
+            .collect();
+        self.pending.drain(..whole);
+        frames
+    }
+
+    /// The zero-padded remainder at a flush, or None if it should carry over instead.
+    fn finish(&mut self, frame_size: usize, pad: bool) -> Option<Vec<f32>> {
+        if !pad || self.pending.is_empty() {
+            return None;
+        }
+        let mut frame = std::mem::take(&mut self.pending);
+        frame.resize(frame_size, 0.0);
+        Some(frame)
+    }
+}
+
+struct Suspension {
+    buffer: bool,
+    held: VecDeque<AudioPacket>,
+}
+
+impl ProcessingLoop {
+    pub(crate) fn new(dispatcher: Arc<Dispatcher>, config: Arc<Mutex<AudioConfig>>, metrics: Arc<PipelineMetrics>) -> Self {
+        let initial = config.lock().map(|c| c.clone()).unwrap_or_default();
+        Self {
+            dispatcher,
+            config,
+            metrics,
+            sample_rate: None,
+            awaiting_format: VecDeque::new(),
+            audio_buffer: Vec::new(),
+            frames: FrameAssembler::default(),
+            endpointer: Endpointer::new(&initial, initial.output_sample_rate),
+            utterance_id: 0,
+            spotter: None,
+            keyword_triggered: false,
+            suspended: None,
+            noise_capture: None,
+            interim_sent: 0,
+            last_flushed: None,
+        }
+    }
+
+    /// Closes the current utterance, returning its id.
+    fn end_utterance(&mut self) -> u64 {
+        let id = self.utterance_id;
+        self.utterance_id += 1;
+        self.interim_sent = 0;
+        id
+    }
+
+    pub(crate) fn handle(&mut self, packet: AudioPacket) {
+        match packet {
+            AudioPacket::Suspend { buffer } => {
+                tracing::info!("Background: Processing suspended (buffering: {})", buffer);
+                self.suspended = Some(Suspension { buffer, held: VecDeque::new() });
+            }
+            AudioPacket::Resume => {
+                let Some(suspension) = self.suspended.take() else { return };
+                tracing::info!("Background: Processing resumed, replaying {} packets", suspension.held.len());
+                for held in suspension.held {
+                    self.process(held);
+                }
+            }
+            AudioPacket::ProfileNoise { samples, reply } => {
+                self.noise_capture = Some(NoiseCapture { needed: samples, samples: Vec::with_capacity(samples), reply });
+            }
+            // Applies immediately, even while suspended
+            AudioPacket::SetSpotter(spotter) => {
+                self.spotter = spotter;
+                self.keyword_triggered = false;
+            }
+            packet => match self.suspended.as_mut() {
+                Some(suspension) if suspension.buffer => {
+                    if suspension.held.len() >= MAX_HELD_PACKETS {
+                        // Only audio and meter packets give way; formats, flushes and errors are all replayed
+                        let oldest = suspension.held.iter().position(|held| {
+                            matches!(held, AudioPacket::Samples(_) | AudioPacket::DeviceSamples(..) | AudioPacket::Level(_) | AudioPacket::Waveform(_) | AudioPacket::LatencyProbe(_))
+                        });
+                        if let Some(AudioPacket::Samples(data)) = oldest.and_then(|i| suspension.held.remove(i)) {
+                            pool::SAMPLES.recycle(data);
+                        }
+                    }
+                    suspension.held.push_back(packet);
+                }
+                Some(_) => self.discard(packet),
+                None => self.process(packet),
+            },
+        }
+    }
+
+    /// Suspended without buffering: drop the audio but keep bookkeeping current. Errors and
+    /// recording stops still reach the listener, the app can't ask for them again.
+    fn discard(&mut self, packet: AudioPacket) {
+        match packet {
+            AudioPacket::Format(sr) => {
+                self.awaiting_format.drain(..).for_each(|data| pool::SAMPLES.recycle(data));
+                self.start_stream(sr);
+            }
+            AudioPacket::RecordingStarted => {
+                self.sample_rate = None;
+                self.keyword_triggered = false;
+            }
+            AudioPacket::Flush | AudioPacket::Discard => self.drop_utterance(),
+            AudioPacket::Samples(data) => pool::SAMPLES.recycle(data),
+            AudioPacket::DeviceSamples(..) | AudioPacket::Level(_) | AudioPacket::Waveform(_) => {}
+            packet @ (AudioPacket::StreamError(_)
+            | AudioPacket::RecordingStopped(_)
+            | AudioPacket::ContinueUtterance
+            | AudioPacket::LatencyProbe(_)) => self.process(packet),
+            // Handled by `handle` before suspension is consulted
+            AudioPacket::Suspend { .. }
+            | AudioPacket::Resume
+            | AudioPacket::Shutdown
+            | AudioPacket::SetSpotter(_)
+            | AudioPacket::ProfileNoise { .. } => {}
+        }
+    }
+
+    fn start_stream(&mut self, sample_rate: u32) {
+        let config = self.current_config();
+        tracing::info!("Background: Audio stream started at {} Hz", sample_rate);
+        self.sample_rate = Some(sample_rate);
+        self.endpointer = Endpointer::new(&config, sample_rate);
+    }
+
+    /// The current stream's rate; only unknown between a recording start and its Format,
+    /// when no samples are processed.
+    fn rate(&self) -> u32 {
+        self.sample_rate.unwrap_or(audio::TARGET_SAMPLE_RATE as u32)
+    }
+
+    fn hold_until_format(&mut self, data: Vec<f32>) {
+        if self.awaiting_format.len() >= MAX_AWAITING_FORMAT {
+            tracing::warn!("Background: Still no stream format; dropping the oldest waiting audio");
+            if let Some(dropped) = self.awaiting_format.pop_front() {
+                pool::SAMPLES.recycle(dropped);
+            }
+        }
+        self.awaiting_format.push_back(data);
+    }
+
+    fn current_config(&self) -> AudioConfig {
+        self.config.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    fn process(&mut self, packet: AudioPacket) {
+        let packet = match packet {
+            // Processed with a stale rate, the VAD and loudness maths would be off
+            AudioPacket::Samples(data) if self.sample_rate.is_none() => return self.hold_until_format(data),
+            packet => packet,
+        };
+        let config = self.current_config();
+        if let AudioPacket::Samples(data) = &packet {
+            self.record_noise(data);
+        }
+        match packet {
+            AudioPacket::Format(sr) => {
+                self.start_stream(sr);
+                while let Some(data) = self.awaiting_format.pop_front() {
+                    self.process(AudioPacket::Samples(data));
+                }
+            }
+            AudioPacket::RecordingStarted => {
+                tracing::info!("Background: Recording started, waiting for the stream format");
+                self.sample_rate = None;
+                // Every recording waits for its own keyword
+                self.keyword_triggered = false;
+            }
+            AudioPacket::Samples(data) if !self.keyword_gate(&config, &data) => {
+                self.keep_warm(&config, data.len());
+                pool::SAMPLES.recycle(data);
+            }
+            AudioPacket::Samples(data) => match config.dispatch_mode {
+                // Streaming Mode: Forward immediately to listener (Swift/Inference)
+                DispatchMode::Streaming => self.stream(&config, data),
+                DispatchMode::Utterance => {
+                    if self.audio_buffer.is_empty() {
+                        // A new utterance: the flushed one can no longer be continued
+                        self.last_flushed = None;
+                    }
+                    self.audio_buffer.extend_from_slice(&data);
+                    let chunk_len = data.len();
+                    let endpoint = self.endpointer.process(&data, &config);
+                    pool::SAMPLES.recycle(data);
+                    if let Some(endpoint) = endpoint {
+                        if endpoint == Endpoint::Silence {
+                            let keep = self.rate() as usize * config.vad_trailing_silence_ms as usize / 1000;
+                            let cut = self.endpointer.trailing_silence().saturating_sub(keep);
+                            self.audio_buffer.truncate(self.audio_buffer.len().saturating_sub(cut));
+                        }
+                        let utterance = std::mem::take(&mut self.audio_buffer);
+                        let id = self.end_utterance();
+                        finalize_utterance(&self.dispatcher, &config, self.rate(), utterance, id);
+                    } else if config.vad_enabled && !self.endpointer.is_speech() {
+                        self.trim_lead_in(&config, chunk_len);
+                        self.keep_warm(&config, chunk_len);
+                    } else {
+                        self.interim(&config);
+                    }
+                }
+            },
+            // Multi-device capture is always streamed so each chunk keeps its device tag
+            AudioPacket::DeviceSamples(device, data) => {
+                self.dispatcher.notify(|l| l.on_device_audio_data(device, data));
+            }
+            AudioPacket::Level(lvl) => self.dispatcher.level(lvl),
+            AudioPacket::Waveform(envelope) => self.dispatcher.notify(|l| l.on_waveform(envelope)),
+            // Streamed chunks have reached the listener by now; buffered ones the VAD
+            AudioPacket::LatencyProbe(captured_at) => self.metrics.record_latency(captured_at.elapsed()),
+            AudioPacket::StreamError(error) => self.dispatcher.notify(|l| l.on_error(error)),
+            AudioPacket::RecordingStopped(reason) => self.dispatcher.notify(|l| l.on_recording_stopped(reason)),
+            AudioPacket::ContinueUtterance => {
+                if let Some((id, mut utterance)) = self.last_flushed.take() {
+                    tracing::info!("Background: Continuing utterance {} ({} samples)", id, utterance.len());
+                    utterance.append(&mut self.audio_buffer);
+                    self.audio_buffer = utterance;
+                    self.utterance_id = id;
+                    self.interim_sent = 0;
+                }
+            }
+            AudioPacket::Flush => {
+                tracing::info!("Background: Flush processing (End of capture)");
+                if !self.awaiting_format.is_empty() {
+                    // No stream ever announced itself (it failed to open); the audio can't be placed
+                    tracing::warn!("Background: Dropping {} chunks that never got a stream format", self.awaiting_format.len());
+                    self.awaiting_format.drain(..).for_each(|data| pool::SAMPLES.recycle(data));
+                }
+                self.flush(&config);
+            }
+            AudioPacket::Discard => {
+                tracing::info!("Background: Discarding the open utterance (stopped while paused)");
+                self.drop_utterance();
+            }
+            // Control packets are handled in `handle`
+            AudioPacket::Suspend { .. }
+            | AudioPacket::Resume
+            | AudioPacket::Shutdown
+            | AudioPacket::SetSpotter(_)
+            | AudioPacket::ProfileNoise { .. } => {}
+        }
+    }
+
+    fn flush(&mut self, config: &AudioConfig) {
+        self.endpointer.reset();
+        let frame_size = config.dispatch_frame_size as usize;
+        if frame_size > 0 {
+            if let Some(frame) = self.frames.finish(frame_size, config.pad_final_frame) {
+                self.dispatcher.audio(config, frame, self.utterance_id);
+            }
+        }
+        let utterance = std::mem::take(&mut self.audio_buffer);
+        let id = self.end_utterance();
+        if !utterance.is_empty() {
+            self.last_flushed = Some((id, utterance.clone()));
+        }
+        finalize_utterance(&self.dispatcher, config, self.rate(), utterance, id);
+    }
+
+    /// Ends the utterance without dispatching any of it.
+    fn drop_utterance(&mut self) {
+        self.endpointer.reset();
+        self.audio_buffer.clear();
+        // Discarded audio must not come back through `ContinueUtterance` either
+        self.last_flushed = None;
+        self.frames.pending.clear();
+        self.end_utterance();
+    }
+
+    /// Dispatches the utterance so far once another `interim_interval_ms` of it has built up.
+    fn interim(&mut self, config: &AudioConfig) {
+        let interval = self.rate() as usize * config.interim_interval_ms as usize / 1000;
+        if interval == 0 || self.audio_buffer.len() - self.interim_sent < interval {
+            return;
+        }
+        self.interim_sent = self.audio_buffer.len();
+        self.dispatcher.utterance(config, self.audio_buffer.clone(), self.utterance_id, false);
+    }
+
+    /// Feeds a pending noise calibration; replies once it has enough audio.
+    fn record_noise(&mut self, data: &[f32]) {
+        let Some(capture) = self.noise_capture.as_mut() else { return };
+        let take = (capture.needed - capture.samples.len()).min(data.len());
+        capture.samples.extend_from_slice(&data[..take]);
+        if capture.samples.len() >= capture.needed {
+            if let Some(capture) = self.noise_capture.take() {
+                let profile = NoiseProfile::from_samples(&capture.samples, self.rate());
+                tracing::info!("Background: Noise profile captured (floor {:.5} RMS)", profile.floor_rms);
+                let _ = capture.reply.send(profile);
+            }
+        }
+    }
+
+    /// Waiting for speech onset: drops all but the last `vad_leading_silence_ms` of the wait
+    /// from the buffer, leaving whatever an earlier `ContinueUtterance` put before it.
+    fn trim_lead_in(&mut self, config: &AudioConfig, chunk_len: usize) {
+        let keep = self.rate() as usize * config.vad_leading_silence_ms as usize / 1000;
+        // Earlier chunks of the wait were trimmed to `keep` already
+        let waited = (self.endpointer.waited_for_onset().saturating_sub(chunk_len).min(keep) + chunk_len).min(self.audio_buffer.len());
+        let excess = waited.saturating_sub(keep);
+        if excess > 0 {
+            let start = self.audio_buffer.len() - waited;
+            self.audio_buffer.drain(start..start + excess);
+        }
+    }
+
+    /// With `keep_warm_silence`, stands in for a chunk that isn't dispatched (silence
+    /// between utterances, closed keyword gate) with zeros, so a streaming model keeps
+    /// receiving input at the chunk cadence.
+    fn keep_warm(&self, config: &AudioConfig, len: usize) {
+        if !config.keep_warm_silence || len == 0 {
+            return;
+        }
+        let mut silence = pool::SAMPLES.take(len);
+        silence.resize(len, 0.0);
+        self.dispatcher.audio(config, silence, self.utterance_id);
+    }
+
+    /// Feeds the keyword spotter; returns whether `data` should be dispatched.
+    fn keyword_gate(&mut self, config: &AudioConfig, data: &[f32]) -> bool {
+        let Some(spotter) = self.spotter.clone() else { return true };
+        let heard = spotter.process(data.to_vec());
+        match (self.keyword_triggered, heard) {
+            (false, true) => {
+                tracing::info!("Background: Keyword detected, dispatch opened");
+                self.keyword_triggered = true;
+                // The keyword itself is not part of the utterance
+                false
+            }
+            (true, true) => {
+                tracing::info!("Background: Keyword detected, utterance closed");
+                self.keyword_triggered = false;
+                self.flush(config);
+                false
+            }
+            (triggered, false) => triggered,
+        }
+    }
+
+    fn stream(&mut self, config: &AudioConfig, data: Vec<f32>) {
+        let frame_size = config.dispatch_frame_size as usize;
+        if frame_size == 0 {
+            // Framing was switched off: hand over any remainder before the plain chunks
+            if !self.frames.pending.is_empty() {
+                let rest = std::mem::take(&mut self.frames.pending);
+                self.dispatcher.audio(config, rest, self.utterance_id);
+            }
+            self.dispatcher.audio(config, data, self.utterance_id);
+            return;
+        }
+        for frame in self.frames.push(&data, frame_size) {
+            self.dispatcher.audio(config, frame, self.utterance_id);
+        }
+        pool::SAMPLES.recycle(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{Event, MarkerSpotter, RecordingListener, KEYWORD_MARKER};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// A loop dispatching to a fresh recording listener, its stream already at 16 kHz.
+    fn processing(config: AudioConfig) -> (ProcessingLoop, RecordingListener) {
+        let listener = RecordingListener::default();
+        let dispatcher = Arc::new(Dispatcher::new());
+        *dispatcher.listener.lock().unwrap() = Some(Arc::new(listener.clone()));
+        let mut processing = ProcessingLoop::new(dispatcher, Arc::new(Mutex::new(config)), Arc::new(PipelineMetrics::new()));
+        processing.handle(AudioPacket::Format(16000));
+        (processing, listener)
+    }
+
+    fn streaming() -> AudioConfig {
+        AudioConfig { dispatch_mode: DispatchMode::Streaming, dispatch_frame_size: 0, ..Default::default() }
+    }
+
+    fn chunk(value: f32) -> AudioPacket {
+        AudioPacket::Samples(vec![value; 480])
+    }
+
+    #[test]
+    fn suspended_processing_calls_no_listener_and_resume_replays_in_order() {
+        let (mut processing, listener) = processing(streaming());
+        processing.handle(AudioPacket::Suspend { buffer: true });
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Level(0.1));
+        processing.handle(chunk(0.2));
+        assert_eq!(listener.calls(), 0);
+
+        processing.handle(AudioPacket::Resume);
+        processing.handle(chunk(0.3));
+        let firsts: Vec<f32> = listener.audio().iter().map(|(data, _)| data[0]).collect();
+        assert_eq!(firsts, [0.1, 0.2, 0.3]);
+        assert!(listener.events().contains(&Event::Level(0.1)));
+    }
+
+    #[test]
+    fn suspended_processing_without_buffering_discards() {
+        let (mut processing, listener) = processing(streaming());
+        processing.handle(AudioPacket::Suspend { buffer: false });
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Level(0.1));
+        processing.handle(AudioPacket::Resume);
+        assert_eq!(listener.calls(), 0);
+
+        processing.handle(chunk(0.2));
+        assert_eq!(listener.audio().len(), 1);
+        assert_eq!(listener.audio()[0].0[0], 0.2);
+    }
+
+    #[test]
+    fn suspended_processing_without_buffering_still_reports_errors_and_stops() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+        let before = listener.calls();
+
+        processing.handle(AudioPacket::Suspend { buffer: false });
+        processing.handle(AudioPacket::StreamError(crate::state::SupraSonicError::AudioBackend("xrun".to_string())));
+        processing.handle(AudioPacket::RecordingStopped(crate::audio::StopReason::SilenceTimeout));
+        // Reopens the flushed utterance even though the listener isn't called
+        processing.handle(AudioPacket::ContinueUtterance);
+        assert_eq!(listener.calls(), before + 2);
+        assert!(listener.events().contains(&Event::Error(r#"AudioBackend("xrun")"#.to_string())));
+
+        processing.handle(AudioPacket::Resume);
+        processing.handle(chunk(0.2));
+        processing.handle(AudioPacket::Flush);
+        let finals = listener.audio();
+        assert_eq!(finals.len(), 2);
+        assert_eq!(finals[0].1, finals[1].1);
+        assert_eq!(finals[1].0.len(), 960);
+    }
+
+    #[test]
+    fn a_full_suspension_buffer_evicts_audio_not_control_packets() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(AudioPacket::Suspend { buffer: true });
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+        processing.handle(AudioPacket::Format(8000));
+        for _ in 0..MAX_HELD_PACKETS {
+            processing.handle(chunk(0.2));
+        }
+        processing.handle(AudioPacket::Resume);
+        processing.handle(AudioPacket::Flush);
+
+        let finals = listener.audio();
+        assert_eq!(finals.len(), 1, "the first chunk went, but its flush was kept");
+        assert_eq!(finals[0].0.len(), 480 * (MAX_HELD_PACKETS - 2));
+        assert_eq!(processing.rate(), 8000);
+    }
+
+    fn utterance_mode() -> AudioConfig {
+        AudioConfig { dispatch_mode: DispatchMode::Utterance, ..Default::default() }
+    }
+
+    #[test]
+    fn utterance_ids_are_unique_and_increasing() {
+        let (mut processing, listener) = processing(utterance_mode());
+        for _ in 0..4 {
+            processing.handle(chunk(0.1));
+            processing.handle(chunk(0.1));
+            processing.handle(AudioPacket::Flush);
+        }
+        let ids: Vec<u64> = listener.audio().iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids.len(), 4);
+        assert!(ids.windows(2).all(|w| w[1] > w[0]), "{ids:?}");
+    }
+
+    #[test]
+    fn streamed_chunks_share_their_utterance_id_until_a_flush() {
+        let (mut processing, listener) = processing(streaming());
+        processing.handle(chunk(0.1));
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+        processing.handle(chunk(0.1));
+        let ids: Vec<u64> = listener.audio().iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0], ids[1]);
+        assert!(ids[2] > ids[1]);
+    }
+
+    fn framed(pad_final_frame: bool) -> AudioConfig {
+        AudioConfig { dispatch_frame_size: 512, pad_final_frame, ..streaming() }
+    }
+
+    #[test]
+    fn frames_are_exactly_the_configured_size() {
+        let (mut processing, listener) = processing(framed(false));
+        // 480-sample chunks don't divide into 512-sample frames
+        for _ in 0..16 {
+            processing.handle(chunk(0.1));
+        }
+        let frames = listener.audio();
+        assert_eq!(frames.len(), 16 * 480 / 512);
+        assert!(frames.iter().all(|(f, _)| f.len() == 512));
+    }
+
+    #[test]
+    fn frame_remainder_carries_over_a_flush_unless_padded() {
+        let (mut held, listener) = processing(framed(false));
+        held.handle(chunk(0.1));
+        held.handle(AudioPacket::Flush);
+        assert!(listener.audio().is_empty());
+        // The held 480 complete a frame with the next chunk
+        held.handle(chunk(0.2));
+        let frames = listener.audio();
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].0[479], frames[0].0[480]), (0.1, 0.2));
+
+        let (mut padded, listener) = processing(framed(true));
+        padded.handle(chunk(0.1));
+        padded.handle(AudioPacket::Flush);
+        let frames = listener.audio();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.len(), 512);
+        assert_eq!((frames[0].0[479], frames[0].0[480]), (0.1, 0.0));
+    }
+
+    #[test]
+    fn keyword_opens_dispatch_and_the_next_one_closes_the_utterance() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(AudioPacket::SetSpotter(Some(Arc::new(MarkerSpotter))));
+        processing.handle(chunk(0.1));
+        processing.handle(chunk(0.1));
+        assert_eq!(listener.calls(), 0);
+
+        processing.handle(chunk(KEYWORD_MARKER));
+        processing.handle(chunk(0.2));
+        processing.handle(chunk(0.3));
+        assert!(listener.audio().is_empty());
+        // The second match flushes what was said after the first, without either keyword
+        processing.handle(chunk(KEYWORD_MARKER));
+        let utterances = listener.audio();
+        assert_eq!(utterances.len(), 1);
+        assert_eq!(utterances[0].0.len(), 960);
+        assert_eq!((utterances[0].0[0], utterances[0].0[959]), (0.2, 0.3));
+
+        // Re-armed: nothing more until the keyword is heard again
+        processing.handle(chunk(0.4));
+        processing.handle(AudioPacket::Flush);
+        assert_eq!(listener.audio().len(), 1);
+    }
+
+    #[test]
+    fn a_new_recording_waits_for_its_own_keyword() {
+        let (mut processing, listener) = processing(streaming());
+        processing.handle(AudioPacket::SetSpotter(Some(Arc::new(MarkerSpotter))));
+        processing.handle(chunk(KEYWORD_MARKER));
+        processing.handle(chunk(0.2));
+        assert_eq!(listener.audio().len(), 1);
+
+        processing.handle(AudioPacket::Flush);
+        processing.handle(AudioPacket::RecordingStarted);
+        processing.handle(AudioPacket::Format(16000));
+        processing.handle(chunk(0.3));
+        assert_eq!(listener.audio().len(), 1);
+    }
+
+    #[test]
+    fn a_single_clipped_level_keeps_the_latch_set_for_the_hold() {
+        let dispatcher = Dispatcher::new();
+        let hold = Duration::from_millis(200);
+        dispatcher.level(0.9);
+        assert!(!dispatcher.clip_latched(hold));
+
+        dispatcher.level(1.0);
+        // The meter falls right back, the latch doesn't
+        dispatcher.level(0.1);
+        assert!(dispatcher.clip_latched(hold));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(dispatcher.clip_latched(hold));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!dispatcher.clip_latched(hold));
+        // Cleared for good, even under a longer hold
+        assert!(!dispatcher.clip_latched(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn kept_warm_silence_flows_at_the_chunk_cadence() {
+        let vad = |keep_warm_silence| AudioConfig { vad_enabled: true, keep_warm_silence, ..utterance_mode() };
+        let (mut warm, listener) = processing(vad(true));
+        for _ in 0..10 {
+            warm.handle(chunk(0.0));
+        }
+        let audio = listener.audio();
+        assert_eq!(audio.len(), 10);
+        assert!(audio.iter().all(|(data, _)| data.len() == 480 && data.iter().all(|&s| s == 0.0)));
+
+        let (mut quiet, listener) = processing(vad(false));
+        for _ in 0..10 {
+            quiet.handle(chunk(0.0));
+        }
+        assert!(listener.audio().is_empty());
+    }
+
+    #[test]
+    fn a_closed_keyword_gate_sends_zeros_when_kept_warm() {
+        let (mut processing, listener) = processing(AudioConfig { keep_warm_silence: true, ..streaming() });
+        processing.handle(AudioPacket::SetSpotter(Some(Arc::new(MarkerSpotter))));
+        for _ in 0..5 {
+            processing.handle(chunk(0.3));
+        }
+        let audio = listener.audio();
+        assert_eq!(audio.len(), 5);
+        // The gated speech itself never leaks through
+        assert!(audio.iter().all(|(data, _)| data.iter().all(|&s| s == 0.0)));
+    }
+
+    fn swap_listener(processing: &ProcessingLoop, listener: &RecordingListener) {
+        *processing.dispatcher.listener.lock().unwrap() = Some(Arc::new(listener.clone()));
+    }
+
+    #[test]
+    fn an_utterance_in_flight_goes_whole_to_the_new_listener() {
+        let (mut processing, old) = processing(utterance_mode());
+        processing.handle(chunk(0.1));
+        processing.handle(chunk(0.2));
+        let new = RecordingListener::default();
+        swap_listener(&processing, &new);
+        processing.handle(chunk(0.3));
+        processing.handle(AudioPacket::Flush);
+
+        assert!(old.audio().is_empty());
+        let audio = new.audio();
+        assert_eq!(audio.len(), 1);
+        assert_eq!(audio[0].0.len(), 3 * 480);
+        assert_eq!((audio[0].0[0], audio[0].0[3 * 480 - 1]), (0.1, 0.3));
+    }
+
+    #[test]
+    fn swapping_listeners_during_dispatch_neither_loses_nor_repeats_chunks() {
+        let (mut processing, first) = processing(streaming());
+        let second = RecordingListener::default();
+        let dispatcher = processing.dispatcher.clone();
+        let (done, swaps) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+        let swapper = {
+            let (listeners, done, swaps) = ([first.clone(), second.clone()], done.clone(), swaps.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let n = swaps.fetch_add(1, Ordering::SeqCst);
+                    *dispatcher.listener.lock().unwrap() = Some(Arc::new(listeners[n % 2].clone()));
+                    std::thread::yield_now();
+                }
+            })
+        };
+        while swaps.load(Ordering::SeqCst) < 2 {
+            std::thread::yield_now();
+        }
+        for i in 0..2000 {
+            processing.handle(chunk(i as f32));
+        }
+        done.store(true, Ordering::SeqCst);
+        swapper.join().unwrap();
+
+        let received = |l: &RecordingListener| -> Vec<f32> { l.audio().iter().map(|(data, _)| data[0]).collect() };
+        let (a, b) = (received(&first), received(&second));
+        // Each listener saw its share in order, and together they saw every chunk once
+        assert!(a.windows(2).all(|w| w[0] < w[1]) && b.windows(2).all(|w| w[0] < w[1]));
+        let mut all = [a, b].concat();
+        all.sort_by(f32::total_cmp);
+        assert_eq!(all, (0..2000).map(|i| i as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn history_holds_the_most_recent_utterances_in_order() {
+        let (mut processing, _listener) = processing(utterance_mode());
+        for i in 0..10 {
+            processing.handle(chunk(i as f32));
+            processing.handle(AudioPacket::Flush);
+        }
+        let dispatcher = &processing.dispatcher;
+        for n in 0..HISTORY_UTTERANCES {
+            assert_eq!(dispatcher.recent_utterance(n), Some(vec![(9 - n) as f32; 480]));
+        }
+        assert_eq!(dispatcher.recent_utterance(HISTORY_UTTERANCES), None);
+    }
+
+    #[test]
+    fn history_size_cap_follows_the_output_rate() {
+        // 600k samples is 37.5 s at 16 kHz but 75 s at 8 kHz, against a 120 s cap
+        let kept = |rate: u32| {
+            let (config, dispatcher) = (AudioConfig { output_sample_rate: rate, ..Default::default() }, Dispatcher::new());
+            for i in 0..4 {
+                dispatcher.remember_utterance(&config, &vec![i as f32; 600_000]);
+            }
+            (0..).take_while(|&n| dispatcher.recent_utterance(n).is_some()).count()
+        };
+        assert_eq!(kept(16000), 3);
+        assert_eq!(kept(8000), 1);
+    }
+
+    #[test]
+    fn interims_resend_the_whole_buffer_under_one_id_until_the_final() {
+        let (mut processing, listener) = processing(AudioConfig { interim_interval_ms: 60, ..utterance_mode() });
+        for _ in 0..6 {
+            processing.handle(chunk(0.1));
+        }
+        processing.handle(AudioPacket::Flush);
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+
+        let utterances = listener.utterances();
+        let id = utterances[0].1;
+        // Every 60ms (two chunks), the buffer so far; then all of it, final
+        assert_eq!(
+            utterances,
+            [(960, id, false), (1920, id, false), (2880, id, false), (2880, id, true), (480, id + 1, true)]
+        );
+        // With interims on, the final goes out as utterance audio only
+        assert!(listener.audio().is_empty());
+    }
+
+    /// Length of the utterance a VAD ending dispatches after 5 chunks of speech.
+    fn vad_flushed_len(vad_trailing_silence_ms: u32) -> usize {
+        let (mut processing, listener) = processing(AudioConfig { vad_enabled: true, vad_trailing_silence_ms, ..utterance_mode() });
+        for _ in 0..5 {
+            processing.handle(chunk(0.3));
+        }
+        // vad_min_silence_ms (600) of silence ends it
+        for _ in 0..20 {
+            processing.handle(chunk(0.0));
+        }
+        let audio = listener.audio();
+        assert_eq!(audio.len(), 1, "the VAD didn't end the utterance");
+        assert!(audio[0].0[..2400].iter().all(|&s| s == 0.3));
+        audio[0].0.len()
+    }
+
+    #[test]
+    fn vad_final_keeps_the_configured_trailing_silence() {
+        assert_eq!(vad_flushed_len(0), 2400);
+        assert_eq!(vad_flushed_len(300), 2400 + 4800);
+        // Never more than was waited for
+        assert_eq!(vad_flushed_len(1000), 2400 + 9600);
+    }
+
+    #[test]
+    fn mel_bands_dispatch_feature_frames_instead_of_audio() {
+        let (mut processing, listener) = processing(AudioConfig { mel_bands: 40, ..streaming() });
+        for _ in 0..33 {
+            processing.handle(chunk(0.1));
+        }
+        let events = listener.events();
+        assert!(!events.iter().any(|e| matches!(e, Event::Audio(..))), "no raw audio alongside features");
+        let values: usize = events.iter().map(|e| match e {
+            Event::Features(frames, 40, _) => frames.len(),
+            _ => 0,
+        }).sum();
+        // 15840 samples: 25 ms windows every 10 ms, carried across chunks
+        assert_eq!(values, ((15840 - 400) / 160 + 1) * 40);
+    }
+
+    /// Stops (flushes) and starts the next recording, continuing the utterance or not.
+    fn restart(processing: &mut ProcessingLoop, continue_utterance: bool) {
+        processing.handle(AudioPacket::Flush);
+        processing.handle(AudioPacket::RecordingStarted);
+        if continue_utterance {
+            processing.handle(AudioPacket::ContinueUtterance);
+        }
+        processing.handle(AudioPacket::Format(16000));
+    }
+
+    #[test]
+    fn continue_mode_keeps_the_prior_buffer_and_fresh_mode_clears_it() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(chunk(0.1));
+        processing.handle(chunk(0.1));
+        restart(&mut processing, true);
+        processing.handle(chunk(0.2));
+        restart(&mut processing, false);
+        processing.handle(chunk(0.3));
+        processing.handle(AudioPacket::Flush);
+
+        let finals = listener.audio();
+        let id = finals[0].1;
+        // The continued final repeats the first one's id with the new audio appended
+        let shape: Vec<(usize, u64)> = finals.iter().map(|(audio, id)| (audio.len(), *id)).collect();
+        assert_eq!(shape, [(960, id), (1440, id), (480, id + 1)]);
+        assert_eq!((finals[1].0[0], finals[1].0[1439]), (0.1, 0.2));
+    }
+
+    #[test]
+    fn discarded_audio_cannot_be_continued() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+        processing.handle(AudioPacket::Discard);
+        processing.handle(AudioPacket::ContinueUtterance);
+        processing.handle(chunk(0.2));
+        processing.handle(AudioPacket::Flush);
+        assert_eq!(listener.audio().iter().map(|(audio, _)| audio.len()).collect::<Vec<_>>(), [480, 480]);
+    }
+
+    #[test]
+    fn stream_errors_reach_on_error_with_their_kind() {
+        let (mut processing, listener) = processing(streaming());
+        processing.handle(AudioPacket::StreamError(crate::state::SupraSonicError::DeviceDisconnected("USB Mic".to_string())));
+        processing.handle(AudioPacket::StreamError(crate::state::SupraSonicError::AudioBackend("xrun".to_string())));
+        let errors: Vec<String> = listener.events().into_iter().filter_map(|e| if let Event::Error(e) = e { Some(e) } else { None }).collect();
+        assert_eq!(errors, [r#"DeviceDisconnected("USB Mic")"#, r#"AudioBackend("xrun")"#]);
+    }
+
+    #[test]
+    fn discard_drops_the_open_utterance_that_flush_would_dispatch() {
+        let (mut processing, listener) = processing(utterance_mode());
+        processing.handle(chunk(0.1));
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Discard);
+        assert!(listener.audio().is_empty());
+
+        // The next utterance starts from scratch
+        processing.handle(chunk(0.2));
+        processing.handle(AudioPacket::Flush);
+        let finals = listener.audio();
+        assert_eq!(finals.len(), 1);
+        assert!(finals[0].0.len() == 480 && finals[0].0.iter().all(|s| *s == 0.2));
+    }
+
+    #[test]
+    fn samples_ahead_of_a_new_format_wait_for_its_rate() {
+        let (mut processing, _listener) = processing(AudioConfig { assign_speakers: true, ..utterance_mode() });
+        let assigned = processing.dispatcher.assignment_queue.1.clone();
+        processing.handle(AudioPacket::Format(8000));
+        processing.handle(chunk(0.1));
+        processing.handle(AudioPacket::Flush);
+        assert_eq!(assigned.try_recv().map(|(_, audio, rate)| (audio.len(), rate)), Ok((480, 8000)));
+
+        // The next recording's first samples beat its Format: they mustn't be taken as 8 kHz
+        processing.handle(AudioPacket::RecordingStarted);
+        processing.handle(chunk(0.2));
+        processing.handle(chunk(0.2));
+        assert!(processing.audio_buffer.is_empty(), "held, not processed");
+        processing.handle(AudioPacket::Format(16000));
+        processing.handle(chunk(0.3));
+        processing.handle(AudioPacket::Flush);
+        let (_, audio, rate) = assigned.try_recv().unwrap();
+        assert_eq!(rate, 16000);
+        assert_eq!(audio.len(), 480 * 3);
+        assert_eq!((audio[0], audio[960]), (0.2, 0.3), "the held samples come first");
+
+        // A recording that never announced a format: its audio is dropped with the flush
+        processing.handle(AudioPacket::RecordingStarted);
+        processing.handle(chunk(0.4));
+        processing.handle(AudioPacket::Flush);
+        processing.handle(AudioPacket::Format(16000));
+        processing.handle(AudioPacket::Flush);
+        assert!(assigned.try_recv().is_err());
+    }
+
+    /// The finals dispatched for `lead` silent chunks, `speech` chunks of speech and 20
+    /// silent ones (the default 600 ms `vad_min_silence_ms`), each 480 samples.
+    fn vad_finals(config: AudioConfig, lead: usize, speech: usize) -> Vec<Vec<f32>> {
+        let (mut processing, listener) = processing(AudioConfig { vad_enabled: true, ..config });
+        let chunks = [(lead, 0.0), (speech, 0.3), (20, 0.0)];
+        for (count, value) in chunks {
+            for _ in 0..count {
+                processing.handle(chunk(value));
+            }
+        }
+        listener.audio().into_iter().map(|(audio, _)| audio).collect()
+    }
+
+    #[test]
+    fn trailing_silence_ends_the_utterance_with_a_bounded_pre_roll() {
+        // The default 300 ms of pre-roll, the speech and the 600 ms of trailing silence
+        let finals = vad_finals(utterance_mode(), 30, 5);
+        assert_eq!(finals.len(), 1);
+        assert_eq!(finals[0].len(), 4800 + 2400 + 9600);
+        assert!(finals[0][..4800].iter().all(|&s| s == 0.0) && finals[0][4800..7200].iter().all(|&s| s == 0.3));
+
+        assert_eq!(vad_finals(AudioConfig { vad_leading_silence_ms: 0, ..utterance_mode() }, 30, 5)[0].len(), 2400 + 9600);
+        // Never more than was waited
+        assert_eq!(vad_finals(AudioConfig { vad_leading_silence_ms: 5000, ..utterance_mode() }, 30, 5)[0].len(), 14400 + 2400 + 9600);
+    }
+
+    #[test]
+    fn the_duration_cap_cuts_long_speech_counted_from_onset() {
+        let config = AudioConfig { max_utterance_ms: 300, ..utterance_mode() };
+        let lens: Vec<usize> = vad_finals(config.clone(), 30, 25).iter().map(Vec::len).collect();
+        // Two capped pieces (the first with its pre-roll), then the rest: over the cap
+        // during the trailing silence, which ends it rather than being cut into pieces
+        assert_eq!(lens, [4800 + 4800, 4800, 2400 + 9600]);
+
+        // With the VAD off the cap alone splits the stream
+        let (mut processing, listener) = processing(config);
+        for _ in 0..25 {
+            processing.handle(chunk(0.3));
+        }
+        processing.handle(AudioPacket::Flush);
+        assert_eq!(listener.audio().iter().map(|(a, _)| a.len()).collect::<Vec<_>>(), [4800, 4800, 2400]);
+    }
+
+    #[test]
+    fn pre_roll_trimming_leaves_a_continued_utterance_alone() {
+        let (mut processing, listener) = processing(AudioConfig { vad_enabled: true, ..utterance_mode() });
+        for _ in 0..5 {
+            processing.handle(chunk(0.2));
+        }
+        restart(&mut processing, true);
+        let chunks = [(30, 0.0), (5, 0.3), (20, 0.0)];
+        for (count, value) in chunks {
+            for _ in 0..count {
+                processing.handle(chunk(value));
+            }
+        }
+        let finals = listener.audio();
+        assert_eq!(finals.len(), 2);
+        assert_eq!(finals[0].1, finals[1].1, "continued under the same id");
+        let continued = &finals[1].0;
+        assert_eq!(continued.len(), 2400 + 4800 + 2400 + 9600);
+        assert!(continued[..2400].iter().all(|&s| s == 0.2));
+    }
+}
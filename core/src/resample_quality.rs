@@ -0,0 +1,125 @@
+// Resampler A/B measurement on a synthetic reference signal.
+//
+// The reference is a handful of tones at exact 1 Hz multiples. After resampling, each
+// in-band tone is fitted back out of the output by least squares (integer cycles over the
+// analysis window keep the fits orthogonal); whatever the fit leaves over is error. That
+// makes the score independent of resampler delay, which differs per kind.
+
+use std::f64::consts::PI;
+use std::time::Instant;
+
+use crate::audio::{self, ResamplerKind, StreamResampler, TARGET_SAMPLE_RATE};
+
+// In-band tones (Hz) and their amplitude
+const REFERENCE_TONES: [f64; 4] = [250.0, 1000.0, 3100.0, 6300.0];
+const TONE_AMPLITUDE: f64 = 0.2;
+// Out-of-band tone (Hz, only when the source can carry it): should be removed, not folded back
+const ALIAS_TONE: f64 = 11_000.0;
+// Reference length, and the part of the output actually scored (skips edge transients)
+const REFERENCE_SECS: f64 = 2.5;
+const ANALYSIS_SKIP: usize = TARGET_SAMPLE_RATE / 4;
+const ANALYSIS_LEN: usize = TARGET_SAMPLE_RATE;
+
+/// How one resampler did on the reference signal.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct ResamplerComparison {
+    pub kind: ResamplerKind,
+    /// Reference tones vs everything else in the output (noise, distortion, aliases).
+    pub snr_db: f64,
+    /// Processing time per second of source audio.
+    pub cpu_ms_per_second: f64,
+}
+
+/// Runs every resampler kind on the reference at `source_rate`, fastest kind first.
+/// Kinds rubato can't build at that rate are left out.
+pub fn compare_resamplers(source_rate: u32) -> Vec<ResamplerComparison> {
+    let reference = reference_signal(source_rate);
+    let seconds = reference.len() as f64 / source_rate as f64;
+    [ResamplerKind::Linear, ResamplerKind::Cubic, ResamplerKind::Sinc, ResamplerKind::Fft]
+        .into_iter()
+        .filter_map(|kind| {
+            let resampler = audio::build_resampler_kind(kind, source_rate as usize, TARGET_SAMPLE_RATE, 1.0).ok()?;
+            let mut stream = StreamResampler::from_resampler(resampler, source_rate as usize, TARGET_SAMPLE_RATE).ok()?;
+            let started = Instant::now();
+            let mut output = stream.process(&reference);
+            stream.finish_into(&mut output);
+            let elapsed = started.elapsed();
+            Some(ResamplerComparison {
+                kind,
+                snr_db: tone_snr_db(output.get(ANALYSIS_SKIP..ANALYSIS_SKIP + ANALYSIS_LEN)?),
+                cpu_ms_per_second: elapsed.as_secs_f64() * 1000.0 / seconds,
+            })
+        })
+        .collect()
+}
+
+fn reference_signal(source_rate: u32) -> Vec<f32> {
+    let len = (REFERENCE_SECS * source_rate as f64) as usize;
+    let nyquist = source_rate as f64 / 2.0;
+    let tones: Vec<f64> = REFERENCE_TONES
+        .iter()
+        .copied()
+        .chain((ALIAS_TONE < nyquist * 0.9).then_some(ALIAS_TONE))
+        .filter(|f| *f < nyquist)
+        .collect();
+    (0..len)
+        .map(|n| {
+            let t = n as f64 / source_rate as f64;
+            tones.iter().map(|f| TONE_AMPLITUDE * (2.0 * PI * f * t).sin()).sum::<f64>() as f32
+        })
+        .collect()
+}
+
+/// Power of the fitted reference tones over the power of the residual, in dB.
+fn tone_snr_db(output: &[f32]) -> f64 {
+    let rate = TARGET_SAMPLE_RATE as f64;
+    let len = output.len() as f64;
+    let mut residual: Vec<f64> = output.iter().map(|&s| s as f64).collect();
+    let mut signal_power = 0.0;
+    for f in REFERENCE_TONES {
+        let w = 2.0 * PI * f / rate;
+        let (mut sin_sum, mut cos_sum) = (0.0, 0.0);
+        for (n, y) in residual.iter().enumerate() {
+            let (sin, cos) = (w * n as f64).sin_cos();
+            sin_sum += y * sin;
+            cos_sum += y * cos;
+        }
+        let (a, b) = (2.0 * sin_sum / len, 2.0 * cos_sum / len);
+        for (n, y) in residual.iter_mut().enumerate() {
+            let (sin, cos) = (w * n as f64).sin_cos();
+            *y -= a * sin + b * cos;
+        }
+        signal_power += (a * a + b * b) / 2.0;
+    }
+    let noise_power = residual.iter().map(|y| y * y).sum::<f64>() / len;
+    10.0 * (signal_power / noise_power.max(f64::MIN_POSITIVE)).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snr(results: &[ResamplerComparison], kind: ResamplerKind) -> f64 {
+        results.iter().find(|r| r.kind == kind).map(|r| r.snr_db).unwrap()
+    }
+
+    #[test]
+    fn higher_quality_kinds_score_better_on_the_reference() {
+        for rate in [44100, 48000] {
+            let results = compare_resamplers(rate);
+            assert_eq!(results.len(), 4, "{} Hz: every kind builds", rate);
+            // Neither fast kind low-passes, so the out-of-band tone folds back into both
+            let fast = snr(&results, ResamplerKind::Linear).max(snr(&results, ResamplerKind::Cubic));
+            for kind in [ResamplerKind::Sinc, ResamplerKind::Fft] {
+                assert!(snr(&results, kind) > fast + 20.0, "{} Hz: {:?} {:.1} dB vs fast {:.1} dB", rate, kind, snr(&results, kind), fast);
+            }
+            assert!(results.iter().all(|r| r.cpu_ms_per_second > 0.0));
+        }
+    }
+
+    #[test]
+    fn an_exact_copy_scores_far_above_any_resampler() {
+        let reference = reference_signal(TARGET_SAMPLE_RATE as u32);
+        assert!(tone_snr_db(&reference[ANALYSIS_SKIP..ANALYSIS_SKIP + ANALYSIS_LEN]) > 100.0);
+    }
+}
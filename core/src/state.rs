@@ -1,39 +1,162 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use crossbeam_channel::{unbounded, Sender};
-use crate::{AudioEngine, audio::AudioPacket};
-use crate::diarization::DiarizationService;
+use std::thread::JoinHandle;
+use crossbeam_channel::{unbounded, RecvTimeoutError, Sender};
+use crate::AudioEngine;
+use crate::audio::{
+    self, AudioConfig, AudioPacket, AudioProcessor, GainEnvelope, GainPoint, PermissionStatus, ResamplerLatency,
+};
+use crate::devices::{DevicePreference, DevicePreferences};
+use crate::diagnostics;
+use crate::diarization::{self, DiarizationService, DistanceMetric, RegistryChangeKind, RegistryChanges, RegistryParts, RegistrySummary, Segment, SpeakerRegistration, SpeakerRegistry};
+use crate::diarization_eval::{self, DiarizationReport};
+use crate::export::{self, SessionMetadata};
+use crate::noise::NoiseProfile;
+use crate::metrics::{self, CaptureLatency, Heartbeat, MetricsSnapshot, PipelineMetrics};
+use crate::offline::{self, AudioFileFormat};
+use crate::processing::{Dispatcher, ProcessingLoop};
+use crate::resample_quality::{self, ResamplerComparison};
+use crate::transcript::{LowConfidencePolicy, SegmentHook, SessionTranscript, MAX_SMOOTHING_WINDOW, MIN_MAX_SEGMENT_SECS};
 
 #[uniffi::export(callback_interface)]
 pub trait TranscriptionListener: Send + Sync {
-    fn on_audio_data(&self, audio_data: Vec<f32>);
+    /// `utterance_id` increases with every utterance; all chunks of one utterance share it.
+    /// Pass it back in `Segment::utterance_id` to correlate the transcript.
+    fn on_audio_data(&self, audio_data: Vec<f32>, utterance_id: u64);
+    /// Called instead of `on_audio_data` when the config asks for `SampleFormat::I16`.
+    fn on_audio_data_i16(&self, audio_data: Vec<i16>, utterance_id: u64);
     fn on_level_changed(&self, level: f32);
+    /// With `waveform_resolution_ms` set: (min, max) sample pairs for the latest chunk,
+    /// interleaved, oldest first.
+    fn on_waveform(&self, envelope: Vec<f32>);
+    /// Audio from one device when recording several at once (`start_recording_devices`).
+    fn on_device_audio_data(&self, device: String, audio_data: Vec<f32>);
+    /// An utterance was dropped instead of dispatched; `reason` says why.
+    fn on_audio_skipped(&self, reason: String);
+    /// A transcript segment was submitted with `submit_segment`.
+    fn on_segment(&self, segment: Segment);
+    /// Utterance mode with `interim_interval_ms` set: the whole utterance so far, every
+    /// interval (`is_final` false), then once complete (`is_final` true, replacing
+    /// `on_audio_data`). All calls for one utterance share `utterance_id`.
+    fn on_utterance_audio(&self, audio_data: Vec<f32>, utterance_id: u64, is_final: bool);
+    /// With `mel_bands` set: log-mel frames in place of `on_audio_data`, frame after frame,
+    /// `n_mels` values each (lowest band first).
+    fn on_features(&self, features: Vec<f32>, n_mels: u32, utterance_id: u64);
+    /// A running capture stream failed or changed. Capture reopens the devices on its own
+    /// after a disconnect (`DeviceDisconnected`) or backend fault (`AudioBackend`), and
+    /// keeps going after a `SampleRateChanged` or `InputSilent`.
+    fn on_error(&self, error: SupraSonicError);
+    /// Live recording ended, whether asked to or on its own (`auto_stop_silence_ms`).
+    /// Comes after everything captured before the stop has been dispatched.
+    fn on_recording_stopped(&self, reason: audio::StopReason);
+    /// With `assign_speakers`: the speaker of utterance `utterance_id`, some time after its
+    /// audio was dispatched. Embedding failures go to `on_error` instead.
+    fn on_speaker_assigned(&self, utterance_id: u64, speaker_id: String);
 }
 
+/// Host-provided wake-word detector, fed every output chunk before anything else sees it.
+#[uniffi::export(callback_interface)]
+pub trait KeywordSpotter: Send + Sync {
+    /// Return true when the keyword was heard in (or ending at) this chunk.
+    fn process(&self, audio_data: Vec<f32>) -> bool;
+}
+
+/// Host-provided speaker embedding model (ECAPA, x-vector, ...). The crate ships none;
+/// this is what the audio-based enroll/assign calls run.
+#[uniffi::export(callback_interface)]
+pub trait SpeakerEmbedder: Send + Sync {
+    /// Embedding of the voice in `samples` (mono, at `sample_rate`).
+    fn embed(&self, samples: Vec<f32>, sample_rate: u32) -> Result<Vec<f32>, SupraSonicError>;
+}
+
+/// Told about every change to the speaker registry, for UIs that show the speaker list.
+#[uniffi::export(callback_interface)]
+pub trait RegistryListener: Send + Sync {
+    /// Called once per affected speaker, on the thread that made the change. Re-registering
+    /// a speaker under its current name, or anything else that changed nothing, isn't reported.
+    fn on_registry_changed(&self, speaker_id: String, change: RegistryChangeKind);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Record)]
+pub struct SpeakerColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+// Longest accepted noise calibration clip
+const MAX_NOISE_PROFILE_MS: u32 = 30_000;
+// How long past the clip duration `capture_noise_profile` waits for audio
+const NOISE_PROFILE_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(uniffi::Object)]
 pub struct AppState {
     audio: Mutex<AudioEngine>,
-    is_recording: Mutex<BoolState>,
+    // Resampler/chunker for `push_samples`, rebuilt when the pushed or output rate changes
+    push_processor: Mutex<Option<(u32, AudioProcessor)>>,
+    // `AudioEngine::recording_starts` as of the push processor's last Format packet
+    push_format_starts: AtomicU64,
+    // Shared with the engine, which clears it when `auto_stop_silence_ms` ends the recording
+    is_recording: Arc<AtomicBool>,
     data_tx: Sender<AudioPacket>,
-    listener: Arc<Mutex<Option<Arc<dyn TranscriptionListener>>>>,
+    dispatcher: Arc<Dispatcher>,
+    config: Arc<Mutex<AudioConfig>>,
     diarization: Arc<DiarizationService>,
-}
-
-struct BoolState {
-    value: bool,
+    device_prefs: DevicePreferences,
+    session: Mutex<SessionTranscript>,
+    session_metadata: Mutex<Option<SessionMetadata>>,
+    segment_hooks: Mutex<Vec<Arc<dyn SegmentHook>>>,
+    metrics: Arc<PipelineMetrics>,
+    // Last result of `capture_noise_profile`
+    noise_profile: Mutex<Option<NoiseProfile>>,
+    // Runtime meter switch shared by every AudioProcessor (see `set_level_reporting`)
+    level_reporting: Arc<AtomicBool>,
+    // cpal can't query OS permissions; hosts that can (AVFoundation, WinRT) report it here
+    mic_permission: Mutex<Option<PermissionStatus>>,
+    processing_heartbeat: Arc<Heartbeat>,
+    processing_thread: Mutex<Option<JoinHandle<()>>>,
+    // Runs `assign_speakers` off the processing thread
+    assignment_thread: Mutex<Option<JoinHandle<()>>>,
+    // Set by the processing thread once it has handled its last packet
+    processing_finished: Arc<AtomicBool>,
+    is_shut_down: Arc<AtomicBool>,
 }
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
 pub enum SupraSonicError {
     #[error("Audio error: {0}")]
     Audio(String),
+    /// The named input device went away mid-stream (unplugged, disabled); worth reconnecting.
+    #[error("Input device disconnected: {0}")]
+    DeviceDisconnected(String),
+    /// The audio backend failed a running stream for some other reason.
+    #[error("Audio backend error: {0}")]
+    AudioBackend(String),
+    /// An input device started delivering audio at another rate than it reported. Capture
+    /// carries on, resampling from the new rate when it is a standard one.
+    #[error("Input sample rate changed: {0}")]
+    SampleRateChanged(String),
+    /// An input device has delivered nothing but exact zeros for `dead_input_ms`, most
+    /// likely muted. Capture carries on; reported again if it goes dead after recovering.
+    #[error("Input is silent: {0}")]
+    InputSilent(String),
     #[error("Inference error: {0}")]
     Inference(String),
     #[error("Lock error: {0}")]
     Lock(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
     #[error("General error: {0}")]
     General(String),
 }
 
+// A callback (e.g. `SpeakerEmbedder::embed`) failed in a way the host didn't declare
+impl From<uniffi::UnexpectedUniFFICallbackError> for SupraSonicError {
+    fn from(e: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        SupraSonicError::General(e.reason)
+    }
+}
+
 #[uniffi::export]
 impl AppState {
     #[uniffi::constructor]
@@ -41,123 +164,1096 @@ impl AppState {
         use std::sync::Once;
         static INIT: Once = Once::new();
         INIT.call_once(|| {
-            tracing_subscriber::fmt::init();
+            use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+            let _ = tracing_subscriber::fmt().finish().with(diagnostics::layer()).try_init();
         });
 
         let (tx, rx) = unbounded();
-        let listener: Arc<Mutex<Option<Arc<dyn TranscriptionListener>>>> = Arc::new(Mutex::new(None));
+        let dispatcher = Arc::new(Dispatcher::new());
+        let config = Arc::new(Mutex::new(AudioConfig::default()));
+        let metrics = Arc::new(PipelineMetrics::new());
+        let level_reporting = Arc::new(AtomicBool::new(true));
+        let is_recording = Arc::new(AtomicBool::new(false));
         
         // Spawn Background Processing Loop
-        let listener_clone = listener.clone();
-        std::thread::spawn(move || {
-            while let Ok(packet) = rx.recv() {
-                match packet {
-                    AudioPacket::Format(sr) => {
-                        tracing::info!("Background: Audio stream started at {} Hz", sr);
-                    }
-                    AudioPacket::Samples(data) => {
-                        // Streaming Mode: Forward immediately to listener (Swift/Inference)
-                        if let Ok(l) = listener_clone.lock() {
-                            if let Some(listener) = l.as_ref() {
-                                listener.on_audio_data(data);
-                            }
-                        }
-                    }
-                    AudioPacket::Level(lvl) => {
-                        if let Ok(l) = listener_clone.lock() {
-                            if let Some(listener) = l.as_ref() {
-                                listener.on_level_changed(lvl);
-                            }
-                        }
-                    }
-                    AudioPacket::Flush => {
-                         tracing::info!("Background: Flush processing (End of capture)");
-                    }
+        let dispatcher_clone = dispatcher.clone();
+        let config_clone = config.clone();
+        let metrics_clone = metrics.clone();
+        let processing_heartbeat = Arc::new(Heartbeat::new());
+        let heartbeat_clone = processing_heartbeat.clone();
+        let processing_finished = Arc::new(AtomicBool::new(false));
+        let finished_clone = processing_finished.clone();
+        let processing_thread = std::thread::spawn(move || {
+            let mut processing = ProcessingLoop::new(dispatcher_clone, config_clone, metrics_clone);
+            loop {
+                heartbeat_clone.beat();
+                match rx.recv_timeout(metrics::HEARTBEAT_INTERVAL) {
+                    Ok(AudioPacket::Shutdown) => break,
+                    Ok(packet) => processing.handle(packet),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
             }
+            finished_clone.store(true, Ordering::SeqCst);
         });
 
+        let device_prefs = DevicePreferences::load(std::path::Path::new(&storage_path));
+        let diarization = Arc::new(DiarizationService::new(storage_path));
+        let is_shut_down = Arc::new(AtomicBool::new(false));
+        let assignment_thread = {
+            let (dispatcher, diarization, is_shut_down) = (dispatcher.clone(), diarization.clone(), is_shut_down.clone());
+            std::thread::spawn(move || {
+                let jobs = &dispatcher.assignment_queue.1;
+                // Utterances still queued at shutdown are dropped, not embedded
+                while !is_shut_down.load(Ordering::SeqCst) {
+                    let Ok((utterance_id, utterance, sample_rate)) = jobs.recv_timeout(metrics::HEARTBEAT_INTERVAL) else { continue };
+                    match diarization.assign_audio(utterance, sample_rate) {
+                        Ok(speaker_id) => dispatcher.notify(|l| l.on_speaker_assigned(utterance_id, speaker_id)),
+                        Err(e) => dispatcher.notify(|l| l.on_error(e)),
+                    }
+                }
+            })
+        };
+
         Self {
-            audio: Mutex::new(AudioEngine::new(tx.clone())),
-            is_recording: Mutex::new(BoolState { value: false }),
+            audio: Mutex::new(AudioEngine::new(tx.clone(), config.clone(), metrics.clone(), level_reporting.clone(), is_recording.clone())),
+            push_processor: Mutex::new(None),
+            push_format_starts: AtomicU64::new(0),
+            is_recording,
             data_tx: tx,
-            listener: listener,
-            diarization: Arc::new(DiarizationService::new(storage_path)),
+            dispatcher,
+            config,
+            device_prefs,
+            diarization,
+            session: Mutex::new(SessionTranscript::new()),
+            session_metadata: Mutex::new(None),
+            segment_hooks: Mutex::new(Vec::new()),
+            metrics,
+            level_reporting,
+            noise_profile: Mutex::new(None),
+            mic_permission: Mutex::new(None),
+            processing_heartbeat,
+            processing_thread: Mutex::new(Some(processing_thread)),
+            assignment_thread: Mutex::new(Some(assignment_thread)),
+            processing_finished,
+            is_shut_down,
         }
     }
     
-    pub fn register_speaker(&self, id: String, name: String) {
-        self.diarization.register_speaker(id, name);
+    pub fn register_speaker(&self, id: String, name: String) -> Result<SpeakerRegistration, SupraSonicError> {
+        self.diarization.register_speaker(id, name)
     }
     
     pub fn get_speaker_name(&self, id: String) -> String {
         self.diarization.get_speaker_name(id)
     }
 
+    pub fn enroll_speaker_embedding(&self, id: String, embedding: Vec<f32>) -> Result<(), SupraSonicError> {
+        self.diarization.enroll_embedding(id, embedding)
+    }
+
+    pub fn remove_speaker(&self, id: String) -> Result<bool, SupraSonicError> {
+        self.diarization.remove_speaker(id)
+    }
+
+    /// How long embedding updates may wait before being written to disk (0 = immediately).
+    pub fn set_registry_autosave_interval_ms(&self, interval_ms: u32) {
+        self.diarization.set_autosave_interval(std::time::Duration::from_millis(interval_ms as u64));
+    }
+
+    /// How many speakers assignment may create per session for voices nobody enrolled
+    /// matches; past that they go to the nearest of those. 0 (the default) means "Guest".
+    pub fn set_max_auto_speakers(&self, max: u32) {
+        self.diarization.set_max_auto_speakers(max);
+    }
+
+    /// Writes speaker changes still pending autosave. Call before the app exits.
+    pub fn flush_speaker_registry(&self) {
+        self.diarization.flush_save();
+    }
+
+    /// With a threshold, `finalize_speaker_session` merges this session's auto speakers
+    /// that score at least that close to each other (cosine similarity, or negated distance
+    /// under `Euclidean`). None (the default) keeps them all.
+    pub fn set_speaker_merge_threshold(&self, threshold: Option<f32>) {
+        self.diarization.set_merge_threshold(threshold);
+    }
+
+    /// End-of-meeting consolidation: merges near-duplicate auto speakers if asked to (see
+    /// `set_speaker_merge_threshold`), saves the speaker registry right away and returns
+    /// the speakers added, removed, renamed, enrolled and merged since the last call (or
+    /// since startup). Unlike `flush_speaker_registry`, reports a failed write.
+    pub fn finalize_speaker_session(&self) -> Result<RegistryChanges, SupraSonicError> {
+        self.diarization.finalize_session()
+    }
+
+    /// How speaker embeddings are compared, as stored with the registry.
+    pub fn speaker_metric(&self) -> DistanceMetric {
+        self.diarization.metric()
+    }
+
+    /// Switches the metric voices are matched under (e.g. `Euclidean` for a model trained
+    /// for it) and saves it with the registry. Doesn't touch enrolled embeddings, which
+    /// should come from a model suited to the new metric.
+    pub fn set_speaker_metric(&self, metric: DistanceMetric) -> Result<(), SupraSonicError> {
+        self.diarization.set_metric(metric)
+    }
+
+    /// Id of the enrolled speaker whose voice matches `embedding`, or "Guest". With
+    /// `set_max_auto_speakers`, unmatched voices get speakers of their own instead.
+    pub fn assign_speaker(&self, embedding: Vec<f32>) -> String {
+        self.diarization.assign_speaker(&embedding)
+    }
+
+    /// Installs the model used by `enroll_speaker_audio` and `assign_speaker_audio`.
+    pub fn set_speaker_embedder(&self, embedder: Box<dyn SpeakerEmbedder>) {
+        self.diarization.set_embedder(Some(Arc::from(embedder)));
+    }
+
+    pub fn clear_speaker_embedder(&self) {
+        self.diarization.set_embedder(None);
+    }
+
+    /// Reports speakers being added, renamed, enrolled or removed, whether by these calls
+    /// an import, or assignment creating one (see `set_max_auto_speakers`).
+    pub fn set_registry_listener(&self, listener: Box<dyn RegistryListener>) {
+        self.diarization.set_listener(Some(Arc::from(listener)));
+    }
+
+    pub fn clear_registry_listener(&self) {
+        self.diarization.set_listener(None);
+    }
+
+    /// Enrolls `id` from a voice sample, embedded with the installed `SpeakerEmbedder`.
+    pub fn enroll_speaker_audio(&self, id: String, samples: Vec<f32>, sample_rate: u32) -> Result<(), SupraSonicError> {
+        self.diarization.enroll_audio(id, samples, sample_rate)
+    }
+
+    /// Like `assign_speaker`, embedding `samples` with the installed `SpeakerEmbedder` first.
+    pub fn assign_speaker_audio(&self, samples: Vec<f32>, sample_rate: u32) -> Result<String, SupraSonicError> {
+        self.diarization.assign_audio(samples, sample_rate)
+    }
+
+    pub fn get_speaker_color(&self, id: String) -> SpeakerColor {
+        let (r, g, b) = diarization::speaker_color(&id);
+        SpeakerColor { r, g, b }
+    }
+
+    /// Bundles the recording, its transcript and the speakers it references into one zip,
+    /// plus the last recording's diagnostic log when `diagnostic_log` is on and the audio's
+    /// checksum when `export_audio_checksum` is.
+    pub fn export_session(
+        &self,
+        path: String,
+        audio_data: Vec<f32>,
+        sample_rate: u32,
+        segments: Vec<Segment>,
+        speaker_parts: RegistryParts,
+    ) -> Result<(), SupraSonicError> {
+        let registry = self.diarization.registry_snapshot();
+        let config = self.audio_config();
+        let diagnostics = Some(diagnostics::text()).filter(|log| config.diagnostic_log && !log.is_empty());
+        let metadata = self.session_metadata();
+        let extras = export::ExportExtras {
+            diagnostics: diagnostics.as_deref(),
+            audio_checksum: config.export_audio_checksum,
+            metadata: metadata.as_ref(),
+        };
+        export::export_session(
+            std::path::Path::new(&path),
+            &audio_data,
+            sample_rate,
+            &segments,
+            &registry,
+            speaker_parts,
+            extras,
+        )
+            .map_err(|e| SupraSonicError::General(e.to_string()))
+    }
+
+    /// Backs up the speaker registry (or just its names or embeddings) as JSON at `path`.
+    pub fn export_speakers(&self, path: String, parts: RegistryParts) -> Result<(), SupraSonicError> {
+        let json = self.diarization.registry_snapshot().with_parts(parts).to_json();
+        std::fs::write(&path, json).map_err(|e| SupraSonicError::General(format!("{}: {}", path, e)))
+    }
+
+    /// Merges `parts` of a registry backup into the current one; returns the speakers touched.
+    /// Import a names-only and an embeddings-only backup in turn to reassemble a full one.
+    pub fn import_speakers(&self, path: String, parts: RegistryParts) -> Result<u32, SupraSonicError> {
+        let json = std::fs::read_to_string(&path).map_err(|e| SupraSonicError::General(format!("{}: {}", path, e)))?;
+        self.diarization.import_registry(&json, parts)
+    }
+
+    /// Summarises a registry backup without importing it, so the UI can preview it.
+    pub fn preview_speakers(&self, path: String) -> Result<RegistrySummary, SupraSonicError> {
+        let json = std::fs::read_to_string(&path).map_err(|e| SupraSonicError::General(format!("{}: {}", path, e)))?;
+        SpeakerRegistry::validate_json(&json)
+    }
+
+    /// Diarization error rate of `hypothesis` (e.g. the session transcript) against
+    /// hand-labelled `reference` segments, for tuning speaker matching on real recordings.
+    /// Only times and speaker ids are compared.
+    pub fn evaluate_diarization(
+        &self,
+        reference: Vec<Segment>,
+        hypothesis: Vec<Segment>,
+    ) -> Result<DiarizationReport, SupraSonicError> {
+        if reference.iter().chain(&hypothesis).any(|s| !s.start.is_finite() || !s.end.is_finite()) {
+            return Err(SupraSonicError::InvalidArgument("Segment times must be finite".to_string()));
+        }
+        Ok(diarization_eval::evaluate(&reference, &hypothesis))
+    }
+
+    /// Records a transcribed segment in the session transcript and forwards it via `on_segment`.
+    pub fn submit_segment(&self, segment: Segment) -> Result<(), SupraSonicError> {
+        self.record_segment(segment, None)
+    }
+
+    /// Like `submit_segment`, with the speaker id assigned from the segment's voice
+    /// `embedding` (as `assign_speaker` does). Such segments take part in speaker
+    /// smoothing, so `on_segment` may also deliver a relabelled earlier segment.
+    pub fn submit_segment_with_embedding(&self, mut segment: Segment, embedding: Vec<f32>) -> Result<(), SupraSonicError> {
+        if embedding.is_empty() || embedding.iter().any(|x| !x.is_finite()) {
+            return Err(SupraSonicError::InvalidArgument("Embedding must be non-empty and finite".to_string()));
+        }
+        segment.speaker_id = self.diarization.assign_speaker(&embedding);
+        self.record_segment(segment, Some(embedding))
+    }
+
+    /// Smooths auto-assigned speaker labels over `window` consecutive segments (odd, 3 up
+    /// to `MAX_SMOOTHING_WINDOW`, or 0 = off): a middle segment outvoted by its neighbours
+    /// takes their speaker when its voice is at least `min_similarity` (cosine, -1..=1)
+    /// close to theirs. Lower similarity smooths more aggressively.
+    pub fn set_speaker_smoothing(&self, window: u32, min_similarity: f32) -> Result<(), SupraSonicError> {
+        if window != 0 && (window < 3 || window.is_multiple_of(2) || window > MAX_SMOOTHING_WINDOW) {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "Smoothing window must be 0 or odd within 3..={}, got {}",
+                MAX_SMOOTHING_WINDOW, window
+            )));
+        }
+        if !(-1.0..=1.0).contains(&min_similarity) {
+            return Err(SupraSonicError::InvalidArgument(format!("min_similarity must be within -1..=1, got {}", min_similarity)));
+        }
+        self.session.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.set_speaker_smoothing(window, min_similarity);
+        Ok(())
+    }
+
+    /// Cuts submitted segments longer than `max_seconds` into pieces no longer than it, all
+    /// with the segment's speaker. Each cut falls between words, at a clause break where
+    /// one is close (see `SessionTranscript`). 0 = no limit; otherwise at least
+    /// `MIN_MAX_SEGMENT_SECS`.
+    pub fn set_max_segment_duration(&self, max_seconds: f64) -> Result<(), SupraSonicError> {
+        if !max_seconds.is_finite() || (max_seconds != 0.0 && max_seconds < MIN_MAX_SEGMENT_SECS) {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "max_seconds must be 0 or at least {}, got {}",
+                MIN_MAX_SEGMENT_SECS, max_seconds
+            )));
+        }
+        self.session.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.set_max_segment_duration(max_seconds);
+        Ok(())
+    }
+
+    /// Filters likely hallucinations: submitted segments with a `confidence` below
+    /// `min_confidence` are dropped or marked per `policy`. 0 turns the filter off.
+    pub fn set_segment_confidence_filter(&self, min_confidence: f32, policy: LowConfidencePolicy) -> Result<(), SupraSonicError> {
+        if !(0.0..=1.0).contains(&min_confidence) {
+            return Err(SupraSonicError::InvalidArgument(format!("min_confidence must be within 0..=1, got {}", min_confidence)));
+        }
+        self.session.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.set_confidence_filter(min_confidence, policy);
+        Ok(())
+    }
+
+    /// Tells the segmenter that `speaker_id` took over at `at_seconds` (the timeline of
+    /// `Segment::start`), e.g. from a conferencing SDK's active-speaker events. Segments
+    /// submitted afterwards are split at the turn and attributed to it, overriding their
+    /// own (embedding-based) speaker id.
+    pub fn push_speaker_turn(&self, at_seconds: f64, speaker_id: String) -> Result<(), SupraSonicError> {
+        if !at_seconds.is_finite() || at_seconds < 0.0 {
+            return Err(SupraSonicError::InvalidArgument(format!("Turn time must be finite and >= 0, got {}", at_seconds)));
+        }
+        let speaker_id = speaker_id.trim().to_string();
+        if speaker_id.is_empty() {
+            return Err(SupraSonicError::InvalidArgument("Speaker id must not be empty".to_string()));
+        }
+        self.session.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.add_turn(at_seconds, speaker_id);
+        Ok(())
+    }
+
+    /// Everything submitted since the session started (or was last cleared).
+    pub fn session_segments(&self) -> Vec<Segment> {
+        self.session.lock().map(|s| s.segments().to_vec()).unwrap_or_default()
+    }
+
+    /// Clears the transcript and the session metadata.
+    pub fn clear_session(&self) {
+        if let Ok(mut session) = self.session.lock() {
+            session.clear();
+        }
+        self.clear_session_metadata();
+    }
+
+    /// Attaches `metadata` to the current session, replacing any set before. It goes into
+    /// `export_transcript_json` and the `export_session` archive until the session is cleared.
+    pub fn set_session_metadata(&self, metadata: SessionMetadata) -> Result<(), SupraSonicError> {
+        *self.session_metadata.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))? = Some(metadata);
+        Ok(())
+    }
+
+    pub fn session_metadata(&self) -> Option<SessionMetadata> {
+        self.session_metadata.lock().ok().and_then(|m| m.clone())
+    }
+
+    pub fn clear_session_metadata(&self) {
+        if let Ok(mut metadata) = self.session_metadata.lock() {
+            *metadata = None;
+        }
+    }
+
+    /// The session transcript with its metadata, as JSON (see `export::TranscriptExport`).
+    pub fn export_transcript_json(&self) -> String {
+        export::TranscriptExport { metadata: self.session_metadata(), segments: self.session_segments() }.to_json()
+    }
+
+    /// Gates dispatch on a keyword: audio reaches the listener only after the spotter
+    /// fires, and a second match flushes the utterance and re-arms it.
+    ///
+    /// The spotter only hears what is captured or pushed, so keep `start_recording` running
+    /// while armed (or use `listen_for_keyword`, which opens the microphone itself). Levels
+    /// and multi-device audio are not gated.
+    pub fn set_keyword_spotter(&self, spotter: Box<dyn KeywordSpotter>) {
+        let _ = self.data_tx.send(AudioPacket::SetSpotter(Some(Arc::from(spotter))));
+    }
+
+    /// Wake-word mode: the keyword starts the recording. The microphone opens now so the
+    /// spotter can listen, but nothing reaches the listener until it fires; from there the
+    /// audio is dispatched as a new utterance, which the next match closes (and re-arms).
+    /// `stop_recording` ends it; `clear_keyword_spotter` goes back to plain recording.
+    pub fn listen_for_keyword(&self, spotter: Box<dyn KeywordSpotter>) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        self.ensure_mic_permission()?;
+        self.set_keyword_spotter(spotter);
+        let recording = self.is_recording.load(Ordering::SeqCst);
+        if recording {
+            return Ok(());
+        }
+        self.start_recording()
+    }
+
+    pub fn clear_keyword_spotter(&self) {
+        let _ = self.data_tx.send(AudioPacket::SetSpotter(None));
+    }
+
+    /// Sets or replaces the listener; safe to call at any time, including mid-recording.
+    ///
+    /// Each callback is delivered to exactly one listener: whichever was current when that
+    /// chunk (or, in Utterance mode, the whole utterance) was dispatched. A callback already
+    /// running on the old listener finishes there; everything after goes to the new one.
+    /// Nothing is dropped or repeated by the swap, and utterance ids carry on unchanged.
+    /// Call this directly to swap; going through `clear_listener` sends whatever is
+    /// dispatched in between to the polling queues instead.
     pub fn set_listener(&self, listener: Box<dyn TranscriptionListener>) {
-        if let Ok(mut l) = self.listener.lock() {
+        if let Ok(mut l) = self.dispatcher.listener.lock() {
             *l = Some(Arc::from(listener));
         }
     }
 
+    /// Removes the listener; processed output then queues up for `poll_audio`/`poll_level`.
+    pub fn clear_listener(&self) {
+        if let Ok(mut l) = self.dispatcher.listener.lock() {
+            *l = None;
+        }
+    }
+
+    /// Audio of a recently dispatched utterance (Utterance mode), `n` back from the latest
+    /// (0 = last), e.g. to re-transcribe it. The last 8 utterances are kept, up to about
+    /// 2 minutes of audio in total; None if `n` goes beyond that.
+    pub fn last_utterance_audio(&self, n: u32) -> Option<Vec<f32>> {
+        self.dispatcher.recent_utterance(n as usize)
+    }
+
+    /// Next processed audio buffer, oldest first, when no listener is set.
+    ///
+    /// Always f32, regardless of `sample_format`. Returns None when nothing is queued.
+    pub fn poll_audio(&self) -> Option<Vec<f32>> {
+        self.dispatcher.audio_queue.1.try_recv().ok()
+    }
+
+    /// Next queued level, oldest first, when no listener is set.
+    pub fn poll_level(&self) -> Option<f32> {
+        self.dispatcher.level_queue.1.try_recv().ok()
+    }
+
+    /// Clip LED state: true from a clipped level (peak at full scale) until `clip_hold_ms`
+    /// later, so a momentary clip is still visible on the next UI frame.
+    pub fn is_clip_latched(&self) -> bool {
+        let hold = std::time::Duration::from_millis(self.audio_config().clip_hold_ms as u64);
+        self.dispatcher.clip_latched(hold)
+    }
+
+    pub fn set_audio_config(&self, config: AudioConfig) -> Result<(), SupraSonicError> {
+        config.validate().map_err(|e| SupraSonicError::Audio(e.to_string()))?;
+        let mut c = self.config.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+        *c = config;
+        Ok(())
+    }
+
+    /// The current config as JSON, for saving or sharing a tuned setup.
+    pub fn audio_config_json(&self) -> String {
+        self.audio_config().to_json()
+    }
+
+    /// Replaces the config with one saved by `audio_config_json`; fields it lacks are reset
+    /// to their defaults.
+    pub fn set_audio_config_json(&self, json: String) -> Result<(), SupraSonicError> {
+        let config = AudioConfig::from_json(&json).map_err(|e| SupraSonicError::InvalidArgument(e.to_string()))?;
+        self.set_audio_config(config)
+    }
+
+    /// Resamples a reference signal from `source_rate` with every resampler kind and reports
+    /// fidelity and CPU cost, so the choice can rest on numbers measured on this machine.
+    /// Takes up to a fraction of a second; keep it off the UI thread.
+    pub fn compare_resamplers(&self, source_rate: u32) -> Result<Vec<ResamplerComparison>, SupraSonicError> {
+        if !(audio::MIN_SOURCE_SAMPLE_RATE..=audio::MAX_SOURCE_SAMPLE_RATE).contains(&source_rate) {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "Unsupported sample rate {} Hz (expected {}..={} Hz)",
+                source_rate, audio::MIN_SOURCE_SAMPLE_RATE, audio::MAX_SOURCE_SAMPLE_RATE
+            )));
+        }
+        Ok(resample_quality::compare_resamplers(source_rate))
+    }
+
+    /// A copy of the current config buffered for about `target_latency_ms` at a device's
+    /// `source_rate` (see `AudioConfig::for_target_latency`). Not applied; pass it to
+    /// `set_audio_config` to use it.
+    pub fn suggest_config_for_latency(&self, target_latency_ms: u32, source_rate: u32) -> Result<AudioConfig, SupraSonicError> {
+        self.audio_config()
+            .for_target_latency(target_latency_ms, source_rate)
+            .map_err(|e| SupraSonicError::InvalidArgument(e.to_string()))
+    }
+
+    /// Delay the resampler adds for `source_rate` input under the current config, so hosts
+    /// can shift timestamps. `offline` selects the `transcribe_file` (FFT) resampler.
+    pub fn resampler_latency(&self, source_rate: u32, offline: bool) -> Result<ResamplerLatency, SupraSonicError> {
+        let (tx, _rx) = crossbeam_channel::bounded(0);
+        let config = self.audio_config();
+        let processor = if offline {
+            AudioProcessor::new_offline(source_rate as usize, config, tx, self.metrics.clone())
+        } else {
+            AudioProcessor::new(source_rate as usize, config, tx, None, self.metrics.clone())
+        };
+        processor
+            .map(|p| p.resampler_latency())
+            .map_err(|e| SupraSonicError::Audio(e.to_string()))
+    }
+
+    /// Forces the rate live capture is resampled from; takes effect on the next recording.
+    pub fn set_source_rate_override(&self, sample_rate: Option<u32>) -> Result<(), SupraSonicError> {
+        let mut config = self.audio_config();
+        config.source_rate_override = sample_rate;
+        self.set_audio_config(config)
+    }
+
+    /// Delivers audio at the rate the loaded model expects (8..=48 kHz) from now on, e.g.
+    /// after swapping models: the resampling target, the chunk size and `output_sample_rate`
+    /// all follow. Pushed audio switches at the next `push_samples`. A live recording can't
+    /// change rate underneath its streams, so this fails until it is stopped.
+    pub fn set_model_sample_rate(&self, sample_rate: u32) -> Result<(), SupraSonicError> {
+        if !(audio::MIN_OUTPUT_SAMPLE_RATE..=audio::MAX_OUTPUT_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "Unsupported model sample rate {} Hz (expected {}..={} Hz)",
+                sample_rate, audio::MIN_OUTPUT_SAMPLE_RATE, audio::MAX_OUTPUT_SAMPLE_RATE
+            )));
+        }
+        let recording = self.is_recording.load(Ordering::SeqCst);
+        let mut config = self.audio_config();
+        if recording && config.output_sample_rate != sample_rate {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "Can't switch to {} Hz while recording at {} Hz; stop the recording first",
+                sample_rate, config.output_sample_rate
+            )));
+        }
+        config.output_sample_rate = sample_rate;
+        self.set_audio_config(config)
+    }
+
+    /// Rate processed audio is delivered at (see `set_model_sample_rate`).
+    pub fn output_sample_rate(&self) -> u32 {
+        self.audio_config().output_sample_rate
+    }
+
+    pub fn audio_config(&self) -> AudioConfig {
+        self.config.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    /// The config the running (or last) recording's stream actually got after device
+    /// negotiation and fallbacks: the rate it is resampled from, the frame size delivered,
+    /// the downmix in effect (see `AudioConfig::effective`). With several devices, that of
+    /// the first one. Before any recording, the requested config.
+    pub fn effective_config(&self) -> AudioConfig {
+        self.audio
+            .lock()
+            .ok()
+            .and_then(|audio| audio.effective_config())
+            .unwrap_or_else(|| self.audio_config())
+    }
+
+    /// Turns level computation and `on_level_changed` on or off without touching the audio,
+    /// e.g. while the meter is off-screen. Takes effect on the next chunk; `emit_levels`
+    /// still has to be on for levels to flow.
+    pub fn set_level_reporting(&self, enabled: bool) {
+        self.level_reporting.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Records the next `duration_ms` of audio (live or pushed; keep the room quiet) and
+    /// profiles its noise floor. Blocks until enough audio has arrived; fails if none
+    /// does for a few seconds beyond `duration_ms`. The profile is also kept for
+    /// `noise_profile`. Nothing in the pipeline applies it yet; it is for the host's
+    /// noise reduction.
+    pub fn capture_noise_profile(&self, duration_ms: u32) -> Result<NoiseProfile, SupraSonicError> {
+        self.ensure_running()?;
+        if !(1..=MAX_NOISE_PROFILE_MS).contains(&duration_ms) {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "duration_ms must be within 1..={}, got {}",
+                MAX_NOISE_PROFILE_MS, duration_ms
+            )));
+        }
+        let samples = self.audio_config().output_sample_rate as usize * duration_ms as usize / 1000;
+        let (reply, profile) = crossbeam_channel::bounded(1);
+        let _ = self.data_tx.send(AudioPacket::ProfileNoise { samples: samples.max(1), reply });
+        let timeout = std::time::Duration::from_millis(duration_ms as u64) + NOISE_PROFILE_GRACE;
+        let profile = profile
+            .recv_timeout(timeout)
+            .map_err(|_| SupraSonicError::Audio("No audio arrived for the noise profile; is recording running?".to_string()))?;
+        if let Ok(mut stored) = self.noise_profile.lock() {
+            *stored = Some(profile.clone());
+        }
+        Ok(profile)
+    }
+
+    /// The profile from the last successful `capture_noise_profile`.
+    pub fn noise_profile(&self) -> Option<NoiseProfile> {
+        self.noise_profile.lock().ok().and_then(|p| p.clone())
+    }
+
+    /// Callback buffer size the audio backend negotiated for the current recording, and
+    /// the latency it implies. None until the first callback (or without live capture).
+    /// With several devices it is whichever reported last.
+    pub fn capture_latency(&self) -> Option<CaptureLatency> {
+        self.metrics.capture_latency()
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.reset();
+    }
+
+    /// False if the processing or capture thread stopped ticking (e.g. it panicked).
+    ///
+    /// A listener callback blocking for longer than the 2s timeout also reports unhealthy.
+    pub fn is_pipeline_healthy(&self) -> bool {
+        let processing = self.processing_heartbeat.is_alive(metrics::HEARTBEAT_TIMEOUT);
+        let capture = self.audio.lock().map(|a| a.is_alive()).unwrap_or(false);
+        processing && capture
+    }
+
+    /// Microphone permission as last reported by the host, or `Unsupported` if never reported.
+    pub fn microphone_permission_status(&self) -> PermissionStatus {
+        self.mic_permission.lock().ok().and_then(|p| *p).unwrap_or(PermissionStatus::Unsupported)
+    }
+
+    /// Lets the host pass on what the OS said (e.g. after its own permission prompt).
+    /// While `Denied`, starting a recording fails up front instead of inside the audio thread.
+    pub fn set_microphone_permission_status(&self, status: PermissionStatus) {
+        if let Ok(mut p) = self.mic_permission.lock() {
+            *p = Some(status);
+        }
+    }
+
+    /// Same as `set_microphone_permission_status`, from a raw `AVAuthorizationStatus`.
+    pub fn set_av_authorization_status(&self, raw: i64) {
+        self.set_microphone_permission_status(PermissionStatus::from_av_authorization(raw));
+    }
+
     pub fn start_recording(&self) -> Result<(), SupraSonicError> {
-        let audio = self.audio.lock().map_err(|e: std::sync::PoisonError<_>| SupraSonicError::Lock(e.to_string()))?;
-        audio.start_capture().map_err(|e| SupraSonicError::Audio(e.to_string()))?;
+        self.begin_recording(|audio| audio.start_capture())
+    }
+
+    /// Like `start_recording`, but reopens the utterance the last stop flushed (Utterance
+    /// mode), e.g. after stopping mid-sentence by accident. New audio is appended to it and
+    /// the final is dispatched again under the same `utterance_id`, so the host can replace
+    /// its transcript. Only possible while nothing has been recorded or discarded since
+    /// that stop; otherwise, and always with `start_recording`, the new recording starts fresh.
+    pub fn continue_recording(&self) -> Result<(), SupraSonicError> {
+        self.begin_recording(|audio| audio.continue_capture())
+    }
+
+    pub fn list_input_devices(&self) -> Vec<String> {
+        AudioEngine::list_input_devices()
+    }
+
+    /// Remembers the current config's device-specific settings (source rate override,
+    /// downmix mode and channels, meter ballistics) for `device`, persisted beside the speaker registry.
+    pub fn remember_device_config(&self, device: String) {
+        self.device_prefs.remember(device, DevicePreference::from_config(&self.audio_config()));
+    }
+
+    /// The settings remembered for `device`, or the defaults if it was never remembered.
+    pub fn device_preference(&self, device: String) -> DevicePreference {
+        self.device_prefs.get(&device).unwrap_or_default()
+    }
+
+    /// Switches the current config to `device`'s remembered (or default) settings. Fails
+    /// with `SupraSonicError::Audio`, leaving the config as it was, if they don't validate
+    /// (e.g. a hand-edited preferences file).
+    pub fn apply_device_preference(&self, device: String) -> Result<(), SupraSonicError> {
+        self.apply_preference(self.device_preference(device))
+    }
+
+    /// Records from several input devices at once; audio arrives via `on_device_audio_data`.
+    /// A single device starts with its remembered settings, if any (see `remember_device_config`).
+    pub fn start_recording_devices(&self, device_names: Vec<String>) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        self.ensure_mic_permission()?;
+        if let Some(preference) = device_names.first().filter(|_| device_names.len() == 1).and_then(|d| self.device_prefs.get(d)) {
+            self.apply_preference(preference)?;
+        }
+        self.with_engine(|audio| audio.start_capture_devices(device_names))?;
+        self.begin_diagnostics();
         
-        let mut rec = self.is_recording.lock().map_err(|e: std::sync::PoisonError<_>| SupraSonicError::Lock(e.to_string()))?;
-        rec.value = true;
+        self.is_recording.store(true, Ordering::SeqCst);
         
-        tracing::info!("State: Recording started");
+        tracing::info!("State: Multi-device recording started");
         Ok(())
     }
 
+    /// Stops capture and releases the devices, paused or not. Audio not dispatched yet
+    /// is flushed as the end of the utterance, unless recording is paused and
+    /// `stop_while_paused` is `Discard`, in which case it is dropped.
     pub fn stop_recording(&self) -> Result<(), SupraSonicError> {
-        let audio = self.audio.lock().map_err(|e: std::sync::PoisonError<_>| SupraSonicError::Lock(e.to_string()))?;
-        audio.stop_capture();
+        self.with_engine(|audio| {
+            audio.stop_capture();
+            Ok(())
+        })?;
         
-        let mut rec = self.is_recording.lock().map_err(|e: std::sync::PoisonError<_>| SupraSonicError::Lock(e.to_string()))?;
-        rec.value = false;
-        
-        // Signal flush to processing loop
-        let _ = self.data_tx.send(AudioPacket::Flush);
+        self.is_recording.store(false, Ordering::SeqCst);
         
+        // The engine flushes (or, stopped while paused with `StopWhilePaused::Discard`,
+        // discards) once the streams have drained; flushing here could overtake their tails
         tracing::info!("State: Recording stopped");
+        diagnostics::end();
+        Ok(())
+    }
+
+    /// Pauses capture without ending the utterance; `resume_recording` continues it seamlessly.
+    pub fn pause_recording(&self) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        self.with_engine(|audio| audio.pause_capture())
+    }
+
+    pub fn resume_recording(&self) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        self.with_engine(|audio| audio.resume_capture())
+    }
+
+    /// Playback the host is sending to the speakers, to be cancelled from the microphone
+    /// when `echo_tail_ms` is set. Push it as it is played, not later; `start_recording`
+    /// discards what was pushed before it. Only the default-device recording is cancelled.
+    pub fn push_echo_reference(&self, samples: Vec<f32>, sample_rate: u32) -> Result<(), SupraSonicError> {
+        if !(audio::MIN_SOURCE_SAMPLE_RATE..=audio::MAX_SOURCE_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(SupraSonicError::InvalidArgument(format!("Unsupported reference sample rate: {}", sample_rate)));
+        }
+        self.with_engine(|audio| audio.push_echo_reference(&samples, sample_rate))
+    }
+
+    /// Automates the capture gain: `points` (time into the recording -> linear gain) are
+    /// interpolated linearly, the first value held before them and the last after. Times
+    /// count from each recording's start, pauses and stream-error recovery included. Empty
+    /// restores unity gain.
+    /// Takes effect on the next captured block, recording or not.
+    pub fn set_gain_envelope(&self, points: Vec<GainPoint>) -> Result<(), SupraSonicError> {
+        let envelope = if points.is_empty() {
+            None
+        } else {
+            Some(GainEnvelope::new(points).map_err(|e| SupraSonicError::InvalidArgument(e.to_string()))?)
+        };
+        self.with_engine(|audio| {
+            audio.set_gain_envelope(envelope);
+            Ok(())
+        })
+    }
+
+    /// Stops recording, dispatches what is still queued, joins the background threads and
+    /// writes pending speaker changes. Afterwards every fallible call returns an error.
+    ///
+    /// Idempotent; also runs on drop. Must not be called from a listener callback, since
+    /// it waits for the thread that runs those callbacks.
+    pub fn shutdown(&self) {
+        if self.is_shut_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tracing::info!("State: Shutting down");
+
+        if let Ok(mut slot) = self.push_processor.lock() {
+            if let Some((_, mut processor)) = slot.take() {
+                processor.finish();
+            }
+        }
+        if let Ok(mut audio) = self.audio.lock() {
+            audio.shutdown();
+        }
+        self.is_recording.store(false, Ordering::SeqCst);
+
+        let _ = self.data_tx.send(AudioPacket::Flush);
+        let _ = self.data_tx.send(AudioPacket::Shutdown);
+        if let Some(thread) = self.processing_thread.lock().ok().and_then(|mut t| t.take()) {
+            let _ = thread.join();
+        }
+        if let Some(thread) = self.assignment_thread.lock().ok().and_then(|mut t| t.take()) {
+            let _ = thread.join();
+        }
+        self.diarization.flush_save();
+    }
+
+    /// Stops listener callbacks without touching the capture stream (e.g. while a model reloads).
+    ///
+    /// With `buffer` the held audio is delivered on resume (up to ~60s, oldest dropped first);
+    /// without it, audio arriving while suspended is discarded.
+    pub fn suspend_processing(&self, buffer: bool) {
+        let _ = self.data_tx.send(AudioPacket::Suspend { buffer });
+    }
+
+    pub fn resume_processing(&self) {
+        let _ = self.data_tx.send(AudioPacket::Resume);
+    }
+
+    /// Splits interleaved multi-channel audio into one buffer per channel (see
+    /// `audio::deinterleave`), e.g. to pick the channel to `push_samples`. A trailing
+    /// partial frame is dropped.
+    pub fn deinterleave(&self, data: Vec<f32>, channels: u32) -> Result<Vec<Vec<f32>>, SupraSonicError> {
+        if channels == 0 {
+            return Err(SupraSonicError::InvalidArgument("Channel count must be at least 1".to_string()));
+        }
+        Ok(audio::deinterleave(&data, channels as usize))
+    }
+
+    /// Inverse of `deinterleave`, up to the shortest channel.
+    pub fn interleave(&self, channels: Vec<Vec<f32>>) -> Vec<f32> {
+        audio::interleave(&channels)
+    }
+
+    /// Feeds externally captured mono audio through the same resample/chunk pipeline as the mic.
+    ///
+    /// Consecutive pushes at the same rate share resampler state; call `flush` to end the utterance.
+    /// Fails with `SupraSonicError::Audio` for empty input or a rate outside 1 kHz..=768 kHz.
+    pub fn push_samples(&self, samples: Vec<f32>, sample_rate: u32) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        if !(audio::MIN_SOURCE_SAMPLE_RATE..=audio::MAX_SOURCE_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(SupraSonicError::Audio(format!(
+                "Unsupported sample rate {} Hz (expected {}..={} Hz)",
+                sample_rate, audio::MIN_SOURCE_SAMPLE_RATE, audio::MAX_SOURCE_SAMPLE_RATE
+            )));
+        }
+        if samples.is_empty() {
+            return Err(SupraSonicError::Audio("push_samples called with no samples".to_string()));
+        }
+
+        let mut slot = self.push_processor.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+
+        let config = self.audio_config();
+        // A start counted only after this read gets its Format on the next push
+        let starts = self.with_engine(|audio| Ok(audio.recording_starts()))?;
+        let mut announce = self.push_format_starts.swap(starts, Ordering::SeqCst) != starts;
+        if slot.as_ref().map(|(rate, p)| (*rate, p.output_rate())) != Some((sample_rate, config.output_sample_rate)) {
+            if let Some((_, mut previous)) = slot.take() {
+                previous.finish();
+            }
+            let processor = AudioProcessor::new(sample_rate as usize, config, self.data_tx.clone(), None, self.metrics.clone())
+                .map_err(|e| SupraSonicError::Audio(e.to_string()))?
+                .with_level_reporting(self.level_reporting.clone());
+            *slot = Some((sample_rate, processor));
+            announce = true;
+        }
+
+        if let Some((_, processor)) = slot.as_mut() {
+            // A recording start since the last Format left the loop without a rate
+            if announce {
+                let _ = self.data_tx.send(AudioPacket::Format(processor.output_rate()));
+            }
+            processor.push(&samples);
+        }
+        Ok(())
+    }
+
+    /// Runs a WAV file through the pipeline on a background thread; output arrives via the
+    /// listener like live audio, followed by a flush.
+    ///
+    /// `file_backpressure` decides what happens when the listener falls behind, and
+    /// `file_realtime_factor` optionally caps the feeder's speed further.
+    pub fn transcribe_file(&self, path: String) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        let bytes = std::fs::read(&path).map_err(|e| SupraSonicError::Audio(format!("{}: {}", path, e)))?;
+        self.transcribe_bytes(bytes, AudioFileFormat::Wav)
+    }
+
+    /// `transcribe_file` for audio already in memory, encoded as `format`. A failure once
+    /// feeding has begun (e.g. `FileBackpressure::Error`) reaches the listener's `on_error`,
+    /// after what was fed so far, which is then flushed as the end of the utterance.
+    pub fn transcribe_bytes(&self, data: Vec<u8>, format: AudioFileFormat) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        let decoded = offline::decode(&data, format).map_err(|e| SupraSonicError::Audio(e.to_string()))?;
+        let config = self.audio_config();
+        let data_tx = self.data_tx.clone();
+        let metrics = self.metrics.clone();
+        let level_reporting = self.level_reporting.clone();
+        let cancelled = self.is_shut_down.clone();
+
+        std::thread::spawn(move || {
+            if let Err(e) = offline::feed(decoded, config, data_tx.clone(), metrics, level_reporting, &cancelled) {
+                tracing::error!("File transcription failed: {}", e);
+                // Nobody is left to tell after a shutdown
+                if !cancelled.load(Ordering::SeqCst) {
+                    let _ = data_tx.send(AudioPacket::StreamError(SupraSonicError::Audio(format!("File transcription failed: {}", e))));
+                    let _ = data_tx.send(AudioPacket::Flush);
+                }
+            }
+        });
         Ok(())
     }
 
     pub fn flush(&self) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        // Pushed audio still sitting in the resampler/chunker belongs to this utterance
+        if let Ok(mut slot) = self.push_processor.lock() {
+            if let Some((_, mut processor)) = slot.take() {
+                processor.finish();
+            }
+        }
         let _ = self.data_tx.send(AudioPacket::Flush);
         Ok(())
     }
 }
 
+impl AppState {
+    fn apply_preference(&self, preference: DevicePreference) -> Result<(), SupraSonicError> {
+        let mut config = self.config.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?;
+        let mut applied = config.clone();
+        preference.apply(&mut applied);
+        applied.validate().map_err(|e| SupraSonicError::Audio(e.to_string()))?;
+        *config = applied;
+        Ok(())
+    }
+
+    /// Runs `hook` on every final segment submitted from now on, after those added earlier.
+    pub fn add_segment_hook(&self, hook: Arc<dyn SegmentHook>) {
+        if let Ok(mut hooks) = self.segment_hooks.lock() {
+            hooks.push(hook);
+        }
+    }
+
+    pub fn clear_segment_hooks(&self) {
+        if let Ok(mut hooks) = self.segment_hooks.lock() {
+            hooks.clear();
+        }
+    }
+
+    /// Starts the recording's diagnostic log, if the config asks for one.
+    fn begin_diagnostics(&self) {
+        if self.audio_config().diagnostic_log {
+            diagnostics::begin();
+        }
+    }
+
+    /// Validates and stores a submitted segment, then notifies every piece it became.
+    fn record_segment(&self, segment: Segment, voice: Option<Vec<f32>>) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        if !(segment.start.is_finite() && segment.end.is_finite()) || segment.end < segment.start {
+            return Err(SupraSonicError::InvalidArgument(format!(
+                "Segment bounds must be finite with end >= start, got {}..{}",
+                segment.start, segment.end
+            )));
+        }
+        if let Some(lang) = &segment.lang {
+            let valid = !lang.is_empty() && lang.split('-').all(|p| !p.is_empty() && p.len() <= 8 && p.chars().all(|c| c.is_ascii_alphanumeric()));
+            if !valid {
+                return Err(SupraSonicError::InvalidArgument(format!("Not a BCP-47 language tag: {:?}", lang)));
+            }
+        }
+        if let Some(confidence) = segment.confidence.filter(|c| !(0.0..=1.0).contains(c)) {
+            return Err(SupraSonicError::InvalidArgument(format!("Segment confidence must be within 0..=1, got {}", confidence)));
+        }
+        let hooks = self.segment_hooks.lock().map(|h| h.clone()).unwrap_or_default();
+        let pieces = self.session.lock().map_err(|e| SupraSonicError::Lock(e.to_string()))?.push_voiced(segment, voice, |piece| {
+            hooks.iter().all(|hook| match hook.on_segment_finalized(piece) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::info!("Segment rejected by hook: {}", e);
+                    false
+                }
+            })
+        });
+        for piece in pieces {
+            self.dispatcher.notify(|l| l.on_segment(piece));
+        }
+        Ok(())
+    }
+
+    fn ensure_running(&self) -> Result<(), SupraSonicError> {
+        if self.is_shut_down.load(Ordering::SeqCst) {
+            return Err(SupraSonicError::General("AppState has been shut down".to_string()));
+        }
+        Ok(())
+    }
+
+    fn ensure_mic_permission(&self) -> Result<(), SupraSonicError> {
+        if self.microphone_permission_status() == PermissionStatus::Denied {
+            return Err(SupraSonicError::Audio("Microphone access was denied".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Blocks for each processed buffer in turn, as `poll_audio` would return them: one per
+    /// utterance in Utterance mode, the chunks in Streaming mode. Stopping a recording
+    /// doesn't end it, since another may follow; it ends after `shutdown`, once the audio
+    /// flushed by it has been yielded. Only fed while no listener is set.
+    pub fn utterances(&self) -> impl Iterator<Item = Vec<f32>> + '_ {
+        let queue = &self.dispatcher.audio_queue.1;
+        std::iter::from_fn(move || loop {
+            match queue.recv_timeout(metrics::HEARTBEAT_INTERVAL) {
+                Ok(audio) => return Some(audio),
+                Err(_) if self.processing_finished.load(Ordering::SeqCst) => return queue.try_recv().ok(),
+                Err(_) => {}
+            }
+        })
+    }
+
+    fn begin_recording(&self, start: impl FnOnce(&AudioEngine) -> anyhow::Result<()>) -> Result<(), SupraSonicError> {
+        self.ensure_running()?;
+        self.ensure_mic_permission()?;
+        self.with_engine(start)?;
+        self.begin_diagnostics();
+        
+        self.is_recording.store(true, Ordering::SeqCst);
+        
+        tracing::info!("State: Recording started");
+        Ok(())
+    }
+
+    fn with_engine<T>(&self, f: impl FnOnce(&AudioEngine) -> anyhow::Result<T>) -> Result<T, SupraSonicError> {
+        let audio = self.audio.lock().map_err(|e: std::sync::PoisonError<_>| SupraSonicError::Lock(e.to_string()))?;
+        f(&audio).map_err(|e| SupraSonicError::Audio(e.to_string()))
+    }
+}
+
+impl Drop for AppState {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 // --- Windows/C# Compatibility Layer ---
 
+// Audio buffer contract for every function below:
+// - Samples are mono, at the output rate (16 kHz by default), contiguous, in native byte
+//   order (little-endian on all supported targets): IEEE-754 `f32` in [-1.0, 1.0] or
+//   two's-complement `i16`.
+// - Lengths and capacities are counted in samples, never bytes.
+// - Pointers handed to callbacks are only valid for the duration of the callback;
+//   copy the data out before returning.
+// - Buffers passed *in* (e.g. `suprasonic_poll_audio`) are owned by the caller; Rust only
+//   writes up to `capacity` samples and never keeps the pointer.
 #[cfg(any(target_os = "windows", feature = "csharp"))]
 mod c_api {
     use super::*;
-    use std::sync::OnceLock;
+    use crate::audio::SampleFormat;
+    use std::sync::{Mutex, OnceLock};
+
+    #[cfg(target_endian = "big")]
+    compile_error!("The C API audio buffer contract assumes a little-endian target");
 
     static APP_STATE: OnceLock<Arc<AppState>> = OnceLock::new();
-    static mut AUDIO_CALLBACK: Option<extern "C" fn(*const f32, u32)> = None;
-    static mut LEVEL_CALLBACK: Option<extern "C" fn(f32)> = None;
+    // Set from the host's thread, called from the processing thread
+    static AUDIO_CALLBACK: Mutex<Option<extern "C" fn(*const f32, u32)>> = Mutex::new(None);
+    static AUDIO_I16_CALLBACK: Mutex<Option<extern "C" fn(*const i16, u32)>> = Mutex::new(None);
+    static LEVEL_CALLBACK: Mutex<Option<extern "C" fn(f32)>> = Mutex::new(None);
+    static DEVICE_AUDIO_CALLBACK: Mutex<Option<extern "C" fn(*const std::os::raw::c_char, *const f32, u32)>> = Mutex::new(None);
+    // A polled buffer that did not fit the caller's capacity, returned by the next poll
+    static POLLED_AUDIO: Mutex<Option<Vec<f32>>> = Mutex::new(None);
+
+    /// Copies `src` into the caller's buffer. Returns the sample count written, or
+    /// `-(src.len())` without writing anything when `capacity` is too small.
+    pub(crate) fn copy_to_caller<T: Copy>(src: &[T], dst: *mut T, capacity: u32) -> i32 {
+        if src.len() > capacity as usize || dst.is_null() {
+            return -(src.len() as i32);
+        }
+        unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+        src.len() as i32
+    }
+
+    /// The callback in `slot`, copied out so the lock isn't held while the host runs it.
+    fn callback<F: Copy>(slot: &Mutex<Option<F>>) -> Option<F> {
+        slot.lock().ok().and_then(|cb| *cb)
+    }
+
+    fn install<F>(slot: &Mutex<Option<F>>, cb: F) {
+        if let Ok(mut slot) = slot.lock() {
+            *slot = Some(cb);
+        }
+    }
 
     struct CSharpListener;
     impl TranscriptionListener for CSharpListener {
-        fn on_audio_data(&self, audio_data: Vec<f32>) {
-            unsafe {
-                if let Some(cb) = AUDIO_CALLBACK {
-                    cb(audio_data.as_ptr(), audio_data.len() as u32);
-                }
+        fn on_audio_data(&self, audio_data: Vec<f32>, _utterance_id: u64) {
+            if let Some(cb) = callback(&AUDIO_CALLBACK) {
+                cb(audio_data.as_ptr(), audio_data.len() as u32);
+            }
+        }
+        fn on_audio_data_i16(&self, audio_data: Vec<i16>, _utterance_id: u64) {
+            if let Some(cb) = callback(&AUDIO_I16_CALLBACK) {
+                cb(audio_data.as_ptr(), audio_data.len() as u32);
             }
         }
         fn on_level_changed(&self, level: f32) {
-            unsafe {
-                if let Some(cb) = LEVEL_CALLBACK {
-                    cb(level);
-                }
+            if let Some(cb) = callback(&LEVEL_CALLBACK) {
+                cb(level);
+            }
+        }
+        fn on_waveform(&self, _envelope: Vec<f32>) {
+            // Not exposed on the C API; hosts draw from the polled audio instead
+        }
+        fn on_device_audio_data(&self, device: String, audio_data: Vec<f32>) {
+            let Ok(device) = std::ffi::CString::new(device) else { return };
+            if let Some(cb) = callback(&DEVICE_AUDIO_CALLBACK) {
+                cb(device.as_ptr(), audio_data.as_ptr(), audio_data.len() as u32);
+            }
+        }
+        fn on_audio_skipped(&self, reason: String) {
+            tracing::info!("C-API: audio skipped ({})", reason);
+        }
+        fn on_segment(&self, _segment: Segment) {
+            // Hosts on the C API read segments back with suprasonic_session_segments_json
+        }
+        fn on_utterance_audio(&self, audio_data: Vec<f32>, utterance_id: u64, is_final: bool) {
+            // No interim callback on the C API; the final utterance goes out as usual
+            if is_final {
+                self.on_audio_data(audio_data, utterance_id);
             }
         }
+        fn on_error(&self, error: SupraSonicError) {
+            tracing::warn!("C-API: {}", error);
+        }
+        fn on_recording_stopped(&self, reason: crate::audio::StopReason) {
+            tracing::info!("C-API: recording stopped ({:?})", reason);
+        }
+        fn on_speaker_assigned(&self, utterance_id: u64, speaker_id: String) {
+            // No assignment callback on the C API; leave `assign_speakers` off there
+            tracing::info!("C-API: utterance {} is {}", utterance_id, speaker_id);
+        }
+        fn on_features(&self, _features: Vec<f32>, _n_mels: u32, _utterance_id: u64) {
+            // No feature callback on the C API; leave `mel_bands` at 0 there
+        }
     }
 
     #[no_mangle]
@@ -170,12 +1266,90 @@ mod c_api {
 
     #[no_mangle]
     pub extern "C" fn suprasonic_set_audio_callback(cb: extern "C" fn(*const f32, u32)) {
-        unsafe { AUDIO_CALLBACK = Some(cb); }
+        install(&AUDIO_CALLBACK, cb);
+    }
+
+    #[no_mangle]
+    pub extern "C" fn suprasonic_set_audio_i16_callback(cb: extern "C" fn(*const i16, u32)) {
+        install(&AUDIO_I16_CALLBACK, cb);
+    }
+
+    /// 0 = f32 (default), 1 = i16.
+    #[no_mangle]
+    pub extern "C" fn suprasonic_set_sample_format(format: i32) -> i32 {
+        let sample_format = match format {
+            0 => SampleFormat::F32,
+            1 => SampleFormat::I16,
+            _ => return -1,
+        };
+        if let Some(state) = APP_STATE.get() {
+            let mut config = state.audio_config();
+            config.sample_format = sample_format;
+            match state.set_audio_config(config) {
+                Ok(_) => 0,
+                Err(_) => -1,
+            }
+        } else { -2 }
+    }
+
+    #[no_mangle]
+    pub extern "C" fn suprasonic_set_device_audio_callback(cb: extern "C" fn(*const std::os::raw::c_char, *const f32, u32)) {
+        install(&DEVICE_AUDIO_CALLBACK, cb);
     }
 
     #[no_mangle]
     pub extern "C" fn suprasonic_set_level_callback(cb: extern "C" fn(f32)) {
-        unsafe { LEVEL_CALLBACK = Some(cb); }
+        install(&LEVEL_CALLBACK, cb);
+    }
+
+    /// Switches from callbacks to polling: audio then queues for `suprasonic_poll_audio`.
+    #[no_mangle]
+    pub extern "C" fn suprasonic_use_polling() -> i32 {
+        if let Some(state) = APP_STATE.get() {
+            state.clear_listener();
+            0
+        } else { -2 }
+    }
+
+    /// Copies the next queued f32 buffer into `dst` (see the buffer contract above).
+    ///
+    /// Returns the number of samples written, 0 when nothing is queued, or `-needed` if
+    /// `capacity` is too small; the buffer is then kept for the next call.
+    #[no_mangle]
+    pub extern "C" fn suprasonic_poll_audio(dst: *mut f32, capacity: u32) -> i32 {
+        let Some(state) = APP_STATE.get() else { return 0 };
+        let Ok(mut held) = POLLED_AUDIO.lock() else { return 0 };
+        let Some(buffer) = held.take().or_else(|| state.poll_audio()) else { return 0 };
+
+        let written = copy_to_caller(&buffer, dst, capacity);
+        if written < 0 {
+            *held = Some(buffer);
+        }
+        written
+    }
+
+    /// Returns the speaker's color packed as 0x00RRGGBB.
+    #[no_mangle]
+    pub extern "C" fn suprasonic_speaker_color(id: *const std::os::raw::c_char) -> u32 {
+        if id.is_null() { return 0; }
+        let id = unsafe { std::ffi::CStr::from_ptr(id) }.to_string_lossy();
+        let (r, g, b) = diarization::speaker_color(&id);
+        ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+    }
+
+    /// Session transcript as a JSON array. Free the result with `suprasonic_free_string`.
+    #[no_mangle]
+    pub extern "C" fn suprasonic_session_segments_json() -> *mut std::os::raw::c_char {
+        let Some(state) = APP_STATE.get() else { return std::ptr::null_mut() };
+        let json = export::segments_to_json(&state.session_segments());
+        std::ffi::CString::new(json).map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+    }
+
+    #[no_mangle]
+    pub extern "C" fn suprasonic_free_string(s: *mut std::os::raw::c_char) {
+        if !s.is_null() {
+            unsafe { drop(std::ffi::CString::from_raw(s)) };
+        }
     }
 
     #[no_mangle]
@@ -207,4 +1381,582 @@ mod c_api {
             }
         } else { -2 }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn caller_buffer_receives_the_samples_as_they_are() {
+            let src = [0.5f32, -0.25, 1.0, -1.0];
+            let mut dst = [0.0f32; 8];
+            assert_eq!(copy_to_caller(&src, dst.as_mut_ptr(), dst.len() as u32), 4);
+            assert_eq!(dst[..4], src);
+            assert_eq!(dst[4..], [0.0; 4]);
+
+            // Native little-endian layout, as the contract promises
+            let pcm = [1i16, -2, i16::MAX];
+            let mut out = [0i16; 3];
+            copy_to_caller(&pcm, out.as_mut_ptr(), 3);
+            let bytes: Vec<u8> = out.iter().flat_map(|s| s.to_ne_bytes()).collect();
+            assert_eq!(bytes, [0x01, 0x00, 0xfe, 0xff, 0xff, 0x7f]);
+        }
+
+        #[test]
+        fn too_small_or_null_caller_buffer_is_left_untouched() {
+            let src = [0.5f32; 4];
+            let mut dst = [9.0f32; 3];
+            assert_eq!(copy_to_caller(&src, dst.as_mut_ptr(), dst.len() as u32), -4);
+            assert_eq!(dst, [9.0; 3]);
+            assert_eq!(copy_to_caller(&src, std::ptr::null_mut(), 16), -4);
+        }
+
+        static LEVELS_SEEN: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+        extern "C" fn count_level(level: f32) {
+            assert_eq!(level, 0.5);
+            LEVELS_SEEN.fetch_add(1, Ordering::SeqCst);
+        }
+
+        #[test]
+        fn callbacks_installed_from_another_thread_are_called() {
+            CSharpListener.on_level_changed(0.5);
+            assert_eq!(LEVELS_SEEN.load(Ordering::SeqCst), 0, "none installed yet");
+            std::thread::spawn(|| suprasonic_set_level_callback(count_level)).join().unwrap();
+            let dispatch = std::thread::spawn(|| (0..100).for_each(|_| CSharpListener.on_level_changed(0.5)));
+            dispatch.join().unwrap();
+            assert_eq!(LEVELS_SEEN.load(Ordering::SeqCst), 100);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{segment, RecordingListener};
+    use std::sync::atomic::AtomicUsize;
+
+    /// A fresh state whose registry lives in its own temp file.
+    fn state() -> AppState {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "suprasonic-state-{}-{}.json",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_file(&path);
+        AppState::new(path.to_string_lossy().into_owned())
+    }
+
+    fn ramp(len: usize) -> Vec<f32> {
+        (0..len).map(|i| i as f32 / len as f32 * 0.5).collect()
+    }
+
+    /// Everything still queued for polling.
+    fn drain(state: &AppState) -> Vec<Vec<f32>> {
+        std::iter::from_fn(|| state.poll_audio()).collect()
+    }
+
+    #[test]
+    fn polling_returns_buffered_audio_in_order_then_none() {
+        let state = state();
+        assert_eq!(state.poll_audio(), None);
+        assert_eq!(state.poll_level(), None);
+
+        let input = ramp(16000);
+        state.push_samples(input[..8000].to_vec(), 16000).unwrap();
+        state.push_samples(input[8000..].to_vec(), 16000).unwrap();
+        // Shutting down flushes and joins the processing thread, so everything is queued
+        state.shutdown();
+
+        let buffers = drain(&state);
+        assert!(buffers.len() > 1);
+        let polled: Vec<f32> = buffers.concat();
+        assert!(polled.len() >= input.len());
+        assert!(polled[..input.len()].windows(2).all(|w| w[1] >= w[0]), "polled audio out of order");
+        assert!(state.poll_level().is_some());
+        assert_eq!(state.poll_audio(), None);
+    }
+
+    #[test]
+    fn session_segments_hold_what_the_listener_was_given() {
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        for (speaker, text) in [("spk_0", "hello"), ("spk_1", "hi there"), ("spk_0", "how are you")] {
+            state.submit_segment(segment(speaker, text)).unwrap();
+        }
+
+        let session = state.session_segments();
+        assert_eq!(session.len(), 3);
+        assert_eq!(session, listener.segments());
+
+        state.clear_session();
+        assert!(state.session_segments().is_empty());
+        state.submit_segment(segment("spk_1", "after clearing")).unwrap();
+        assert_eq!(state.session_segments(), listener.segments()[3..]);
+    }
+
+    #[test]
+    fn push_samples_rejects_a_bad_rate_or_no_data() {
+        let state = state();
+        for rate in [0, 999, 1_000_000] {
+            assert!(matches!(state.push_samples(vec![0.1; 160], rate), Err(SupraSonicError::Audio(_))), "{rate} Hz");
+        }
+        assert!(matches!(state.push_samples(Vec::new(), 16000), Err(SupraSonicError::Audio(_))));
+        // Nothing was set up for the rejected pushes
+        assert!(state.push_processor.lock().unwrap().is_none());
+        state.push_samples(vec![0.1; 160], 16000).unwrap();
+    }
+
+    #[test]
+    fn recording_fails_fast_once_the_host_reports_mic_access_denied() {
+        let state = state();
+        assert_eq!(state.microphone_permission_status(), PermissionStatus::Unsupported);
+        // AVAuthorizationStatus.denied
+        state.set_av_authorization_status(2);
+        assert_eq!(state.microphone_permission_status(), PermissionStatus::Denied);
+        let err = state.start_recording().unwrap_err();
+        assert!(err.to_string().contains("denied"), "{err}");
+        assert!(state.start_recording_devices(vec!["mic".to_string()]).is_err());
+    }
+
+    #[test]
+    fn pipeline_turns_unhealthy_when_the_processing_thread_dies() {
+        let state = state();
+        assert!(state.is_pipeline_healthy());
+
+        // The next callback panics on the processing thread
+        state.set_listener(Box::new(RecordingListener::panicking()));
+        state.push_samples(vec![0.1; 1600], 16000).unwrap();
+        let deadline = std::time::Instant::now() + metrics::HEARTBEAT_TIMEOUT * 3;
+        while state.is_pipeline_healthy() {
+            assert!(std::time::Instant::now() < deadline, "still healthy after the processing thread died");
+            std::thread::sleep(metrics::HEARTBEAT_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn segments_with_a_malformed_language_tag_are_rejected() {
+        let state = state();
+        let bad = Segment { lang: Some("en_US".to_string()), ..segment("spk_0", "hi") };
+        assert!(matches!(state.submit_segment(bad), Err(SupraSonicError::InvalidArgument(_))));
+        let good = Segment { lang: Some("en-US".to_string()), ..segment("spk_0", "hi") };
+        state.submit_segment(good).unwrap();
+        assert_eq!(state.session_segments()[0].lang.as_deref(), Some("en-US"));
+    }
+
+    #[test]
+    fn shutdown_joins_the_threads_and_later_calls_fail() {
+        let state = state();
+        state.shutdown();
+        assert!(state.processing_thread.lock().unwrap().is_none());
+        assert!(state.assignment_thread.lock().unwrap().is_none());
+        assert!(state.processing_finished.load(Ordering::SeqCst));
+
+        assert!(state.start_recording().is_err());
+        assert!(state.push_samples(vec![0.1; 160], 16000).is_err());
+        assert!(state.flush().is_err());
+        // Idempotent, as Drop calls it again
+        state.shutdown();
+    }
+
+    #[cfg(feature = "capture")]
+    #[test]
+    fn listening_for_a_keyword_opens_the_microphone() {
+        use crate::test_support::MarkerSpotter;
+
+        let state = state();
+        state.listen_for_keyword(Box::new(MarkerSpotter)).unwrap();
+        assert!(state.is_recording.load(Ordering::SeqCst));
+        // Already listening: arming again keeps the recording
+        state.listen_for_keyword(Box::new(MarkerSpotter)).unwrap();
+        state.stop_recording().unwrap();
+        assert!(!state.is_recording.load(Ordering::SeqCst));
+
+        state.set_microphone_permission_status(PermissionStatus::Denied);
+        assert!(state.listen_for_keyword(Box::new(MarkerSpotter)).is_err());
+        assert!(!state.is_recording.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn noise_profile_is_captured_from_pushed_audio_and_kept() {
+        let state = Arc::new(state());
+        assert!(state.capture_noise_profile(0).is_err());
+        assert_eq!(state.noise_profile(), None);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let pusher = {
+            let (state, done) = (state.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    state.push_samples(vec![0.02; 1600], 16000).unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+            })
+        };
+        let profile = state.capture_noise_profile(200).unwrap();
+        done.store(true, Ordering::SeqCst);
+        pusher.join().unwrap();
+
+        assert_eq!(profile.duration_ms, 200);
+        assert!((profile.floor_rms - 0.02).abs() < 1e-4);
+        assert_eq!(state.noise_profile(), Some(profile));
+    }
+
+    #[test]
+    fn reselecting_a_device_applies_its_remembered_config() {
+        let state = state();
+        let mut config = state.audio_config();
+        config.downmix_mode = crate::audio::DownmixMode::FirstChannel;
+        config.source_rate_override = Some(44100);
+        state.set_audio_config(config).unwrap();
+        state.remember_device_config("Interface".to_string());
+
+        // Another device, never configured: back to the defaults
+        state.apply_device_preference("Webcam".to_string()).unwrap();
+        assert_eq!(state.audio_config().downmix_mode, AudioConfig::default().downmix_mode);
+        assert_eq!(state.audio_config().source_rate_override, None);
+
+        state.apply_device_preference("Interface".to_string()).unwrap();
+        assert_eq!(state.audio_config().downmix_mode, crate::audio::DownmixMode::FirstChannel);
+        assert_eq!(state.audio_config().source_rate_override, Some(44100));
+    }
+
+    #[test]
+    fn each_device_gets_its_own_meter_ballistics() {
+        let state = state();
+        let configure = |device: &str, attack, release| {
+            state.set_audio_config(AudioConfig { meter_attack_ms: attack, meter_release_ms: release, ..state.audio_config() }).unwrap();
+            state.remember_device_config(device.to_string());
+        };
+        configure("Instrument", 1, 50);
+        configure("Voice", 25, 500);
+
+        let meter = |device: &str| {
+            state.apply_device_preference(device.to_string()).unwrap();
+            (state.audio_config().meter_attack_ms, state.audio_config().meter_release_ms)
+        };
+        assert_eq!(meter("Instrument"), (1, 50));
+        assert_eq!(meter("Voice"), (25, 500));
+        assert_eq!(meter("Unknown"), (10, 300));
+        assert!(state.set_audio_config(AudioConfig { meter_release_ms: 10_001, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn an_invalid_remembered_preference_is_refused() {
+        let state = state();
+        let mut preference = DevicePreference::from_config(&state.audio_config());
+        preference.meter_release_ms = 10_001;
+        preference.source_rate_override = Some(44100);
+        state.device_prefs.remember("Edited".to_string(), preference);
+
+        assert!(matches!(state.apply_device_preference("Edited".to_string()), Err(SupraSonicError::Audio(_))));
+        let config = state.audio_config();
+        assert_eq!((config.meter_release_ms, config.source_rate_override), (300, None));
+    }
+
+    /// The final utterance `transcribe` produces on a fresh state, in Utterance mode.
+    fn transcribed(transcribe: impl FnOnce(&AppState) -> Result<(), SupraSonicError>) -> Vec<f32> {
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        state.set_audio_config(AudioConfig { dispatch_mode: audio::DispatchMode::Utterance, ..Default::default() }).unwrap();
+        transcribe(&state).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while listener.audio().is_empty() {
+            assert!(std::time::Instant::now() < deadline, "the file never came through");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        listener.audio().remove(0).0
+    }
+
+    #[test]
+    fn a_failed_file_transcription_reports_and_flushes_what_it_fed() {
+        use crate::test_support::Event;
+        let state = state();
+        // Every level takes a while, so the file soon gets more than the bound ahead
+        let listener = RecordingListener::slow(std::time::Duration::from_millis(2));
+        state.set_listener(Box::new(listener.clone()));
+        state
+            .set_audio_config(AudioConfig {
+                dispatch_mode: audio::DispatchMode::Utterance,
+                vad_enabled: false,
+                file_backpressure: audio::FileBackpressure::Error,
+                ..Default::default()
+            })
+            .unwrap();
+        let samples: Vec<f32> = (0..16000 * 30).map(|i| 0.3 * (i as f32 * 0.07).sin()).collect();
+        state.transcribe_bytes(export::encode_wav(&samples, 16000), AudioFileFormat::Wav).unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        while listener.audio().is_empty() {
+            assert!(std::time::Instant::now() < deadline, "the partial utterance was never flushed");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let events = listener.events();
+        let error = events.iter().position(|e| matches!(e, Event::Error(e) if e.contains("fell behind"))).expect("on_error");
+        let flushed = events.iter().position(|e| matches!(e, Event::Audio(..))).unwrap();
+        assert!(error < flushed);
+        let partial = listener.audio()[0].0.len();
+        assert!(partial > 0 && partial < samples.len(), "{partial} samples");
+    }
+
+    #[test]
+    fn bytes_and_file_transcribe_to_identical_output() {
+        let samples: Vec<f32> = (0..44100).map(|i| 0.3 * (i as f32 * 0.07).sin()).collect();
+        let wav = export::encode_wav(&samples, 44100);
+        let path = std::env::temp_dir().join(format!("suprasonic-transcribe-{}.wav", std::process::id()));
+        std::fs::write(&path, &wav).unwrap();
+
+        let from_file = transcribed(|state| state.transcribe_file(path.to_string_lossy().into_owned()));
+        let from_bytes = transcribed(|state| state.transcribe_bytes(wav, AudioFileFormat::Wav));
+        let _ = std::fs::remove_file(&path);
+        assert!(from_file.len().abs_diff(16000) <= 480, "{} samples for 1 s", from_file.len());
+        assert_eq!(from_file, from_bytes);
+
+        let state = state();
+        assert!(state.transcribe_bytes(b"not a wav".to_vec(), AudioFileFormat::Wav).is_err());
+        assert!(state.transcribe_file("/nonexistent/file.wav".to_string()).is_err());
+    }
+
+    /// Records every segment it sees; rejects those whose text starts with "spam".
+    #[derive(Default)]
+    struct SpamFilter {
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl SegmentHook for SpamFilter {
+        fn on_segment_finalized(&self, segment: &Segment) -> anyhow::Result<()> {
+            self.seen.lock().unwrap().push(segment.text.clone());
+            if segment.text.starts_with("spam") {
+                anyhow::bail!("looks like spam");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn segment_hooks_run_per_final_segment_and_rejection_suppresses_dispatch() {
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        let (filter, after) = (Arc::new(SpamFilter::default()), Arc::new(SpamFilter::default()));
+        state.add_segment_hook(filter.clone());
+        state.add_segment_hook(after.clone());
+
+        state.submit_segment(Segment { is_final: false, ..segment("spk_0", "hel") }).unwrap();
+        state.submit_segment(segment("spk_0", "hello")).unwrap();
+        state.submit_segment(segment("spk_0", "spam offer")).unwrap();
+        state.submit_segment(segment("spk_1", "bye")).unwrap();
+
+        // Interims aren't final, and a rejection stops the later hooks too
+        assert_eq!(*filter.seen.lock().unwrap(), ["hello", "spam offer", "bye"]);
+        assert_eq!(*after.seen.lock().unwrap(), ["hello", "bye"]);
+        let dispatched: Vec<String> = listener.segments().into_iter().filter(|s| s.is_final).map(|s| s.text).collect();
+        assert_eq!(dispatched, ["hello", "bye"]);
+        assert_eq!(state.session_segments().iter().map(|s| s.text.as_str()).collect::<Vec<_>>(), ["hello", "bye"]);
+
+        state.clear_segment_hooks();
+        state.submit_segment(segment("spk_0", "spam again")).unwrap();
+        assert_eq!(state.session_segments().len(), 3);
+    }
+
+    #[test]
+    fn max_segment_duration_rejects_values_that_would_cut_mid_word() {
+        let state = state();
+        for max_seconds in [0.1, 0.49, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(state.set_max_segment_duration(max_seconds).is_err(), "{max_seconds}");
+        }
+        for max_seconds in [0.0, MIN_MAX_SEGMENT_SECS, 30.0] {
+            assert!(state.set_max_segment_duration(max_seconds).is_ok(), "{max_seconds}");
+        }
+    }
+
+    #[test]
+    fn session_metadata_round_trips_through_the_json_export() {
+        let state = state();
+        state.submit_segment(segment("spk_0", "hello")).unwrap();
+        let untagged = export::TranscriptExport::from_json(&state.export_transcript_json()).unwrap();
+        assert_eq!(untagged.metadata, None);
+        assert!(!state.export_transcript_json().contains("metadata"), "left out when unset");
+
+        let metadata = SessionMetadata {
+            title: Some("Weekly sync \u{2014} \"Q3\"".to_string()),
+            participants: vec!["Alice".to_string(), "Bob".to_string()],
+            timestamp_ms: Some(1_790_000_000_000),
+            notes: None,
+        };
+        state.set_session_metadata(metadata.clone()).unwrap();
+        let export = export::TranscriptExport::from_json(&state.export_transcript_json()).unwrap();
+        assert_eq!(export.metadata, Some(metadata));
+        assert_eq!(export.segments, state.session_segments());
+
+        // Only the fields that were set need to be present
+        let sparse = export::TranscriptExport::from_json(r#"{"metadata": {"title": "t"}, "segments": []}"#).unwrap();
+        assert_eq!(sparse.metadata, Some(SessionMetadata { title: Some("t".to_string()), ..Default::default() }));
+
+        state.clear_session();
+        assert_eq!(state.session_metadata(), None);
+    }
+
+    #[test]
+    fn utterances_are_yielded_in_order_and_end_on_shutdown() {
+        let state = Arc::new(state());
+        state.set_audio_config(AudioConfig { dispatch_mode: audio::DispatchMode::Utterance, ..Default::default() }).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let consumer = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                for utterance in state.utterances() {
+                    tx.send(utterance.len()).unwrap();
+                }
+            })
+        };
+
+        // Told apart by length: 0.6 s, 1.2 s and 1.8 s of tone
+        for tenths in [6, 12, 18] {
+            let tone: Vec<f32> = (0..1600 * tenths).map(|i| 0.3 * (i as f32 * 0.07).sin()).collect();
+            state.push_samples(tone, 16000).unwrap();
+            state.flush().unwrap();
+        }
+        let received: Vec<usize> = (0..3).map(|_| rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap()).collect();
+        for (len, tenths) in received.iter().zip([6, 12, 18]) {
+            assert!(len.abs_diff(1600 * tenths) <= 480, "{received:?}");
+        }
+
+        // Stopping isn't the end; shutting down is, once nothing is left to yield
+        state.stop_recording().unwrap();
+        assert!(rx.recv_timeout(std::time::Duration::from_millis(300)).is_err());
+        assert!(!consumer.is_finished());
+        state.shutdown();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while !consumer.is_finished() {
+            assert!(std::time::Instant::now() < deadline, "the iterator never ended");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        consumer.join().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[cfg(feature = "capture")]
+    #[test]
+    fn pushed_audio_is_announced_again_after_a_recording_start() {
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        state.set_audio_config(AudioConfig { dispatch_mode: audio::DispatchMode::Utterance, ..Default::default() }).unwrap();
+        state.push_samples(vec![0.1; 16000], 16000).unwrap();
+
+        // A recording start resets the loop's rate; whether or not a device opens, the
+        // processor kept for pushing has to send its Format again
+        let _ = state.start_recording();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while state.with_engine(|audio| Ok(audio.recording_starts())).unwrap() == 0 {
+            assert!(std::time::Instant::now() < deadline, "the start never reached the engine");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let _ = state.stop_recording();
+        state.push_samples(vec![0.2; 8000], 16000).unwrap();
+        state.flush().unwrap();
+        // Should a device have opened, its stop flushes a final of its own first
+        let pushed_after = || {
+            let finals = listener.audio();
+            finals.iter().flat_map(|(audio, _)| audio).filter(|&&s| (s - 0.2).abs() < 1e-6).count()
+        };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while pushed_after() < 8000 - 480 {
+            assert!(std::time::Instant::now() < deadline, "{} of the 8000 samples pushed after the start", pushed_after());
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    /// Takes `delay` per utterance; the embedding points the way its first sample says.
+    struct SlowEmbedder {
+        delay: std::time::Duration,
+    }
+
+    impl SpeakerEmbedder for SlowEmbedder {
+        fn embed(&self, samples: Vec<f32>, _sample_rate: u32) -> Result<Vec<f32>, SupraSonicError> {
+            std::thread::sleep(self.delay);
+            let angle = samples[0] * std::f32::consts::PI;
+            Ok(vec![angle.cos(), angle.sin()])
+        }
+    }
+
+    #[test]
+    fn slow_speaker_assignment_never_holds_up_dispatch() {
+        use crate::test_support::Event;
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        state.register_speaker("alice".to_string(), "Alice".to_string()).unwrap();
+        state.enroll_speaker_embedding("alice".to_string(), vec![1.0, 0.0]).unwrap();
+        state.set_speaker_embedder(Box::new(SlowEmbedder { delay: std::time::Duration::from_millis(400) }));
+        state.set_audio_config(AudioConfig { dispatch_mode: audio::DispatchMode::Utterance, assign_speakers: true, ..Default::default() }).unwrap();
+
+        // Alice, someone else, Alice again
+        for value in [0.0, 0.5, 0.0] {
+            state.push_samples(vec![value; 8000], 16000).unwrap();
+            state.flush().unwrap();
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let assigned = || -> Vec<(u64, String)> {
+            let events = listener.events();
+            events.into_iter().filter_map(|e| if let Event::SpeakerAssigned(id, speaker) = e { Some((id, speaker)) } else { None }).collect()
+        };
+        while assigned().len() < 3 {
+            assert!(std::time::Instant::now() < deadline, "only {:?} assigned", assigned());
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // Every final went out while the first one was still being embedded
+        let events = listener.events();
+        let first_assigned = events.iter().position(|e| matches!(e, Event::SpeakerAssigned(..))).unwrap();
+        let finals: Vec<u64> = events[..first_assigned].iter().filter_map(|e| if let Event::Audio(_, id) = e { Some(*id) } else { None }).collect();
+        assert_eq!(finals.len(), 3, "{events:?}");
+        let is_alice: Vec<(u64, bool)> = assigned().into_iter().map(|(id, speaker)| (id, speaker == "alice")).collect();
+        assert_eq!(is_alice, [(finals[0], true), (finals[1], false), (finals[2], true)]);
+    }
+
+    #[test]
+    fn model_sample_rate_retargets_the_resampler_and_output_rate() {
+        let state = state();
+        let listener = RecordingListener::default();
+        state.set_listener(Box::new(listener.clone()));
+        state.set_audio_config(AudioConfig { dispatch_mode: audio::DispatchMode::Utterance, ..Default::default() }).unwrap();
+        let utterance = |pushed_rate: u32| {
+            let before = listener.audio().len();
+            state.push_samples(vec![0.1; pushed_rate as usize], pushed_rate).unwrap();
+            state.flush().unwrap();
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            while listener.audio().len() == before {
+                assert!(std::time::Instant::now() < deadline, "no final");
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            listener.audio().pop().unwrap().0.len()
+        };
+        assert_eq!(state.output_sample_rate(), 16000);
+        assert!(utterance(44100).abs_diff(16000) <= 480);
+
+        // One second of audio comes out as one second at the model's rate
+        state.set_model_sample_rate(8000).unwrap();
+        assert_eq!((state.output_sample_rate(), state.audio_config().output_sample_rate), (8000, 8000));
+        assert!(utterance(44100).abs_diff(8000) <= 240);
+        assert!(utterance(8000).abs_diff(8000) <= 240, "no resampling at the model's own rate");
+        state.set_model_sample_rate(48000).unwrap();
+        assert!(utterance(16000).abs_diff(48000) <= 1440);
+
+        for rate in [0, 7999, 48001, 96000] {
+            assert!(matches!(state.set_model_sample_rate(rate), Err(SupraSonicError::InvalidArgument(_))), "{rate}");
+        }
+        assert_eq!(state.output_sample_rate(), 48000);
+
+        // Mid-recording only the current rate is accepted
+        state.is_recording.store(true, Ordering::SeqCst);
+        assert!(state.set_model_sample_rate(16000).is_err());
+        assert!(state.set_model_sample_rate(48000).is_ok());
+        state.is_recording.store(false, Ordering::SeqCst);
+        assert!(state.set_model_sample_rate(16000).is_ok());
+    }
 }
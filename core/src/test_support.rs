@@ -0,0 +1,162 @@
+// Shared by the unit tests: a listener that records the callbacks it receives.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audio::StopReason;
+use crate::diarization::Segment;
+use crate::state::{KeywordSpotter, SupraSonicError, TranscriptionListener};
+
+/// A recorded listener callback, with its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Event {
+    Audio(Vec<f32>, u64),
+    /// `on_utterance_audio`: the audio, its utterance id and `is_final`.
+    Utterance(Vec<f32>, u64, bool),
+    Level(f32),
+    Segment(Segment),
+    /// `on_features`: the frames, bands per frame and the utterance id.
+    Features(Vec<f32>, u32, u64),
+    /// `on_error`, as the error's `Debug` form (it isn't `Clone`).
+    Error(String),
+    /// `on_speaker_assigned`: the utterance id and its speaker.
+    SpeakerAssigned(u64, String),
+}
+
+/// Records callbacks in order; clones share the record. Every callback is counted, the
+/// ones the tests look into are also kept as `Event`s.
+#[derive(Clone, Default)]
+pub(crate) struct RecordingListener {
+    events: Arc<Mutex<Vec<Event>>>,
+    calls: Arc<AtomicUsize>,
+    // Panic in every callback, to take down the thread delivering it
+    panics: bool,
+    // Time every callback takes, to fall behind the pipeline
+    delay: Option<Duration>,
+}
+
+impl RecordingListener {
+    /// A listener whose every callback panics.
+    pub(crate) fn panicking() -> Self {
+        Self { panics: true, ..Self::default() }
+    }
+
+    /// A listener taking `delay` over every callback.
+    pub(crate) fn slow(delay: Duration) -> Self {
+        Self { delay: Some(delay), ..Self::default() }
+    }
+
+    pub(crate) fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Callbacks of any kind received so far.
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// The f32 audio chunks received, in order.
+    pub(crate) fn audio(&self) -> Vec<(Vec<f32>, u64)> {
+        self.events().into_iter().filter_map(|e| if let Event::Audio(data, id) = e { Some((data, id)) } else { None }).collect()
+    }
+
+    /// `on_utterance_audio` calls as (sample count, utterance id, is_final), in order.
+    pub(crate) fn utterances(&self) -> Vec<(usize, u64, bool)> {
+        self.events().into_iter().filter_map(|e| if let Event::Utterance(data, id, is_final) = e { Some((data.len(), id, is_final)) } else { None }).collect()
+    }
+
+    /// The segments received, in order.
+    pub(crate) fn segments(&self) -> Vec<Segment> {
+        self.events().into_iter().filter_map(|e| if let Event::Segment(s) = e { Some(s) } else { None }).collect()
+    }
+
+    fn record(&self, event: Option<Event>) {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        assert!(!self.panics, "listener panicked on purpose");
+        if let Some(delay) = self.delay {
+            std::thread::sleep(delay);
+        }
+        if let Some(event) = event {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+}
+
+impl TranscriptionListener for RecordingListener {
+    fn on_audio_data(&self, audio_data: Vec<f32>, utterance_id: u64) {
+        self.record(Some(Event::Audio(audio_data, utterance_id)));
+    }
+
+    fn on_audio_data_i16(&self, _audio_data: Vec<i16>, _utterance_id: u64) {
+        self.record(None);
+    }
+
+    fn on_level_changed(&self, level: f32) {
+        self.record(Some(Event::Level(level)));
+    }
+
+    fn on_waveform(&self, _envelope: Vec<f32>) {
+        self.record(None);
+    }
+
+    fn on_device_audio_data(&self, _device: String, _audio_data: Vec<f32>) {
+        self.record(None);
+    }
+
+    fn on_audio_skipped(&self, _reason: String) {
+        self.record(None);
+    }
+
+    fn on_segment(&self, segment: Segment) {
+        self.record(Some(Event::Segment(segment)));
+    }
+
+    fn on_utterance_audio(&self, audio_data: Vec<f32>, utterance_id: u64, is_final: bool) {
+        self.record(Some(Event::Utterance(audio_data, utterance_id, is_final)));
+    }
+
+    fn on_features(&self, features: Vec<f32>, n_mels: u32, utterance_id: u64) {
+        self.record(Some(Event::Features(features, n_mels, utterance_id)));
+    }
+
+    fn on_error(&self, error: SupraSonicError) {
+        self.record(Some(Event::Error(format!("{:?}", error))));
+    }
+
+    fn on_recording_stopped(&self, _reason: StopReason) {
+        self.record(None);
+    }
+
+    fn on_speaker_assigned(&self, utterance_id: u64, speaker_id: String) {
+        self.record(Some(Event::SpeakerAssigned(utterance_id, speaker_id)));
+    }
+}
+
+/// A final segment of `speaker_id` saying `text`, over 0..1 s.
+pub(crate) fn segment(speaker_id: &str, text: &str) -> Segment {
+    Segment {
+        start: 0.0,
+        end: 1.0,
+        text: text.to_string(),
+        speaker_id: speaker_id.to_string(),
+        is_final: true,
+        utterance_id: None,
+        lang: None,
+        confidence: None,
+        low_confidence: false,
+    }
+}
+
+/// Sample value `MarkerSpotter` takes for the keyword.
+pub(crate) const KEYWORD_MARKER: f32 = 0.9;
+
+/// "Hears" the keyword in any chunk starting with `KEYWORD_MARKER`.
+pub(crate) struct MarkerSpotter;
+
+impl KeywordSpotter for MarkerSpotter {
+    fn process(&self, audio_data: Vec<f32>) -> bool {
+        audio_data.first() == Some(&KEYWORD_MARKER)
+    }
+}
+
@@ -0,0 +1,421 @@
+// Session transcript aggregation.
+
+use crate::diarization::{self, Segment};
+
+/// In-process hook run on every final segment before it is stored and dispatched, e.g. to
+/// forward it to a backend. For Rust embedders; doesn't cross the FFI.
+pub trait SegmentHook: Send + Sync {
+    /// Returning an error rejects the segment: it is neither stored nor sent to
+    /// `on_segment`, and the error is logged. Runs with the transcript locked, so it must
+    /// not call back into `AppState`'s transcript methods.
+    fn on_segment_finalized(&self, segment: &Segment) -> anyhow::Result<()>;
+}
+
+// Largest smoothing window `AppState::set_speaker_smoothing` accepts
+pub const MAX_SMOOTHING_WINDOW: u32 = 15;
+// Shortest maximum `AppState::set_max_segment_duration` accepts (besides 0 = off); below
+// it most pieces would hold a word or less
+pub const MIN_MAX_SEGMENT_SECS: f64 = 0.5;
+
+/// Every segment submitted during the current session, in order.
+///
+/// Interim (non-final) segments are revisions of the utterance in progress: each one
+/// replaces the previous interim segment, and the final segment replaces the last interim.
+///
+/// External speaker turns (see `add_turn`) take precedence over the speaker id a segment
+/// was submitted with: segments are split at every turn inside them and each piece is
+/// attributed to the turn it starts in. Only audio before the first turn keeps its own id.
+///
+/// Segments longer than the configured maximum (see `set_max_segment_duration`) are cut
+/// into pieces no longer than it, all keeping the speaker: each at the last word boundary
+/// that fits, or a clause break shortly before it.
+///
+/// Segments pushed with the voice embedding their speaker was assigned from can be
+/// smoothed (see `set_speaker_smoothing`): a lone label that disagrees with most of its
+/// neighbours is snapped to theirs when the voices are close.
+#[derive(Debug, Clone, Default)]
+pub struct SessionTranscript {
+    segments: Vec<Segment>,
+    // (start time, speaker id), sorted by time
+    turns: Vec<(f64, String)>,
+    // Segments with a confidence below this get `low_confidence_policy` (0 = off)
+    min_confidence: f32,
+    low_confidence_policy: LowConfidencePolicy,
+    // Per stored segment: the embedding its speaker was auto-assigned from, if any
+    voices: Vec<Option<Vec<f32>>>,
+    // Segments per smoothing window (0 = off) and the voice similarity needed to relabel
+    smoothing_window: usize,
+    smoothing_similarity: f32,
+    // Longest segment stored, in seconds (0 = unlimited)
+    max_segment_secs: f64,
+}
+
+/// What happens to a segment whose confidence is below the configured minimum.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum LowConfidencePolicy {
+    /// Never stored nor forwarded (it still supersedes the interim it revises).
+    #[default]
+    Drop,
+    /// Kept and forwarded with `Segment::low_confidence` set.
+    Mark,
+}
+
+impl SessionTranscript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `segment`, split at speaker turns; returns the pieces as stored (none if it
+    /// was dropped for low confidence).
+    pub fn push(&mut self, segment: Segment) -> Vec<Segment> {
+        self.push_voiced(segment, None, |_| true)
+    }
+
+    /// `push` for a segment whose speaker was assigned from `voice`, keeping only the final
+    /// pieces `accept` returns true for. What it returns may start with an earlier
+    /// segment, revised by speaker smoothing.
+    pub fn push_voiced(
+        &mut self,
+        mut segment: Segment,
+        voice: Option<Vec<f32>>,
+        mut accept: impl FnMut(&Segment) -> bool,
+    ) -> Vec<Segment> {
+        // An interim may have been split too; all of its pieces are superseded
+        while self.segments.last().is_some_and(|s| !s.is_final) {
+            self.segments.pop();
+            self.voices.pop();
+        }
+        // Segments without a confidence always pass
+        segment.low_confidence = segment.confidence.is_some_and(|c| c < self.min_confidence);
+        if segment.low_confidence && self.low_confidence_policy == LowConfidencePolicy::Drop {
+            return Vec::new();
+        }
+        let pieces = self.split_at_turns(segment);
+        // Turns override the assignment, so only a segment no turn touched keeps its voice
+        let voice = voice.filter(|_| pieces.len() == 1 && self.speaker_at(pieces[0].start).is_none());
+        let mut pieces: Vec<Segment> = pieces.into_iter().flat_map(|piece| self.split_long(piece)).collect();
+        pieces.retain(|piece| !piece.is_final || accept(piece));
+        if pieces.is_empty() {
+            return pieces;
+        }
+        self.segments.extend(pieces.iter().cloned());
+        self.voices.resize(self.segments.len() - 1, None);
+        self.voices.push(voice);
+        if let Some(revised) = self.smooth() {
+            pieces.insert(0, revised);
+        }
+        pieces
+    }
+
+    /// With `window` >= 3 (odd), relabels the middle of the last `window` final, voiced
+    /// segments when a strict majority of the others share another speaker whose voices
+    /// are, on average, at least `min_similarity` (cosine) close to its own. Lower values
+    /// smooth more aggressively. 0 turns smoothing off.
+    pub fn set_speaker_smoothing(&mut self, window: u32, min_similarity: f32) {
+        self.smoothing_window = window as usize;
+        self.smoothing_similarity = min_similarity;
+    }
+
+    /// Applies speaker smoothing to the newest full window; returns the segment it relabelled.
+    fn smooth(&mut self) -> Option<Segment> {
+        let window = self.smoothing_window;
+        if window < 3 || self.segments.len() < window {
+            return None;
+        }
+        let start = self.segments.len() - window;
+        let tail = &self.segments[start..];
+        if tail.iter().any(|s| !s.is_final) {
+            return None;
+        }
+        let voices: Vec<&[f32]> = self.voices[start..].iter().map(|v| v.as_deref()).collect::<Option<_>>()?;
+        let centre = window / 2;
+        let neighbours = || (0..window).filter(move |&i| i != centre);
+        let votes = |id: &str| neighbours().filter(|&i| tail[i].speaker_id == id).count();
+        let majority = neighbours().map(|i| tail[i].speaker_id.as_str()).max_by_key(|id| votes(id))?;
+        if majority == tail[centre].speaker_id || votes(majority) * 2 < window {
+            return None;
+        }
+        let agreeing: Vec<f32> = neighbours()
+            .filter(|&i| tail[i].speaker_id == majority)
+            .map(|i| diarization::cosine_similarity(voices[centre], voices[i]))
+            .collect();
+        if agreeing.iter().sum::<f32>() / (agreeing.len() as f32) < self.smoothing_similarity {
+            return None;
+        }
+        let majority = majority.to_string();
+        let segment = &mut self.segments[start + centre];
+        segment.speaker_id = majority;
+        Some(segment.clone())
+    }
+
+    /// Cuts segments longer than `max_secs` between words (0 = no limit).
+    pub fn set_max_segment_duration(&mut self, max_secs: f64) {
+        self.max_segment_secs = max_secs;
+    }
+
+    /// Applies `policy` to segments whose confidence is below `min_confidence` (0 = off).
+    pub fn set_confidence_filter(&mut self, min_confidence: f32, policy: LowConfidencePolicy) {
+        self.min_confidence = min_confidence;
+        self.low_confidence_policy = policy;
+    }
+
+    /// Records that `speaker_id` started talking at `at` seconds (session time).
+    pub fn add_turn(&mut self, at: f64, speaker_id: String) {
+        let index = self.turns.partition_point(|(t, _)| *t <= at);
+        self.turns.insert(index, (at, speaker_id));
+    }
+
+    /// The speaker of the turn in progress at `at`, if any turn has started by then.
+    fn speaker_at(&self, at: f64) -> Option<&str> {
+        let index = self.turns.partition_point(|(t, _)| *t <= at);
+        index.checked_sub(1).map(|i| self.turns[i].1.as_str())
+    }
+
+    /// Cuts `segment` at each turn strictly inside it.
+    fn split_at_turns(&self, segment: Segment) -> Vec<Segment> {
+        let mut bounds = vec![segment.start];
+        bounds.extend(self.turns.iter().map(|(t, _)| *t).filter(|t| *t > segment.start && *t < segment.end));
+        bounds.dedup();
+        bounds.push(segment.end);
+        split_at(segment, &bounds, |at| self.speaker_at(at))
+    }
+
+    /// Cuts `segment` into pieces within the maximum duration. Without word timings each
+    /// word is taken to last in proportion to its length; a piece ends after the last word
+    /// that fits, or after a clause-ending one (',', '.', '?'...) in the second half of
+    /// the window, a likely pause. Where no word ends in reach, it is cut at the limit.
+    fn split_long(&self, segment: Segment) -> Vec<Segment> {
+        let max = self.max_segment_secs;
+        if max <= 0.0 || segment.end - segment.start <= max {
+            return vec![segment];
+        }
+        let words: Vec<&str> = segment.text.split_whitespace().collect();
+        let chars: usize = words.iter().map(|w| w.chars().count()).sum();
+        let mut word_ends = Vec::with_capacity(words.len());
+        let mut elapsed = 0;
+        for word in &words {
+            elapsed += word.chars().count();
+            word_ends.push(segment.start + (segment.end - segment.start) * elapsed as f64 / chars as f64);
+        }
+
+        let mut pieces = Vec::new();
+        let (mut start, mut first_word) = (segment.start, 0);
+        while segment.end - start > max {
+            let limit = start + max;
+            let fitting = word_ends[first_word..].iter().take_while(|end| **end <= limit).count();
+            let candidates = first_word..first_word + fitting;
+            let pause = candidates.clone().rev().take_while(|i| word_ends[*i] >= start + max / 2.0).find(|i| ends_clause(words[*i]));
+            let (cut, next_word) = match pause.or(candidates.last()) {
+                Some(last) => (word_ends[last], last + 1),
+                None => (limit, first_word),
+            };
+            pieces.push(Segment { start, end: cut, text: words[first_word..next_word].join(" "), ..segment.clone() });
+            (start, first_word) = (cut, next_word);
+        }
+        pieces.push(Segment { start, text: words[first_word..].join(" "), ..segment });
+        pieces
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.voices.clear();
+        self.turns.clear();
+    }
+}
+
+fn ends_clause(word: &str) -> bool {
+    word.ends_with([',', '.', ';', ':', '?', '!'])
+}
+
+/// Cuts `segment` at `bounds` (its start, the cut points, its end), attributing each piece
+/// to `speaker_at` its start or else the segment's own speaker. Without word timings the
+/// words are shared out in proportion to each piece's duration, so cuts fall between words.
+fn split_at<'a>(segment: Segment, bounds: &[f64], speaker_at: impl Fn(f64) -> Option<&'a str>) -> Vec<Segment> {
+    let words: Vec<&str> = segment.text.split_whitespace().collect();
+    let duration = segment.end - segment.start;
+    let mut pieces = Vec::with_capacity(bounds.len() - 1);
+    let mut word_start = 0;
+    for (i, span) in bounds.windows(2).enumerate() {
+        let word_end = if i == bounds.len() - 2 {
+            words.len()
+        } else {
+            ((span[1] - segment.start) / duration * words.len() as f64).round() as usize
+        };
+        let speaker_id = speaker_at(span[0]).map_or_else(|| segment.speaker_id.clone(), str::to_string);
+        pieces.push(Segment {
+            start: span[0],
+            end: span[1],
+            text: words[word_start..word_end.max(word_start)].join(" "),
+            speaker_id,
+            ..segment.clone()
+        });
+        word_start = word_end.max(word_start);
+    }
+    if pieces.len() == 1 {
+        // Nothing to cut: keep the text exactly as submitted
+        pieces[0].text = segment.text;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::segment;
+
+    fn timed(speaker_id: &str, text: &str, start: f64, end: f64) -> Segment {
+        Segment { start, end, ..segment(speaker_id, text) }
+    }
+
+    #[test]
+    fn pushed_turn_splits_a_segment_at_its_time() {
+        let mut transcript = SessionTranscript::new();
+        transcript.add_turn(3.0, "bob".to_string());
+        let pieces = transcript.push(timed("alice", "one two three four five six", 0.0, 6.0));
+
+        let spans: Vec<(f64, f64, &str, &str)> =
+            pieces.iter().map(|s| (s.start, s.end, s.text.as_str(), s.speaker_id.as_str())).collect();
+        // Before the first turn the segment keeps its own (embedding) speaker
+        assert_eq!(spans, [(0.0, 3.0, "one two three", "alice"), (3.0, 6.0, "four five six", "bob")]);
+        assert_eq!(transcript.segments(), &pieces[..]);
+
+        // Later segments are attributed to the turn they fall in, whatever they were assigned
+        let later = transcript.push(timed("alice", "seven", 6.0, 7.0));
+        assert_eq!(later[0].speaker_id, "bob");
+    }
+
+    #[test]
+    fn a_final_replaces_every_piece_of_a_split_interim() {
+        let mut transcript = SessionTranscript::new();
+        transcript.add_turn(1.0, "bob".to_string());
+        let interim = Segment { is_final: false, ..timed("alice", "one two", 0.0, 2.0) };
+        assert_eq!(transcript.push(interim).len(), 2);
+        transcript.push(timed("alice", "one two three", 0.0, 3.0));
+        let texts: Vec<&str> = transcript.segments().iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["one", "two three"]);
+    }
+
+    #[test]
+    fn below_threshold_segments_are_dropped_or_marked_per_policy() {
+        let confident = |c: f32| Segment { confidence: Some(c), ..segment("alice", "hello") };
+
+        let mut transcript = SessionTranscript::new();
+        transcript.set_confidence_filter(0.5, LowConfidencePolicy::Drop);
+        assert!(transcript.push(confident(0.2)).is_empty());
+        assert_eq!(transcript.push(confident(0.8)).len(), 1);
+        // No confidence at all always passes
+        assert_eq!(transcript.push(segment("alice", "hi")).len(), 1);
+        assert_eq!(transcript.segments().len(), 2);
+
+        let mut transcript = SessionTranscript::new();
+        transcript.set_confidence_filter(0.5, LowConfidencePolicy::Mark);
+        let low = transcript.push(confident(0.2));
+        assert!(low[0].low_confidence);
+        assert!(!transcript.push(confident(0.8))[0].low_confidence);
+        assert_eq!(transcript.segments().len(), 2);
+    }
+
+    #[test]
+    fn dropped_final_still_supersedes_its_interim() {
+        let mut transcript = SessionTranscript::new();
+        transcript.set_confidence_filter(0.5, LowConfidencePolicy::Drop);
+        transcript.push(Segment { is_final: false, ..segment("alice", "hel") });
+        transcript.push(Segment { confidence: Some(0.1), ..segment("alice", "hello") });
+        assert!(transcript.segments().is_empty());
+    }
+
+    /// Pushes a final segment spoken by `voice`, returning the pieces as `push_voiced` does.
+    fn voiced(transcript: &mut SessionTranscript, speaker_id: &str, voice: [f32; 2]) -> Vec<Segment> {
+        transcript.push_voiced(segment(speaker_id, "word"), Some(voice.to_vec()), |_| true)
+    }
+
+    fn speakers(transcript: &SessionTranscript) -> Vec<&str> {
+        transcript.segments().iter().map(|s| s.speaker_id.as_str()).collect()
+    }
+
+    #[test]
+    fn a_mislabeled_segment_between_two_agreeing_neighbours_is_corrected() {
+        let mut transcript = SessionTranscript::new();
+        transcript.set_speaker_smoothing(3, 0.9);
+        voiced(&mut transcript, "alice", [1.0, 0.0]);
+        voiced(&mut transcript, "bob", [0.99, 0.1]);
+        let pieces = voiced(&mut transcript, "alice", [0.98, 0.05]);
+
+        // The relabelled segment is re-sent ahead of the new one
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[0].speaker_id, "alice");
+        assert_eq!(speakers(&transcript), ["alice", "alice", "alice"]);
+    }
+
+    #[test]
+    fn a_distinct_voice_or_disabled_smoothing_keeps_its_label() {
+        let mut transcript = SessionTranscript::new();
+        transcript.set_speaker_smoothing(3, 0.9);
+        voiced(&mut transcript, "alice", [1.0, 0.0]);
+        voiced(&mut transcript, "bob", [0.0, 1.0]);
+        assert_eq!(voiced(&mut transcript, "alice", [0.98, 0.05]).len(), 1);
+        assert_eq!(speakers(&transcript), ["alice", "bob", "alice"]);
+
+        let mut transcript = SessionTranscript::new();
+        voiced(&mut transcript, "alice", [1.0, 0.0]);
+        voiced(&mut transcript, "bob", [0.99, 0.1]);
+        voiced(&mut transcript, "alice", [0.98, 0.05]);
+        assert_eq!(speakers(&transcript), ["alice", "bob", "alice"], "off by default");
+    }
+
+    #[test]
+    fn lower_similarity_smooths_more_aggressively() {
+        let run = |min_similarity: f32| {
+            let mut transcript = SessionTranscript::new();
+            transcript.set_speaker_smoothing(5, min_similarity);
+            for (speaker, voice) in [("alice", [1.0, 0.0]), ("alice", [1.0, 0.1]), ("bob", [0.7, 0.7]), ("alice", [1.0, 0.0]), ("alice", [0.9, 0.1])] {
+                voiced(&mut transcript, speaker, voice);
+            }
+            transcript.segments()[2].speaker_id.clone()
+        };
+        assert_eq!(run(0.95), "bob");
+        assert_eq!(run(0.6), "alice");
+    }
+
+    #[test]
+    fn long_segments_are_split_between_words_within_the_maximum() {
+        let mut transcript = SessionTranscript::new();
+        transcript.set_max_segment_duration(20.0);
+        // Five minutes of one speaker, words of uneven length
+        let words: Vec<String> = (0..750).map(|i| "a".repeat(1 + i % 7)).collect();
+        let text = words.join(" ");
+        let pieces = transcript.push(timed("alice", &text, 10.0, 310.0));
+
+        assert!(pieces.len() >= 15, "{}", pieces.len());
+        assert!(pieces.iter().all(|p| p.end - p.start <= 20.0 + 1e-9 && p.speaker_id == "alice" && p.is_final));
+        assert_eq!((pieces[0].start, pieces.last().unwrap().end), (10.0, 310.0));
+        assert!(pieces.windows(2).all(|w| w[0].end == w[1].start));
+        // Whole words only: putting the pieces back together gives the text
+        let rejoined: Vec<&str> = pieces.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(rejoined.join(" "), text);
+        assert_eq!(transcript.segments(), pieces);
+    }
+
+    #[test]
+    fn long_segments_are_cut_at_a_clause_break_when_one_is_close() {
+        let mut transcript = SessionTranscript::new();
+        transcript.set_max_segment_duration(6.0);
+        // Eight four-character words over 8 s: one per second
+        let pieces = transcript.push(timed("alice", "aaaa bbbb ccc. dddd eeee ffff gggg hhhh", 0.0, 8.0));
+        let texts: Vec<&str> = pieces.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(texts, ["aaaa bbbb ccc.", "dddd eeee ffff gggg hhhh"]);
+        assert_eq!(pieces[0].end, 3.0);
+
+        // Too early in the window to be worth a short piece: the last word that fits wins
+        let pieces = transcript.push(timed("bob", "aaa. bbbb cccc dddd eeee ffff gggg hhhh", 8.0, 16.0));
+        assert_eq!(pieces.iter().map(|p| p.text.as_str()).collect::<Vec<_>>(), ["aaa. bbbb cccc dddd eeee ffff", "gggg hhhh"]);
+
+        // No words to cut between: plain cuts at the limit
+        let pieces = transcript.push(timed("carol", "", 16.0, 31.0));
+        assert_eq!(pieces.iter().map(|p| (p.start, p.end)).collect::<Vec<_>>(), [(16.0, 22.0), (22.0, 28.0), (28.0, 31.0)]);
+    }
+}
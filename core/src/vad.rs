@@ -0,0 +1,361 @@
+// Heuristic speech detection helpers.
+
+use crate::audio::AudioConfig;
+
+// Analysis frame for utterance classification
+const FRAME_MS: usize = 20;
+// Frames below this RMS count as silence
+const SILENCE_RMS: f32 = 0.005;
+// Speech swings in loudness at the syllable rate; tones and hum don't
+const MIN_ENERGY_MODULATION: f32 = 0.25;
+// Speech alternates voiced/unvoiced sounds, so its ZCR wanders a lot
+const MIN_ZCR_VARIATION: f32 = 0.2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeechClass {
+    Speech,
+    NonSpeech(String),
+}
+
+/// Classifies a whole utterance as speech or clearly non-speech (silence, tones, hum).
+///
+/// Errs on the side of `Speech`: only signals that are both stationary in loudness
+/// and in zero-crossing rate are rejected.
+pub fn classify_utterance(samples: &[f32], sample_rate: u32) -> SpeechClass {
+    let frame_len = (sample_rate as usize * FRAME_MS / 1000).max(1);
+    let frames: Vec<(f32, f32)> = samples
+        .chunks_exact(frame_len)
+        .map(|f| (rms(f), zero_crossing_rate(f)))
+        .collect();
+
+    if frames.is_empty() {
+        return SpeechClass::Speech;
+    }
+
+    let voiced: Vec<&(f32, f32)> = frames.iter().filter(|(r, _)| *r > SILENCE_RMS).collect();
+    if voiced.is_empty() {
+        return SpeechClass::NonSpeech("silence".to_string());
+    }
+
+    let energies: Vec<f32> = frames.iter().map(|(r, _)| *r).collect();
+    let zcrs: Vec<f32> = voiced.iter().map(|(_, z)| *z).collect();
+    let energy_modulation = coefficient_of_variation(&energies);
+    let zcr_variation = coefficient_of_variation(&zcrs);
+
+    if energy_modulation < MIN_ENERGY_MODULATION && zcr_variation < MIN_ZCR_VARIATION {
+        return SpeechClass::NonSpeech(format!(
+            "stationary signal (energy modulation {:.2}, zcr variation {:.2})",
+            energy_modulation, zcr_variation
+        ));
+    }
+
+    SpeechClass::Speech
+}
+
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+fn zero_crossing_rate(samples: &[f32]) -> f32 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let crossings = samples
+        .windows(2)
+        .filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0))
+        .count();
+    crossings as f32 / (samples.len() - 1) as f32
+}
+
+fn coefficient_of_variation(values: &[f32]) -> f32 {
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    if mean <= f32::EPSILON {
+        return 0.0;
+    }
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+    variance.sqrt() / mean
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VadEvent {
+    None,
+    SpeechStart,
+    /// Speech was followed by at least the minimum silence; the utterance can be finalized.
+    SpeechEnd,
+}
+
+/// Streaming energy VAD fed one chunk at a time.
+///
+/// Uses hysteresis: speech starts at `onset_threshold` but only counts as silent again
+/// below the lower `offset_threshold`, so levels hovering in between don't toggle state.
+/// Short pauses (stop consonants, breaths) don't end an utterance: silence must last
+/// `min_silence_ms` before `SpeechEnd` fires.
+pub struct Vad {
+    onset_threshold: f32,
+    offset_threshold: f32,
+    min_silence_samples: usize,
+    in_speech: bool,
+    silence_run: usize,
+    // Length of the silence run that ended the last utterance
+    ended_silence: usize,
+}
+
+impl Vad {
+    pub fn new(onset_threshold: f32, offset_threshold: f32, min_silence_ms: u32, sample_rate: u32) -> Self {
+        Self {
+            onset_threshold,
+            offset_threshold: offset_threshold.min(onset_threshold),
+            min_silence_samples: sample_rate as usize * min_silence_ms as usize / 1000,
+            in_speech: false,
+            silence_run: 0,
+            ended_silence: 0,
+        }
+    }
+
+    pub fn process(&mut self, chunk: &[f32]) -> VadEvent {
+        let level = rms(chunk);
+        let loud = if self.in_speech {
+            level >= self.offset_threshold
+        } else {
+            level >= self.onset_threshold
+        };
+
+        if loud {
+            self.silence_run = 0;
+            if !self.in_speech {
+                self.in_speech = true;
+                return VadEvent::SpeechStart;
+            }
+            return VadEvent::None;
+        }
+
+        if self.in_speech {
+            self.silence_run += chunk.len();
+            if self.silence_run >= self.min_silence_samples {
+                self.in_speech = false;
+                self.ended_silence = std::mem::take(&mut self.silence_run);
+                return VadEvent::SpeechEnd;
+            }
+        }
+        VadEvent::None
+    }
+
+    /// Samples of silence at the end of the utterance the last `SpeechEnd` closed.
+    pub fn trailing_silence(&self) -> usize {
+        self.ended_silence
+    }
+
+    pub fn is_speech(&self) -> bool {
+        self.in_speech
+    }
+
+    /// In speech, but waiting out a silence that may end it.
+    pub fn in_pause(&self) -> bool {
+        self.in_speech && self.silence_run > 0
+    }
+
+    pub fn reset(&mut self) {
+        self.in_speech = false;
+        self.silence_run = 0;
+    }
+}
+
+/// Why the endpointer closed an utterance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Speech was followed by `vad_min_silence_ms` of silence.
+    Silence,
+    /// The utterance reached `max_utterance_ms` first.
+    MaxDuration,
+}
+
+/// Decides where Utterance-mode utterances end: on trailing silence (with `vad_enabled`)
+/// or at `max_utterance_ms`, whichever comes first.
+///
+/// With the VAD on, the duration counts from speech onset, so silence waited out before
+/// anyone speaks never uses up the cap; without it, from the last endpoint. Speech cut by
+/// the cap carries on into the next utterance without needing a new onset. The cap waits
+/// out a pause in speech rather than cutting it into utterances of silence: the silence
+/// ends it, or it falls on the chunk where speech resumes.
+pub struct Endpointer {
+    vad: Vad,
+    sample_rate: u32,
+    // Samples counted against the cap since the utterance started
+    elapsed: usize,
+    // Samples fed, with the VAD on, since the last endpoint or reset without speech starting
+    waiting: usize,
+}
+
+impl Endpointer {
+    /// The VAD thresholds are taken from `config` now; the rules (whether the VAD is on,
+    /// the cap) are read per chunk, so they can change mid-stream.
+    pub fn new(config: &AudioConfig, sample_rate: u32) -> Self {
+        Self {
+            vad: Vad::new(config.vad_onset_threshold, config.vad_offset_threshold, config.vad_min_silence_ms, sample_rate),
+            sample_rate,
+            elapsed: 0,
+            waiting: 0,
+        }
+    }
+
+    /// Feeds the chunk just appended to the utterance; returns why it ends there, if it does.
+    pub fn process(&mut self, chunk: &[f32], config: &AudioConfig) -> Option<Endpoint> {
+        if config.vad_enabled {
+            match self.vad.process(chunk) {
+                VadEvent::SpeechEnd => {
+                    self.elapsed = 0;
+                    return Some(Endpoint::Silence);
+                }
+                VadEvent::SpeechStart => (self.elapsed, self.waiting) = (0, 0),
+                VadEvent::None if !self.vad.is_speech() => {
+                    self.waiting += chunk.len();
+                    return None;
+                }
+                VadEvent::None => {}
+            }
+        }
+        self.elapsed += chunk.len();
+        let max_samples = self.sample_rate as usize * config.max_utterance_ms as usize / 1000;
+        if max_samples > 0 && self.elapsed >= max_samples && !self.vad.in_pause() {
+            self.elapsed = 0;
+            return Some(Endpoint::MaxDuration);
+        }
+        None
+    }
+
+    /// Samples of silence at the end of the utterance the last `Endpoint::Silence` closed.
+    pub fn trailing_silence(&self) -> usize {
+        self.vad.trailing_silence()
+    }
+
+    pub fn is_speech(&self) -> bool {
+        self.vad.is_speech()
+    }
+
+    /// Samples fed while waiting for speech onset, this chunk included; 0 once it starts.
+    pub fn waited_for_onset(&self) -> usize {
+        self.waiting
+    }
+
+    pub fn reset(&mut self) {
+        self.vad.reset();
+        self.elapsed = 0;
+        self.waiting = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 16000;
+
+    fn tone(hz: f32, secs: f32) -> Vec<f32> {
+        (0..(RATE as f32 * secs) as usize).map(|i| 0.3 * (2.0 * PI * hz * i as f32 / RATE as f32).sin()).collect()
+    }
+
+    #[test]
+    fn pure_tone_is_non_speech() {
+        assert!(matches!(classify_utterance(&tone(440.0, 1.0), RATE), SpeechClass::NonSpeech(_)));
+    }
+
+    #[test]
+    fn syllable_modulated_signal_is_speech() {
+        // A 150 Hz voice swelling and fading four times a second
+        let speech: Vec<f32> = tone(150.0, 1.0)
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * (0.5 - 0.5 * (2.0 * PI * 4.0 * i as f32 / RATE as f32).cos()))
+            .collect();
+        assert_eq!(classify_utterance(&speech, RATE), SpeechClass::Speech);
+    }
+
+    #[test]
+    fn silence_is_non_speech() {
+        assert!(matches!(classify_utterance(&vec![0.0; RATE as usize], RATE), SpeechClass::NonSpeech(_)));
+    }
+
+    /// Events from feeding `signal` to `vad` in 20ms chunks.
+    fn events(vad: &mut Vad, signal: &[f32]) -> Vec<VadEvent> {
+        signal.chunks(RATE as usize / 50).map(|c| vad.process(c)).filter(|e| *e != VadEvent::None).collect()
+    }
+
+    #[test]
+    fn short_gap_within_speech_does_not_end_the_utterance() {
+        let mut vad = Vad::new(0.05, 0.02, 600, RATE);
+        // "stop" - 150ms plosive gap - "sign"
+        let mut signal = tone(150.0, 0.4);
+        signal.extend(vec![0.0; RATE as usize * 150 / 1000]);
+        signal.extend(tone(150.0, 0.4));
+        assert_eq!(events(&mut vad, &signal), [VadEvent::SpeechStart]);
+        assert!(vad.is_speech());
+    }
+
+    #[test]
+    fn pause_longer_than_the_minimum_silence_ends_the_utterance() {
+        let mut vad = Vad::new(0.05, 0.02, 600, RATE);
+        let mut signal = tone(150.0, 0.4);
+        signal.extend(vec![0.0; RATE as usize * 700 / 1000]);
+        assert_eq!(events(&mut vad, &signal), [VadEvent::SpeechStart, VadEvent::SpeechEnd]);
+        assert!(!vad.is_speech());
+        assert_eq!(vad.trailing_silence(), RATE as usize * 600 / 1000);
+    }
+
+    #[test]
+    fn level_between_the_thresholds_holds_the_current_state() {
+        // Chunks whose RMS sits between offset (0.02) and onset (0.05)
+        let hovering = vec![0.035; 320];
+        let loud = vec![0.2; 320];
+
+        let mut vad = Vad::new(0.05, 0.02, 0, RATE);
+        // Silent: hovering never reaches onset
+        assert!((0..50).all(|_| vad.process(&hovering) == VadEvent::None));
+        assert!(!vad.is_speech());
+
+        // In speech: hovering never drops below offset
+        assert_eq!(vad.process(&loud), VadEvent::SpeechStart);
+        assert!((0..50).all(|_| vad.process(&hovering) == VadEvent::None));
+        assert!(vad.is_speech());
+    }
+
+    #[test]
+    fn endpointer_ends_on_silence_or_the_duration_cap_whichever_is_first() {
+        let config = AudioConfig { vad_enabled: true, max_utterance_ms: 300, ..Default::default() };
+        let mut endpointer = Endpointer::new(&config, RATE);
+        let (speech, silence) = ([0.3f32; 480], [0.0f32; 480]);
+
+        // Silence before onset counts towards neither rule, however long
+        assert!((0..100).all(|_| endpointer.process(&silence, &config).is_none()));
+        assert_eq!(endpointer.waited_for_onset(), 100 * 480);
+
+        // 300 ms of speech from onset hit the cap; speech goes on without a new onset
+        let ends: Vec<_> = (0..25).map(|_| endpointer.process(&speech, &config)).collect();
+        assert_eq!(endpointer.waited_for_onset(), 0);
+        assert_eq!(ends.iter().position(Option::is_some), Some(9));
+        assert_eq!(ends.iter().flatten().collect::<Vec<_>>(), [&Endpoint::MaxDuration; 2]);
+
+        // The 5 chunks left pass the cap in the pause that follows; it waits, and the
+        // 600 ms of silence end the utterance
+        let ends: Vec<_> = (0..20).map(|_| endpointer.process(&silence, &config)).collect();
+        assert_eq!(ends.iter().position(Option::is_some), Some(19));
+        assert_eq!(ends[19], Some(Endpoint::Silence));
+        assert_eq!(endpointer.trailing_silence(), 20 * 480);
+
+        // A pause shorter than that: the cap falls where speech resumes
+        let mut ends: Vec<_> = (0..8).map(|_| endpointer.process(&speech, &config)).collect();
+        ends.extend((0..5).map(|_| endpointer.process(&silence, &config)));
+        ends.push(endpointer.process(&speech, &config));
+        assert_eq!(ends.iter().position(Option::is_some), Some(13));
+        assert_eq!(ends[13], Some(Endpoint::MaxDuration));
+
+        // Without the VAD the cap counts every chunk
+        let config = AudioConfig { max_utterance_ms: 90, ..Default::default() };
+        let mut endpointer = Endpointer::new(&config, RATE);
+        let ends: Vec<_> = (0..6).map(|_| endpointer.process(&silence, &config)).collect();
+        assert_eq!(ends, [None, None, Some(Endpoint::MaxDuration), None, None, Some(Endpoint::MaxDuration)]);
+    }
+}
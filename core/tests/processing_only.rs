@@ -0,0 +1,28 @@
+// What a server build gets: the processing paths work with or without the `capture`
+// feature, and live capture fails cleanly when it's compiled out.
+
+use suprasonic_core::state::AppState;
+
+fn state(name: &str) -> AppState {
+    let path = std::env::temp_dir().join(format!("suprasonic-{}-{}.json", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    AppState::new(path.to_string_lossy().into_owned())
+}
+
+#[test]
+fn pushed_samples_are_processed() {
+    let state = state("push");
+    state.push_samples(vec![0.1; 48000], 48000).unwrap();
+    state.shutdown();
+    let processed: usize = std::iter::from_fn(|| state.poll_audio()).map(|b| b.len()).sum();
+    // One second at the 16 kHz output rate
+    assert!(processed >= 16000, "{processed} samples");
+}
+
+#[cfg(not(feature = "capture"))]
+#[test]
+fn live_capture_is_unavailable() {
+    let state = state("no-capture");
+    assert!(state.list_input_devices().is_empty());
+    assert!(state.start_recording().is_err());
+}